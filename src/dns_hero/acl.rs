@@ -0,0 +1,109 @@
+use std::net::IpAddr;
+
+/// Which clients may do something, like transfer a zone: the ones at certain
+/// addresses, and the ones that sign with certain TSIG keys. An empty list lets
+/// nobody in.
+#[derive(Clone)]
+pub struct Acl {
+    networks: Vec<Network>,
+    /// Key names, lowercase and without a trailing dot.
+    keys: Vec<String>,
+}
+
+#[derive(Clone)]
+struct Network {
+    address: IpAddr,
+    prefix_length: u8,
+}
+
+impl Default for Acl {
+    fn default() -> Acl {
+        Acl::new()
+    }
+}
+
+impl Acl {
+    pub fn new() -> Acl {
+        Acl {
+            networks: vec![],
+            keys: vec![],
+        }
+    }
+
+    /// Reads a list of addresses, networks, and keys, like `10.0.0.0/8 ::1
+    /// key:transfer-key`. `any` lets everyone in.
+    pub fn parse(args: &[String]) -> Result<Acl, String> {
+        let mut acl = Acl::new();
+
+        for arg in args {
+            if arg == "any" {
+                acl.networks.push(Network { address: "0.0.0.0".parse().unwrap(), prefix_length: 0 });
+                acl.networks.push(Network { address: "::".parse().unwrap(), prefix_length: 0 });
+                continue;
+            }
+
+            if let Some(key) = arg.strip_prefix("key:") {
+                if key.is_empty() {
+                    return Err(String::from("key: needs a key name"));
+                }
+                acl.keys.push(key.trim_end_matches('.').to_lowercase());
+                continue;
+            }
+
+            let (address, prefix_length) = match arg.split_once('/') {
+                Some((address, prefix_length)) => (address, Some(prefix_length)),
+                None => (arg.as_str(), None),
+            };
+
+            let address: IpAddr = address.parse().map_err(|_| format!("Bad address {}", arg))?;
+            let max_length = if address.is_ipv4() { 32 } else { 128 };
+            let prefix_length = match prefix_length {
+                Some(length) => length.parse::<u8>().ok().filter(|length| *length <= max_length).ok_or(format!("Bad network {}", arg))?,
+                None => max_length,
+            };
+
+            acl.networks.push(Network { address, prefix_length });
+        }
+
+        Ok(acl)
+    }
+
+    /// Whether a client gets in, by its address or the key it signed with, if any.
+    pub fn allows(&self, address: IpAddr, key: Option<&String>) -> bool {
+        let address = canonical_address(address);
+        let has_key = key.map(|key| self.keys.contains(key)).unwrap_or(false);
+        has_key || self.networks.iter().any(|network| network.contains(address))
+    }
+}
+
+impl Network {
+    fn contains(&self, address: IpAddr) -> bool {
+        let (network, address) = match (self.address, address) {
+            (IpAddr::V4(network), IpAddr::V4(address)) => (network.octets().to_vec(), address.octets().to_vec()),
+            (IpAddr::V6(network), IpAddr::V6(address)) => (network.octets().to_vec(), address.octets().to_vec()),
+            _ => return false,
+        };
+
+        let whole_bytes = self.prefix_length as usize / 8;
+        let extra_bits = self.prefix_length % 8;
+        if network[..whole_bytes] != address[..whole_bytes] {
+            return false;
+        }
+
+        if extra_bits == 0 {
+            return true;
+        }
+
+        let mask = 0xFFu8 << (8 - extra_bits);
+        network[whole_bytes] & mask == address[whole_bytes] & mask
+    }
+}
+
+/// The IPv4 address inside an IPv4-mapped IPv6 one, like a dual-stack socket
+/// gives us, or the address as it is.
+pub fn canonical_address(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(address),
+        IpAddr::V4(_) => address,
+    }
+}
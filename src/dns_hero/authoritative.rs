@@ -0,0 +1,581 @@
+use std::collections::{ HashMap, HashSet };
+use std::fs;
+use std::sync::{ Arc, Mutex, MutexGuard, RwLock };
+use std::time::SystemTime;
+
+use super::acl::Acl;
+use super::data::*;
+use super::enums::*;
+use super::journal::*;
+use super::transfer::{ serial_is_newer, soa_serial };
+use super::util::*;
+use super::zone::{ read_zone_file, save_zone };
+
+/// How many CNAMEs in a row are followed inside a zone before giving up on a loop.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// A zone we're the authority for.
+#[derive(Clone)]
+pub struct Zone {
+    /// Lowercase, without a trailing dot. Empty for the root.
+    pub origin: String,
+    /// Records by lowercased owner name.
+    records: HashMap<String, Vec<Resource>>,
+    /// Every name in the zone, plus the empty non-terminals between them and the
+    /// origin, so we can tell NODATA from NXDOMAIN.
+    names: HashSet<String>,
+    soa: Resource,
+}
+
+/// What a zone has to say about a question.
+pub struct ZoneAnswer {
+    pub response_code: ResponseCode,
+    /// False for referrals, where the answer is really the child zone's to give.
+    pub authoritative: bool,
+    pub answers: Vec<Resource>,
+    pub authorities: Vec<Resource>,
+    pub additional_records: Vec<Resource>,
+}
+
+impl ZoneAnswer {
+    fn new() -> ZoneAnswer {
+        ZoneAnswer {
+            response_code: ResponseCode::NoError,
+            authoritative: true,
+            answers: vec![],
+            authorities: vec![],
+            additional_records: vec![],
+        }
+    }
+
+    pub fn refused() -> ZoneAnswer {
+        ZoneAnswer {
+            response_code: ResponseCode::Refused,
+            authoritative: false,
+            ..ZoneAnswer::new()
+        }
+    }
+
+    /// For a zone we can't vouch for at the moment.
+    pub fn failed() -> ZoneAnswer {
+        ZoneAnswer {
+            response_code: ResponseCode::ServFail,
+            authoritative: false,
+            ..ZoneAnswer::new()
+        }
+    }
+
+    /// Just the zone's SOA, as the answer.
+    pub fn soa_only(zone: &Zone) -> ZoneAnswer {
+        ZoneAnswer {
+            answers: vec![zone.soa.clone()],
+            ..ZoneAnswer::new()
+        }
+    }
+}
+
+impl Zone {
+    /// Builds a zone from its records, which must include exactly one SOA, at the
+    /// origin, and nothing from outside the zone.
+    #[allow(clippy::ptr_arg)]
+    pub fn new(origin: &String, records: Vec<Resource>) -> Result<Zone, String> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        let mut by_name: HashMap<String, Vec<Resource>> = HashMap::new();
+        let mut names = HashSet::new();
+        let mut soa = None;
+
+        for record in records {
+            let name = owner_name(&record).ok_or("Record without a name")?;
+
+            if !is_within(&name, &origin) {
+                return Err(format!("{} is outside of zone {}", name, origin));
+            }
+
+            if record.rtype == Type::SOA {
+                if name != origin {
+                    return Err(format!("SOA for {} in zone {}", name, origin));
+                }
+                if soa.is_some() {
+                    return Err(format!("More than one SOA in zone {}", origin));
+                }
+                soa = Some(record.clone());
+            }
+
+            // Add the name and everything between it and the origin.
+            let mut ancestor = name.clone();
+            while names.insert(ancestor.clone()) && ancestor != origin {
+                ancestor = parent_name(&ancestor);
+            }
+
+            by_name.entry(name).or_default().push(record);
+        }
+
+        Ok(Zone {
+            soa: soa.ok_or(format!("No SOA in zone {}", origin))?,
+            origin,
+            records: by_name,
+            names,
+        })
+    }
+
+    pub fn load(path: &String, origin: &String) -> Result<Zone, String> {
+        let records = read_zone_file(path, origin).map_err(|err| err.to_string())?;
+        Zone::new(origin, records).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        is_within(name, &self.origin)
+    }
+
+    pub fn soa(&self) -> &Resource {
+        &self.soa
+    }
+
+    pub fn serial(&self) -> u32 {
+        soa_serial(&self.soa).unwrap_or(0)
+    }
+
+    /// Every record in the zone, SOA first and the rest sorted by name, the way a
+    /// transfer sends them.
+    pub fn records(&self) -> Vec<Resource> {
+        let mut names: Vec<&String> = self.records.keys().collect();
+        names.sort();
+
+        let mut records = vec![self.soa.clone()];
+        for name in names {
+            records.extend(self.records[name].iter().filter(|record| record.rtype != Type::SOA).cloned());
+        }
+
+        records
+    }
+
+    /// The records of one type at a name, with no CNAMEs or wildcards involved.
+    pub fn rrset(&self, name: &str, rtype: Type) -> Vec<Resource> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.records.get(&name).into_iter().flatten()
+            .filter(|record| record.rtype == rtype)
+            .cloned()
+            .collect()
+    }
+
+    /// Whether a name exists in the zone, even if only as an empty non-terminal.
+    pub fn has_name(&self, name: &str) -> bool {
+        self.names.contains(name)
+    }
+
+    /// Every record of one type, wherever it is in the zone.
+    pub fn records_of_type(&self, rtype: Type) -> Vec<&Resource> {
+        self.records.values().flatten().filter(|record| record.rtype == rtype).collect()
+    }
+
+    /// Answers a question for a name in this zone, following RFC 1034 section 4.3.2:
+    /// delegations first, then exact matches (chasing CNAMEs that stay in the
+    /// zone), then wildcards, and NXDOMAIN or NODATA with the SOA otherwise.
+    pub fn lookup(&self, question: &Question) -> ZoneAnswer {
+        let mut answer = ZoneAnswer::new();
+        let mut owner = question.label.clone();
+        let mut name = match &question.label {
+            Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+            Label::Pointer(_) => {
+                answer.response_code = ResponseCode::FormErr;
+                return answer;
+            },
+        };
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            if !self.contains(&name) {
+                // The chain left the zone. The client's resolver can take it from here.
+                return answer;
+            }
+
+            if let Some(cut) = self.find_cut(&name, question.qtype) {
+                if answer.answers.is_empty() {
+                    self.refer(&cut, &mut answer);
+                }
+                return answer;
+            }
+
+            let records = match self.records.get(&name) {
+                Some(records) => records.clone(),
+                None if self.names.contains(&name) => {
+                    // An empty non-terminal. It exists; it just has no records.
+                    self.deny(ResponseCode::NoError, &mut answer);
+                    return answer;
+                },
+                None => match self.find_wildcard(&name) {
+                    Some(records) => records,
+                    None => {
+                        self.deny(ResponseCode::NXDomain, &mut answer);
+                        return answer;
+                    },
+                },
+            };
+
+            let matching: Vec<Resource> = records.iter()
+                .filter(|record| record.rtype == question.qtype || question.qtype == Type::ANY)
+                .map(|record| with_owner(record, &owner))
+                .collect();
+
+            if !matching.is_empty() {
+                self.add_glue(&matching, &mut answer);
+                answer.answers.extend(matching);
+                return answer;
+            }
+
+            let cname = match records.iter().find(|record| record.rtype == Type::CNAME) {
+                Some(cname) => cname,
+                None => {
+                    self.deny(ResponseCode::NoError, &mut answer);
+                    return answer;
+                },
+            };
+
+            answer.answers.push(with_owner(cname, &owner));
+            name = match read_name(&cname.data, 0) {
+                Some((target, _)) => target.to_lowercase(),
+                None => return answer,
+            };
+            owner = Label::Domain(name.clone());
+        }
+
+        answer
+    }
+
+    /// Finds the highest zone cut at or above `name`, if the name's been delegated
+    /// away. DS records live on the parent's side of a cut, so they're ours to answer.
+    fn find_cut(&self, name: &str, qtype: Type) -> Option<String> {
+        let relative = match name.len().checked_sub(self.origin.len()) {
+            Some(0) | None => return None,
+            Some(length) => name[..length].trim_end_matches('.'),
+        };
+
+        let mut cut = self.origin.clone();
+        for label in relative.split('.').rev() {
+            cut = if cut.is_empty() { label.to_string() } else { format!("{}.{}", label, cut) };
+
+            if cut == name && qtype == Type::DS {
+                return None;
+            }
+
+            let has_ns = self.records.get(&cut)
+                .map(|records| records.iter().any(|record| record.rtype == Type::NS))
+                .unwrap_or(false);
+
+            if has_ns {
+                return Some(cut);
+            }
+        }
+
+        None
+    }
+
+    /// Points the client at a child zone's name servers, with their addresses when
+    /// we have them.
+    fn refer(&self, cut: &String, answer: &mut ZoneAnswer) {
+        let name_servers: Vec<Resource> = self.records[cut].iter()
+            .filter(|record| record.rtype == Type::NS)
+            .cloned()
+            .collect();
+
+        answer.authoritative = false;
+        self.add_glue(&name_servers, answer);
+        answer.authorities.extend(name_servers);
+    }
+
+    /// Finds the wildcard that would match a name that doesn't exist: `*.` plus the
+    /// closest ancestor of the name that does exist.
+    fn find_wildcard(&self, name: &str) -> Option<Vec<Resource>> {
+        self.records.get(&wildcard_at(&self.closest_encloser(name)?)).cloned()
+    }
+
+    /// The closest ancestor of a name that exists in the zone.
+    pub fn closest_encloser(&self, name: &str) -> Option<String> {
+        let mut encloser = parent_name(name);
+        while !self.names.contains(&encloser) {
+            if encloser == self.origin || encloser.is_empty() {
+                return None;
+            }
+            encloser = parent_name(&encloser);
+        }
+
+        Some(encloser)
+    }
+
+    /// Says there's nothing to be had, with the SOA so it can be cached for a while.
+    fn deny(&self, response_code: ResponseCode, answer: &mut ZoneAnswer) {
+        let mut soa = self.soa.clone();
+        if soa.data.len() >= 4 {
+            soa.ttl = soa.ttl.min(get_u32(&soa.data, soa.data.len() - 4));
+        }
+
+        answer.response_code = response_code;
+        answer.authorities.push(soa);
+    }
+
+    /// Adds the addresses of the names NS, MX, and SRV records point at, when
+    /// they're in this zone, so the client doesn't have to look them up.
+    fn add_glue(&self, records: &Vec<Resource>, answer: &mut ZoneAnswer) {
+        for record in records {
+            let target_offset = match record.rtype {
+                Type::NS => 0,
+                Type::MX => 2,
+                Type::SRV => 6,
+                _ => continue,
+            };
+
+            let target = match read_name(&record.data, target_offset) {
+                Some((target, _)) => target.to_lowercase(),
+                None => continue,
+            };
+
+            for address in self.records.get(&target).into_iter().flatten() {
+                let is_address = address.rtype == Type::A || address.rtype == Type::AAAA;
+                let is_new = !answer.additional_records.iter().any(|existing| existing.rtype == address.rtype && existing.data == address.data && existing.label.eq_ignore_case(&address.label));
+                if is_address && is_new {
+                    answer.additional_records.push(address.clone());
+                }
+            }
+        }
+    }
+}
+
+/// A zone we serve, along with what we need to hand it out to secondaries.
+#[derive(Clone)]
+pub struct HostedZone {
+    pub           zone: Zone,
+    pub        journal: Journal,
+    pub allow_transfer: Acl,
+    /// Who may change the zone with UPDATE.
+    pub   allow_update: Acl,
+    /// Secondaries to send a NOTIFY to when the zone changes.
+    pub         notify: Vec<String>,
+    /// The master file, for zones we're the primary for. Secondary zones don't
+    /// have one.
+    pub           file: Option<String>,
+    /// Set on a secondary zone whose primaries have been out of reach for too
+    /// long. We keep the copy, but don't answer from it.
+    pub        expired: bool,
+    /// When the file was last changed, as of our last look at it.
+    modified: Option<SystemTime>,
+}
+
+impl HostedZone {
+    /// Loads a zone from its master file, along with its journal, which lives
+    /// next to it with `.jnl` on the end.
+    pub fn load(file: &String, origin: &String, allow_transfer: Acl, allow_update: Acl, notify: Vec<String>) -> Result<HostedZone, String> {
+        let modified = modified_time(file);
+        let zone = Zone::load(file, origin)?;
+        let journal = Journal::load(&journal_path(file), &zone.origin, zone.serial());
+
+        Ok(HostedZone {
+            zone,
+            journal,
+            allow_transfer,
+            allow_update,
+            notify,
+            file: Some(file.clone()),
+            expired: false,
+            modified,
+        })
+    }
+
+    /// A copy of a zone we got from its primary.
+    pub fn transferred(zone: Zone, journal: Journal, allow_transfer: Acl, notify: Vec<String>) -> HostedZone {
+        HostedZone {
+            zone,
+            journal,
+            allow_transfer,
+            allow_update: Acl::new(),
+            notify,
+            file: None,
+            expired: false,
+            modified: None,
+        }
+    }
+
+    /// A new copy of the zone with these records instead. The change goes in the
+    /// journal, and back into the master file, if the zone has one.
+    pub fn changed(&self, records: Vec<Resource>) -> Result<HostedZone, String> {
+        let zone = Zone::new(&self.zone.origin, records)?;
+        let mut changed = self.clone();
+
+        if let Some(diff) = Diff::between(&self.zone.records(), &zone.records()) {
+            changed.journal.record(diff);
+        }
+
+        if let Some(file) = &self.file {
+            save_zone(file, &zone.records()).map_err(|err| format!("Failed to save {}: {}", file, err))?;
+            // So the reload check doesn't take our own write for an edit.
+            changed.modified = modified_time(file);
+        }
+
+        changed.zone = zone;
+        Ok(changed)
+    }
+
+    /// Reads the zone file again if it's changed since we last looked, journaling
+    /// the difference. None means there's nothing new to serve.
+    fn reload(&self) -> Option<HostedZone> {
+        let file = self.file.as_ref()?;
+        let modified = modified_time(file);
+        if modified == self.modified {
+            return None;
+        }
+
+        let mut reloaded = self.clone();
+        reloaded.modified = modified;
+
+        let zone = match Zone::load(file, &self.zone.origin) {
+            Ok(zone) => zone,
+            Err(err) => {
+                println!("Keeping the old copy of {}: {}", self.zone.origin, err);
+                return Some(reloaded);
+            },
+        };
+
+        if !serial_is_newer(zone.serial(), self.zone.serial()) {
+            println!("{} changed, but its serial didn't go up from {}, so it's staying as it was", self.zone.origin, self.zone.serial());
+            return Some(reloaded);
+        }
+
+        println!("Reloaded {} at serial {}", zone.origin, zone.serial());
+        if let Some(diff) = Diff::between(&self.zone.records(), &zone.records()) {
+            reloaded.journal.record(diff);
+        }
+        reloaded.zone = zone;
+        Some(reloaded)
+    }
+}
+
+/// The zones we serve. Each one is swapped out whole when it changes, so anyone
+/// still in the middle of answering from the old copy can finish with it.
+pub struct Zones {
+    zones: RwLock<Vec<Arc<HostedZone>>>,
+    /// Held by anything that makes a new copy of a zone from the current one, so
+    /// two changes at once can't lose one another.
+    changes: Mutex<()>,
+}
+
+impl Zones {
+    pub fn new(zones: Vec<HostedZone>) -> Zones {
+        Zones {
+            zones: RwLock::new(zones.into_iter().map(Arc::new).collect()),
+            changes: Mutex::new(()),
+        }
+    }
+
+    pub fn lock_changes(&self) -> MutexGuard<'_, ()> {
+        self.changes.lock().unwrap()
+    }
+
+    /// Finds the most specific zone a name belongs to.
+    pub fn find(&self, name: &str) -> Option<Arc<HostedZone>> {
+        let name = name.trim_end_matches('.').to_lowercase();
+        self.zones.read().unwrap().iter()
+            .filter(|hosted| hosted.zone.contains(&name))
+            .max_by_key(|hosted| hosted.zone.origin.len())
+            .cloned()
+    }
+
+    /// Finds the zone that answers a question about a name. That's the most specific
+    /// one, except for a DS at a zone's apex, which belongs to the parent zone, if
+    /// we have that too (RFC 4035 section 3.1.4.1).
+    pub fn find_for(&self, name: &str, qtype: Type) -> Option<Arc<HostedZone>> {
+        let hosted = self.find(name)?;
+        let is_apex = hosted.zone.origin == name.trim_end_matches('.').to_lowercase();
+        if qtype == Type::DS && is_apex && !hosted.zone.origin.is_empty() {
+            if let Some(parent) = self.find(&parent_name(&hosted.zone.origin)) {
+                return Some(parent);
+            }
+        }
+
+        Some(hosted)
+    }
+
+    /// Finds the zone with exactly this origin.
+    pub fn get(&self, origin: &str) -> Option<Arc<HostedZone>> {
+        let origin = origin.trim_end_matches('.').to_lowercase();
+        self.zones.read().unwrap().iter()
+            .find(|hosted| hosted.zone.origin == origin)
+            .cloned()
+    }
+
+    /// Swaps in a new copy of a zone, in place of the one with the same origin, or
+    /// adds it if we didn't have it yet.
+    pub fn insert(&self, hosted: Arc<HostedZone>) {
+        let mut zones = self.zones.write().unwrap();
+        match zones.iter_mut().find(|existing| existing.zone.origin == hosted.zone.origin) {
+            Some(slot) => *slot = hosted,
+            None => zones.push(hosted),
+        }
+    }
+
+    /// Stops or starts answering from a zone, without forgetting it.
+    pub fn set_expired(&self, origin: &str, expired: bool) {
+        let _changing = self.lock_changes();
+        if let Some(hosted) = self.get(origin) {
+            let mut hosted = (*hosted).clone();
+            hosted.expired = expired;
+            self.insert(Arc::new(hosted));
+        }
+    }
+
+    /// Picks up any zone files that have changed on disk, and hands back the zones
+    /// that have a new serial.
+    pub fn reload_changed(&self) -> Vec<Arc<HostedZone>> {
+        let _changing = self.lock_changes();
+        let current: Vec<Arc<HostedZone>> = self.zones.read().unwrap().clone();
+        let mut changed = vec![];
+
+        for hosted in current {
+            if let Some(reloaded) = hosted.reload() {
+                let reloaded = Arc::new(reloaded);
+                self.insert(reloaded.clone());
+                if reloaded.zone.serial() != hosted.zone.serial() {
+                    changed.push(reloaded);
+                }
+            }
+        }
+
+        changed
+    }
+}
+
+fn modified_time(path: &String) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+pub fn owner_name(record: &Resource) -> Option<String> {
+    match &record.label {
+        Label::Domain(name) => Some(name.trim_end_matches('.').to_lowercase()),
+        Label::Pointer(_) => None,
+    }
+}
+
+pub fn wildcard_at(encloser: &str) -> String {
+    if encloser.is_empty() { String::from("*") } else { format!("*.{}", encloser) }
+}
+
+pub fn with_owner(record: &Resource, owner: &Label) -> Resource {
+    let mut record = record.clone();
+    record.label = owner.clone();
+    record
+}
+
+pub fn is_within(name: &str, origin: &str) -> bool {
+    origin.is_empty() || name == origin || name.ends_with(&format!(".{}", origin))
+}
+
+pub fn parent_name(name: &str) -> String {
+    match name.find('.') {
+        Some(dot) => name[dot + 1..].to_string(),
+        None => String::new(),
+    }
+}
+
+/// Where a DNAME sends a name below its owner: the labels under the owner, moved
+/// over to the replacement. Names go without the trailing dot, so the root is "".
+pub fn dname_target(name: &str, owner: &str, replacement: &str) -> String {
+    let prefix = if owner.is_empty() { name } else { &name[..name.len() - owner.len() - 1] };
+    if replacement.is_empty() { prefix.to_string() } else { format!("{}.{}", prefix, replacement) }
+}
@@ -0,0 +1,459 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::time::{ Duration, Instant };
+
+use super::data::{ Packet, RecordLocation, Section };
+use super::enums::*;
+use super::util::*;
+
+pub const DEFAULT_CACHE_CAPACITY: usize = 4096;
+
+/// Upstreams occasionally hand out absurd TTLs. Nothing is kept longer than this.
+pub const MAX_CACHE_TTL: u32 = 86400;
+
+/// RFC 2308 suggests capping negative answers at a few hours.
+pub const MAX_NEGATIVE_CACHE_TTL: u32 = 10800;
+
+/// How long past expiry an entry may still be served when upstreams are down (RFC 8767).
+pub const MAX_STALE_AGE: u64 = 86400;
+
+/// The TTL handed out on stale answers, per RFC 8767's recommendation.
+pub const STALE_TTL: u32 = 30;
+
+/// Entries hit at least this many times get refreshed before they expire.
+pub const PREFETCH_MIN_HITS: u32 = 3;
+
+/// Identifies a cache snapshot file. Bump the version whenever the layout changes;
+/// snapshots with any other version are ignored.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"DNSHCACH";
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// What a cached response is filed under.
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub      name: String,
+    pub     qtype: u16,
+    pub    qclass: u16,
+    pub dnssec_ok: bool,
+}
+
+impl CacheKey {
+    /// Builds the key for a raw query. Only single-question queries are cacheable,
+    /// so anything else (or anything malformed) gives None.
+    pub fn from_query(query: &Packet) -> Option<CacheKey> {
+        if query.data.len() < 12 || query.get_question_count() != 1 {
+            return None;
+        }
+
+        let (name, index) = read_name(&query.data, 12)?;
+        if index + 4 > query.data.len() {
+            return None;
+        }
+
+        let dnssec_ok = query.locate_records()?.iter().any(|record| {
+            record.rtype == Type::OPT.to_u16() && get_u16(&query.data, record.ttl_offset + 2) & 0x8000 != 0
+        });
+
+        Some(CacheKey {
+            name: name.to_lowercase(),
+            qtype: get_u16(&query.data, index),
+            qclass: get_u16(&query.data, index + 2),
+            dnssec_ok,
+        })
+    }
+}
+
+struct CacheEntry {
+    response: Vec<u8>,
+    inserted: Instant,
+    last_used: Instant,
+    ttl: u32,
+    hits: u32,
+    prefetching: bool,
+}
+
+impl CacheEntry {
+    fn age(&self, now: Instant) -> u64 {
+        now.duration_since(self.inserted).as_secs()
+    }
+
+    fn is_fresh(&self, now: Instant) -> bool {
+        self.age(now) < self.ttl as u64
+    }
+
+    fn is_servable(&self, now: Instant) -> bool {
+        self.age(now) < self.ttl as u64 + MAX_STALE_AGE
+    }
+}
+
+/// A bounded, in-memory store of upstream responses.
+///
+/// Responses are kept as raw packets. On the way out, the ID is swapped for the
+/// client's and every TTL is reduced by however long the entry has been sitting
+/// here, so clients never see data outlive what the upstream promised.
+///
+/// Expired entries hang around for a while in case the upstreams go away, in which
+/// case they can still be served (marked with a short TTL) rather than failing.
+pub struct Cache {
+    capacity: usize,
+    entries: HashMap<CacheKey, CacheEntry>,
+}
+
+impl Cache {
+    pub fn new(capacity: usize) -> Cache {
+        Cache {
+            capacity,
+            entries: HashMap::new(),
+        }
+    }
+
+    /// Looks up a live entry and returns it ready to send to the client with the given ID.
+    pub fn get(&mut self, key: &CacheKey, id: u16) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let entry = self.entries.get_mut(key)?;
+        if !entry.is_fresh(now) {
+            return None;
+        }
+
+        entry.last_used = now;
+        entry.hits += 1;
+
+        let age = entry.age(now) as u32;
+        restamp_response(&entry.response, id, |ttl| ttl.saturating_sub(age))
+    }
+
+    /// Looks up an expired entry that's still within the serve-stale window, for
+    /// when the upstreams can't be reached. Every TTL in it is set to `STALE_TTL`.
+    pub fn get_stale(&mut self, key: &CacheKey, id: u16) -> Option<Vec<u8>> {
+        let now = Instant::now();
+        let entry = self.entries.get_mut(key)?;
+        if !entry.is_servable(now) {
+            return None;
+        }
+
+        entry.last_used = now;
+        restamp_response(&entry.response, id, |_| STALE_TTL)
+    }
+
+    /// Whether a popular entry is close enough to expiring that it should be
+    /// refreshed in the background. Only says yes once per entry, so the caller
+    /// that gets true is responsible for re-inserting it.
+    pub fn claim_prefetch(&mut self, key: &CacheKey) -> bool {
+        let now = Instant::now();
+        let entry = match self.entries.get_mut(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+
+        let remaining = (entry.ttl as u64).saturating_sub(entry.age(now));
+        let due = entry.is_fresh(now) && remaining * 10 <= entry.ttl as u64;
+
+        if due && !entry.prefetching && entry.hits >= PREFETCH_MIN_HITS {
+            entry.prefetching = true;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Stores a response if it's something worth keeping, evicting if the cache is full.
+    ///
+    /// Successful answers live as long as their shortest TTL. NXDOMAIN and NODATA
+    /// answers live as long as the SOA that came with them allows (RFC 2308).
+    pub fn insert(&mut self, key: CacheKey, response: &Vec<u8>) {
+        let packet = Packet::from_vec(response);
+        let ttl = positive_ttl(&packet)
+            .map(|ttl| ttl.min(MAX_CACHE_TTL))
+            .or_else(|| negative_ttl(&packet));
+
+        if let Some(ttl) = ttl {
+            self.store(key, response, ttl);
+        }
+    }
+
+    /// Stores a response for a fixed time, regardless of what's in it.
+    pub fn insert_for(&mut self, key: CacheKey, response: &Vec<u8>, ttl: u32) {
+        self.store(key, response, ttl.min(MAX_CACHE_TTL));
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn store(&mut self, key: CacheKey, response: &Vec<u8>, ttl: u32) {
+        if ttl == 0 {
+            return;
+        }
+
+        if !self.entries.contains_key(&key) && self.entries.len() >= self.capacity {
+            self.make_room();
+        }
+
+        // A refreshed entry keeps its popularity so it can keep being prefetched.
+        let hits = self.entries.get(&key).map(|entry| entry.hits).unwrap_or(0);
+
+        let now = Instant::now();
+        self.entries.insert(key, CacheEntry {
+            response: response.clone(),
+            inserted: now,
+            last_used: now,
+            ttl,
+            hits,
+            prefetching: false,
+        });
+    }
+
+    fn make_room(&mut self) {
+        let now = Instant::now();
+        self.entries.retain(|_, entry| entry.is_servable(now));
+
+        if self.entries.len() < self.capacity {
+            return;
+        }
+
+        // Stale entries are only a fallback, so they go before anything fresh.
+        let stale_key = self.entries.iter()
+            .find(|(_, entry)| !entry.is_fresh(now))
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = stale_key {
+            self.entries.remove(&key);
+            return;
+        }
+
+        let oldest = self.entries.iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(key, _)| key.clone());
+
+        if let Some(key) = oldest {
+            self.entries.remove(&key);
+        }
+    }
+
+    /// Writes every servable entry to `path`, so a restarted daemon doesn't start cold.
+    ///
+    /// Entries are stamped with wall-clock expiry times, since `Instant`s don't
+    /// survive a restart. The file is written beside `path` and moved into place,
+    /// so a crash partway through can't leave a half-written snapshot behind.
+    pub fn save(&self, path: &String) -> io::Result<()> {
+        let now = Instant::now();
+        let wall_now = unix_time();
+
+        let mut body = Vec::<u8>::new();
+        let entries: Vec<(&CacheKey, &CacheEntry)> = self.entries.iter()
+            .filter(|(_, entry)| entry.is_servable(now))
+            .collect();
+
+        body.extend_from_slice(&(entries.len() as u32).to_be_bytes());
+        for (key, entry) in entries {
+            let remaining = entry.ttl as i64 - entry.age(now) as i64;
+
+            body.extend_from_slice(&(key.name.len() as u16).to_be_bytes());
+            body.extend_from_slice(key.name.as_bytes());
+            body.extend_from_slice(&key.qtype.to_be_bytes());
+            body.extend_from_slice(&key.qclass.to_be_bytes());
+            body.push(key.dnssec_ok as u8);
+            body.extend_from_slice(&entry.ttl.to_be_bytes());
+            body.extend_from_slice(&(wall_now as i64 + remaining).to_be_bytes());
+            body.extend_from_slice(&entry.hits.to_be_bytes());
+            body.extend_from_slice(&(entry.response.len() as u32).to_be_bytes());
+            body.extend_from_slice(&entry.response);
+        }
+
+        let mut file = Vec::<u8>::new();
+        file.extend_from_slice(SNAPSHOT_MAGIC);
+        file.push(SNAPSHOT_VERSION);
+        file.extend_from_slice(&checksum(&body).to_be_bytes());
+        file.append(&mut body);
+
+        let temp_path = format!("{}.tmp", path);
+        fs::write(&temp_path, &file)?;
+        fs::rename(&temp_path, path)
+    }
+
+    /// Reads a snapshot written by `save`, with each entry's TTL wound down by
+    /// however long the daemon was gone.
+    ///
+    /// A missing, corrupt, or old-format file just gives an empty cache.
+    pub fn load(path: &String, capacity: usize) -> Cache {
+        let mut cache = Cache::new(capacity);
+
+        let file = match fs::read(path) {
+            Ok(file) => file,
+            Err(_) => return cache,
+        };
+
+        match cache.restore(&file) {
+            Some(count) => println!("Loaded {} cache entries from {}", count, path),
+            None => {
+                println!("Ignoring unreadable cache snapshot {}", path);
+                cache.entries.clear();
+            },
+        }
+
+        cache
+    }
+
+    fn restore(&mut self, file: &Vec<u8>) -> Option<usize> {
+        let mut reader = SnapshotReader { data: file, index: 0 };
+        if reader.bytes(SNAPSHOT_MAGIC.len())? != SNAPSHOT_MAGIC || reader.u8()? != SNAPSHOT_VERSION {
+            return None;
+        }
+
+        let expected_checksum = reader.u64()?;
+        if checksum(&file[reader.index..]) != expected_checksum {
+            return None;
+        }
+
+        let now = Instant::now();
+        let wall_now = unix_time() as i64;
+        let mut loaded = 0;
+
+        for _ in 0..reader.u32()? {
+            let name_length = reader.u16()? as usize;
+            let name = String::from_utf8(reader.bytes(name_length)?.to_vec()).ok()?;
+            let key = CacheKey {
+                name,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+                dnssec_ok: reader.u8()? != 0,
+            };
+
+            let ttl = reader.u32()?;
+            let expires_at = reader.u64()? as i64;
+            let hits = reader.u32()?;
+            let response_length = reader.u32()? as usize;
+            let response = reader.bytes(response_length)?.to_vec();
+
+            // Back-date the entry so its age picks up where it left off. If the
+            // clock can't go back that far, it's too old to matter anyway.
+            let age = ttl as i64 - (expires_at - wall_now);
+            let inserted = match now.checked_sub(Duration::from_secs(age.max(0) as u64)) {
+                Some(inserted) => inserted,
+                None => continue,
+            };
+
+            let entry = CacheEntry {
+                response,
+                inserted,
+                last_used: inserted,
+                ttl,
+                hits,
+                prefetching: false,
+            };
+
+            if entry.is_servable(now) && self.entries.len() < self.capacity {
+                self.entries.insert(key, entry);
+                loaded += 1;
+            }
+        }
+
+        Some(loaded)
+    }
+}
+
+struct SnapshotReader<'a> {
+    data: &'a Vec<u8>,
+    index: usize,
+}
+
+impl<'a> SnapshotReader<'a> {
+    fn bytes(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.index..self.index + count)?;
+        self.index += count;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let mut raw = [0; 2];
+        raw.copy_from_slice(self.bytes(2)?);
+        Some(u16::from_be_bytes(raw))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let mut raw = [0; 4];
+        raw.copy_from_slice(self.bytes(4)?);
+        Some(u32::from_be_bytes(raw))
+    }
+
+    fn u64(&mut self) -> Option<u64> {
+        let mut raw = [0; 8];
+        raw.copy_from_slice(self.bytes(8)?);
+        Some(u64::from_be_bytes(raw))
+    }
+}
+
+/// FNV-1a, which is plenty to notice a truncated or scribbled-on snapshot.
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x100000001b3))
+}
+
+/// Whether a record's TTL field is actually a TTL. OPT repurposes it for flags.
+fn has_real_ttl(record: &RecordLocation) -> bool {
+    record.rtype != Type::OPT.to_u16()
+}
+
+/// How long a successful answer may be cached: the smallest TTL in it.
+///
+/// Gives None for anything that isn't a complete, successful answer.
+fn positive_ttl(response: &Packet) -> Option<u32> {
+    if response.data.len() < 12 || !response.is_response() || response.is_truncated() {
+        return None;
+    }
+
+    match response.get_response_code() {
+        ResponseCode::NoError => {},
+        _ => return None,
+    }
+
+    if response.get_answer_count() == 0 {
+        return None;
+    }
+
+    response.locate_records()?.iter()
+        .filter(|record| has_real_ttl(record))
+        .map(|record| record.get_ttl(response))
+        .min()
+}
+
+/// How long an NXDOMAIN or NODATA response may be cached.
+///
+/// Per RFC 2308, that's the lesser of the TTL and MINIMUM field of the SOA in the
+/// authority section. Without an SOA, the response isn't cached at all.
+pub fn negative_ttl(response: &Packet) -> Option<u32> {
+    if response.data.len() < 12 || !response.is_response() || response.is_truncated() {
+        return None;
+    }
+
+    match response.get_response_code() {
+        ResponseCode::NXDomain => {},
+        ResponseCode::NoError if response.get_answer_count() == 0 => {},
+        _ => return None,
+    }
+
+    response.locate_records()?.iter()
+        .filter(|record| record.section == Section::Authority && record.rtype == Type::SOA.to_u16())
+        .filter(|record| record.rdata_length >= 4)
+        .map(|record| {
+            // MINIMUM is the last field of the SOA, after the two names and four other counters.
+            let minimum = get_u32(&response.data, record.rdata_offset + record.rdata_length - 4);
+            record.get_ttl(response).min(minimum).min(MAX_NEGATIVE_CACHE_TTL)
+        })
+        .next()
+}
+
+/// Copies a cached response, giving it the client's ID and passing every TTL through `adjust_ttl`.
+fn restamp_response<F: Fn(u32) -> u32>(response: &Vec<u8>, id: u16, adjust_ttl: F) -> Option<Vec<u8>> {
+    let mut packet = Packet::from_vec(response);
+    let records = packet.locate_records()?;
+
+    for record in records.iter().filter(|record| has_real_ttl(record)) {
+        let ttl = record.get_ttl(&packet);
+        set_u32(&mut packet.data, adjust_ttl(ttl), record.ttl_offset);
+    }
+
+    packet.set_id(id);
+    Some(packet.data)
+}
@@ -1,11 +1,28 @@
 extern crate base64;
 use base64::{ encode, decode };
 
-use super::data::{ Packet, DecomposedPacket, Question, Label };
+use std::fs;
+use std::process;
+use std::io::{ self, Read };
+
+use super::data::{ Packet, DecomposedPacket, Question, Label, Resource };
 use super::enums::*;
+use super::util::random_u16;
 use super::doh::*;
 use super::udp::*;
 use super::daemon::run_dns_daemon;
+use super::config::DaemonConfig;
+use super::tcp::*;
+use super::transfer::*;
+use super::keys::*;
+use super::sig0::sign_message;
+use super::tsig::*;
+use super::update::parse_update_script;
+use super::signer::{ sign_zone as sign_zone_records, DenialChain, SignOptions, DEFAULT_REFRESH, DEFAULT_SIGNATURE_VALIDITY };
+use super::trace::trace_chain;
+use super::validator::TrustAnchor;
+use super::dnssec::opt_record;
+use super::zone::{ parse_hex, parse_ttl, parse_type, parse_zone, read_zone_file, write_answers, write_name, write_record, write_zone };
 
 enum Authority {
     CBSecurity,
@@ -15,11 +32,13 @@ enum Authority {
     CloudFlare,
 }
 
+#[allow(clippy::upper_case_acronyms)]
 enum Transport {
     UDP,
     DoH,
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn b64(args: &Vec<String>) {
     if args.len() < 3 {
         println!("Missing required domain arg (www.example.com, etc.)");
@@ -27,12 +46,12 @@ pub fn b64(args: &Vec<String>) {
     }
 
     let mut packet = DecomposedPacket::new();
-    packet.id = 0x1234;
+    packet.id = random_u16();
     packet.recursion_desired = true;
 
-    for i in 2..args.len() {
+    for arg in args.iter().skip(2) {
         packet.questions.push(Question {
-            label: Label::Domain(args[i].clone()),
+            label: Label::Domain(arg.clone()),
             qtype: Type::A,
             qclass: Class::Internet,
         });
@@ -44,6 +63,7 @@ pub fn b64(args: &Vec<String>) {
     println!("{}", packet_b64);
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn explain(args: &Vec<String>) {
     if args.len() < 3 {
         println!("Missing required packet base64");
@@ -57,6 +77,7 @@ pub fn explain(args: &Vec<String>) {
     println!("{}", packet);
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn resolve(args: &Vec<String>) {
     if args.len() < 3 {
         println!("Missing required domain arg (www.example.com, etc.)");
@@ -64,7 +85,7 @@ pub fn resolve(args: &Vec<String>) {
     }
 
     let mut packet = DecomposedPacket::new();
-    packet.id = 0x1234;
+    packet.id = random_u16();
     packet.recursion_desired = true;
 
     packet.questions.push(Question {
@@ -77,18 +98,42 @@ pub fn resolve(args: &Vec<String>) {
 
     let mut transport = Transport::UDP;
     let mut authority = Authority::CBAdult;
+    let mut zone_format = false;
+    let mut server = None;
+    let mut sig0_file = None;
 
-    for i in 3..args.len() {
-        match args[i].as_str() {
+    let mut arg_iter = args.iter().skip(3);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
             "--doh" => transport = Transport::DoH,
             "--cb-family" => authority = Authority::CBFamily,
             "--cb-security" => authority = Authority::CBSecurity,
             "--cloudflare" => authority = Authority::CloudFlare,
             "--google" => authority = Authority::Google,
+            "--zone-format" => zone_format = true,
+            "--server" => server = arg_iter.next().cloned(),
+            "--sig0" => sig0_file = arg_iter.next().cloned(),
             _ => {},
         }
     }
 
+    // A signature covers the query's ID, so it has to go out exactly as signed,
+    // which only asking a server directly does.
+    let sig0_key = match (sig0_file, &server) {
+        (Some(path), Some(_)) => match SigningKey::load(&path) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                println!("{}", err);
+                return
+            },
+        },
+        (Some(_), None) => {
+            println!("--sig0 needs --server");
+            return
+        },
+        (None, _) => None,
+    };
+
     let authority_address = String::from(match (&transport, &authority) {
         (Transport::UDP, Authority::CBSecurity) => CLEAN_BROWSING_SECURITY_DNS_IP,
         (Transport::UDP, Authority::CBAdult) => CLEAN_BROWSING_ADULT_DNS_IP,
@@ -102,16 +147,472 @@ pub fn resolve(args: &Vec<String>) {
         (Transport::DoH, Authority::Google) => GOOGLE_URL,
     });
 
-    println!("{}", DecomposedPacket::from_packet(&match transport {
-        Transport::DoH => {
+    let response = DecomposedPacket::from_packet(&match (&server, transport) {
+        (Some(server), _) => {
+            let query = match &sig0_key {
+                Some(key) => sign_message(&raw_packet.data, key),
+                None => raw_packet.data.clone(),
+            };
+            Packet::init_from_full(exchange_tcp(&query, server).expect("TCP exchange failed"))
+        },
+        (None, Transport::DoH) => {
             // DoH doesn't like padding
             let packet_b64 = encode(&raw_packet.data).replace("=", "");
-            resolve_doh(&authority_address, &packet_b64)
+            resolve_doh(&authority_address, &packet_b64).expect("DoH request failed")
+        },
+        (None, Transport::UDP) => Packet::init_from_full(exchange_udp(&raw_packet.data, &authority_address).expect("UDP exchange failed")),
+    });
+
+    if zone_format {
+        print!("{}", write_answers(&response));
+    } else {
+        println!("{}", response);
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn zone(args: &Vec<String>) {
+    if args.len() < 4 {
+        println!("Missing required zone file and origin args");
+        return
+    }
+
+    // A file of - means the zone is coming in on stdin.
+    let parsed = if args[2] == "-" {
+        let mut text = String::new();
+        io::stdin().read_to_string(&mut text).expect("Could not read stdin");
+        parse_zone(&text, &args[3])
+    } else {
+        read_zone_file(&args[2], &args[3])
+    };
+
+    match parsed {
+        Ok(records) => print!("{}", write_zone(&records)),
+        Err(err) => println!("{}", err),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn transfer(args: &Vec<String>) {
+    if args.len() < 4 {
+        println!("Missing required server and zone args");
+        return
+    }
+
+    let server = &args[2];
+    let zone = &args[3];
+    let mut current_file = None;
+    let mut output_file = None;
+    let mut key_file = None;
+
+    let mut arg_iter = args.iter().skip(4);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--ixfr" => current_file = arg_iter.next().cloned(),
+            "--output" => output_file = arg_iter.next().cloned(),
+            "--key" => key_file = arg_iter.next().cloned(),
+            _ => {},
+        }
+    }
+
+    let key = match key_file.map(|path| load_signing_key(&path)).transpose() {
+        Ok(key) => key,
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    let transferred = match current_file {
+        Some(path) => match read_zone_file(&path, zone) {
+            Ok(current) => ixfr(server, zone, &current, key.as_ref()),
+            Err(err) => {
+                println!("{}", err);
+                return
+            },
+        },
+        None => axfr(server, zone, key.as_ref()),
+    };
+
+    let records = match transferred {
+        Ok(records) => records,
+        Err(err) => {
+            println!("Transfer failed: {}", err);
+            return
+        },
+    };
+
+    match output_file {
+        Some(path) => {
+            fs::write(&path, write_zone(&records)).expect("Could not write zone file");
+            let serial = records.iter().find_map(soa_serial).unwrap_or(0);
+            println!("Wrote {} records at serial {} to {}", records.len(), serial, path);
+        },
+        None => print!("{}", write_zone(&records)),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn update(args: &Vec<String>) {
+    if args.len() < 4 {
+        println!("Missing required server and zone args");
+        return
+    }
+
+    let server = &args[2];
+    let zone = &args[3];
+    let mut script_file = None;
+    let mut key_file = None;
+    let mut sig0_file = None;
+
+    let mut arg_iter = args.iter().skip(4);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--key" => key_file = arg_iter.next().cloned(),
+            "--sig0" => sig0_file = arg_iter.next().cloned(),
+            _ => script_file = Some(arg.clone()),
+        }
+    }
+
+    let sig0_key = match sig0_file.map(|path| SigningKey::load(&path)).transpose() {
+        Ok(key) => key,
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    let mut session = match key_file.map(|path| load_signing_key(&path)).transpose() {
+        Ok(key) => key.as_ref().map(TsigSession::new),
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    // The commands come from a file, or from stdin when there isn't one.
+    let script = match script_file.filter(|path| path != "-") {
+        Some(path) => fs::read_to_string(path).expect("Could not read update script"),
+        None => {
+            let mut text = String::new();
+            io::stdin().read_to_string(&mut text).expect("Could not read stdin");
+            text
+        },
+    };
+
+    let message = match parse_update_script(&script, zone) {
+        Ok(message) => message,
+        Err(err) => {
+            println!("{}", err);
+            return
         },
-        Transport::UDP => Packet::init_from_full(exchange_udp(&raw_packet.data, &authority_address)),
+    };
+
+    let mut raw_message = message.to_raw().data;
+    if let Some(session) = &mut session {
+        raw_message = session.sign(&raw_message);
+    } else if let Some(key) = &sig0_key {
+        raw_message = sign_message(&raw_message, key);
+    }
+
+    let result = exchange_tcp(&raw_message, server).and_then(|response| match &mut session {
+        Some(session) => session.verify(&response).map_err(|err| io::Error::new(io::ErrorKind::Other, err.describe())),
+        None => Ok(response),
+    });
+
+    match result {
+        Ok(response) => {
+            let response = Packet::from_vec(&response);
+            if response.get_id() != message.id || !response.is_response() {
+                println!("Update failed: the server's answer doesn't match");
+            } else {
+                println!("Update {}: {}", zone, response.get_response_code());
+            }
+        },
+        Err(err) => println!("Update failed: {}", err),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn tsig_keygen(args: &Vec<String>) {
+    if args.len() < 3 {
+        println!("Missing required key name arg");
+        return
+    }
+
+    let algorithm = match args.get(3) {
+        Some(name) => match TsigAlgorithm::from_name(name) {
+            Some(algorithm) => algorithm,
+            None => {
+                println!("Unsupported algorithm {}, try hmac-sha256 or hmac-sha512", name);
+                return
+            },
+        },
+        None => TsigAlgorithm::HmacSha256,
+    };
+
+    print!("{}", TsigKey::generate(&args[2], algorithm).to_key_file());
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn keygen(args: &Vec<String>) {
+    if args.len() < 3 {
+        println!("Missing required key name arg");
+        return
+    }
+
+    let mut algorithm = KeyAlgorithm::Ed25519;
+    let mut directory = String::from(".");
+    let mut flags = HOST_KEY_FLAGS;
+
+    let mut arg_iter = args.iter().skip(3);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--directory" => directory = arg_iter.next().cloned().unwrap_or(directory),
+            "--zsk" => flags = ZONE_SIGNING_KEY_FLAGS,
+            "--ksk" => flags = KEY_SIGNING_KEY_FLAGS,
+            name => match KeyAlgorithm::from_name(name) {
+                Some(chosen) => algorithm = chosen,
+                None => {
+                    println!("Unsupported algorithm {}, try ED25519, ECDSAP256SHA256, or ECDSAP384SHA384", name);
+                    return
+                },
+            },
+        }
+    }
+
+    // Zone keys are published as DNSKEYs, and SIG(0) keys as KEYs.
+    let rtype = if flags == HOST_KEY_FLAGS { Type::KEY } else { Type::DNSKEY };
+    let key = SigningKey::generate(&args[2], algorithm, flags);
+    match key.save(&directory, rtype) {
+        Ok(base) => println!("{}", base),
+        Err(err) => println!("{}", err),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn sign_zone(args: &Vec<String>) {
+    if args.len() < 5 {
+        println!("Missing required zone file, origin, and key args");
+        return
+    }
+
+    let origin = &args[3];
+    let mut key_files = vec![];
+    let mut output_file = None;
+    let mut use_nsec3 = false;
+    let mut opt_out = false;
+    let mut salt = None;
+    let mut iterations = None;
+    let mut validity = None;
+    let mut refresh = None;
+
+    let mut arg_iter = args.iter().skip(4);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--output" => output_file = arg_iter.next().cloned(),
+            "--nsec3" => use_nsec3 = true,
+            "--opt-out" => {
+                use_nsec3 = true;
+                opt_out = true;
+            },
+            "--salt" => salt = arg_iter.next().cloned(),
+            "--iterations" => iterations = arg_iter.next().cloned(),
+            "--validity" => validity = arg_iter.next().cloned(),
+            "--refresh" => refresh = arg_iter.next().cloned(),
+            _ => key_files.push(arg.clone()),
+        }
+    }
+
+    let chain = match use_nsec3 {
+        true => parse_nsec3_options(salt, iterations, opt_out),
+        false => Ok(DenialChain::Nsec),
+    };
+
+    let options = chain.and_then(|chain| Ok(SignOptions {
+        validity: validity.map(|text| parse_ttl(&text)).transpose()?.unwrap_or(DEFAULT_SIGNATURE_VALIDITY),
+        refresh: refresh.map(|text| parse_ttl(&text)).transpose()?.unwrap_or(DEFAULT_REFRESH),
+        chain,
     }));
+
+    let options = match options {
+        Ok(options) if options.refresh < options.validity => options,
+        Ok(_) => {
+            println!("Signatures would be due for a refresh as soon as they're made, --refresh has to be less than --validity");
+            return
+        },
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    let keys = match key_files.iter().map(SigningKey::load).collect::<Result<Vec<SigningKey>, String>>() {
+        Ok(keys) => keys,
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    let records = match read_zone_file(&args[2], origin) {
+        Ok(records) => records,
+        Err(err) => {
+            println!("{}", err);
+            return
+        },
+    };
+
+    let signed = match sign_zone_records(&records, origin, &keys, &options) {
+        Ok(signed) => signed,
+        Err(err) => {
+            println!("Signing failed: {}", err);
+            return
+        },
+    };
+
+    match output_file {
+        Some(path) => {
+            fs::write(&path, write_zone(&signed.records)).expect("Could not write zone file");
+            println!("Signed {} at serial {}: {} new signatures, {} kept", origin, signed.serial, signed.made, signed.kept);
+            println!("Wrote {} records to {}", signed.records.len(), path);
+            for ds in signed.records.iter().filter(|record| record.rtype == Type::CDS) {
+                println!("DS for the parent: {}", write_record(&Resource { rtype: Type::DS, ..ds.clone() }));
+            }
+        },
+        None => print!("{}", write_zone(&signed.records)),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+pub fn dnssec_trace(args: &Vec<String>) {
+    if args.len() < 3 {
+        println!("Missing required domain arg (www.example.com, etc.)");
+        return
+    }
+
+    let mut qtype = Type::A;
+    let mut server = None;
+    let mut anchor_file = None;
+
+    let mut arg_iter = args.iter().skip(3);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--server" => server = arg_iter.next().cloned(),
+            "--trust-anchor-file" => anchor_file = arg_iter.next().cloned(),
+            text => match parse_type(text) {
+                Some(rtype) => qtype = rtype,
+                None => {
+                    println!("Unknown type {}", text);
+                    return
+                },
+            },
+        }
+    }
+
+    let anchors = match anchor_file {
+        Some(path) => match TrustAnchor::load_file(&path) {
+            Ok(anchors) => anchors,
+            Err(err) => {
+                println!("{}", err);
+                return
+            },
+        },
+        None => TrustAnchor::root(),
+    };
+
+    // A server named on the command line is asked over TCP, like `resolve` does.
+    // Otherwise it's Cloudflare, which hands back DNSSEC records, over UDP unless
+    // the answer doesn't fit.
+    let query = |name: &String, qtype: Type| -> Result<DecomposedPacket, String> {
+        let mut packet = DecomposedPacket::new();
+        packet.id = random_u16();
+        packet.recursion_desired = true;
+        packet.checking_disabled = true;
+        packet.questions.push(Question {
+            label: Label::Domain(name.clone()),
+            qtype,
+            qclass: Class::Internet,
+        });
+        packet.additional_records.push(opt_record(true));
+
+        let raw_query = packet.to_raw().data;
+        let response = match &server {
+            Some(server) => exchange_tcp(&raw_query, server),
+            None => {
+                let address = String::from(CLOUDFLARE_DNS_IP);
+                exchange_udp(&raw_query, &address).and_then(|response| {
+                    if DecomposedPacket::from_packet(&Packet::from_vec(&response)).is_truncated {
+                        exchange_tcp(&raw_query, &address)
+                    } else {
+                        Ok(response)
+                    }
+                })
+            },
+        };
+
+        let response = response.map_err(|err| format!("couldn't ask for {} {}: {}", write_name(name), qtype, err))?;
+        Ok(DecomposedPacket::from_packet(&Packet::from_vec(&response)))
+    };
+
+    // The trace prints its own verdict, the exit code tells scripts which one it
+    // was: 0 secure, 1 insecure, 2 broken.
+    match trace_chain(&args[2], qtype, &anchors, &query) {
+        Ok(true) => process::exit(0),
+        Ok(false) => process::exit(1),
+        Err(_) => process::exit(2),
+    }
+}
+
+/// Reads the NSEC3 salt, in hex or `-` for none, and the number of extra
+/// iterations. Both default to nothing extra, as RFC 9276 recommends.
+fn parse_nsec3_options(salt: Option<String>, iterations: Option<String>, opt_out: bool) -> Result<DenialChain, String> {
+    let salt = match salt.as_deref() {
+        Some("-") | None => vec![],
+        Some(hex) => parse_hex(hex)?,
+    };
+
+    let iterations = match iterations {
+        Some(text) => text.parse().map_err(|_| format!("Bad iteration count {}", text))?,
+        None => 0,
+    };
+
+    Ok(DenialChain::Nsec3 { salt, iterations, opt_out })
+}
+
+/// The key to sign with, which is the first one in the file.
+fn load_signing_key(path: &String) -> Result<TsigKey, String> {
+    load_key_file(path)?.into_iter().next().ok_or(format!("{}: no keys in the file", path))
 }
 
-pub fn daemon(_args: &Vec<String>) {
-    run_dns_daemon();
+#[allow(clippy::ptr_arg)]
+pub fn daemon(args: &Vec<String>) {
+    let mut config_file = None;
+    let mut cache_file = None;
+
+    let mut arg_iter = args.iter().skip(2);
+    while let Some(arg) = arg_iter.next() {
+        match arg.as_str() {
+            "--config" => config_file = arg_iter.next().cloned(),
+            "--cache-file" => cache_file = arg_iter.next().cloned(),
+            _ => {},
+        }
+    }
+
+    let mut config = match config_file {
+        Some(path) => match DaemonConfig::load(&path) {
+            Ok(config) => config,
+            Err(err) => {
+                println!("{}", err);
+                return
+            },
+        },
+        None => DaemonConfig::default(),
+    };
+
+    if cache_file.is_some() {
+        config.cache_file = cache_file;
+    }
+
+    run_dns_daemon(config);
 }
@@ -0,0 +1,358 @@
+use std::fs;
+
+use super::acl::Acl;
+use super::cache::DEFAULT_CACHE_CAPACITY;
+use super::doh::CLEAN_BROWSING_FAMILY_URL;
+use super::local::StaticRecord;
+use super::resolver::DNS_PORT;
+use super::udp::CLEAN_BROWSING_SECURITY_DNS_IP;
+use super::upstream::Strategy;
+use super::validator::TrustAnchor;
+
+pub const DEFAULT_LISTEN_ADDRESS: &str = "127.0.0.1:53";
+
+const DEFAULT_PIPELINE: [&str; 7] = ["hosts", "zones", "cache", "lists", "routes", "authority", "forward"];
+
+const DEFAULT_BLOCK_LIST: [&str; 1] = [
+    "lego.com",
+];
+
+const DEFAULT_ALLOW_LIST: [&str; 1] = [
+    "reddit.com",
+];
+
+/// A `zone` line: we're the authority for `origin`, and its records are in `file`.
+/// Only the addresses in `allow_transfer` may pull a copy with AXFR or IXFR over
+/// TCP, only the ones in `allow_update` may change it with dynamic updates, which
+/// are written back to the file, and the ones in `notify` hear about it when it
+/// changes. The file is checked for changes every so often, too. Signed zones
+/// refuse updates, since we don't have their keys to sign the changes with.
+pub struct ZoneConfig {
+    pub         origin: String,
+    pub           file: String,
+    pub allow_transfer: Acl,
+    pub   allow_update: Acl,
+    pub         notify: Vec<String>,
+}
+
+/// A `secondary` line: we serve a copy of `origin`, transferred from `primaries`,
+/// and saved in `file` if there is one. It's refreshed whenever a primary sends a
+/// NOTIFY, or its SOA says it's time. Transfers are signed with `transfer_key`, if
+/// there is one.
+pub struct SecondaryConfig {
+    pub         origin: String,
+    pub      primaries: Vec<String>,
+    pub allow_transfer: Acl,
+    pub         notify: Vec<String>,
+    pub           file: Option<String>,
+    pub   transfer_key: Option<String>,
+}
+
+/// A `forward-zone` line: queries under `suffix` go to these upstreams instead.
+pub struct ForwardZoneConfig {
+    pub        suffix: String,
+    pub     upstreams: Vec<String>,
+    pub bypass_filter: bool,
+}
+
+/// Everything the daemon can be told in its config file.
+///
+/// The file has one directive per line, and `#` starts a comment. Anything left
+/// out keeps its default, except the block and allow lists, which start out empty
+/// once there's a config file at all.
+///
+/// ```text
+/// listen      127.0.0.1:53
+/// cache-file  /var/cache/dns-hero
+/// cache-size  4096
+/// pipeline    hosts zones cache lists routes authority forward
+/// block       lego.com
+/// allow       reddit.com
+/// authority   https://doh.cleanbrowsing.org/doh/family-filter
+/// forward     185.228.168.9 https://cloudflare-dns.com/dns-query
+/// forward-strategy  fastest
+/// forward-zone  corp.example 10.0.0.53 bypass-filter
+/// forward-zone  *.168.192.in-addr.arpa 192.168.1.1
+/// root-hints  /etc/dns-hero/named.root
+/// hosts-file  /etc/hosts
+/// record      dev.local A 10.0.0.5
+/// tsig-keys   /etc/dns-hero/keys.conf
+/// trust-anchor  . DS 20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D
+/// trust-anchor-file  /etc/dns-hero/anchors.zone
+/// zone        example.test /etc/dns-hero/example.test.zone allow-transfer 10.0.0.0/8 key:transfer-key notify 10.0.0.2 allow-update key:update-key
+/// secondary   partner.test 192.0.2.53 file /var/lib/dns-hero/partner.test.zone transfer-key partner-key
+/// ```
+pub struct DaemonConfig {
+    /// `listen`: the address to serve on, over UDP and, when there are zones, TCP.
+    pub             listen: String,
+    /// `cache-file`: where the cache is kept across restarts.
+    pub         cache_file: Option<String>,
+    /// `cache-size`: how many answers the cache holds.
+    pub         cache_size: usize,
+    /// `pipeline`: the stages a query goes through, in order. The `validate` stage
+    /// isn't in it unless it's asked for, and checks the DNSSEC signatures on
+    /// whatever the stages after it answer with.
+    pub           pipeline: Vec<String>,
+    /// `block`: domains that are always refused.
+    pub         block_list: Vec<String>,
+    /// `allow`: domains that skip the filtering authority.
+    pub         allow_list: Vec<String>,
+    /// `authority`: the filtering DoH server the authority stage asks.
+    pub          authority: String,
+    /// `forward`: the upstreams of the forward stage. IPs, with ports if need be,
+    /// for UDP, URLs for DoH, or `recursive` to resolve queries ourselves, starting
+    /// from the root servers. The authority stage answers every query that isn't
+    /// allowed or routed around it, so for `recursive` to see them, `authority` has to be
+    /// dropped from `pipeline`.
+    pub            forward: Vec<String>,
+    /// `forward-strategy`: one of failover, round-robin, random, fastest, or race,
+    /// which can be given how many upstreams to ask at once, as in `race 3`.
+    pub   forward_strategy: Strategy,
+    /// `forward-zone`: a domain and everything under it go to their own upstreams.
+    /// `bypass-filter` keeps those queries away from the filtering authority.
+    pub      forward_zones: Vec<ForwardZoneConfig>,
+    /// `hosts-file`: hosts files the hosts stage answers from.
+    pub        hosts_files: Vec<String>,
+    /// `record`: single names for the hosts stage to answer (see
+    /// `StaticRecord::parse` for the record types it knows).
+    pub            records: Vec<StaticRecord>,
+    /// `zone`: zones served authoritatively from master files.
+    pub              zones: Vec<ZoneConfig>,
+    /// `secondary`: zones served from copies transferred from their primaries.
+    pub        secondaries: Vec<SecondaryConfig>,
+    /// `tsig-keys`: key files (see `tsig::load_key_file`). Signed messages that
+    /// come in are checked against these keys, and answered signed.
+    pub          key_files: Vec<String>,
+    /// `trust-anchor`: DS or DNSKEY records the chains of trust start from. The
+    /// root's own keys are used if there aren't any, here or in a file.
+    pub      trust_anchors: Vec<TrustAnchor>,
+    /// `trust-anchor-file`: master files with more trust anchors.
+    pub trust_anchor_files: Vec<String>,
+    /// `root-hints`: a master file with the root servers recursion starts from,
+    /// instead of the built-in ones.
+    pub         root_hints: Option<String>,
+    /// `recursion-port`: the port recursive queries go to, for a test hierarchy on
+    /// one host.
+    pub     recursion_port: u16,
+}
+
+impl Default for DaemonConfig {
+    fn default() -> DaemonConfig {
+        DaemonConfig {
+            listen: String::from(DEFAULT_LISTEN_ADDRESS),
+            cache_file: None,
+            cache_size: DEFAULT_CACHE_CAPACITY,
+            pipeline: DEFAULT_PIPELINE.iter().map(|stage| String::from(*stage)).collect(),
+            block_list: DEFAULT_BLOCK_LIST.iter().map(|domain| String::from(*domain)).collect(),
+            allow_list: DEFAULT_ALLOW_LIST.iter().map(|domain| String::from(*domain)).collect(),
+            authority: String::from(CLEAN_BROWSING_FAMILY_URL),
+            forward: vec![String::from(CLEAN_BROWSING_SECURITY_DNS_IP)],
+            forward_strategy: Strategy::Failover,
+            forward_zones: vec![],
+            hosts_files: vec![],
+            records: vec![],
+            zones: vec![],
+            secondaries: vec![],
+            key_files: vec![],
+            trust_anchors: vec![],
+            trust_anchor_files: vec![],
+            root_hints: None,
+            recursion_port: DNS_PORT,
+        }
+    }
+}
+
+impl DaemonConfig {
+    pub fn load(path: &String) -> Result<DaemonConfig, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        DaemonConfig::parse(&text).map_err(|err| format!("{}: {}", path, err))
+    }
+
+    pub fn parse(text: &str) -> Result<DaemonConfig, String> {
+        let mut config = DaemonConfig {
+            block_list: vec![],
+            allow_list: vec![],
+            forward: vec![],
+            ..Default::default()
+        };
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let directive = match words.next() {
+                Some(directive) => directive,
+                None => continue,
+            };
+
+            let args: Vec<String> = words.map(String::from).collect();
+            config.apply(directive, &args).map_err(|err| format!("line {}: {}", line_index + 1, err))?;
+        }
+
+        if config.forward.is_empty() {
+            config.forward = DaemonConfig::default().forward;
+        }
+
+        Ok(config)
+    }
+
+    fn apply(&mut self, directive: &str, args: &Vec<String>) -> Result<(), String> {
+        match directive {
+            "listen" => self.listen = single_arg(directive, args)?,
+            "cache-file" => self.cache_file = Some(single_arg(directive, args)?),
+            "cache-size" => {
+                let size = single_arg(directive, args)?;
+                self.cache_size = size.parse().map_err(|_| format!("Bad cache size {}", size))?;
+            },
+            "pipeline" => self.pipeline = at_least_one_arg(directive, args)?,
+            "block" => self.block_list.append(&mut at_least_one_arg(directive, args)?),
+            "allow" => self.allow_list.append(&mut at_least_one_arg(directive, args)?),
+            "authority" => self.authority = single_arg(directive, args)?,
+            "forward" => self.forward.append(&mut at_least_one_arg(directive, args)?),
+            "forward-strategy" => self.forward_strategy = Strategy::parse(args)?,
+            "forward-zone" => self.forward_zones.push(parse_forward_zone(args)?),
+            "hosts-file" => self.hosts_files.append(&mut at_least_one_arg(directive, args)?),
+            "record" => self.records.push(StaticRecord::parse(args)?),
+            "zone" => self.zones.push(parse_zone_config(args)?),
+            "secondary" => self.secondaries.push(parse_secondary_config(args)?),
+            "tsig-keys" => self.key_files.append(&mut at_least_one_arg(directive, args)?),
+            "trust-anchor" => self.trust_anchors.push(TrustAnchor::parse(args)?),
+            "trust-anchor-file" => self.trust_anchor_files.append(&mut at_least_one_arg(directive, args)?),
+            "root-hints" => self.root_hints = Some(single_arg(directive, args)?),
+            "recursion-port" => {
+                let port = single_arg(directive, args)?;
+                self.recursion_port = port.parse().map_err(|_| format!("Bad recursion port {}", port))?;
+            },
+            _ => return Err(format!("Unknown directive {}", directive)),
+        }
+
+        Ok(())
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn parse_forward_zone(args: &Vec<String>) -> Result<ForwardZoneConfig, String> {
+    let (suffix, rest) = match args.split_first() {
+        Some(split) => split,
+        None => return Err(String::from("forward-zone needs a domain and at least one upstream")),
+    };
+
+    let suffix = suffix.trim_start_matches("*.").trim_end_matches('.').to_lowercase();
+    let bypass_filter = rest.iter().any(|arg| arg == "bypass-filter");
+    let upstreams: Vec<String> = rest.iter().filter(|arg| *arg != "bypass-filter").cloned().collect();
+
+    if upstreams.is_empty() {
+        return Err(format!("forward-zone {} needs at least one upstream", suffix));
+    }
+
+    Ok(ForwardZoneConfig {
+        suffix,
+        upstreams,
+        bypass_filter,
+    })
+}
+
+fn parse_zone_config(args: &Vec<String>) -> Result<ZoneConfig, String> {
+    let (positional, options) = split_zone_options(args)?;
+    let (origin, file) = match positional {
+        [origin, file] if options.file.is_none() && options.transfer_key.is_none() => (origin, file),
+        _ => return Err(String::from("zone takes an origin and a file")),
+    };
+
+    Ok(ZoneConfig {
+        origin: origin.clone(),
+        file: file.clone(),
+        allow_transfer: options.allow_transfer,
+        allow_update: options.allow_update,
+        notify: options.notify,
+    })
+}
+
+fn parse_secondary_config(args: &Vec<String>) -> Result<SecondaryConfig, String> {
+    let (positional, options) = split_zone_options(args)?;
+    let (origin, primaries) = match positional {
+        [origin, primaries @ ..] if !primaries.is_empty() => (origin, primaries),
+        _ => return Err(String::from("secondary takes an origin and at least one primary")),
+    };
+
+    if options.has_update_list {
+        return Err(format!("Secondary zone {} can't take updates, only its primary can", origin));
+    }
+
+    Ok(SecondaryConfig {
+        origin: origin.trim_end_matches('.').to_lowercase(),
+        primaries: primaries.to_vec(),
+        allow_transfer: options.allow_transfer,
+        notify: options.notify,
+        file: options.file,
+        transfer_key: options.transfer_key,
+    })
+}
+
+/// The options that can follow a zone's own arguments.
+struct ZoneOptions {
+    allow_transfer: Acl,
+    allow_update: Acl,
+    has_update_list: bool,
+    notify: Vec<String>,
+    file: Option<String>,
+    transfer_key: Option<String>,
+}
+
+/// Splits a `zone` or `secondary` line into its own arguments and the options
+/// after them. Each option is a keyword followed by its values.
+///
+/// The lists after `allow-transfer` and `allow-update` may hold addresses,
+/// networks, `any`, or `key:<name>`, which lets in anyone who signs with that
+/// TSIG key, wherever they are. For SIG(0), the name is the signer's, whose KEY
+/// records have to be in one of our zones.
+#[allow(clippy::ptr_arg)]
+fn split_zone_options(args: &Vec<String>) -> Result<(&[String], ZoneOptions), String> {
+    let is_keyword = |arg: &String| ["allow-transfer", "allow-update", "notify", "file", "transfer-key"].contains(&arg.as_str());
+    let first_option = args.iter().position(is_keyword).unwrap_or(args.len());
+    let mut options = ZoneOptions {
+        allow_transfer: Acl::new(),
+        allow_update: Acl::new(),
+        has_update_list: false,
+        notify: vec![],
+        file: None,
+        transfer_key: None,
+    };
+
+    let mut rest = &args[first_option..];
+    while let Some((keyword, after)) = rest.split_first() {
+        let value_count = after.iter().position(is_keyword).unwrap_or(after.len());
+        let values = &after[..value_count];
+        match (keyword.as_str(), values) {
+            (_, []) => return Err(format!("{} needs a value", keyword)),
+            ("allow-transfer", _) => options.allow_transfer = Acl::parse(values)?,
+            ("allow-update", _) => {
+                options.allow_update = Acl::parse(values)?;
+                options.has_update_list = true;
+            },
+            ("notify", _) => options.notify.extend(values.iter().cloned()),
+            ("file", [file]) => options.file = Some(file.clone()),
+            ("transfer-key", [key]) => options.transfer_key = Some(key.trim_end_matches('.').to_lowercase()),
+            _ => return Err(format!("{} takes exactly one value", keyword)),
+        }
+
+        rest = &after[value_count..];
+    }
+
+    Ok((&args[..first_option], options))
+}
+
+fn single_arg(directive: &str, args: &Vec<String>) -> Result<String, String> {
+    match args.as_slice() {
+        [arg] => Ok(arg.clone()),
+        _ => Err(format!("{} takes exactly one value", directive)),
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn at_least_one_arg(directive: &str, args: &Vec<String>) -> Result<Vec<String>, String> {
+    if args.is_empty() {
+        Err(format!("{} needs at least one value", directive))
+    } else {
+        Ok(args.clone())
+    }
+}
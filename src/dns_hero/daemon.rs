@@ -1,156 +1,478 @@
-extern crate base64;
-use base64::encode;
+extern crate libc;
 
-use std::sync::mpsc::{Sender, Receiver};
-use std::sync::mpsc;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{ self, TrySendError };
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::thread;
-use std::net::UdpSocket;
+use std::net::{ SocketAddr, TcpListener, TcpStream, UdpSocket };
+use std::time::Duration;
 
-use super::udp::*;
-use super::doh::*;
 use super::data::*;
 use super::enums::*;
-use super::util::*;
+use super::authoritative::*;
+use super::cache::*;
+use super::config::DaemonConfig;
+use super::local::LocalRecords;
+use super::notify::*;
+use super::pipeline::*;
+use super::resolver::*;
+use super::secondary::Secondary;
+use super::sig0::verify_message;
+use super::stages::*;
+use super::tcp::*;
+use super::transfer::serve_transfer;
+use super::tsig::*;
+use super::update::answer_update;
+use super::upstream::*;
+use super::validator::*;
 
-enum BlockAllowStatus {
-    Neutral,
-    Block,
-    Allow,
+/// How often the cache is written out, when there's somewhere to write it.
+const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(300);
+
+/// How often zone files are checked for changes.
+const ZONE_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often secondary zones look at their timers.
+const SECONDARY_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many threads answer UDP queries. Most of their time goes to waiting on
+/// upstreams, so there are plenty.
+const UDP_WORKERS: usize = 64;
+
+/// How many UDP queries can wait for a worker. Past that, new ones are dropped.
+const UDP_QUEUE_SIZE: usize = 1024;
+
+/// Set from the signal handler; the listener loop notices it within a second.
+static SHUTDOWN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn request_shutdown(_signal: libc::c_int) {
+    SHUTDOWN_REQUESTED.store(true, Ordering::SeqCst);
 }
 
-pub fn run_dns_daemon() {
-    let (tx_udp, rx_udp): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
-    let (tx_udp_r, rx_udp_r): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
+pub fn run_dns_daemon(config: DaemonConfig) {
+    let cache = Arc::new(Mutex::new(match &config.cache_file {
+        Some(path) => Cache::load(path, config.cache_size),
+        None => Cache::new(config.cache_size),
+    }));
+
+    let keys = match load_keys(&config) {
+        Ok(keys) => Arc::new(keys),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        },
+    };
 
-    let (tx_doh, rx_doh): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
-    let (tx_doh_r, rx_doh_r): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
+    let zones = match load_zones(&config) {
+        Ok(zones) => Arc::new(zones),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        },
+    };
 
-    thread::spawn(move || {
-        let address = String::from(CLEAN_BROWSING_SECURITY_DNS_IP);
-        loop {
-            let request = rx_udp.recv().expect("Failed to get request");
-            let response = exchange_udp(&request, &address);
-            tx_udp_r.send(response).expect("Failed to reply.");
-        }
-    });
-
-    thread::spawn(move || {
-        let address = String::from(CLEAN_BROWSING_FAMILY_URL);
-        loop {
-            let request = rx_doh.recv().expect("Failed to get request");
-            let packet_b64 = encode(&request).replace("=", "");
-            let response = resolve_doh(&address, &packet_b64);
-            tx_doh_r.send(response.data).expect("Failed to reply.");
-        }
-    });
+    let pipeline = match build_pipeline(&config, &cache, &zones) {
+        Ok(pipeline) => Arc::new(pipeline),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        },
+    };
+
+    if !config.zones.is_empty() {
+        let zones = zones.clone();
+        thread::spawn(move || loop {
+            thread::sleep(ZONE_CHECK_INTERVAL);
+            for hosted in zones.reload_changed() {
+                notify_secondaries(&hosted);
+            }
+        });
+    }
+
+    let secondaries = match build_secondaries(&config, &keys) {
+        Ok(secondaries) => Arc::new(secondaries),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        },
+    };
+
+    for secondary in secondaries.iter() {
+        let secondary = secondary.clone();
+        let zones = zones.clone();
+        thread::spawn(move || maintain_secondary(&secondary, &zones));
+    }
+
+    if let Some(path) = config.cache_file.clone() {
+        let cache = cache.clone();
+        thread::spawn(move || loop {
+            thread::sleep(SNAPSHOT_INTERVAL);
+            save_cache(&cache, &path);
+        });
+    }
 
-    let socket = UdpSocket::bind("127.0.0.1:53").expect("Could not open UDP socket (you probably aren't root.)");
+    unsafe {
+        libc::signal(libc::SIGINT, request_shutdown as *const () as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, request_shutdown as *const () as libc::sighandler_t);
+    }
+
+    let socket = UdpSocket::bind(&config.listen).expect("Could not open UDP socket (you probably aren't root.)");
+    socket.set_read_timeout(Some(Duration::from_secs(1))).expect("Could not set socket timeout");
+
+    // TCP is needed for zone transfers, and nice to have otherwise, so a forwarder
+    // that can't get it goes on without it.
+    match TcpListener::bind(&config.listen) {
+        Ok(listener) => {
+            let pipeline = pipeline.clone();
+            let zones = zones.clone();
+            let secondaries = secondaries.clone();
+            let keys = keys.clone();
+            thread::spawn(move || serve_tcp(listener, pipeline, zones, secondaries, keys));
+        },
+        Err(err) if config.zones.is_empty() && config.secondaries.is_empty() => println!("Not serving TCP, since its socket couldn't be opened: {}", err),
+        Err(err) => {
+            println!("Could not open TCP socket for serving zones (you probably aren't root): {}", err);
+            return;
+        },
+    }
+
+    let (queue, queued) = mpsc::sync_channel::<(Vec<u8>, SocketAddr)>(UDP_QUEUE_SIZE);
+    let queued = Arc::new(Mutex::new(queued));
+    for _ in 0..UDP_WORKERS {
+        let queued = queued.clone();
+        let socket = socket.try_clone().expect("Could not clone UDP socket");
+        let pipeline = pipeline.clone();
+        let zones = zones.clone();
+        let secondaries = secondaries.clone();
+        let keys = keys.clone();
+        thread::spawn(move || loop {
+            let next = queued.lock().unwrap().recv();
+            match next {
+                Ok((packet, address)) => answer_datagram(&socket, packet, address, &pipeline, &zones, &secondaries, &keys),
+                Err(_) => return,
+            }
+        });
+    }
 
     loop {
+        if SHUTDOWN_REQUESTED.load(Ordering::SeqCst) {
+            println!("Shutting down");
+            if let Some(path) = &config.cache_file {
+                save_cache(&cache, path);
+            }
+
+            return;
+        }
+
         let mut buf = [0; 65536];
-        let (received_bytes, address) = socket.recv_from(&mut buf).expect("Error receiving data");
+        let (received_bytes, address) = match socket.recv_from(&mut buf) {
+            Ok(received) => received,
+            Err(err) => match err.kind() {
+                // Just the timeout, so the shutdown flag gets checked.
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted => continue,
+                _ => panic!("Error receiving data: {}", err),
+            },
+        };
 
         let packet = buf[..received_bytes].to_vec();
+        if Packet::from_vec(&packet).locate_records().is_none() {
+            println!("Ignoring malformed packet from {}", address);
+            continue;
+        }
 
-        tx_udp.send(packet.clone()).expect("Failed to send request");
-        tx_doh.send(packet.clone()).expect("Failed to send request");
-
-        let parsed_packet = DecomposedPacket::from_packet(&Packet::from_vec(&packet));
-        println!("Resolve\n{}", parsed_packet);
-
-        // Pretend for sake of example that this takes a while
-        let block_status = get_block_allow_status(&parsed_packet);
-        let block_res = make_block_packet(parsed_packet.clone());
-
-        let main_res = rx_udp_r.recv().expect("Failed to receive");
-        let authority_res = rx_doh_r.recv().expect("Failed to receive");
-
-        let response_packet = match block_status {
-            BlockAllowStatus::Block => block_res, // Blocked. Don't even bother with network result
-            BlockAllowStatus::Allow => main_res, // Allowed. Don't worry about what CB said
-            BlockAllowStatus::Neutral => {
-                if authority_blocked_request(&authority_res) {
-                    println!("Blocking via CB");
-                    block_res // CB said to block, so block
-                } else {
-                    // We're not blocking, but the authority may have enforced safe search
-                    println!("List and authority are neutral");
-                    authority_res
-                }
+        if let Err(TrySendError::Full(_)) = queue.try_send((packet, address)) {
+            println!("Too busy, so dropping a query from {}", address);
+        }
+    }
+}
+
+/// Answers one query that came in over UDP.
+fn answer_datagram(socket: &UdpSocket, packet: Vec<u8>, address: SocketAddr, pipeline: &Pipeline, zones: &Arc<Zones>, secondaries: &Vec<Arc<Secondary>>, keys: &Vec<TsigKey>) {
+    let (packet, mut signature) = match check_signature(packet, keys, zones, address) {
+        Ok(checked) => checked,
+        Err(response) => {
+            send_datagram(socket, &response, address);
+            return;
+        },
+    };
+
+    let key = signature.key.clone();
+    let mut request = Request::new(packet, ClientInfo { address, over_tcp: false });
+    println!("Resolve for {}\n{}", request.client.address, request.packet);
+
+    let mut response_packet = match request.packet.opcode {
+        Opcode::Notify => answer_notify(&request.packet, address.ip(), secondaries, zones),
+        Opcode::Update => answer_update(&request.packet, address.ip(), key.as_ref(), zones),
+        _ => pipeline.resolve(&mut request),
+    };
+    if let Some(session) = &mut signature.session {
+        response_packet = session.sign(&response_packet);
+    }
+    println!("Responding with \n{}", DecomposedPacket::from_packet(&Packet::from_vec(&response_packet)));
+
+    send_datagram(socket, &response_packet, address);
+}
+
+#[allow(clippy::ptr_arg)]
+fn send_datagram(socket: &UdpSocket, data: &Vec<u8>, address: SocketAddr) {
+    if let Err(err) = socket.send_to(data, address) {
+        println!("Failed to send response to {}: {}", address, err);
+    }
+}
+
+/// Takes TCP connections until the process exits. Each one gets its own thread.
+fn serve_tcp(listener: TcpListener, pipeline: Arc<Pipeline>, zones: Arc<Zones>, secondaries: Arc<Vec<Arc<Secondary>>>, keys: Arc<Vec<TsigKey>>) {
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                println!("Error accepting TCP connection: {}", err);
+                continue;
+            },
+        };
+
+        let pipeline = pipeline.clone();
+        let zones = zones.clone();
+        let secondaries = secondaries.clone();
+        let keys = keys.clone();
+        thread::spawn(move || {
+            if let Ok(address) = stream.peer_addr() {
+                handle_tcp_connection(stream, address, &pipeline, &zones, &secondaries, &keys);
             }
+        });
+    }
+}
+
+/// Answers queries on a TCP connection until the client hangs up or goes quiet.
+/// Zone transfers, NOTIFYs, and UPDATEs are handled here; everything else goes through the
+/// pipeline like it would over UDP.
+fn handle_tcp_connection(mut stream: TcpStream, address: SocketAddr, pipeline: &Pipeline, zones: &Arc<Zones>, secondaries: &Vec<Arc<Secondary>>, keys: &Vec<TsigKey>) {
+    if stream.set_read_timeout(Some(TCP_TIMEOUT)).and(stream.set_write_timeout(Some(TCP_TIMEOUT))).is_err() {
+        return;
+    }
+
+    while let Ok(packet) = read_tcp_message(&mut stream) {
+        if Packet::from_vec(&packet).locate_records().is_none() {
+            println!("Ignoring malformed packet from {}", address);
+            return;
+        }
+
+        let (packet, mut signature) = match check_signature(packet, keys, zones, address) {
+            Ok(checked) => checked,
+            Err(response) => {
+                let _ = send_tcp_message(&mut stream, &response);
+                return;
+            },
+        };
+
+        let key = signature.key.clone();
+        let mut request = Request::new(packet, ClientInfo { address, over_tcp: true });
+        println!("Resolve over TCP for {}\n{}", request.client.address, request.packet);
+
+        let qtype = request.packet.questions.first().map(|question| question.qtype);
+        let response = match (request.packet.opcode, qtype) {
+            (Opcode::Notify, _) => answer_notify(&request.packet, address.ip(), secondaries, zones),
+            (Opcode::Update, _) => answer_update(&request.packet, address.ip(), key.as_ref(), zones),
+            (_, Some(Type::AXFR)) | (_, Some(Type::IXFR)) => {
+                if let Err(err) = serve_transfer(&mut stream, &request.packet, zones, address.ip(), signature.session.as_mut()) {
+                    println!("Error answering {} over TCP: {}", address, err);
+                    return;
+                }
+                continue;
+            },
+            _ => pipeline.resolve(&mut request),
+        };
+
+        let result = match &mut signature.session {
+            Some(session) => send_tcp_message(&mut stream, &session.sign(&response)),
+            None => send_tcp_message(&mut stream, &response),
         };
 
-        println!("Responding with \n{}", DecomposedPacket::from_packet(&Packet::from_vec(&response_packet)));
+        if let Err(err) = result {
+            println!("Error answering {} over TCP: {}", address, err);
+            return;
+        }
+    }
+}
+
+/// Keeps a secondary zone fresh for as long as the daemon runs, starting from the
+/// copy saved last time, if any.
+fn maintain_secondary(secondary: &Secondary, zones: &Arc<Zones>) {
+    secondary.load_saved(zones);
+
+    loop {
+        if secondary.is_due() {
+            refresh_secondary(secondary, zones);
+        }
 
-        socket.send_to(&response_packet, address).expect("Failed to send response.");
+        secondary.check_expiry(zones);
+        thread::sleep(SECONDARY_CHECK_INTERVAL);
     }
 }
 
-const BLOCK_LIST: [&str; 1]= [
-    "lego.com",
-];
+/// Who signed a message that came in, going by its signature.
+struct Signature {
+    /// The name of the TSIG key, or of the SIG(0) signer. It's what transfer and
+    /// update lists go by.
+    key: Option<String>,
+    /// With TSIG, the answer gets signed too.
+    session: Option<TsigSession>,
+}
+
+/// Checks the signature on a message, if it has one: TSIG with one of our keys,
+/// or SIG(0) with a KEY in one of our zones. What comes back is the message
+/// without its signature, and who signed it. If the signature's no good, the
+/// error is the answer to send instead.
+#[allow(clippy::ptr_arg)]
+fn check_signature(packet: Vec<u8>, keys: &Vec<TsigKey>, zones: &Zones, address: SocketAddr) -> Result<(Vec<u8>, Signature), Vec<u8>> {
+    match verify_request(&packet, keys) {
+        Ok(Some((session, stripped))) => {
+            let key = Some(session.key.name.clone());
+            return Ok((stripped, Signature { key, session: Some(session) }));
+        },
+        Ok(None) => {},
+        Err(response) => {
+            println!("Rejecting a badly signed message from {}", address);
+            return Err(response);
+        },
+    }
 
-const ALLOW_LIST: [&str; 1]= [
-    "reddit.com",
-];
+    match verify_message(&packet, zones) {
+        Ok(Some((signer, stripped))) => Ok((stripped, Signature { key: Some(signer), session: None })),
+        Ok(None) => Ok((packet, Signature { key: None, session: None })),
+        Err(err) => {
+            println!("Rejecting a message from {}: {}", address, err);
+            Err(error_packet(&packet, ResponseCode::NotAuth))
+        },
+    }
+}
 
-fn get_block_allow_status(parsed_packet: &DecomposedPacket) -> BlockAllowStatus {
-    if parsed_packet.questions.len() <= 0 {
-        return BlockAllowStatus::Neutral
+fn load_keys(config: &DaemonConfig) -> Result<Vec<TsigKey>, String> {
+    let mut keys = vec![];
+    for path in &config.key_files {
+        keys.extend(load_key_file(path)?);
     }
 
-    match &parsed_packet.questions[0].label {
-        Label::Pointer(_) => BlockAllowStatus::Neutral,
-        Label::Domain(domain_value) => {
-            let domain_str = domain_value.as_str();
+    Ok(keys)
+}
 
-            if BLOCK_LIST.iter().any(|val| val == &domain_str) {
-                println!("Blocking via block list");
-                BlockAllowStatus::Block
-            } else if ALLOW_LIST.iter().any(|val| val == &domain_str) {
-                println!("Allowing via allow list");
-                BlockAllowStatus::Allow
-            } else {
-                BlockAllowStatus::Neutral
-            }
+#[allow(clippy::ptr_arg)]
+fn build_secondaries(config: &DaemonConfig, keys: &Vec<TsigKey>) -> Result<Vec<Arc<Secondary>>, String> {
+    config.secondaries.iter().map(|secondary| {
+        let transfer_key = match &secondary.transfer_key {
+            Some(name) => Some(keys.iter().find(|key| key.name == *name).cloned().ok_or(format!("Unknown key {} for secondary {}", name, secondary.origin))?),
+            None => None,
+        };
+
+        Ok(Arc::new(Secondary::new(&secondary.origin, secondary.primaries.clone(), secondary.allow_transfer.clone(), secondary.notify.clone(), secondary.file.clone(), transfer_key)))
+    }).collect()
+}
+
+fn load_zones(config: &DaemonConfig) -> Result<Zones, String> {
+    let zones = config.zones.iter()
+        .map(|zone| HostedZone::load(&zone.file, &zone.origin, zone.allow_transfer.clone(), zone.allow_update.clone(), zone.notify.clone()))
+        .collect::<Result<Vec<HostedZone>, String>>()?;
+    Ok(Zones::new(zones))
+}
+
+/// Puts together the configured stages, in order.
+fn build_pipeline(config: &DaemonConfig, cache: &Arc<Mutex<Cache>>, zones: &Arc<Zones>) -> Result<Pipeline, String> {
+    let mut stages = Vec::<Box<dyn Handler>>::new();
+    let resolver = build_resolver(config)?;
+
+    // The authority answers everything it isn't told to let through, so nothing
+    // behind it ever reaches the forward stage.
+    let position = |name: &str| config.pipeline.iter().position(|stage| stage == name);
+    if let (Some(authority), Some(forward)) = (position("authority"), position("forward")) {
+        if authority < forward && config.forward.iter().any(|spec| spec == RECURSIVE_UPSTREAM) {
+            println!("Warning: authority comes before forward in the pipeline, so only allowed and bypass-filter names will be resolved recursively. Drop authority to resolve everything.");
         }
     }
+
+    for stage in &config.pipeline {
+        stages.push(match stage.as_str() {
+            "hosts" => Box::new(HostsStage::new(load_local_records(config)?)),
+            "zones" => Box::new(ZoneStage::new(zones.clone())),
+            "cache" => Box::new(CacheStage::new(cache.clone())),
+            "lists" => Box::new(ListStage::new(config.block_list.clone(), config.allow_list.clone())),
+            "authority" => Box::new(AuthorityStage::new(config.authority.clone())),
+            "routes" => {
+                let zones = config.forward_zones.iter().map(|zone| ForwardZone {
+                    suffix: zone.suffix.clone(),
+                    upstreams: Arc::new(UpstreamGroup::new(parse_upstreams(&zone.upstreams, &resolver), config.forward_strategy)),
+                    bypass_filter: zone.bypass_filter,
+                }).collect();
+                Box::new(RouteStage::new(zones))
+            },
+            "forward" => {
+                Box::new(ForwardStage::new(UpstreamGroup::new(parse_upstreams(&config.forward, &resolver), config.forward_strategy)))
+            },
+            "validate" => Box::new(ValidateStage::new(load_trust_anchors(config)?)),
+            _ => return Err(format!("Unknown pipeline stage {}", stage)),
+        });
+    }
+
+    Ok(Pipeline::new(stages))
 }
 
-fn make_block_packet(mut work_packet: DecomposedPacket) -> Vec<u8> {
-    work_packet.answers.push(Resource {
-        label: work_packet.questions[0].label.clone(),
-        rtype: Type::A,
-        rclass: Class::Internet,
-        ttl: 10,
-        length: 4,
-        data: vec![208, 185, 195, 92],
-    });
+/// The resolver behind any `recursive` upstreams. They all share one, so they
+/// share what it learns about where zones' servers are.
+fn build_resolver(config: &DaemonConfig) -> Result<Option<Arc<Resolver>>, String> {
+    let recursive = config.forward.iter()
+        .chain(config.forward_zones.iter().flat_map(|zone| zone.upstreams.iter()))
+        .any(|spec| spec == RECURSIVE_UPSTREAM);
+    if !recursive {
+        return Ok(None);
+    }
 
-    work_packet.is_response = true;
-    work_packet.response_code = ResponseCode::NoError;
+    let hints = match &config.root_hints {
+        Some(path) => Resolver::load_hints(path)?,
+        None => Resolver::root_hints(),
+    };
 
-    work_packet.to_raw().data
+    Ok(Some(Arc::new(Resolver::new(&hints, config.recursion_port)?)))
 }
 
-const CLEAN_BROWSING_AUTHORITY: &str = "cleanbrowsing.rpz.noc.org";
+#[allow(clippy::ptr_arg)]
+fn parse_upstreams(specs: &Vec<String>, resolver: &Option<Arc<Resolver>>) -> Vec<Upstream> {
+    specs.iter().map(|spec| match resolver {
+        Some(resolver) if spec == RECURSIVE_UPSTREAM => Upstream::Recursive(spec.clone(), resolver.clone()),
+        _ => Upstream::parse(spec),
+    }).collect()
+}
 
-fn authority_blocked_request(packet: &Vec<u8>) -> bool {
-    let nice_packet = DecomposedPacket::from_packet(&Packet::from_vec(packet));
-    match nice_packet.response_code {
-        ResponseCode::NXDomain => {
-            nice_packet.authorities.into_iter().any(|authority| {
-                match authority.rtype {
-                    Type::SOA => {
-                        match parse_label(&authority.data, 0).0 {
-                            Label::Pointer(_) => false,
-                            Label::Domain(val) => val.as_str() == CLEAN_BROWSING_AUTHORITY,
-                        }
-                    },
-                    _ => false,
-                }
-            })
-        },
-        _ => false,
+fn load_local_records(config: &DaemonConfig) -> Result<LocalRecords, String> {
+    let mut records = LocalRecords::new();
+
+    // Config records go first, so their reverse entries win over a hosts file's.
+    for record in &config.records {
+        records.add(record.clone());
+    }
+
+    for path in &config.hosts_files {
+        records.load_hosts(path)?;
+    }
+
+    Ok(records)
+}
+
+fn load_trust_anchors(config: &DaemonConfig) -> Result<Vec<TrustAnchor>, String> {
+    let mut anchors = config.trust_anchors.clone();
+    for path in &config.trust_anchor_files {
+        anchors.extend(TrustAnchor::load_file(path)?);
+    }
+
+    if anchors.is_empty() {
+        anchors = TrustAnchor::root();
+    }
+
+    Ok(anchors)
+}
+
+fn save_cache(cache: &Mutex<Cache>, path: &String) {
+    match cache.lock().unwrap().save(path) {
+        Ok(()) => println!("Saved cache to {}", path),
+        Err(err) => println!("Failed to save cache to {}: {}", path, err),
     }
 }
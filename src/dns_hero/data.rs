@@ -1,10 +1,11 @@
 use std::iter;
 use std::fmt;
 
+use super::dnssec::*;
 use super::util::*;
 use super::enums::*;
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub enum Label {
     Pointer(u8),
     Domain(String),
@@ -15,7 +16,7 @@ impl Label {
     pub fn to_raw(&self, output: &mut Vec<u8>) {
         match self {
             Label::Domain(domain) => {
-                let mut label_bytes = str_domain_to_dns_domain(&domain);
+                let mut label_bytes = str_domain_to_dns_domain(domain);
                 output.append(&mut label_bytes);
             },
             Label::Pointer(offset) => {
@@ -26,6 +27,14 @@ impl Label {
             },
         }
     }
+
+    /// Compares two labels the way DNS does, ignoring ASCII case (RFC 4343).
+    pub fn eq_ignore_case(&self, other: &Label) -> bool {
+        match (self, other) {
+            (Label::Domain(domain), Label::Domain(other_domain)) => domain.eq_ignore_ascii_case(other_domain),
+            _ => self == other,
+        }
+    }
 }
 
 impl fmt::Display for Label {
@@ -72,12 +81,21 @@ impl Resource {
 impl fmt::Display for Resource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         coalesce_result!(match self.rtype {
-            Type::A => write!(f, " {}.{}.{}.{} ", self.data[0], self.data[1], self.data[2], self.data[3]),
-            Type::SOA => {
-                let parse_result = parse_label(&self.data, 0);
-                write!(f, "{} ", parse_result.0)
+            Type::A if self.data.len() == 4 => write!(f, " {}.{}.{}.{} ", self.data[0], self.data[1], self.data[2], self.data[3]),
+            Type::SOA if !self.data.is_empty() => match read_name(&self.data, 0) {
+                Some((name, _)) => write!(f, "{} ", name),
+                None => write!(f, "{} bytes ", self.length),
+            },
+            // DNSSEC material is just noise as a byte count, so it's shown the way a
+            // master file has it, with the key tag that RRSIGs and DSes refer to.
+            Type::DNSKEY | Type::CDNSKEY | Type::KEY => match Dnskey::parse(&self.data) {
+                Some(key) => write!(f, " {} ; key tag {} ", key, key.key_tag()),
+                None => write!(f, "{} bytes ", self.length),
+            },
+            _ => match present_dnssec_rdata(self.rtype, &self.data) {
+                Some(text) => write!(f, " {} ", text),
+                None => write!(f, "{} bytes ", self.length),
             },
-            _ => write!(f, "{} bytes ", self.length),
         });
         write!(f, "({}, {}, {})", self.label, self.rtype, self.rclass)
     }
@@ -124,6 +142,12 @@ pub struct DecomposedPacket {
     pub  additional_records: Vec<Resource>,
 }
 
+impl Default for DecomposedPacket {
+    fn default() -> DecomposedPacket {
+        DecomposedPacket::new()
+    }
+}
+
 impl DecomposedPacket {
     pub fn from_packet(raw: &Packet) -> DecomposedPacket {
         let id = raw.get_id();
@@ -284,6 +308,32 @@ impl fmt::Display for DecomposedPacket {
     }
 }
 
+/// Which part of a packet a resource record lives in.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Section {
+    Answer,
+    Authority,
+    Additional,
+}
+
+/// Where a resource record's fixed fields sit in a raw packet.
+pub struct RecordLocation {
+    pub      section: Section,
+    /// Where the record's owner name starts.
+    pub        start: usize,
+    pub        rtype: u16,
+    pub   ttl_offset: usize,
+    pub rdata_offset: usize,
+    pub rdata_length: usize,
+}
+
+impl RecordLocation {
+    /// Gets the record's TTL from the packet it was located in.
+    pub fn get_ttl(&self, packet: &Packet) -> u32 {
+        get_u32(&packet.data, self.ttl_offset)
+    }
+}
+
 /// Encapsulates a packet.
 pub struct Packet {
     pub data: Vec<u8>,
@@ -308,6 +358,7 @@ impl Packet {
     }
 
     /// Creates a new packet by copying an existing vector.
+    #[allow(clippy::ptr_arg)]
     pub fn from_vec(data: &Vec<u8>) -> Packet {
         Packet {
             data: data.clone()
@@ -363,4 +414,92 @@ impl Packet {
     pub fn set_additional_record_count(&mut self, count: u16) {
         set_u16(&mut self.data, count, 10);
     }
+
+    /// Walks the packet and finds the fixed fields of every resource record
+    /// without fully decoding it, so they can be read or patched in place.
+    ///
+    /// Returns None if the packet is truncated or otherwise malformed.
+    pub fn locate_records(&self) -> Option<Vec<RecordLocation>> {
+        if self.data.len() < 12 {
+            return None;
+        }
+
+        let mut index = 12;
+        for _ in 0..self.get_question_count() {
+            index = read_name(&self.data, index)?.1 + 4;
+        }
+
+        let sections = [
+            (Section::Answer, self.get_answer_count()),
+            (Section::Authority, self.get_authority_count()),
+            (Section::Additional, self.get_additional_record_count()),
+        ];
+
+        let mut records = Vec::<RecordLocation>::new();
+        for (section, count) in sections.iter() {
+            for _ in 0..*count {
+                let start = index;
+                index = read_name(&self.data, index)?.1;
+                if index + 10 > self.data.len() {
+                    return None;
+                }
+
+                let rdata_length = get_u16(&self.data, index + 8) as usize;
+                if index + 10 + rdata_length > self.data.len() {
+                    return None;
+                }
+
+                records.push(RecordLocation {
+                    section: *section,
+                    start,
+                    rtype: get_u16(&self.data, index),
+                    ttl_offset: index + 4,
+                    rdata_offset: index + 10,
+                    rdata_length,
+                });
+
+                index += 10 + rdata_length;
+            }
+        }
+
+        Some(records)
+    }
+
+    /// Reads the question section without trusting the packet to be well formed.
+    ///
+    /// Each question comes back as its name along with its raw type and class.
+    pub fn read_questions(&self) -> Option<Vec<(Label, u16, u16)>> {
+        if self.data.len() < 12 {
+            return None;
+        }
+
+        let mut questions = Vec::new();
+        let mut index = 12;
+        for _ in 0..self.get_question_count() {
+            let (name, end) = read_name(&self.data, index)?;
+            if end + 4 > self.data.len() {
+                return None;
+            }
+
+            questions.push((Label::Domain(name), get_u16(&self.data, end), get_u16(&self.data, end + 2)));
+            index = end + 4;
+        }
+
+        Some(questions)
+    }
+
+    /// Gets the response code from the header.
+    pub fn get_response_code(&self) -> ResponseCode {
+        ResponseCode::from_raw(self.data[3] & 0x0F)
+    }
+
+    /// Whether the QR bit is set.
+    pub fn is_response(&self) -> bool {
+        get_flag(self.data[2], 7)
+    }
+
+    /// Whether the TC bit is set.
+    pub fn is_truncated(&self) -> bool {
+        get_flag(self.data[2], 1)
+    }
 }
@@ -0,0 +1,687 @@
+use base64::encode;
+use openssl::hash::{ hash, MessageDigest };
+use std::cmp::Ordering;
+use std::fmt;
+
+use super::data::*;
+use super::enums::*;
+use super::keys::key_tag;
+use super::util::*;
+use super::zone::{ write_hex, write_name, write_type, RdataReader };
+
+/// The flag on a DNSKEY that says it's a zone key. Only those can sign a zone's data.
+pub const ZONE_KEY_FLAG: u16 = 0x0100;
+
+/// The flag on a DNSKEY that marks a key signing key, the one the parent's DS
+/// points at (RFC 3757).
+pub const SECURE_ENTRY_POINT_FLAG: u16 = 0x0001;
+
+/// The bit in an OPT record's TTL that asks for DNSSEC records (RFC 3225).
+const DNSSEC_OK_FLAG: u32 = 0x8000;
+
+/// The UDP payload size we advertise with EDNS, which is what the 2020 DNS flag
+/// day settled on. Signed answers rarely fit in the old 512 bytes.
+pub const EDNS_BUFFER_SIZE: u16 = 1232;
+
+/// The UDP payload every client can take, EDNS or not (RFC 1035 section 2.3.4).
+const MIN_UDP_PAYLOAD_SIZE: usize = 512;
+
+/// The data of a DNSKEY record (RFC 4034 section 2), which is laid out the same
+/// as CDNSKEY and the older KEY.
+#[derive(Clone, PartialEq)]
+pub struct Dnskey {
+    pub      flags: u16,
+    pub   protocol: u8,
+    pub  algorithm: u8,
+    pub public_key: Vec<u8>,
+}
+
+impl Dnskey {
+    pub fn parse(data: &Vec<u8>) -> Option<Dnskey> {
+        let mut reader = RdataReader::new(data);
+        Some(Dnskey {
+            flags: reader.u16()?,
+            protocol: reader.u8()?,
+            algorithm: reader.u8()?,
+            public_key: reader.rest().to_vec(),
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.flags.to_be_bytes().to_vec();
+        rdata.push(self.protocol);
+        rdata.push(self.algorithm);
+        rdata.extend_from_slice(&self.public_key);
+        rdata
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        key_tag(&self.to_rdata())
+    }
+
+    pub fn is_zone_key(&self) -> bool {
+        self.flags & ZONE_KEY_FLAG != 0
+    }
+}
+
+impl fmt::Display for Dnskey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.flags, self.protocol, self.algorithm, encode(&self.public_key))
+    }
+}
+
+/// The data of a DS record (RFC 4034 section 5), which CDS shares: a digest of
+/// one of the child zone's DNSKEYs, kept in the parent.
+#[derive(Clone, PartialEq)]
+pub struct Ds {
+    pub     key_tag: u16,
+    pub   algorithm: u8,
+    pub digest_type: u8,
+    pub      digest: Vec<u8>,
+}
+
+impl Ds {
+    pub fn parse(data: &Vec<u8>) -> Option<Ds> {
+        let mut reader = RdataReader::new(data);
+        Some(Ds {
+            key_tag: reader.u16()?,
+            algorithm: reader.u8()?,
+            digest_type: reader.u8()?,
+            digest: reader.rest().to_vec(),
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.key_tag.to_be_bytes().to_vec();
+        rdata.push(self.algorithm);
+        rdata.push(self.digest_type);
+        rdata.extend_from_slice(&self.digest);
+        rdata
+    }
+
+    /// Makes the DS for one of a zone's DNSKEYs. None if we don't know the digest type.
+    #[allow(clippy::ptr_arg)]
+    pub fn from_dnskey(owner: &String, key: &Dnskey, digest_type: u8) -> Option<Ds> {
+        let mut data = str_domain_to_dns_domain(&owner.to_ascii_lowercase());
+        data.extend(key.to_rdata());
+
+        Some(Ds {
+            key_tag: key.key_tag(),
+            algorithm: key.algorithm,
+            digest_type,
+            digest: hash(ds_digest(digest_type)?, &data).ok()?.to_vec(),
+        })
+    }
+
+    /// Whether this DS is a digest of the DNSKEY.
+    pub fn matches(&self, owner: &String, key: &Dnskey) -> bool {
+        match Ds::from_dnskey(owner, key, self.digest_type) {
+            Some(ds) => ds == *self,
+            None => false,
+        }
+    }
+}
+
+pub const SHA1_DIGEST: u8 = 1;
+pub const SHA256_DIGEST: u8 = 2;
+pub const SHA384_DIGEST: u8 = 4;
+
+/// The hashes DS digests can be made with: SHA-1, SHA-256 (RFC 4509), and SHA-384
+/// (RFC 6605).
+fn ds_digest(digest_type: u8) -> Option<MessageDigest> {
+    match digest_type {
+        SHA1_DIGEST => Some(MessageDigest::sha1()),
+        SHA256_DIGEST => Some(MessageDigest::sha256()),
+        SHA384_DIGEST => Some(MessageDigest::sha384()),
+        _ => None,
+    }
+}
+
+pub fn is_supported_digest(digest_type: u8) -> bool {
+    ds_digest(digest_type).is_some()
+}
+
+impl fmt::Display for Ds {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.key_tag, self.algorithm, self.digest_type, write_hex(&self.digest))
+    }
+}
+
+/// The data of an RRSIG record (RFC 4034 section 3), which the older SIG shares.
+#[derive(Clone, PartialEq)]
+pub struct Rrsig {
+    pub type_covered: Type,
+    pub    algorithm: u8,
+    pub       labels: u8,
+    pub original_ttl: u32,
+    /// Seconds since the epoch, modulo 2^32, so they're compared with serial
+    /// number arithmetic.
+    pub   expiration: u32,
+    pub    inception: u32,
+    pub      key_tag: u16,
+    /// Lowercase, without a trailing dot.
+    pub       signer: String,
+    pub    signature: Vec<u8>,
+}
+
+impl Rrsig {
+    pub fn parse(data: &Vec<u8>) -> Option<Rrsig> {
+        let mut reader = RdataReader::new(data);
+        Some(Rrsig {
+            type_covered: Type::from_raw(reader.u16()?),
+            algorithm: reader.u8()?,
+            labels: reader.u8()?,
+            original_ttl: reader.u32()?,
+            expiration: reader.u32()?,
+            inception: reader.u32()?,
+            key_tag: reader.u16()?,
+            signer: reader.domain()?.to_ascii_lowercase(),
+            signature: reader.rest().to_vec(),
+        })
+    }
+
+    /// The data up to the signature, which is the part the signature covers.
+    pub fn fields_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.type_covered.to_u16().to_be_bytes().to_vec();
+        rdata.push(self.algorithm);
+        rdata.push(self.labels);
+        rdata.extend_from_slice(&self.original_ttl.to_be_bytes());
+        rdata.extend_from_slice(&self.expiration.to_be_bytes());
+        rdata.extend_from_slice(&self.inception.to_be_bytes());
+        rdata.extend_from_slice(&self.key_tag.to_be_bytes());
+        rdata.extend(str_domain_to_dns_domain(&self.signer));
+        rdata
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = self.fields_rdata();
+        rdata.extend_from_slice(&self.signature);
+        rdata
+    }
+
+    /// What the signature is made over (RFC 4034 section 3.1.8.1): the fields, then
+    /// the RRset in canonical form and order, under the owner it was signed as.
+    pub fn signed_data(&self, owner: &String, records: &[Resource]) -> Vec<u8> {
+        let mut data = self.fields_rdata();
+        for record in canonical_rrset(records) {
+            data.extend(canonical_record(&record, owner, self.original_ttl));
+        }
+        data
+    }
+}
+
+/// How many labels an RRSIG says its owner has, which leaves out the `*` of a
+/// wildcard (RFC 4034 section 3.1.3).
+pub fn label_count(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty() && *label != "*").count()
+}
+
+impl fmt::Display for Rrsig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{} {} {} {} {} {} {} {} {}",
+            write_type(self.type_covered), self.algorithm, self.labels, self.original_ttl,
+            write_timestamp(self.expiration), write_timestamp(self.inception), self.key_tag,
+            write_name(&self.signer), encode(&self.signature),
+        )
+    }
+}
+
+/// The data of an NSEC record (RFC 4034 section 4): the next name in the zone, and
+/// the types that exist at this one.
+#[derive(Clone, PartialEq)]
+pub struct Nsec {
+    pub next: String,
+    pub types: Vec<Type>,
+}
+
+impl Nsec {
+    pub fn parse(data: &Vec<u8>) -> Option<Nsec> {
+        let mut reader = RdataReader::new(data);
+        Some(Nsec {
+            next: reader.domain()?,
+            types: decode_type_bitmap(reader.rest())?,
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = str_domain_to_dns_domain(&self.next);
+        rdata.extend(encode_type_bitmap(&self.types));
+        rdata
+    }
+}
+
+impl fmt::Display for Nsec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", write_name(&self.next), write_types(&self.types))
+    }
+}
+
+/// The data of an NSEC3 record (RFC 5155 section 3): like NSEC, but for hashed
+/// names, so the zone can't be walked.
+#[derive(Clone, PartialEq)]
+pub struct Nsec3 {
+    pub hash_algorithm: u8,
+    pub          flags: u8,
+    pub     iterations: u16,
+    pub           salt: Vec<u8>,
+    pub    next_hashed: Vec<u8>,
+    pub          types: Vec<Type>,
+}
+
+impl Nsec3 {
+    pub fn parse(data: &Vec<u8>) -> Option<Nsec3> {
+        let mut reader = RdataReader::new(data);
+        let hash_algorithm = reader.u8()?;
+        let flags = reader.u8()?;
+        let iterations = reader.u16()?;
+        let salt_length = reader.u8()? as usize;
+        let salt = reader.take(salt_length)?.to_vec();
+        let hash_length = reader.u8()? as usize;
+        let next_hashed = reader.take(hash_length)?.to_vec();
+
+        Some(Nsec3 {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+            next_hashed,
+            types: decode_type_bitmap(reader.rest())?,
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = vec![self.hash_algorithm, self.flags];
+        rdata.extend_from_slice(&self.iterations.to_be_bytes());
+        rdata.push(self.salt.len() as u8);
+        rdata.extend_from_slice(&self.salt);
+        rdata.push(self.next_hashed.len() as u8);
+        rdata.extend_from_slice(&self.next_hashed);
+        rdata.extend(encode_type_bitmap(&self.types));
+        rdata
+    }
+}
+
+impl fmt::Display for Nsec3 {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f, "{} {} {} {} {}{}",
+            self.hash_algorithm, self.flags, self.iterations, write_salt(&self.salt),
+            base32hex_encode(&self.next_hashed), write_types(&self.types),
+        )
+    }
+}
+
+/// The only NSEC3 hash algorithm there is, SHA-1.
+pub const NSEC3_SHA1: u8 = 1;
+
+/// The NSEC3 flag that says the span may have unsigned delegations in it, so it
+/// doesn't prove they aren't there (RFC 5155 section 6).
+pub const NSEC3_OPT_OUT_FLAG: u8 = 0x01;
+
+/// Hashes a name the way NSEC3 does (RFC 5155 section 5): SHA-1 over the
+/// lowercase wire name and the salt, then over the hash and the salt again for
+/// each extra iteration.
+#[allow(clippy::ptr_arg)]
+pub fn nsec3_hash(name: &String, salt: &[u8], iterations: u16) -> Vec<u8> {
+    let mut digest = str_domain_to_dns_domain(&name.to_ascii_lowercase());
+    for _ in 0..=iterations {
+        digest.extend_from_slice(salt);
+        digest = hash(MessageDigest::sha1(), &digest).map(|bytes| bytes.to_vec()).unwrap_or_default();
+    }
+
+    digest
+}
+
+/// The data of an NSEC3PARAM record (RFC 5155 section 4): how the zone's NSEC3
+/// names are hashed.
+#[derive(Clone, PartialEq)]
+pub struct Nsec3Param {
+    pub hash_algorithm: u8,
+    pub          flags: u8,
+    pub     iterations: u16,
+    pub           salt: Vec<u8>,
+}
+
+impl Nsec3Param {
+    pub fn parse(data: &Vec<u8>) -> Option<Nsec3Param> {
+        let mut reader = RdataReader::new(data);
+        let hash_algorithm = reader.u8()?;
+        let flags = reader.u8()?;
+        let iterations = reader.u16()?;
+        let salt_length = reader.u8()? as usize;
+        let salt = reader.take(salt_length)?.to_vec();
+        if !reader.is_done() {
+            return None;
+        }
+
+        Some(Nsec3Param {
+            hash_algorithm,
+            flags,
+            iterations,
+            salt,
+        })
+    }
+
+    pub fn to_rdata(&self) -> Vec<u8> {
+        let mut rdata = vec![self.hash_algorithm, self.flags];
+        rdata.extend_from_slice(&self.iterations.to_be_bytes());
+        rdata.push(self.salt.len() as u8);
+        rdata.extend_from_slice(&self.salt);
+        rdata
+    }
+}
+
+impl fmt::Display for Nsec3Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} {} {}", self.hash_algorithm, self.flags, self.iterations, write_salt(&self.salt))
+    }
+}
+
+/// Whether a message asks for DNSSEC records, with the DO bit in its OPT record.
+pub fn dnssec_ok(packet: &DecomposedPacket) -> bool {
+    packet.additional_records.iter().any(|record| record.rtype == Type::OPT && record.ttl & DNSSEC_OK_FLAG != 0)
+}
+
+pub fn has_edns(packet: &DecomposedPacket) -> bool {
+    packet.additional_records.iter().any(|record| record.rtype == Type::OPT)
+}
+
+/// The biggest UDP response a client can take: what its OPT record says, or 512
+/// bytes without one (RFC 6891 section 6.2.3).
+pub fn udp_payload_size(packet: &DecomposedPacket) -> usize {
+    packet.additional_records.iter()
+        .find(|record| record.rtype == Type::OPT)
+        .map(|record| record.rclass.to_u16() as usize)
+        .unwrap_or(0)
+        .max(MIN_UDP_PAYLOAD_SIZE)
+}
+
+/// An EDNS OPT record (RFC 6891) with no options, which goes in the additional section.
+pub fn opt_record(dnssec_ok: bool) -> Resource {
+    Resource {
+        label: Label::Domain(String::new()),
+        rtype: Type::OPT,
+        rclass: Class::from_raw(EDNS_BUFFER_SIZE),
+        ttl: if dnssec_ok { DNSSEC_OK_FLAG } else { 0 },
+        length: 0,
+        data: vec![],
+    }
+}
+
+/// Sets the DO bit on a message, adding an OPT record if it doesn't have one.
+pub fn set_dnssec_ok(packet: &mut DecomposedPacket) {
+    match packet.additional_records.iter_mut().find(|record| record.rtype == Type::OPT) {
+        Some(opt) => opt.ttl |= DNSSEC_OK_FLAG,
+        None => packet.additional_records.push(opt_record(true)),
+    }
+}
+
+/// Whether an NSEC record's span, from its owner to the next name, has a name
+/// strictly inside it. The last NSEC in a zone wraps around to the apex.
+pub fn nsec_covers(owner: &str, next: &str, name: &str) -> bool {
+    match canonical_name_cmp(owner, next) {
+        Ordering::Less => canonical_name_cmp(owner, name) == Ordering::Less && canonical_name_cmp(name, next) == Ordering::Less,
+        _ => canonical_name_cmp(owner, name) == Ordering::Less || canonical_name_cmp(name, next) == Ordering::Less,
+    }
+}
+
+/// The same for NSEC3, where the span runs between hashes.
+pub fn nsec3_covers(owner_hash: &[u8], next_hash: &[u8], hash: &[u8]) -> bool {
+    if owner_hash < next_hash {
+        owner_hash < hash && hash < next_hash
+    } else {
+        owner_hash < hash || hash < next_hash
+    }
+}
+
+/// Presents the data of the DNSSEC types the way a master file has it. None for
+/// other types, or data that doesn't fit its type.
+pub fn present_dnssec_rdata(rtype: Type, data: &Vec<u8>) -> Option<String> {
+    match rtype {
+        Type::DNSKEY | Type::CDNSKEY | Type::KEY => Dnskey::parse(data).map(|dnskey| dnskey.to_string()),
+        Type::DS | Type::CDS => Ds::parse(data).map(|ds| ds.to_string()),
+        Type::RRSIG | Type::SIG => Rrsig::parse(data).map(|rrsig| rrsig.to_string()),
+        Type::NSEC => Nsec::parse(data).map(|nsec| nsec.to_string()),
+        Type::NSEC3 => Nsec3::parse(data).map(|nsec3| nsec3.to_string()),
+        Type::NSEC3PARAM => Nsec3Param::parse(data).map(|nsec3param| nsec3param.to_string()),
+        _ => None,
+    }
+}
+
+/// Encodes the types at a name as NSEC and NSEC3 records carry them (RFC 4034
+/// section 4.1.2): a bitmap for each block of 256 types that has any in it.
+pub fn encode_type_bitmap(types: &[Type]) -> Vec<u8> {
+    let mut numbers: Vec<u16> = types.iter().map(|rtype| rtype.to_u16()).collect();
+    numbers.sort_unstable();
+    numbers.dedup();
+
+    let mut output = vec![];
+    let mut index = 0;
+    while index < numbers.len() {
+        let window = (numbers[index] >> 8) as u8;
+        let mut bitmap = [0u8; 32];
+        let mut length = 0;
+
+        while index < numbers.len() && (numbers[index] >> 8) as u8 == window {
+            let low = (numbers[index] & 0xFF) as usize;
+            bitmap[low / 8] |= 0x80 >> (low % 8);
+            length = low / 8 + 1;
+            index += 1;
+        }
+
+        output.push(window);
+        output.push(length as u8);
+        output.extend_from_slice(&bitmap[..length]);
+    }
+
+    output
+}
+
+/// Reads a type bitmap back into types. None if it's malformed.
+pub fn decode_type_bitmap(data: &[u8]) -> Option<Vec<Type>> {
+    let mut types = vec![];
+    let mut index = 0;
+
+    while index < data.len() {
+        let window = *data.get(index)? as u16;
+        let length = *data.get(index + 1)? as usize;
+        if length == 0 || length > 32 {
+            return None;
+        }
+
+        let bitmap = data.get(index + 2..index + 2 + length)?;
+        for (byte_index, byte) in bitmap.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    types.push(Type::from_raw((window << 8) | (byte_index * 8 + bit) as u16));
+                }
+            }
+        }
+
+        index += 2 + length;
+    }
+
+    Some(types)
+}
+
+fn write_types(types: &[Type]) -> String {
+    types.iter().map(|rtype| format!(" {}", write_type(*rtype))).collect()
+}
+
+fn write_salt(salt: &[u8]) -> String {
+    if salt.is_empty() { String::from("-") } else { write_hex(salt) }
+}
+
+/// Writes an RRSIG time as YYYYMMDDHHmmSS in UTC (RFC 4034 section 3.2).
+pub fn write_timestamp(time: u32) -> String {
+    let (year, month, day) = civil_from_days((time / 86400) as i64);
+    let seconds = time % 86400;
+    format!("{:04}{:02}{:02}{:02}{:02}{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+/// Reads an RRSIG time, as YYYYMMDDHHmmSS or as plain seconds since the epoch.
+pub fn parse_timestamp(text: &str) -> Result<u32, String> {
+    if text.len() != 14 {
+        return text.parse().map_err(|_| format!("Bad time {}", text));
+    }
+
+    let field = |range: std::ops::Range<usize>| text.get(range).and_then(|digits| digits.parse::<i64>().ok()).ok_or(format!("Bad time {}", text));
+    let (year, month, day) = (field(0..4)?, field(4..6)?, field(6..8)?);
+    let (hour, minute, second) = (field(8..10)?, field(10..12)?, field(12..14)?);
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 60 {
+        return Err(format!("Bad time {}", text));
+    }
+
+    let seconds = days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second;
+    // Times wrap around every 136 years or so, and are compared accordingly.
+    Ok(seconds.rem_euclid(1 << 32) as u32)
+}
+
+/// The date a number of days after 1970-01-01 falls on.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 { shifted_month + 3 } else { shifted_month - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// How many days after 1970-01-01 a date is.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+const BASE32HEX_ALPHABET: &[u8; 32] = b"0123456789abcdefghijklmnopqrstuv";
+
+/// Encodes bytes as base32 with the extended hex alphabet and no padding, which
+/// is how NSEC3 hashes are written (RFC 5155 section 3.3).
+pub fn base32hex_encode(data: &[u8]) -> String {
+    let mut text = String::new();
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for byte in data {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            text.push(BASE32HEX_ALPHABET[((buffer >> bits) & 0x1F) as usize] as char);
+        }
+    }
+
+    if bits > 0 {
+        text.push(BASE32HEX_ALPHABET[((buffer << (5 - bits)) & 0x1F) as usize] as char);
+    }
+
+    text
+}
+
+pub fn base32hex_decode(text: &str) -> Result<Vec<u8>, String> {
+    let mut data = vec![];
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+
+    for character in text.trim_end_matches('=').bytes() {
+        let value = BASE32HEX_ALPHABET.iter()
+            .position(|letter| *letter == character.to_ascii_lowercase())
+            .ok_or(format!("Bad base32 {}", text))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((buffer >> bits) as u8);
+        }
+    }
+
+    Ok(data)
+}
+
+/// How the names in a type's data are laid out, for the types whose names are
+/// lowercased in canonical form (RFC 4034 section 6.2, less NSEC, which RFC 6840
+/// section 5.1 took off the list): the fixed bytes before the names, and how many
+/// names in a row.
+fn canonical_name_layout(rtype: Type) -> Option<(usize, usize)> {
+    match rtype {
+        Type::NS | Type::MD | Type::MF | Type::CNAME | Type::MB | Type::MG | Type::MR | Type::PTR | Type::DNAME | Type::NXT => Some((0, 1)),
+        Type::SOA | Type::MINFO | Type::RP => Some((0, 2)),
+        Type::MX | Type::AFSDB | Type::RT | Type::KX => Some((2, 1)),
+        Type::PX => Some((2, 2)),
+        Type::SRV => Some((6, 1)),
+        Type::RRSIG | Type::SIG => Some((18, 1)),
+        _ => None,
+    }
+}
+
+/// A record's data in canonical form, with the names in it lowercased.
+pub fn canonical_rdata(rtype: Type, data: &Vec<u8>) -> Vec<u8> {
+    let (prefix, names) = match canonical_name_layout(rtype) {
+        Some(layout) if data.len() >= layout.0 => layout,
+        _ => return data.clone(),
+    };
+
+    let mut output = data[..prefix].to_vec();
+    let mut index = prefix;
+    for _ in 0..names {
+        match read_name(data, index) {
+            Some((name, end)) => {
+                output.extend(str_domain_to_dns_domain(&name.to_ascii_lowercase()));
+                index = end;
+            },
+            None => return data.clone(),
+        }
+    }
+
+    output.extend_from_slice(&data[index..]);
+    output
+}
+
+/// A record in canonical wire form (RFC 4034 section 6.2), the way it goes into a
+/// signature: lowercase owner name, no compression, and the TTL the RRSIG says.
+#[allow(clippy::ptr_arg)]
+pub fn canonical_record(record: &Resource, owner: &String, original_ttl: u32) -> Vec<u8> {
+    let rdata = canonical_rdata(record.rtype, &record.data);
+
+    let mut output = str_domain_to_dns_domain(&owner.to_ascii_lowercase());
+    output.extend_from_slice(&record.rtype.to_u16().to_be_bytes());
+    output.extend_from_slice(&record.rclass.to_u16().to_be_bytes());
+    output.extend_from_slice(&original_ttl.to_be_bytes());
+    output.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    output.extend(rdata);
+    output
+}
+
+/// Compares names in canonical order (RFC 4034 section 6.1): label by label from
+/// the right, ignoring case, with parents before their children.
+pub fn canonical_name_cmp(a: &str, b: &str) -> Ordering {
+    let labels = |name: &str| -> Vec<Vec<u8>> {
+        name.trim_end_matches('.').split('.')
+            .filter(|label| !label.is_empty())
+            .rev()
+            .map(|label| label.chars().map(|character| (character as u32 as u8).to_ascii_lowercase()).collect())
+            .collect()
+    };
+
+    labels(a).cmp(&labels(b))
+}
+
+/// Puts an RRset in canonical order, by canonical data, without duplicates (RFC
+/// 4034 section 6.3).
+pub fn canonical_rrset(records: &[Resource]) -> Vec<Resource> {
+    let mut records: Vec<(Vec<u8>, Resource)> = records.iter()
+        .map(|record| (canonical_rdata(record.rtype, &record.data), record.clone()))
+        .collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    records.dedup_by(|a, b| a.0 == b.0);
+    records.into_iter().map(|(_, record)| record).collect()
+}
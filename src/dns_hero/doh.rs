@@ -1,9 +1,15 @@
+extern crate base64;
 extern crate hyper;
 extern crate hyper_tls;
 
+use base64::encode;
+
 use std::fmt::Write;
+use std::io;
 use std::sync::mpsc::{Sender, Receiver};
 use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 use hyper::client::Client;
 use hyper::rt::{self, Future, Stream};
@@ -17,30 +23,53 @@ pub const CLEAN_BROWSING_FAMILY_URL:   &str = "https://doh.cleanbrowsing.org/doh
 pub const CLOUDFLARE_URL:              &str = "https://cloudflare-dns.com/dns-query";
 pub const GOOGLE_URL:                  &str = "https://dns.google/dns-query";
 
-pub fn resolve_doh(base_url: &String, request_b64: &String) -> Packet {
+/// How long to wait on a DoH upstream before giving up on it.
+pub const DOH_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn resolve_doh(base_url: &String, request_b64: &String) -> io::Result<Packet> {
     let mut full_url = String::new();
     let _ = write!(&mut full_url, "{}?dns={}", base_url, request_b64);
 
-    Packet::init_from_full(get_request_sync(&full_url))
+    get_request_sync(&full_url).map(Packet::init_from_full)
 }
 
-fn get_request_sync(url: &String) -> Vec<u8> {
+/// Sends a raw query to a DoH server and gets back the raw response.
+pub fn exchange_doh(output_packet: &Vec<u8>, base_url: &String) -> io::Result<Vec<u8>> {
+    // DoH doesn't like padding
+    let packet_b64 = encode(output_packet).replace("=", "");
+    let response = resolve_doh(base_url, &packet_b64)?;
+
+    if response.data.len() < 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "DoH response too short to be a DNS message"));
+    }
+
+    Ok(response.data)
+}
+
+#[allow(clippy::ptr_arg)]
+fn get_request_sync(url: &String) -> io::Result<Vec<u8>> {
+    let uri = url.parse::<hyper::Uri>().map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+
+    // hyper has no timeout of its own, so the request runs on its own thread and
+    // we stop waiting on it after a while. If it fails, tx is dropped unsent.
     let (tx, rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = mpsc::channel();
-    rt::run(get_request(url).and_then(move |res| {
-        tx.send(res).expect("Failed to send result.");
-        Ok(())
-    }));
+    thread::spawn(move || {
+        rt::run(get_request(uri).and_then(move |res| {
+            let _ = tx.send(res);
+            Ok(())
+        }));
+    });
 
-    rx.recv().expect("Web request failed.")
+    rx.recv_timeout(DOH_TIMEOUT).map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "DoH request failed or timed out"))
 }
 
-fn get_request(url: &String) -> impl Future<Item=Vec<u8>, Error=()> {
+fn get_request(uri: hyper::Uri) -> impl Future<Item=Vec<u8>, Error=()> {
     let https = HttpsConnector::new(4).expect("TLS initialization failed");
     let client = Client::builder()
         .build::<_, hyper::Body>(https);
 
     client
-        .get(url.parse::<hyper::Uri>().unwrap())
+        .get(uri)
         .and_then(|res| {
             let mut response_receiver = Vec::<u8>::new();
             // The body is a stream, and for_each returns a new Future
@@ -1,9 +1,6 @@
 use strum_macros::{ Display };
 
-#[derive(Copy, Clone, Display)]
-#[repr(u16)]
-#[allow(dead_code)]
-pub enum Type {
+dns_code_enum!(Type, "TYPE", {
     A =            1,
     NS =           2,
     MD =           3,
@@ -93,20 +90,9 @@ pub enum Type {
     // 260-32767 are unassigned
     TA =         32768,
     DLV =        32769,
-}
-
-impl Type {
-    pub fn to_raw(&self, output: &mut Vec<u8>) {
-        let raw = *self as u16;
-        output.push(((raw >> 8) & 0x00FF) as u8);
-        output.push((raw & 0x00FF) as u8);
-    }
-}
+});
 
-#[derive(Copy, Clone, Display)]
-#[repr(u16)]
-#[allow(dead_code)]
-pub enum Class {
+dns_code_enum!(Class, "CLASS", {
     Internet = 1,
     // 2 is unassigned
     Chaos = 3,
@@ -114,15 +100,7 @@ pub enum Class {
     // 5-253 are unassigned
     QclassNone = 254,
     QclassAny = 255,
-}
-
-impl Class {
-    pub fn to_raw(&self, output: &mut Vec<u8>) {
-        let raw = *self as u16;
-        output.push(((raw >> 8) & 0x00FF) as u8);
-        output.push((raw & 0x00FF) as u8);
-    }
-}
+});
 
 #[derive(Copy, Clone, Display)]
 #[allow(dead_code)]
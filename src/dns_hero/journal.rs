@@ -0,0 +1,176 @@
+use std::collections::HashSet;
+use std::fs;
+
+use super::data::*;
+use super::enums::*;
+use super::transfer::soa_serial;
+use super::zone::{ parse_zone, write_record, write_zone };
+
+/// How many changes a zone's journal remembers. Secondaries further behind than
+/// that get the whole zone.
+pub const MAX_JOURNAL_DIFFS: usize = 100;
+
+/// One change to a zone, from one serial to the next, laid out the way IXFR sends
+/// it (RFC 1995 section 4).
+#[derive(Clone)]
+pub struct Diff {
+    pub old_soa: Resource,
+    pub deleted: Vec<Resource>,
+    pub new_soa: Resource,
+    pub   added: Vec<Resource>,
+}
+
+impl Diff {
+    /// Works out what changed between two copies of a zone, SOAs included. A record
+    /// whose TTL changed counts as deleted and added again.
+    #[allow(clippy::ptr_arg)]
+    pub fn between(old_records: &Vec<Resource>, new_records: &Vec<Resource>) -> Option<Diff> {
+        let old_soa = old_records.iter().find(|record| record.rtype == Type::SOA)?;
+        let new_soa = new_records.iter().find(|record| record.rtype == Type::SOA)?;
+
+        let old_set: HashSet<String> = old_records.iter().map(write_record).collect();
+        let new_set: HashSet<String> = new_records.iter().map(write_record).collect();
+
+        Some(Diff {
+            old_soa: old_soa.clone(),
+            deleted: old_records.iter()
+                .filter(|record| record.rtype != Type::SOA && !new_set.contains(&write_record(record)))
+                .cloned()
+                .collect(),
+            new_soa: new_soa.clone(),
+            added: new_records.iter()
+                .filter(|record| record.rtype != Type::SOA && !old_set.contains(&write_record(record)))
+                .cloned()
+                .collect(),
+        })
+    }
+
+    pub fn old_serial(&self) -> u32 {
+        soa_serial(&self.old_soa).unwrap_or(0)
+    }
+
+    pub fn new_serial(&self) -> u32 {
+        soa_serial(&self.new_soa).unwrap_or(0)
+    }
+
+    /// The diff's records in IXFR order: old SOA, deletions, new SOA, additions.
+    pub fn to_records(&self) -> Vec<Resource> {
+        let mut records = vec![self.old_soa.clone()];
+        records.extend(self.deleted.iter().cloned());
+        records.push(self.new_soa.clone());
+        records.extend(self.added.iter().cloned());
+        records
+    }
+}
+
+/// The recent changes to a zone, oldest first, so secondaries can catch up with
+/// IXFR instead of pulling the whole zone. It's kept next to the zone file, as a
+/// master file of diffs in IXFR order.
+#[derive(Clone)]
+pub struct Journal {
+    path: Option<String>,
+    diffs: Vec<Diff>,
+}
+
+impl Journal {
+    pub fn new(path: Option<String>) -> Journal {
+        Journal {
+            path,
+            diffs: vec![],
+        }
+    }
+
+    /// Reads a journal back in. It has to lead up to the zone's current serial; if
+    /// the zone moved on without it (say, it was edited while we were stopped), it's
+    /// no use and we start a fresh one.
+    pub fn load(path: &String, origin: &String, serial: u32) -> Journal {
+        let mut journal = Journal::new(Some(path.clone()));
+
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(_) => return journal,
+        };
+
+        let diffs = parse_zone(&text, origin)
+            .map_err(|err| err.to_string())
+            .and_then(|records| split_diffs(&records));
+
+        match diffs {
+            Ok(diffs) => {
+                let leads_to_serial = diffs.last().map(|diff| diff.new_serial() == serial).unwrap_or(true);
+                let is_chained = diffs.windows(2).all(|pair| pair[0].new_serial() == pair[1].old_serial());
+                if leads_to_serial && is_chained {
+                    journal.diffs = diffs;
+                } else {
+                    println!("Starting a new journal for {}, the old one doesn't lead up to serial {}", origin, serial);
+                }
+            },
+            Err(err) => println!("Ignoring journal {}: {}", path, err),
+        }
+
+        journal
+    }
+
+    /// Adds a change, forgets the oldest ones past the limit, and saves the journal.
+    pub fn record(&mut self, diff: Diff) {
+        self.diffs.push(diff);
+        if self.diffs.len() > MAX_JOURNAL_DIFFS {
+            let excess = self.diffs.len() - MAX_JOURNAL_DIFFS;
+            self.diffs.drain(..excess);
+        }
+
+        if let Some(path) = &self.path {
+            let records: Vec<Resource> = self.diffs.iter().flat_map(Diff::to_records).collect();
+            if let Err(err) = fs::write(path, write_zone(&records)) {
+                println!("Failed to save journal {}: {}", path, err);
+            }
+        }
+    }
+
+    /// The changes that take a zone from `serial` to the current one, or None if
+    /// the journal doesn't go back that far.
+    pub fn since(&self, serial: u32) -> Option<&[Diff]> {
+        self.diffs.iter()
+            .position(|diff| diff.old_serial() == serial)
+            .map(|start| &self.diffs[start..])
+    }
+}
+
+/// Where the journal of a zone saved in `file` goes.
+pub fn journal_path(file: &String) -> String {
+    format!("{}.jnl", file)
+}
+
+/// Splits a run of IXFR-ordered records back into diffs.
+#[allow(clippy::ptr_arg)]
+fn split_diffs(records: &Vec<Resource>) -> Result<Vec<Diff>, String> {
+    let mut diffs = vec![];
+    let mut records = records.iter().peekable();
+
+    while let Some(old_soa) = records.next() {
+        if old_soa.rtype != Type::SOA {
+            return Err(String::from("Diff doesn't start with an SOA"));
+        }
+
+        let mut deleted = vec![];
+        while let Some(record) = records.next_if(|record| record.rtype != Type::SOA) {
+            deleted.push(record.clone());
+        }
+
+        let new_soa = records.next().ok_or("Diff is missing its new SOA")?;
+
+        let mut added = vec![];
+        while let Some(record) = records.next_if(|record| record.rtype != Type::SOA) {
+            added.push(record.clone());
+        }
+
+        diffs.push(Diff {
+            old_soa: old_soa.clone(),
+            deleted,
+            new_soa: new_soa.clone(),
+            added,
+        });
+    }
+
+    Ok(diffs)
+}
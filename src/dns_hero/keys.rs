@@ -0,0 +1,363 @@
+use base64::{ decode, encode };
+use openssl::bn::{ BigNum, BigNumContext };
+use openssl::ec::{ EcGroup, EcKey, EcPoint, PointConversionForm };
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::{ hash, MessageDigest };
+use openssl::nid::Nid;
+use openssl::pkey::{ Id, PKey, Private };
+use openssl::rsa::Rsa;
+use openssl::sign::{ Signer, Verifier };
+use std::fs;
+
+use super::data::*;
+use super::enums::*;
+use super::zone::{ read_zone_file, write_record };
+
+/// The flags on the KEY record of a host's key, which is what SIG(0) signers have.
+pub const HOST_KEY_FLAGS: u16 = 512;
+
+/// The flags on the DNSKEY records of a zone's keys: zone signing keys, and the
+/// key signing keys the parent's DS records point at.
+pub const ZONE_SIGNING_KEY_FLAGS: u16 = 256;
+pub const KEY_SIGNING_KEY_FLAGS: u16 = 257;
+
+/// The protocol field of KEY and DNSKEY records, which is always 3 these days.
+const KEY_PROTOCOL: u8 = 3;
+
+/// The long-deprecated RSA/MD5, whose key tags are worked out differently.
+const RSAMD5_ALGORITHM: u8 = 1;
+
+/// RSA/SHA-256 and RSA/SHA-512 (RFC 5702). We don't make keys for them, but much
+/// of the signed DNS (the root, for one) uses them, so we can check them.
+const RSASHA256_ALGORITHM: u8 = 8;
+const RSASHA512_ALGORITHM: u8 = 10;
+
+/// The public-key algorithms we can sign and check signatures with, by their
+/// DNSSEC algorithm numbers (RFC 8624).
+#[derive(Copy, Clone, PartialEq)]
+pub enum KeyAlgorithm {
+    EcdsaP256Sha256,
+    EcdsaP384Sha384,
+    Ed25519,
+}
+
+impl KeyAlgorithm {
+    pub fn from_number(number: u8) -> Option<KeyAlgorithm> {
+        match number {
+            13 => Some(KeyAlgorithm::EcdsaP256Sha256),
+            14 => Some(KeyAlgorithm::EcdsaP384Sha384),
+            15 => Some(KeyAlgorithm::Ed25519),
+            _ => None,
+        }
+    }
+
+    pub fn number(self) -> u8 {
+        match self {
+            KeyAlgorithm::EcdsaP256Sha256 => 13,
+            KeyAlgorithm::EcdsaP384Sha384 => 14,
+            KeyAlgorithm::Ed25519 => 15,
+        }
+    }
+
+    /// Looks an algorithm up by its mnemonic or number, ignoring case.
+    pub fn from_name(name: &str) -> Option<KeyAlgorithm> {
+        match name.to_uppercase().as_str() {
+            "ECDSAP256SHA256" => Some(KeyAlgorithm::EcdsaP256Sha256),
+            "ECDSAP384SHA384" => Some(KeyAlgorithm::EcdsaP384Sha384),
+            "ED25519" => Some(KeyAlgorithm::Ed25519),
+            number => number.parse().ok().and_then(KeyAlgorithm::from_number),
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            KeyAlgorithm::EcdsaP256Sha256 => "ECDSAP256SHA256",
+            KeyAlgorithm::EcdsaP384Sha384 => "ECDSAP384SHA384",
+            KeyAlgorithm::Ed25519 => "ED25519",
+        }
+    }
+
+    /// The curve, hash, and size of each coordinate (and of r and s) for the ECDSA
+    /// algorithms. None for Ed25519, which does its own hashing.
+    fn ecdsa_parameters(self) -> Option<(Nid, MessageDigest, usize)> {
+        match self {
+            KeyAlgorithm::EcdsaP256Sha256 => Some((Nid::X9_62_PRIME256V1, MessageDigest::sha256(), 32)),
+            KeyAlgorithm::EcdsaP384Sha384 => Some((Nid::SECP384R1, MessageDigest::sha384(), 48)),
+            KeyAlgorithm::Ed25519 => None,
+        }
+    }
+}
+
+/// Works out the tag of a key from its KEY or DNSKEY record data (RFC 4034
+/// appendix B). It's only a hint for finding the key, since tags can collide.
+pub fn key_tag(rdata: &[u8]) -> u16 {
+    // RSA/MD5 keys go by the end of their modulus instead (appendix B.1).
+    if rdata.len() >= 7 && rdata[3] == RSAMD5_ALGORITHM {
+        return u16::from_be_bytes([rdata[rdata.len() - 3], rdata[rdata.len() - 2]]);
+    }
+
+    let mut sum: u32 = 0;
+    for (index, byte) in rdata.iter().enumerate() {
+        sum += if index % 2 == 0 { (*byte as u32) << 8 } else { *byte as u32 };
+    }
+
+    sum += (sum >> 16) & 0xFFFF;
+    (sum & 0xFFFF) as u16
+}
+
+/// Whether we can check signatures made with an algorithm.
+pub fn is_supported_algorithm(number: u8) -> bool {
+    number == RSASHA256_ALGORITHM || number == RSASHA512_ALGORITHM || KeyAlgorithm::from_number(number).is_some()
+}
+
+/// The mnemonic for an algorithm number, for showing people.
+pub fn algorithm_name(number: u8) -> String {
+    match number {
+        RSAMD5_ALGORITHM => String::from("RSAMD5"),
+        RSASHA256_ALGORITHM => String::from("RSASHA256"),
+        RSASHA512_ALGORITHM => String::from("RSASHA512"),
+        _ => match KeyAlgorithm::from_number(number) {
+            Some(algorithm) => String::from(algorithm.name()),
+            None => format!("algorithm {}", number),
+        },
+    }
+}
+
+/// Checks a signature over some data with the public key in KEY or DNSKEY record
+/// data. Keys of algorithms we don't know never check out.
+pub fn verify_with_key(rdata: &[u8], data: &[u8], signature: &[u8]) -> bool {
+    let verified = match rdata {
+        [_, _, KEY_PROTOCOL, RSASHA256_ALGORITHM, public_key @ ..] => verify_rsa(MessageDigest::sha256(), public_key, data, signature),
+        [_, _, KEY_PROTOCOL, RSASHA512_ALGORITHM, public_key @ ..] => verify_rsa(MessageDigest::sha512(), public_key, data, signature),
+        [_, _, KEY_PROTOCOL, algorithm, public_key @ ..] => match KeyAlgorithm::from_number(*algorithm) {
+            Some(algorithm) => verify(algorithm, public_key, data, signature),
+            None => return false,
+        },
+        _ => return false,
+    };
+
+    verified.unwrap_or(false)
+}
+
+/// Checks a PKCS #1 v1.5 signature. The key is the exponent's length (in one byte,
+/// or three when it's long), the exponent, and then the modulus (RFC 3110 section 2).
+fn verify_rsa(digest: MessageDigest, public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+    let (exponent_length, rest) = match public_key {
+        [0, high, low, rest @ ..] => (u16::from_be_bytes([*high, *low]) as usize, rest),
+        [length, rest @ ..] => (*length as usize, rest),
+        [] => return Ok(false),
+    };
+
+    if exponent_length == 0 || rest.len() <= exponent_length {
+        return Ok(false);
+    }
+
+    let exponent = BigNum::from_slice(&rest[..exponent_length])?;
+    let modulus = BigNum::from_slice(&rest[exponent_length..])?;
+    let key = PKey::from_rsa(Rsa::from_public_components(modulus, exponent)?)?;
+
+    let mut verifier = Verifier::new(digest, &key)?;
+    verifier.update(data)?;
+    verifier.verify(signature)
+}
+
+fn verify(algorithm: KeyAlgorithm, public_key: &[u8], data: &[u8], signature: &[u8]) -> Result<bool, ErrorStack> {
+    let (curve, digest, length) = match algorithm.ecdsa_parameters() {
+        Some(parameters) => parameters,
+        None => {
+            let key = PKey::public_key_from_raw_bytes(public_key, Id::ED25519)?;
+            return Verifier::new_without_digest(&key)?.verify_oneshot(signature, data);
+        },
+    };
+
+    // ECDSA keys are the point's x and y, and signatures are r and s, each cut to
+    // the same length (RFC 6605 section 4).
+    if public_key.len() != length * 2 || signature.len() != length * 2 {
+        return Ok(false);
+    }
+
+    let group = EcGroup::from_curve_name(curve)?;
+    let x = BigNum::from_slice(&public_key[..length])?;
+    let y = BigNum::from_slice(&public_key[length..])?;
+    let key = EcKey::from_public_key_affine_coordinates(&group, &x, &y)?;
+
+    let r = BigNum::from_slice(&signature[..length])?;
+    let s = BigNum::from_slice(&signature[length..])?;
+    EcdsaSig::from_private_components(r, s)?.verify(&hash(digest, data)?, &key)
+}
+
+/// A key pair we sign with, and the name its public half is published under in
+/// a KEY (or DNSKEY) record.
+pub struct SigningKey {
+    /// Lowercase, without a trailing dot.
+    pub      name: String,
+    pub     flags: u16,
+    pub algorithm: KeyAlgorithm,
+    private_key: PKey<Private>,
+}
+
+impl SigningKey {
+    #[allow(clippy::ptr_arg)]
+    pub fn generate(name: &String, algorithm: KeyAlgorithm, flags: u16) -> SigningKey {
+        let private_key = match algorithm.ecdsa_parameters() {
+            Some((curve, _, _)) => {
+                let group = EcGroup::from_curve_name(curve).expect("Could not get curve");
+                PKey::from_ec_key(EcKey::generate(&group).expect("Could not generate key")).expect("Could not generate key")
+            },
+            None => PKey::generate_ed25519().expect("Could not generate key"),
+        };
+
+        SigningKey {
+            name: name.trim_end_matches('.').to_lowercase(),
+            flags,
+            algorithm,
+            private_key,
+        }
+    }
+
+    /// Reads a key pair saved by `save`, given the path of either of its files, or
+    /// the path they share without the extension.
+    #[allow(clippy::ptr_arg)]
+    pub fn load(path: &String) -> Result<SigningKey, String> {
+        let base = path.trim_end_matches(".key").trim_end_matches(".private");
+        let public_path = format!("{}.key", base);
+        let private_path = format!("{}.private", base);
+
+        let records = read_zone_file(&public_path, &String::new()).map_err(|err| err.to_string())?;
+        let record = records.iter()
+            .find(|record| record.rtype == Type::KEY || record.rtype == Type::DNSKEY)
+            .ok_or(format!("{}: no KEY or DNSKEY record", public_path))?;
+
+        let (flags, algorithm) = match record.data.as_slice() {
+            [high, low, KEY_PROTOCOL, algorithm, ..] => (u16::from_be_bytes([*high, *low]), *algorithm),
+            _ => return Err(format!("{}: bad key data", public_path)),
+        };
+        let algorithm = KeyAlgorithm::from_number(algorithm).ok_or(format!("{}: unsupported algorithm {}", public_path, algorithm))?;
+
+        let private_text = fs::read_to_string(&private_path).map_err(|err| format!("{}: {}", private_path, err))?;
+        let private_bytes = private_text.lines()
+            .find_map(|line| line.strip_prefix("PrivateKey:"))
+            .and_then(|value| decode(value.trim()).ok())
+            .ok_or(format!("{}: no private key", private_path))?;
+
+        let name = match &record.label {
+            Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+            Label::Pointer(_) => String::new(),
+        };
+
+        let key = SigningKey {
+            name,
+            flags,
+            algorithm,
+            private_key: private_key_from_bytes(algorithm, &private_bytes).map_err(|err| format!("{}: {}", private_path, err))?,
+        };
+
+        if key.public_key() != record.data[4..] {
+            return Err(format!("{} and {} aren't halves of the same key", public_path, private_path));
+        }
+
+        Ok(key)
+    }
+
+    /// Writes the key out BIND-style, as `K<name>.+<algorithm>+<tag>.key` with the
+    /// public key record in it, and `.private` next to it. Returns the path they
+    /// share without the extension.
+    #[allow(clippy::ptr_arg)]
+    pub fn save(&self, directory: &String, rtype: Type) -> Result<String, String> {
+        let base = format!("{}/K{}.+{:03}+{:05}", directory.trim_end_matches('/'), self.name, self.algorithm.number(), self.key_tag());
+
+        let public_text = format!("; {} key for {}, key tag {}\n{}\n", self.algorithm.name(), self.name, self.key_tag(), write_record(&self.record(rtype, 3600)));
+        let private_text = format!(
+            "Private-key-format: v1.3\nAlgorithm: {} ({})\nPrivateKey: {}\n",
+            self.algorithm.number(), self.algorithm.name(), encode(&self.private_bytes()),
+        );
+
+        fs::write(format!("{}.key", base), public_text).map_err(|err| format!("{}.key: {}", base, err))?;
+        fs::write(format!("{}.private", base), private_text).map_err(|err| format!("{}.private: {}", base, err))?;
+        Ok(base)
+    }
+
+    /// The public key the way KEY and DNSKEY records carry it.
+    pub fn public_key(&self) -> Vec<u8> {
+        match self.algorithm.ecdsa_parameters() {
+            Some(_) => {
+                let key = self.private_key.ec_key().expect("Not an EC key");
+                let mut context = BigNumContext::new().expect("Could not make BN context");
+                let point = key.public_key().to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut context).expect("Could not get public key");
+                // Without the 0x04 that says it's uncompressed.
+                point[1..].to_vec()
+            },
+            None => self.private_key.raw_public_key().expect("Could not get public key"),
+        }
+    }
+
+    /// The data of the key's KEY or DNSKEY record.
+    pub fn rdata(&self) -> Vec<u8> {
+        let mut rdata = self.flags.to_be_bytes().to_vec();
+        rdata.push(KEY_PROTOCOL);
+        rdata.push(self.algorithm.number());
+        rdata.extend(self.public_key());
+        rdata
+    }
+
+    pub fn key_tag(&self) -> u16 {
+        key_tag(&self.rdata())
+    }
+
+    /// The key's public record, as a KEY or a DNSKEY.
+    pub fn record(&self, rtype: Type, ttl: u32) -> Resource {
+        let rdata = self.rdata();
+        Resource {
+            label: Label::Domain(self.name.clone()),
+            rtype,
+            rclass: Class::Internet,
+            ttl,
+            length: rdata.len() as u16,
+            data: rdata,
+        }
+    }
+
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        match self.algorithm.ecdsa_parameters() {
+            Some((_, digest, length)) => {
+                let key = self.private_key.ec_key().expect("Not an EC key");
+                let signature = EcdsaSig::sign(&hash(digest, data).expect("Could not hash"), &key).expect("Could not sign");
+                let mut output = signature.r().to_vec_padded(length as i32).expect("Bad signature");
+                output.extend(signature.s().to_vec_padded(length as i32).expect("Bad signature"));
+                output
+            },
+            None => Signer::new_without_digest(&self.private_key)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(data))
+                .expect("Could not sign"),
+        }
+    }
+
+    /// The private key the way `.private` files have it: the private scalar for
+    /// ECDSA, and the seed for Ed25519.
+    fn private_bytes(&self) -> Vec<u8> {
+        match self.algorithm.ecdsa_parameters() {
+            Some((_, _, length)) => {
+                let key = self.private_key.ec_key().expect("Not an EC key");
+                key.private_key().to_vec_padded(length as i32).expect("Could not get private key")
+            },
+            None => self.private_key.raw_private_key().expect("Could not get private key"),
+        }
+    }
+}
+
+fn private_key_from_bytes(algorithm: KeyAlgorithm, bytes: &[u8]) -> Result<PKey<Private>, String> {
+    let result = match algorithm.ecdsa_parameters() {
+        Some((curve, _, _)) => (|| {
+            let group = EcGroup::from_curve_name(curve)?;
+            let private_number = BigNum::from_slice(bytes)?;
+            let context = BigNumContext::new()?;
+            let mut public_point = EcPoint::new(&group)?;
+            public_point.mul_generator(&group, &private_number, &context)?;
+            PKey::from_ec_key(EcKey::from_private_components(&group, &private_number, &public_point)?)
+        })(),
+        None => PKey::private_key_from_raw_bytes(bytes, Id::ED25519),
+    };
+
+    result.map_err(|_| String::from("bad private key"))
+}
@@ -0,0 +1,253 @@
+use std::collections::HashMap;
+use std::fs;
+use std::net::{ IpAddr, Ipv4Addr, Ipv6Addr };
+
+use super::data::*;
+use super::enums::*;
+use super::util::*;
+
+/// TTL of answers made up from local records.
+pub const LOCAL_TTL: u32 = 60;
+
+/// How many CNAMEs in a row are followed before giving up on a loop.
+const MAX_CNAME_CHAIN: usize = 8;
+
+/// One record the daemon answers with itself.
+#[derive(Clone)]
+pub struct StaticRecord {
+    /// Lowercase, without a trailing dot.
+    pub  name: String,
+    pub rtype: Type,
+    pub rdata: Vec<u8>,
+}
+
+impl StaticRecord {
+    /// Reads a `record` config line, which goes name, type, then value:
+    ///
+    /// ```text
+    /// record  dev.local      A      10.0.0.5
+    /// record  dev.local      AAAA   fd00::5
+    /// record  www.dev.local  CNAME  dev.local
+    /// record  dev.local      TXT    "built by hand"
+    /// record  5.0.0.10.in-addr.arpa  PTR  dev.local
+    /// ```
+    #[allow(clippy::ptr_arg)]
+    pub fn parse(args: &Vec<String>) -> Result<StaticRecord, String> {
+        if args.len() < 3 {
+            return Err(String::from("record takes a name, a type, and a value"));
+        }
+
+        let name = normalize_name(&args[0])?;
+        let value = args[2..].join(" ");
+
+        let (rtype, rdata) = match args[1].to_uppercase().as_str() {
+            "A" => {
+                let ip: Ipv4Addr = value.parse().map_err(|_| format!("Bad IPv4 address {}", value))?;
+                (Type::A, ip.octets().to_vec())
+            },
+            "AAAA" => {
+                let ip: Ipv6Addr = value.parse().map_err(|_| format!("Bad IPv6 address {}", value))?;
+                (Type::AAAA, ip.octets().to_vec())
+            },
+            "CNAME" => (Type::CNAME, str_domain_to_dns_domain(&normalize_name(&value)?)),
+            "PTR" => (Type::PTR, str_domain_to_dns_domain(&normalize_name(&value)?)),
+            "TXT" => (Type::TXT, txt_rdata(value.trim_matches('"'))),
+            other => return Err(format!("Unsupported record type {}", other)),
+        };
+
+        Ok(StaticRecord {
+            name,
+            rtype,
+            rdata,
+        })
+    }
+
+    /// The record's value as a name, for CNAMEs and PTRs.
+    fn target(&self) -> Option<String> {
+        read_name(&self.rdata, 0).map(|(name, _)| name.to_lowercase())
+    }
+}
+
+/// Names the daemon answers for itself, from static records and hosts files.
+pub struct LocalRecords {
+    records: HashMap<String, Vec<StaticRecord>>,
+}
+
+impl Default for LocalRecords {
+    fn default() -> LocalRecords {
+        LocalRecords::new()
+    }
+}
+
+impl LocalRecords {
+    pub fn new() -> LocalRecords {
+        LocalRecords {
+            records: HashMap::new(),
+        }
+    }
+
+    /// Adds a record. Addresses get a matching PTR record too, unless the reverse
+    /// name already has one.
+    pub fn add(&mut self, record: StaticRecord) {
+        let reverse_name = match record.rtype {
+            Type::A | Type::AAAA => reverse_name(&record.rdata),
+            _ => None,
+        };
+
+        if let Some(reverse_name) = reverse_name {
+            let has_ptr = self.records.get(&reverse_name)
+                .map(|records| records.iter().any(|existing| existing.rtype == Type::PTR))
+                .unwrap_or(false);
+
+            if !has_ptr {
+                let ptr = StaticRecord {
+                    name: reverse_name.clone(),
+                    rtype: Type::PTR,
+                    rdata: str_domain_to_dns_domain(&record.name),
+                };
+                self.records.entry(reverse_name).or_default().push(ptr);
+            }
+        }
+
+        let records = self.records.entry(record.name.clone()).or_default();
+        if !records.iter().any(|existing| existing.rtype == record.rtype && existing.rdata == record.rdata) {
+            records.push(record);
+        }
+    }
+
+    /// Adds every address in a hosts file, in the usual `address name aliases...`
+    /// format. The first name of a line is the one its reverse entry points at. Lines
+    /// with addresses we can't read are skipped.
+    pub fn load_hosts(&mut self, path: &String) -> Result<(), String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+
+        for (line_index, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or("");
+            let mut words = line.split_whitespace();
+            let address = match words.next() {
+                Some(address) => address,
+                None => continue,
+            };
+
+            // Link-local addresses can have a scope, like fe80::1%lo0, which means
+            // nothing to anyone else.
+            let unscoped = address.split('%').next().unwrap_or(address);
+            let (rtype, rdata) = match unscoped.parse::<IpAddr>() {
+                Ok(IpAddr::V4(ip)) => (Type::A, ip.octets().to_vec()),
+                Ok(IpAddr::V6(ip)) => (Type::AAAA, ip.octets().to_vec()),
+                Err(_) => {
+                    println!("{}: line {}: Skipping bad address {}", path, line_index + 1, address);
+                    continue;
+                },
+            };
+
+            for name in words {
+                let name = normalize_name(name).map_err(|err| format!("{}: line {}: {}", path, line_index + 1, err))?;
+                self.add(StaticRecord {
+                    name,
+                    rtype,
+                    rdata: rdata.clone(),
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answers a question from the local records, following CNAMEs as far as they
+    /// stay local. None means the name isn't ours. A name we know with nothing of
+    /// the asked-for type gets an empty answer.
+    pub fn lookup(&self, question: &Question) -> Option<Vec<Resource>> {
+        let asked_name = match &question.label {
+            Label::Pointer(_) => return None,
+            Label::Domain(name) => name,
+        };
+
+        let mut name = asked_name.trim_end_matches('.').to_lowercase();
+        let mut owner = Label::Domain(asked_name.clone());
+        let mut answers = vec![];
+
+        for _ in 0..MAX_CNAME_CHAIN {
+            let records = match self.records.get(&name) {
+                Some(records) => records,
+                None => break,
+            };
+
+            let matching: Vec<&StaticRecord> = records.iter()
+                .filter(|record| record.rtype == question.qtype || question.qtype == Type::ANY)
+                .collect();
+
+            if !matching.is_empty() {
+                answers.extend(matching.into_iter().map(|record| to_resource(&owner, record)));
+                break;
+            }
+
+            let cname = match records.iter().find(|record| record.rtype == Type::CNAME) {
+                Some(cname) => cname,
+                None => break,
+            };
+
+            answers.push(to_resource(&owner, cname));
+            name = cname.target()?;
+            owner = Label::Domain(name.clone());
+        }
+
+        if answers.is_empty() && !self.records.contains_key(&asked_name.trim_end_matches('.').to_lowercase()) {
+            return None;
+        }
+
+        Some(answers)
+    }
+}
+
+fn to_resource(owner: &Label, record: &StaticRecord) -> Resource {
+    Resource {
+        label: owner.clone(),
+        rtype: record.rtype,
+        rclass: Class::Internet,
+        ttl: LOCAL_TTL,
+        length: record.rdata.len() as u16,
+        data: record.rdata.clone(),
+    }
+}
+
+/// Lowercases a name, drops its trailing dot, and makes sure it'll fit in a packet.
+fn normalize_name(name: &str) -> Result<String, String> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    if name.is_empty() || name.len() > 253 || name.split('.').any(|part| part.is_empty() || part.len() > 63) {
+        return Err(format!("Bad name {}", name));
+    }
+
+    Ok(name)
+}
+
+/// Splits text into the 255-byte character strings TXT records are made of.
+fn txt_rdata(text: &str) -> Vec<u8> {
+    let mut rdata = vec![];
+    for chunk in text.as_bytes().chunks(255) {
+        rdata.push(chunk.len() as u8);
+        rdata.extend_from_slice(chunk);
+    }
+
+    if rdata.is_empty() {
+        rdata.push(0);
+    }
+
+    rdata
+}
+
+/// The in-addr.arpa or ip6.arpa name of an address.
+#[allow(clippy::ptr_arg)]
+fn reverse_name(address: &Vec<u8>) -> Option<String> {
+    match address.len() {
+        4 => Some(format!("{}.{}.{}.{}.in-addr.arpa", address[3], address[2], address[1], address[0])),
+        16 => {
+            let nibbles: Vec<String> = address.iter().rev()
+                .flat_map(|byte| vec![byte & 0x0F, byte >> 4])
+                .map(|nibble| format!("{:x}", nibble))
+                .collect();
+            Some(format!("{}.ip6.arpa", nibbles.join(".")))
+        },
+        _ => None,
+    }
+}
@@ -8,3 +8,55 @@ macro_rules! coalesce_result {
         }
     };
 }
+
+/// Declares an enum of DNS code points (types, classes) that can also carry values
+/// it has no name for, so nothing is lost parsing a packet and writing it back out.
+/// Unknown values display the RFC 3597 way, e.g. `TYPE65280`.
+macro_rules! dns_code_enum {
+    ( $name:ident, $unknown_prefix:expr, { $( $variant:ident = $value:expr, )* } ) => {
+        #[derive(Copy, Clone, PartialEq, Eq, Hash)]
+        #[allow(dead_code)]
+        pub enum $name {
+            $( $variant, )*
+            Unknown(u16),
+        }
+
+        impl $name {
+            pub fn from_raw(raw: u16) -> $name {
+                match raw {
+                    $( $value => $name::$variant, )*
+                    _ => $name::Unknown(raw),
+                }
+            }
+
+            pub fn to_u16(self) -> u16 {
+                match self {
+                    $( $name::$variant => $value, )*
+                    $name::Unknown(raw) => raw,
+                }
+            }
+
+            /// Looks a value up by its name, or by the RFC 3597 form, ignoring case.
+            pub fn from_name(name: &str) -> Option<$name> {
+                let name = name.to_uppercase();
+                $( if name == stringify!($variant).to_uppercase() { return Some($name::$variant); } )*
+                name.strip_prefix($unknown_prefix).and_then(|raw| raw.parse().ok()).map($name::from_raw)
+            }
+
+            pub fn to_raw(&self, output: &mut Vec<u8>) {
+                let raw = self.to_u16();
+                output.push(((raw >> 8) & 0x00FF) as u8);
+                output.push((raw & 0x00FF) as u8);
+            }
+        }
+
+        impl ::std::fmt::Display for $name {
+            fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+                match self {
+                    $( $name::$variant => write!(f, stringify!($variant)), )*
+                    $name::Unknown(raw) => write!(f, "{}{}", $unknown_prefix, raw),
+                }
+            }
+        }
+    };
+}
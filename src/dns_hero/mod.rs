@@ -8,3 +8,31 @@ pub mod enums;
 pub mod doh;
 pub mod udp;
 pub mod daemon;
+pub mod cache;
+pub mod config;
+pub mod pipeline;
+pub mod stages;
+pub mod upstream;
+pub mod local;
+pub mod zone;
+pub mod authoritative;
+pub mod zone_dnssec;
+pub mod tcp;
+pub mod transfer;
+pub mod acl;
+pub mod journal;
+pub mod notify;
+pub mod secondary;
+pub mod update;
+pub mod tsig;
+pub mod keys;
+pub mod sig0;
+pub mod dnssec;
+pub mod validator;
+pub mod signer;
+pub mod trace;
+pub mod resolver;
+
+// Stand-in zones the tests share.
+#[cfg(test)]
+pub mod testing;
@@ -0,0 +1,144 @@
+use std::io;
+use std::net::{ IpAddr, SocketAddr, UdpSocket };
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use super::authoritative::*;
+use super::data::*;
+use super::enums::*;
+use super::secondary::Secondary;
+use super::tcp::parse_server_address;
+use super::transfer::{ serial_is_newer, soa_serial };
+use super::util::*;
+
+/// How many times a NOTIFY is sent before giving up on the secondary.
+const NOTIFY_ATTEMPTS: usize = 5;
+
+/// How long to wait for a secondary to acknowledge a NOTIFY, per attempt.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tells a zone's secondaries it's changed (RFC 1996), each from its own thread,
+/// so they can come and get the new copy.
+pub fn notify_secondaries(hosted: &HostedZone) {
+    for target in &hosted.notify {
+        let target = target.clone();
+        let notify = make_notify(&hosted.zone);
+        let origin = hosted.zone.origin.clone();
+
+        thread::spawn(move || match send_notify(&target, &notify) {
+            Ok(()) => println!("{} acknowledged our NOTIFY for {}", target, origin),
+            Err(err) => println!("Couldn't NOTIFY {} about {}: {}", target, origin, err),
+        });
+    }
+}
+
+/// A NOTIFY for a zone, with its current SOA as a hint of what's changed.
+pub fn make_notify(zone: &Zone) -> DecomposedPacket {
+    let mut packet = DecomposedPacket::new();
+    packet.id = random_u16();
+    packet.opcode = Opcode::Notify;
+    packet.is_authoritative = true;
+    packet.questions.push(Question {
+        label: Label::Domain(zone.origin.clone()),
+        qtype: Type::SOA,
+        qclass: Class::Internet,
+    });
+    packet.answers.push(zone.soa().clone());
+    packet
+}
+
+/// Sends a NOTIFY over UDP until the secondary answers it, or we run out of tries.
+fn send_notify(target: &String, notify: &DecomposedPacket) -> io::Result<()> {
+    let address = parse_server_address(target)?;
+    let local_address: SocketAddr = if address.is_ipv4() { "0.0.0.0:0".parse().unwrap() } else { "[::]:0".parse().unwrap() };
+    let socket = UdpSocket::bind(local_address)?;
+    socket.connect(address)?;
+    socket.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+
+    let raw = notify.to_raw().data;
+    let mut buf = [0; 65536];
+
+    for _ in 0..NOTIFY_ATTEMPTS {
+        socket.send(&raw)?;
+
+        match socket.recv(&mut buf) {
+            Ok(received_bytes) => {
+                let response = Packet::from_vec(&buf[..received_bytes].to_vec());
+                let is_ours = received_bytes >= 12 && response.is_response() && response.get_id() == notify.id;
+                if is_ours {
+                    return match response.get_response_code() {
+                        ResponseCode::NoError => Ok(()),
+                        code => Err(io::Error::new(io::ErrorKind::Other, format!("Answered {}", code))),
+                    };
+                }
+            },
+            Err(err) => match err.kind() {
+                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => continue,
+                _ => return Err(err),
+            },
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::TimedOut, "No answer"))
+}
+
+/// Answers a NOTIFY from a primary. If it's for one of our secondary zones and
+/// really came from one of the zone's primaries, a refresh is started in the
+/// background, unless the SOA it came with says we're already current.
+#[allow(clippy::ptr_arg)]
+pub fn answer_notify(query: &DecomposedPacket, client: IpAddr, secondaries: &Vec<Arc<Secondary>>, zones: &Arc<Zones>) -> Vec<u8> {
+    let mut response = query.clone();
+    response.is_response = true;
+    response.answers.clear();
+    response.authorities.clear();
+    response.additional_records.clear();
+
+    let origin = match query.questions.first().map(|question| &question.label) {
+        Some(Label::Domain(name)) => name.trim_end_matches('.').to_lowercase(),
+        _ => {
+            response.response_code = ResponseCode::FormErr;
+            return response.to_raw().data;
+        },
+    };
+
+    let secondary = match secondaries.iter().find(|secondary| secondary.origin == origin) {
+        Some(secondary) => secondary.clone(),
+        None => {
+            println!("Ignoring NOTIFY from {} for {}, we're not a secondary for it", client, origin);
+            response.response_code = ResponseCode::NotAuth;
+            return response.to_raw().data;
+        },
+    };
+
+    if !secondary.is_primary(client) {
+        println!("Refusing NOTIFY from {} for {}, it isn't one of the zone's primaries", client, origin);
+        response.response_code = ResponseCode::Refused;
+        return response.to_raw().data;
+    }
+
+    let hinted_serial = query.answers.iter().find_map(soa_serial);
+    let current_serial = zones.get(&origin).map(|hosted| hosted.zone.serial());
+    let is_stale = match (hinted_serial, current_serial) {
+        (Some(hinted), Some(current)) => serial_is_newer(hinted, current),
+        _ => true,
+    };
+
+    println!("Got NOTIFY from {} for {}", client, origin);
+    if is_stale {
+        let zones = zones.clone();
+        thread::spawn(move || refresh_secondary(&secondary, &zones));
+    }
+
+    response.to_raw().data
+}
+
+/// Refreshes a secondary zone, passing the news on to our own secondaries if it
+/// changed.
+pub fn refresh_secondary(secondary: &Secondary, zones: &Zones) {
+    match secondary.refresh(zones) {
+        Ok(Some(hosted)) => notify_secondaries(&hosted),
+        Ok(None) => {},
+        Err(err) => println!("{}", err),
+    }
+}
@@ -0,0 +1,143 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use super::data::*;
+use super::enums::*;
+use super::upstream::UpstreamGroup;
+
+/// Who sent a query.
+#[derive(Clone)]
+pub struct ClientInfo {
+    pub address: SocketAddr,
+    /// Whether the query came over TCP, where answers can be as big as they like.
+    pub over_tcp: bool,
+}
+
+/// A query making its way through the pipeline.
+#[derive(Clone)]
+pub struct Request {
+    /// The query in wire format, as it will be sent upstream.
+    pub raw: Vec<u8>,
+    /// The same query, parsed. Change it with `modify` so `raw` keeps up.
+    pub packet: DecomposedPacket,
+    pub client: ClientInfo,
+    /// Set by stages (the allow list, for one) that have decided the filtering
+    /// authority shouldn't get a say in this query.
+    pub bypass_filter: bool,
+    /// Where the query should be forwarded, if a stage has picked somewhere other
+    /// than the forward stage's own upstreams.
+    pub upstreams: Option<Arc<UpstreamGroup>>,
+}
+
+impl Request {
+    pub fn new(raw: Vec<u8>, client: ClientInfo) -> Request {
+        let packet = DecomposedPacket::from_packet(&Packet::from_vec(&raw));
+        Request {
+            raw,
+            packet,
+            client,
+            bypass_filter: false,
+            upstreams: None,
+        }
+    }
+
+    /// Changes the query for the stages after this one.
+    pub fn modify<F: FnOnce(&mut DecomposedPacket)>(&mut self, change: F) {
+        change(&mut self.packet);
+        self.raw = self.packet.to_raw().data;
+    }
+}
+
+/// How long the cache may hang on to a response.
+#[derive(Copy, Clone)]
+pub enum CachePolicy {
+    /// Go by the TTLs in the response itself (or its SOA, if it's negative).
+    Records,
+    /// Keep it for exactly this many seconds.
+    For(u32),
+    Never,
+}
+
+/// An answer to a request.
+pub struct Response {
+    pub data: Vec<u8>,
+    pub cache_policy: CachePolicy,
+}
+
+impl Response {
+    /// A response straight from an upstream, cacheable on its own merits.
+    pub fn from_upstream(data: Vec<u8>) -> Response {
+        Response {
+            data,
+            cache_policy: CachePolicy::Records,
+        }
+    }
+
+    /// A response made up locally, which isn't worth caching.
+    pub fn local(data: Vec<u8>) -> Response {
+        Response {
+            data,
+            cache_policy: CachePolicy::Never,
+        }
+    }
+}
+
+/// One stage of the daemon's resolution pipeline.
+///
+/// A stage can answer the request itself, change it and pass it along, or just pass
+/// it along, by calling `next.run`. Since it gets the response from `next.run`
+/// back, it can also look at (or replace) whatever the later stages came up with.
+/// None means no answer could be had, and the client will get a SERVFAIL unless
+/// an earlier stage has something better.
+pub trait Handler: Send + Sync {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response>;
+}
+
+/// The rest of the pipeline after the current stage.
+///
+/// It owns a handle on the stages, so it can be moved to another thread to finish
+/// a request in the background.
+#[derive(Clone)]
+pub struct Next {
+    stages: Arc<Vec<Box<dyn Handler>>>,
+    index: usize,
+}
+
+impl Next {
+    pub fn run(self, request: &mut Request) -> Option<Response> {
+        match self.stages.get(self.index) {
+            Some(stage) => stage.handle(request, Next { stages: self.stages.clone(), index: self.index + 1 }),
+            None => None,
+        }
+    }
+}
+
+/// The stages a query goes through, in order.
+pub struct Pipeline {
+    stages: Arc<Vec<Box<dyn Handler>>>,
+}
+
+impl Pipeline {
+    pub fn new(stages: Vec<Box<dyn Handler>>) -> Pipeline {
+        Pipeline {
+            stages: Arc::new(stages),
+        }
+    }
+
+    /// Runs a request through every stage, giving a SERVFAIL if none of them could answer it.
+    pub fn resolve(&self, request: &mut Request) -> Vec<u8> {
+        let next = Next { stages: self.stages.clone(), index: 0 };
+        match next.run(request) {
+            Some(response) => response.data,
+            None => make_servfail_packet(request.packet.clone()),
+        }
+    }
+}
+
+pub fn make_servfail_packet(mut work_packet: DecomposedPacket) -> Vec<u8> {
+    work_packet.is_response = true;
+    work_packet.recursion_available = true;
+    work_packet.response_code = ResponseCode::ServFail;
+
+    work_packet.to_raw().data
+}
@@ -0,0 +1,906 @@
+use std::collections::{ HashMap, HashSet };
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use super::authoritative::{ dname_target, is_within, owner_name, parent_name };
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::tcp::exchange_tcp;
+use super::udp::*;
+use super::util::{ random_u16, read_name };
+use super::validator::ancestor_with_labels;
+use super::zone::{ parse_zone, read_zone_file };
+
+/// The root servers, as IANA publishes them in named.root. Only their IPv4
+/// addresses, since that's all we send queries over.
+const ROOT_HINTS: &str = "
+. 3600000 NS a.root-servers.net.
+. 3600000 NS b.root-servers.net.
+. 3600000 NS c.root-servers.net.
+. 3600000 NS d.root-servers.net.
+. 3600000 NS e.root-servers.net.
+. 3600000 NS f.root-servers.net.
+. 3600000 NS g.root-servers.net.
+. 3600000 NS h.root-servers.net.
+. 3600000 NS i.root-servers.net.
+. 3600000 NS j.root-servers.net.
+. 3600000 NS k.root-servers.net.
+. 3600000 NS l.root-servers.net.
+. 3600000 NS m.root-servers.net.
+a.root-servers.net. 3600000 A 198.41.0.4
+b.root-servers.net. 3600000 A 170.247.170.2
+c.root-servers.net. 3600000 A 192.33.4.12
+d.root-servers.net. 3600000 A 199.7.91.13
+e.root-servers.net. 3600000 A 192.203.230.10
+f.root-servers.net. 3600000 A 192.5.5.241
+g.root-servers.net. 3600000 A 192.112.36.4
+h.root-servers.net. 3600000 A 198.97.190.53
+i.root-servers.net. 3600000 A 192.36.148.17
+j.root-servers.net. 3600000 A 192.58.128.30
+k.root-servers.net. 3600000 A 193.0.14.129
+l.root-servers.net. 3600000 A 199.7.83.42
+m.root-servers.net. 3600000 A 202.12.27.33
+";
+
+/// What `forward` takes to mean our own resolver, rather than someone else's.
+pub const RECURSIVE_UPSTREAM: &str = "recursive";
+
+/// The port authoritative servers listen on.
+pub const DNS_PORT: u16 = 53;
+
+/// How many queries one lookup may send, all told, before we give up on it. That
+/// covers referrals, CNAMEs, and finding the addresses of name servers.
+const MAX_QUERIES: usize = 100;
+
+/// How long a chain of CNAMEs and DNAMEs we follow.
+const MAX_CHAIN_LENGTH: usize = 12;
+
+/// How deep finding the address of a name server, which needs finding the address
+/// of another name server, and so on, may go.
+const MAX_SERVER_DEPTH: usize = 4;
+
+/// QNAME minimization limits (RFC 9156 section 2.3): no more than this many
+/// minimized queries for a name, and the first few each add just one label.
+const MAX_MINIMISE_COUNT: usize = 10;
+const MINIMISE_ONE_LAB: usize = 4;
+
+/// How long delegations and name server addresses are kept, at most. Their TTLs
+/// can make it shorter.
+const MAX_INFRASTRUCTURE_SECONDS: u32 = 86400;
+
+/// How long to go on with the hints when priming doesn't work out.
+const PRIMING_RETRY_SECONDS: u32 = 300;
+
+/// Past this many, expired entries get swept out of the infrastructure cache.
+const INFRASTRUCTURE_SWEEP_SIZE: usize = 10000;
+
+/// This many failures in a row and a server is left until last for a while.
+const UNRESPONSIVE_FAILURE_COUNT: u32 = 3;
+const UNRESPONSIVE_DURATION: Duration = Duration::from_secs(60);
+
+/// How much a new round trip time moves a server's running average.
+const RTT_WEIGHT: f64 = 0.3;
+
+/// A name server for a zone, and its addresses, if we know them yet.
+#[derive(Clone)]
+struct Nameserver {
+    name: String,
+    addresses: Vec<Ipv4Addr>,
+}
+
+/// The name servers for a zone, as its parent (or the root hints) has them.
+#[derive(Clone)]
+struct ZoneServers {
+    /// Lowercase, without a trailing dot.
+    zone: String,
+    nameservers: Vec<Nameserver>,
+}
+
+/// How a server has been doing lately.
+#[derive(Clone, Default)]
+struct ServerHealth {
+    consecutive_failures: u32,
+    benched_until: Option<Instant>,
+    /// Exponentially weighted moving average of round trip times, in milliseconds.
+    rtt: Option<f64>,
+}
+
+impl ServerHealth {
+    fn is_benched(&self, now: Instant) -> bool {
+        self.benched_until.map(|until| now < until).unwrap_or(false)
+    }
+}
+
+/// What a resolver remembers between lookups about where things are: the name
+/// servers of each zone it's been referred to, the addresses of name servers, and
+/// how quickly each server answers. Answers themselves are left to the cache stage.
+struct Infrastructure {
+    zones: HashMap<String, (ZoneServers, Instant)>,
+    addresses: HashMap<String, (Vec<Ipv4Addr>, Instant)>,
+    health: HashMap<Ipv4Addr, ServerHealth>,
+}
+
+/// What a lookup found: the records, or the proof there aren't any.
+struct Answer {
+    response_code: ResponseCode,
+    answers: Vec<Resource>,
+    authorities: Vec<Resource>,
+}
+
+/// What a server had to say about a name.
+enum Reply {
+    /// Records, or proof there aren't any, from a server with authority over them.
+    Answer(DecomposedPacket),
+    /// The name is further down, in a zone with these servers.
+    Referral(ZoneServers, u32),
+}
+
+/// Resolves names itself, starting from the root servers and following referrals
+/// down to servers with authority over the name (RFC 1034 section 5.3.3), instead
+/// of forwarding to someone else's resolver.
+///
+/// Glue is only taken from servers with authority over it, and answers only for
+/// names inside the zone of the server they came from, so one zone can't say what's
+/// in another. Name servers without glue get their addresses looked up along the
+/// way. Each server is only shown as much of the name as it needs to see (QNAME
+/// minimization, RFC 9156): the queries on the way down ask for one more label at
+/// a time, and only the server with authority over the name sees it all.
+///
+/// DNSSEC records are asked for, and passed on to clients that set DO, so a
+/// `validate` stage in front can check them.
+pub struct Resolver {
+    hints: ZoneServers,
+    port: u16,
+    infrastructure: Mutex<Infrastructure>,
+    case_tracker: Mutex<CaseTracker>,
+}
+
+impl Resolver {
+    /// Makes a resolver that starts from the root servers in these hints (NS records
+    /// for the root, and addresses for them), and asks servers on this port.
+    pub fn new(hints: &Vec<Resource>, port: u16) -> Result<Resolver, String> {
+        let hints = match servers_from_records(&String::new(), hints, hints) {
+            Some((hints, _)) if hints.nameservers.iter().any(|server| !server.addresses.is_empty()) => hints,
+            _ => return Err(String::from("The root hints need NS records for the root, and addresses for them")),
+        };
+
+        Ok(Resolver {
+            hints,
+            port,
+            infrastructure: Mutex::new(Infrastructure {
+                zones: HashMap::new(),
+                addresses: HashMap::new(),
+                health: HashMap::new(),
+            }),
+            case_tracker: Mutex::new(CaseTracker::new()),
+        })
+    }
+
+    /// The root servers IANA publishes.
+    pub fn root_hints() -> Vec<Resource> {
+        parse_zone(ROOT_HINTS, &String::new()).ok().unwrap()
+    }
+
+    /// Reads root hints from a master file, like named.root.
+    pub fn load_hints(path: &String) -> Result<Vec<Resource>, String> {
+        read_zone_file(path, &String::new()).map_err(|err| err.to_string())
+    }
+
+    /// Answers a query, the way an upstream would. Gives up with an `Interrupted`
+    /// error once `cancelled` is set, between queries to servers or during one.
+    pub fn exchange(&self, query: &Vec<u8>, cancelled: &AtomicBool) -> io::Result<Vec<u8>> {
+        let raw = Packet::from_vec(query);
+        if raw.data.len() < 12 || raw.locate_records().is_none() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed query"));
+        }
+
+        let request = DecomposedPacket::from_packet(&raw);
+        let question = match request.questions.as_slice() {
+            [question] => question.clone(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "Queries need exactly one question")),
+        };
+
+        let name = match &question.label {
+            Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+            Label::Pointer(_) => return Err(io::Error::new(io::ErrorKind::InvalidData, "Compressed question")),
+        };
+
+        let mut lookup = Lookup {
+            resolver: self,
+            queries_left: MAX_QUERIES,
+            depth: 0,
+            cancelled,
+        };
+
+        let answer = lookup.resolve(&name, question.qtype).map_err(|err| {
+            if cancelled.load(Ordering::Relaxed) {
+                return io::Error::new(io::ErrorKind::Interrupted, "Cancelled");
+            }
+            println!("Couldn't resolve {} {}: {}", name, question.qtype, err);
+            io::Error::new(io::ErrorKind::Other, err)
+        })?;
+
+        // Clients that didn't ask for DNSSEC records don't get them, unless they
+        // asked for that type outright.
+        let dnssec_ok = dnssec_ok(&request);
+        let wanted = |record: &Resource| dnssec_ok || record.rtype == question.qtype || !matches!(record.rtype, Type::RRSIG | Type::NSEC | Type::NSEC3);
+
+        let mut response = DecomposedPacket::new();
+        response.id = request.id;
+        response.is_response = true;
+        response.opcode = request.opcode;
+        response.recursion_desired = request.recursion_desired;
+        response.recursion_available = true;
+        response.checking_disabled = request.checking_disabled;
+        response.response_code = answer.response_code;
+        response.questions = request.questions.clone();
+        response.answers = answer.answers.into_iter().filter(|record| wanted(record)).collect();
+        response.authorities = answer.authorities.into_iter().filter(|record| wanted(record)).collect();
+        if has_edns(&request) {
+            response.additional_records.push(opt_record(dnssec_ok));
+        }
+
+        Ok(response.to_raw().data)
+    }
+
+    /// The closest zone above a name whose servers we know. None if we don't even
+    /// know the root's yet, other than from the hints.
+    #[allow(clippy::ptr_arg)]
+    fn closest_servers(&self, name: &String) -> Option<ZoneServers> {
+        let now = Instant::now();
+        let infrastructure = self.infrastructure.lock().unwrap();
+        let mut zone = name.clone();
+        loop {
+            if let Some((servers, expires)) = infrastructure.zones.get(&zone) {
+                if *expires > now {
+                    return Some(servers.clone());
+                }
+            }
+
+            if zone.is_empty() {
+                return None;
+            }
+            zone = parent_name(&zone);
+        }
+    }
+
+    fn remember_servers(&self, servers: &ZoneServers, ttl: u32) {
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl.min(MAX_INFRASTRUCTURE_SECONDS) as u64);
+        let mut infrastructure = self.infrastructure.lock().unwrap();
+        if infrastructure.zones.len() >= INFRASTRUCTURE_SWEEP_SIZE {
+            infrastructure.zones.retain(|_, (_, expires)| *expires > now);
+        }
+        infrastructure.zones.insert(servers.zone.clone(), (servers.clone(), expires));
+    }
+
+    fn cached_addresses(&self, server: &String) -> Option<Vec<Ipv4Addr>> {
+        match self.infrastructure.lock().unwrap().addresses.get(server) {
+            Some((addresses, expires)) if *expires > Instant::now() => Some(addresses.clone()),
+            _ => None,
+        }
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn remember_addresses(&self, server: &String, addresses: &Vec<Ipv4Addr>, ttl: u32) {
+        let now = Instant::now();
+        let expires = now + Duration::from_secs(ttl.min(MAX_INFRASTRUCTURE_SECONDS) as u64);
+        let mut infrastructure = self.infrastructure.lock().unwrap();
+        if infrastructure.addresses.len() >= INFRASTRUCTURE_SWEEP_SIZE {
+            infrastructure.addresses.retain(|_, (_, expires)| *expires > now);
+        }
+        infrastructure.addresses.insert(server.clone(), (addresses.clone(), expires));
+    }
+
+    /// Puts addresses in the order to try them: servers that have been failing go
+    /// last, fewest failures first, and the rest go quickest first. Ones we haven't timed yet go first, so
+    /// they get timed.
+    #[allow(clippy::ptr_arg)]
+    fn order_addresses(&self, addresses: &mut Vec<Ipv4Addr>) {
+        let now = Instant::now();
+        let infrastructure = self.infrastructure.lock().unwrap();
+        let health = |address: &Ipv4Addr| infrastructure.health.get(address).cloned().unwrap_or_default();
+        addresses.sort_by(|a, b| {
+            let (a, b) = (health(a), health(b));
+            a.is_benched(now).cmp(&b.is_benched(now))
+                .then(a.consecutive_failures.cmp(&b.consecutive_failures))
+                .then(a.rtt.unwrap_or(0.0).partial_cmp(&b.rtt.unwrap_or(0.0)).unwrap())
+        });
+    }
+
+    fn record_success(&self, address: Ipv4Addr, elapsed: Duration) {
+        let mut infrastructure = self.infrastructure.lock().unwrap();
+        let health = infrastructure.health.entry(address).or_default();
+
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        health.rtt = Some(match health.rtt {
+            Some(average) => average + RTT_WEIGHT * (sample - average),
+            None => sample,
+        });
+
+        health.consecutive_failures = 0;
+        health.benched_until = None;
+    }
+
+    fn record_failure(&self, address: Ipv4Addr) {
+        let mut infrastructure = self.infrastructure.lock().unwrap();
+        let health = infrastructure.health.entry(address).or_default();
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= UNRESPONSIVE_FAILURE_COUNT {
+            health.benched_until = Some(Instant::now() + UNRESPONSIVE_DURATION);
+        }
+    }
+
+    /// Asks one server one question, without recursion, and with DNSSEC records.
+    #[allow(clippy::ptr_arg)]
+    fn query(&self, address: Ipv4Addr, name: &String, qtype: Type, cancelled: &AtomicBool) -> io::Result<DecomposedPacket> {
+        let mut packet = DecomposedPacket::new();
+        packet.id = random_u16();
+        packet.questions.push(Question {
+            label: Label::Domain(name.clone()),
+            qtype,
+            qclass: Class::Internet,
+        });
+        packet.additional_records.push(opt_record(true));
+        let query = packet.to_raw().data;
+
+        let server = format!("{}:{}", address, self.port);
+
+        let started = Instant::now();
+        let mut response = exchange_udp_0x20(&query, &server, &self.case_tracker, cancelled);
+        if let Ok(data) = &response {
+            if Packet::from_vec(data).is_truncated() {
+                response = exchange_tcp(&query, &server);
+            }
+        }
+
+        let response = response.and_then(|data| {
+            let raw = Packet::init_from_full(data);
+            if raw.data.len() < 12 || raw.locate_records().is_none() {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed response"));
+            }
+
+            // Servers copy the random case of the question into their records' owners,
+            // which clients shouldn't see. Nothing cares about case otherwise.
+            let mut packet = DecomposedPacket::from_packet(&raw);
+            for record in packet.answers.iter_mut().chain(packet.authorities.iter_mut()).chain(packet.additional_records.iter_mut()) {
+                if let Label::Domain(owner) = &mut record.label {
+                    *owner = owner.to_lowercase();
+                }
+            }
+            Ok(packet)
+        });
+
+        // Being cancelled isn't the server's fault.
+        match &response {
+            Ok(_) => self.record_success(address, started.elapsed()),
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {},
+            Err(_) => self.record_failure(address),
+        }
+
+        response
+    }
+}
+
+/// The work of answering one query, with the budget it has left.
+struct Lookup<'a> {
+    resolver: &'a Resolver,
+    queries_left: usize,
+    /// How many name server addresses deep we are.
+    depth: usize,
+    /// Set when nobody wants the answer anymore.
+    cancelled: &'a AtomicBool,
+}
+
+impl<'a> Lookup<'a> {
+    /// Looks a name up, following CNAMEs and DNAMEs to wherever they go. The answer
+    /// has the whole chain, and the response code from the end of it.
+    fn resolve(&mut self, name: &String, qtype: Type) -> Result<Answer, String> {
+        let mut answers = vec![];
+        let mut target = name.clone();
+        let mut seen = HashSet::new();
+
+        for _ in 0..MAX_CHAIN_LENGTH {
+            if !seen.insert(target.clone()) {
+                return Err(format!("CNAMEs for {} go round in circles", name));
+            }
+
+            let found = self.lookup(&target, qtype)?;
+            let next = follow_chain(&found.answers, &target, qtype);
+            answers.extend(found.answers);
+
+            match next {
+                Some(next) if matches!(found.response_code, ResponseCode::NoError) => target = next,
+                _ => return Ok(Answer {
+                    response_code: found.response_code,
+                    answers,
+                    authorities: found.authorities,
+                }),
+            }
+        }
+
+        Err(format!("the CNAMEs for {} go on too long", name))
+    }
+
+    /// Finds one name, from the closest servers we know of, following referrals
+    /// down until a server with authority over it answers.
+    fn lookup(&mut self, name: &String, qtype: Type) -> Result<Answer, String> {
+        // DS records are in the parent, so that's as far down as those go.
+        let cut_name = if qtype == Type::DS { parent_name(name) } else { name.clone() };
+        let mut servers = match self.resolver.closest_servers(&cut_name) {
+            Some(servers) => servers,
+            None => self.prime(),
+        };
+
+        let total_labels = count_labels(name);
+        let mut revealed = count_labels(&servers.zone);
+        let mut minimizing = true;
+        let mut minimized_queries = 0;
+
+        loop {
+            // Show the servers one more label than their zone, until they're the ones
+            // for the name, or asking that way stops working.
+            let (ask_name, ask_type) = if minimizing && revealed.max(count_labels(&servers.zone)) < total_labels && minimized_queries < MAX_MINIMISE_COUNT {
+                revealed = next_revealed(revealed.max(count_labels(&servers.zone)), total_labels, minimized_queries);
+                minimized_queries += 1;
+                if revealed < total_labels || qtype == Type::DS {
+                    (ancestor_with_labels(name, revealed), Type::A)
+                } else {
+                    (name.clone(), qtype)
+                }
+            } else {
+                (name.clone(), qtype)
+            };
+            let minimized = ask_name != *name || ask_type != qtype;
+
+            let reply = match self.ask(&servers, &ask_name, ask_type) {
+                Ok(reply) => reply,
+                // Some servers don't cope with being asked about names they don't
+                // have records for, so ask them the whole thing instead.
+                Err(_) if minimized => {
+                    minimizing = false;
+                    continue;
+                },
+                Err(err) => return Err(err),
+            };
+
+            match reply {
+                Reply::Referral(child, _) if qtype == Type::DS && child.zone == *name => {
+                    // The parent has the DS, so it gets asked for it outright.
+                    minimizing = false;
+                },
+                Reply::Referral(child, ttl) => {
+                    self.resolver.remember_servers(&child, ttl);
+                    servers = child;
+                },
+                Reply::Answer(packet) if minimized => {
+                    // Nothing exists under a name that doesn't (RFC 8020).
+                    if let ResponseCode::NXDomain = packet.response_code {
+                        return Ok(Answer {
+                            response_code: ResponseCode::NXDomain,
+                            answers: vec![],
+                            authorities: in_zone(&packet.authorities, &servers.zone),
+                        });
+                    }
+                },
+                Reply::Answer(packet) => return Ok(Answer {
+                    response_code: packet.response_code,
+                    answers: in_zone(&packet.answers, &servers.zone),
+                    authorities: in_zone(&packet.authorities, &servers.zone),
+                }),
+            }
+        }
+    }
+
+    /// Asks the root hints for the current list of root servers (RFC 8109), and
+    /// keeps it. Servers the answer has no addresses for keep the ones in the hints.
+    /// If that leaves none to ask, the hints themselves are kept for a while, rather
+    /// than priming again for every query.
+    fn prime(&mut self) -> ZoneServers {
+        let hints = self.resolver.hints.clone();
+        let primed = match self.ask(&hints, &String::new(), Type::NS) {
+            Ok(Reply::Answer(packet)) => servers_from_records(&String::new(), &packet.answers, &packet.additional_records),
+            _ => None,
+        };
+
+        if let Some((mut servers, ttl)) = primed {
+            for server in servers.nameservers.iter_mut().filter(|server| server.addresses.is_empty()) {
+                if let Some(hint) = hints.nameservers.iter().find(|hint| hint.name == server.name) {
+                    server.addresses = hint.addresses.clone();
+                }
+            }
+
+            if servers.nameservers.iter().any(|server| !server.addresses.is_empty()) {
+                self.resolver.remember_servers(&servers, ttl);
+                return servers;
+            }
+        }
+
+        // Priming that was cut short says nothing about the root servers.
+        if self.cancelled.load(Ordering::Relaxed) {
+            return hints;
+        }
+
+        println!("Couldn't prime the root servers, so sticking with the hints for now");
+        self.resolver.remember_servers(&hints, PRIMING_RETRY_SECONDS);
+        hints
+    }
+
+    /// Asks a zone's servers a question, one after another, until one gives an
+    /// answer that makes sense from a server for that zone.
+    fn ask(&mut self, servers: &ZoneServers, name: &String, qtype: Type) -> Result<Reply, String> {
+        let mut last_error = format!("no servers for {} answered", display_name(&servers.zone));
+
+        // Servers with addresses go first, and the ones we'd have to look up only
+        // if those don't work out.
+        let mut addresses: Vec<Ipv4Addr> = servers.nameservers.iter().flat_map(|server| server.addresses.clone()).collect();
+        let unaddressed: Vec<&Nameserver> = servers.nameservers.iter().filter(|server| server.addresses.is_empty()).collect();
+        self.resolver.order_addresses(&mut addresses);
+
+        let mut unaddressed = unaddressed.into_iter();
+        let mut tried = HashSet::new();
+        loop {
+            let address = match addresses.iter().find(|address| !tried.contains(*address)) {
+                Some(address) => *address,
+                None => match unaddressed.next() {
+                    Some(server) => {
+                        match self.server_addresses(&server.name) {
+                            Ok(found) => addresses.extend(found),
+                            Err(err) => last_error = format!("couldn't find {}: {}", display_name(&server.name), err),
+                        }
+                        continue;
+                    },
+                    None => return Err(last_error),
+                },
+            };
+
+            tried.insert(address);
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(String::from("cancelled"));
+            }
+            if self.queries_left == 0 {
+                return Err(String::from("gave up after too many queries"));
+            }
+            self.queries_left -= 1;
+
+            println!("Asking {} about {} {}", address, display_name(name), qtype);
+            let packet = match self.resolver.query(address, name, qtype, self.cancelled) {
+                Ok(packet) => packet,
+                Err(err) => {
+                    last_error = format!("{} didn't answer: {}", address, err);
+                    continue;
+                },
+            };
+
+            match classify(&packet, &servers.zone, name) {
+                Some(reply) => return Ok(reply),
+                None => {
+                    self.resolver.record_failure(address);
+                    last_error = format!("{} gave a lame answer for {}", address, display_name(&servers.zone));
+                },
+            }
+        }
+    }
+
+    /// Finds the addresses of a name server that came without glue.
+    fn server_addresses(&mut self, server: &String) -> Result<Vec<Ipv4Addr>, String> {
+        if let Some(addresses) = self.resolver.cached_addresses(server) {
+            return Ok(addresses);
+        }
+
+        if self.depth >= MAX_SERVER_DEPTH {
+            return Err(String::from("its name servers go too deep"));
+        }
+
+        self.depth += 1;
+        let found = self.resolve(server, Type::A);
+        self.depth -= 1;
+
+        let answer = found?;
+        let records: Vec<&Resource> = answer.answers.iter().filter(|record| record.rtype == Type::A && record.data.len() == 4).collect();
+        let addresses: Vec<Ipv4Addr> = records.iter().map(|record| Ipv4Addr::new(record.data[0], record.data[1], record.data[2], record.data[3])).collect();
+        if addresses.is_empty() {
+            return Err(String::from("it has no addresses"));
+        }
+
+        let ttl = records.iter().map(|record| record.ttl).min().unwrap_or(0);
+        self.resolver.remember_addresses(server, &addresses, ttl);
+        Ok(addresses)
+    }
+}
+
+/// Makes sense of a server's response to a question about a name in its zone, or
+/// None if it doesn't make any: an error, a referral somewhere that isn't further
+/// down towards the name, or no answer at all from a server that should have one.
+#[allow(clippy::ptr_arg)]
+fn classify(packet: &DecomposedPacket, zone: &String, name: &String) -> Option<Reply> {
+    if !packet.is_response {
+        return None;
+    }
+
+    match packet.response_code {
+        ResponseCode::NoError => {},
+        ResponseCode::NXDomain if packet.is_authoritative => return Some(Reply::Answer(packet.clone())),
+        _ => return None,
+    }
+
+    if !in_zone(&packet.answers, zone).is_empty() {
+        return Some(Reply::Answer(packet.clone()));
+    }
+
+    // A referral has to be to a zone below the server's own, and above the name.
+    let child = packet.authorities.iter()
+        .filter(|record| record.rtype == Type::NS)
+        .filter_map(owner_name)
+        .find(|owner| owner != zone && is_within(owner, zone) && is_within(name, owner));
+
+    if let Some(child) = child {
+        let (servers, ttl) = servers_from_records(&child, &packet.authorities, &in_zone(&packet.additional_records, zone))?;
+        return Some(Reply::Referral(servers, ttl));
+    }
+
+    if packet.is_authoritative {
+        Some(Reply::Answer(packet.clone()))
+    } else {
+        None
+    }
+}
+
+/// Picks out a zone's name servers from NS records, with any of their addresses
+/// that are in the glue, and the lowest TTL of the NS records.
+#[allow(clippy::ptr_arg)]
+fn servers_from_records(zone: &String, records: &Vec<Resource>, glue: &Vec<Resource>) -> Option<(ZoneServers, u32)> {
+    let ns_records: Vec<&Resource> = records.iter()
+        .filter(|record| record.rtype == Type::NS && owner_name(record).as_ref() == Some(zone))
+        .collect();
+
+    let mut nameservers: Vec<Nameserver> = vec![];
+    for record in &ns_records {
+        let name = match read_name(&record.data, 0) {
+            Some((name, _)) => name.trim_end_matches('.').to_lowercase(),
+            None => continue,
+        };
+
+        if nameservers.iter().any(|server| server.name == name) {
+            continue;
+        }
+
+        let addresses = glue.iter()
+            .filter(|record| record.rtype == Type::A && record.data.len() == 4 && owner_name(record).as_ref() == Some(&name))
+            .map(|record| Ipv4Addr::new(record.data[0], record.data[1], record.data[2], record.data[3]))
+            .collect();
+
+        nameservers.push(Nameserver { name, addresses });
+    }
+
+    if nameservers.is_empty() {
+        return None;
+    }
+
+    let ttl = ns_records.iter().map(|record| record.ttl).min().unwrap_or(0);
+    Some((ZoneServers { zone: zone.clone(), nameservers }, ttl))
+}
+
+/// The records about names inside a zone. Anything else, a server for the zone
+/// has no business telling us.
+#[allow(clippy::ptr_arg)]
+fn in_zone(records: &Vec<Resource>, zone: &String) -> Vec<Resource> {
+    records.iter()
+        .filter(|record| record.rtype != Type::OPT && owner_name(record).map(|owner| is_within(&owner, zone)).unwrap_or(false))
+        .cloned()
+        .collect()
+}
+
+/// Follows CNAMEs and DNAMEs from a name through an answer. Some when they lead
+/// somewhere the answer doesn't go, which needs looking up on its own.
+#[allow(clippy::ptr_arg)]
+fn follow_chain(answers: &Vec<Resource>, name: &String, qtype: Type) -> Option<String> {
+    let mut target = name.clone();
+    for _ in 0..answers.len() + 1 {
+        let at_target: Vec<&Resource> = answers.iter().filter(|record| owner_name(record).as_ref() == Some(&target)).collect();
+        if at_target.iter().any(|record| record.rtype == qtype) || qtype == Type::ANY || qtype == Type::CNAME {
+            return None;
+        }
+
+        if let Some(cname) = at_target.iter().find(|record| record.rtype == Type::CNAME) {
+            target = read_name(&cname.data, 0)?.0.trim_end_matches('.').to_lowercase();
+            continue;
+        }
+
+        // Old servers may not make up the CNAME for a DNAME themselves (RFC 6672
+        // section 3.1).
+        let dname = answers.iter()
+            .filter(|record| record.rtype == Type::DNAME)
+            .find_map(|record| {
+                let owner = owner_name(record)?;
+                if owner == target || !is_within(&target, &owner) {
+                    return None;
+                }
+                Some((owner, read_name(&record.data, 0)?.0))
+            });
+
+        match dname {
+            Some((owner, replacement)) => target = dname_target(&target, &owner, &replacement.trim_end_matches('.').to_lowercase()),
+            None => break,
+        }
+    }
+
+    if target == *name { None } else { Some(target) }
+}
+
+/// How many labels of a name to show next (RFC 9156 section 2.3): one more at a
+/// time at first, then bigger steps, so long names don't take too many queries.
+fn next_revealed(revealed: usize, total: usize, minimized_queries: usize) -> usize {
+    if minimized_queries < MINIMISE_ONE_LAB {
+        return revealed + 1;
+    }
+
+    let queries_left = MAX_MINIMISE_COUNT.saturating_sub(minimized_queries).max(1);
+    let labels_left = total - revealed;
+    revealed + ((labels_left + queries_left - 1) / queries_left).max(1)
+}
+
+fn count_labels(name: &str) -> usize {
+    name.split('.').filter(|label| !label.is_empty()).count()
+}
+
+fn display_name(name: &String) -> String {
+    if name.is_empty() { String::from(".") } else { format!("{}.", name) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::UdpSocket;
+    use std::thread;
+    use crate::dns_hero::authoritative::Zone;
+
+    fn records(origin: &str, text: &str) -> Vec<Resource> {
+        parse_zone(&format!("$TTL 300\n{}", text), &String::from(origin)).unwrap()
+    }
+
+    fn name(text: &str) -> String {
+        String::from(text)
+    }
+
+    fn response(authoritative: bool, answers: Vec<Resource>, authorities: Vec<Resource>, additional_records: Vec<Resource>) -> DecomposedPacket {
+        let mut packet = DecomposedPacket::new();
+        packet.is_response = true;
+        packet.is_authoritative = authoritative;
+        packet.answers = answers;
+        packet.authorities = authorities;
+        packet.additional_records = additional_records;
+        packet
+    }
+
+    /// Answers queries to this address from the zone, for as long as the tests run.
+    fn serve(socket: UdpSocket, zone: Zone) {
+        thread::spawn(move || {
+            let mut buffer = [0; 65536];
+            while let Ok((length, client)) = socket.recv_from(&mut buffer) {
+                let query = DecomposedPacket::from_packet(&Packet::from_vec(&buffer[..length].to_vec()));
+                let answer = zone.lookup(&query.questions[0]);
+
+                let mut packet = response(answer.authoritative, answer.answers, answer.authorities, answer.additional_records);
+                packet.id = query.id;
+                packet.response_code = answer.response_code;
+                packet.questions = query.questions;
+                socket.send_to(&packet.to_raw().data, client).unwrap();
+            }
+        });
+    }
+
+    #[test]
+    fn follows_referrals_down_from_the_root() {
+        let root_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = root_socket.local_addr().unwrap().port();
+        let test_socket = UdpSocket::bind(("127.0.0.2", port)).unwrap();
+        let example_socket = UdpSocket::bind(("127.0.0.3", port)).unwrap();
+
+        let soa = "@ SOA ns hostmaster 1 3600 600 86400 300\n@ NS ns\n";
+        let root = records("", &format!("{}ns A 127.0.0.1\ntest NS ns.test.\nns.test. A 127.0.0.2\n", soa));
+        // The glue for the root's server is out of the zone, and mustn't be taken.
+        let test = records("test", &format!("{}ns A 127.0.0.2\nexample NS ns.example\nns.example A 127.0.0.3\n", soa));
+        let example = records("example.test", &format!("{}ns A 127.0.0.3\nwww CNAME web\nweb A 192.0.2.1\n", soa));
+        serve(root_socket, Zone::new(&name(""), root).unwrap());
+        serve(test_socket, Zone::new(&name("test"), test).unwrap());
+        serve(example_socket, Zone::new(&name("example.test"), example).unwrap());
+
+        let resolver = Resolver::new(&records("", ". NS a.root.\na.root. A 127.0.0.1\n"), port).unwrap();
+        let cancelled = AtomicBool::new(false);
+        let mut lookup = Lookup { resolver: &resolver, queries_left: MAX_QUERIES, depth: 0, cancelled: &cancelled };
+        let answer = lookup.resolve(&name("www.example.test"), Type::A).unwrap();
+
+        assert!(matches!(answer.response_code, ResponseCode::NoError));
+        let types: Vec<String> = answer.answers.iter().map(|record| record.rtype.to_string()).collect();
+        assert_eq!(types, vec!["CNAME", "A"]);
+        assert_eq!(answer.answers[1].data, vec![192, 0, 2, 1]);
+
+        let missing = lookup.resolve(&name("nope.example.test"), Type::A).unwrap();
+        assert!(matches!(missing.response_code, ResponseCode::NXDomain));
+    }
+
+    #[test]
+    fn cancelled_lookups_give_up() {
+        let resolver = Resolver::new(&records("", ". NS a.root.\na.root. A 127.0.0.1\n"), 9).unwrap();
+        let mut query = DecomposedPacket::new();
+        query.questions.push(Question { label: Label::Domain(name("www.example.test")), qtype: Type::A, qclass: Class::Internet });
+
+        let result = resolver.exchange(&query.to_raw().data, &AtomicBool::new(true));
+        assert_eq!(result.unwrap_err().kind(), io::ErrorKind::Interrupted);
+        let infrastructure = resolver.infrastructure.lock().unwrap();
+        assert!(infrastructure.health.is_empty() && infrastructure.zones.is_empty());
+    }
+
+    #[test]
+    fn in_zone_drops_records_from_outside() {
+        let answers = records("example.test", "www A 192.0.2.1\nwww.other.test. A 192.0.2.2\nexample.test.evil. A 192.0.2.3\n");
+        let kept = in_zone(&answers, &name("example.test"));
+        assert_eq!(kept.len(), 1);
+        assert_eq!(owner_name(&kept[0]), Some(name("www.example.test")));
+    }
+
+    #[test]
+    fn classify_only_takes_referrals_towards_the_name() {
+        let zone = name("test");
+        let cut = records("test", "example NS ns.example\nns.example A 192.0.2.1\nns.other. A 192.0.2.2\n");
+        let (ns, glue) = (vec![cut[0].clone()], vec![cut[1].clone(), cut[2].clone()]);
+
+        match classify(&response(false, vec![], ns.clone(), glue.clone()), &zone, &name("www.example.test")) {
+            Some(Reply::Referral(servers, ttl)) => {
+                assert_eq!(servers.zone, "example.test");
+                assert_eq!(servers.nameservers[0].addresses, vec![Ipv4Addr::new(192, 0, 2, 1)]);
+                assert_eq!(ttl, 300);
+            },
+            _ => panic!("expected a referral"),
+        }
+
+        // Not on the way to the name, and not below the server's zone.
+        assert!(classify(&response(false, vec![], ns.clone(), glue.clone()), &zone, &name("www.other.test")).is_none());
+        let upward = records("", "other. NS ns.other.\n");
+        assert!(classify(&response(false, vec![], upward, vec![]), &zone, &name("www.other")).is_none());
+
+        // Answers about other zones don't count, and only authoritative servers
+        // get to say there's nothing.
+        let stray = records("", "www.other. A 192.0.2.9\n");
+        assert!(classify(&response(false, stray.clone(), vec![], vec![]), &zone, &name("www.example.test")).is_none());
+        assert!(matches!(classify(&response(true, stray, vec![], vec![]), &zone, &name("www.test")), Some(Reply::Answer(_))));
+    }
+
+    #[test]
+    fn next_revealed_steps_up_after_the_first_few() {
+        assert_eq!(next_revealed(0, 6, 0), 1);
+        assert_eq!(next_revealed(3, 6, 3), 4);
+        // Six queries left for sixteen labels is three at a time.
+        assert_eq!(next_revealed(4, 20, 4), 7);
+        // Out of queries, it's the whole name.
+        assert_eq!(next_revealed(4, 20, MAX_MINIMISE_COUNT), 20);
+    }
+
+    #[test]
+    fn follow_chain_goes_through_cnames_and_dnames() {
+        let answers = records("example.test", "www CNAME web\nweb CNAME cdn.other.test.\n");
+        assert_eq!(follow_chain(&answers, &name("www.example.test"), Type::A), Some(name("cdn.other.test")));
+
+        let answers = records("example.test", "www CNAME web\nweb A 192.0.2.1\n");
+        assert_eq!(follow_chain(&answers, &name("www.example.test"), Type::A), None);
+        assert_eq!(follow_chain(&answers, &name("www.example.test"), Type::CNAME), None);
+
+        // Without a made up CNAME, the DNAME gets applied here. The one at the name
+        // itself doesn't apply to it, and the one that does comes after it.
+        let answers = records("example.test", "a.b DNAME elsewhere.test.\nb DNAME other.test.\n");
+        assert_eq!(follow_chain(&answers, &name("a.b.example.test"), Type::A), Some(name("a.other.test")));
+    }
+
+    #[test]
+    fn dname_at_the_root_keeps_the_dot() {
+        assert_eq!(dname_target("a.example", "", "replacement.test"), "a.example.replacement.test");
+        assert_eq!(dname_target("a.example.test", "example.test", "other.test"), "a.other.test");
+        assert_eq!(dname_target("a.example.test", "example.test", ""), "a");
+    }
+}
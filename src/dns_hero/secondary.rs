@@ -0,0 +1,236 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::ptr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant, SystemTime };
+
+use super::acl::{ canonical_address, Acl };
+use super::authoritative::*;
+use super::data::Resource;
+use super::journal::*;
+use super::tcp::parse_server_address;
+use super::transfer::*;
+use super::tsig::TsigKey;
+use super::util::*;
+use super::zone::save_zone;
+
+/// How long to wait before trying again when we've never managed to get a copy,
+/// so there's no SOA to take a retry interval from.
+const INITIAL_RETRY: Duration = Duration::from_secs(60);
+
+/// A zone we serve a copy of, kept up to date by transfers from its primaries.
+/// Between NOTIFYs, it's checked every REFRESH seconds, or RETRY seconds after a
+/// failed check, going by the SOA (RFC 1034 section 4.3.5). If the primaries
+/// can't be reached for EXPIRE seconds, we stop answering for it.
+pub struct Secondary {
+    /// Lowercase, without a trailing dot.
+    pub         origin: String,
+    pub      primaries: Vec<String>,
+    pub allow_transfer: Acl,
+    pub         notify: Vec<String>,
+    /// Where our copy is saved, so it survives a restart. Its journal goes next
+    /// to it with `.jnl` on the end.
+    pub           file: Option<String>,
+    /// What our transfer queries are signed with, if the primaries want them signed.
+    pub   transfer_key: Option<TsigKey>,
+    timers: Mutex<Timers>,
+    /// Held while a refresh is going, so NOTIFYs arriving in a burst don't each
+    /// start a transfer of their own.
+    refreshing: Mutex<()>,
+}
+
+struct Timers {
+    next_refresh: Instant,
+    /// When our copy goes stale, if we have one.
+    expires_at: Option<Instant>,
+}
+
+impl Secondary {
+    #[allow(clippy::ptr_arg)]
+    pub fn new(origin: &String, primaries: Vec<String>, allow_transfer: Acl, notify: Vec<String>, file: Option<String>, transfer_key: Option<TsigKey>) -> Secondary {
+        Secondary {
+            origin: origin.trim_end_matches('.').to_lowercase(),
+            primaries,
+            allow_transfer,
+            notify,
+            file,
+            transfer_key,
+            timers: Mutex::new(Timers {
+                next_refresh: Instant::now(),
+                expires_at: None,
+            }),
+            refreshing: Mutex::new(()),
+        }
+    }
+
+    /// Whether an address is one of this zone's primaries.
+    pub fn is_primary(&self, address: IpAddr) -> bool {
+        self.primaries.iter()
+            .filter_map(|primary| parse_server_address(primary).ok())
+            .any(|primary| canonical_address(primary.ip()) == canonical_address(address))
+    }
+
+    /// Starts serving the copy we saved last time, if there is one. It's good for
+    /// EXPIRE seconds from when it was last known to be current, which is when the
+    /// file was last touched. It gets refreshed right away regardless.
+    pub fn load_saved(&self, zones: &Zones) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let modified = match fs::metadata(file).and_then(|metadata| metadata.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return,
+        };
+
+        let zone = match Zone::load(file, &self.origin) {
+            Ok(zone) => zone,
+            Err(err) => {
+                println!("Ignoring our saved copy of {}: {}", self.origin, err);
+                return;
+            },
+        };
+
+        let age = SystemTime::now().duration_since(modified).unwrap_or_default();
+        let expire = soa_timers(zone.soa()).map(|(_, _, expire)| Duration::from_secs(expire as u64)).unwrap_or_default();
+        let remaining = expire.saturating_sub(age);
+        self.timers.lock().unwrap().expires_at = Some(Instant::now() + remaining);
+
+        println!("Loaded our saved copy of {} at serial {}", self.origin, zone.serial());
+        let journal = Journal::load(&journal_path(file), &self.origin, zone.serial());
+        let mut hosted = HostedZone::transferred(zone, journal, self.allow_transfer.clone(), self.notify.clone());
+        hosted.expired = remaining.is_zero();
+        zones.insert(Arc::new(hosted));
+    }
+
+    /// Whether it's time to check with the primaries again.
+    pub fn is_due(&self) -> bool {
+        Instant::now() >= self.timers.lock().unwrap().next_refresh
+    }
+
+    /// Stops answering for the zone if we haven't heard from a primary in too long.
+    pub fn check_expiry(&self, zones: &Zones) {
+        let has_expired = match self.timers.lock().unwrap().expires_at {
+            Some(expires_at) => Instant::now() >= expires_at,
+            None => false,
+        };
+
+        let is_serving = zones.get(&self.origin).map(|hosted| !hosted.expired).unwrap_or(false);
+        if has_expired && is_serving {
+            println!("Our copy of {} has expired, no more answers for it until a primary comes back", self.origin);
+            zones.set_expired(&self.origin, true);
+        }
+    }
+
+    /// Brings our copy up to date from the first primary that answers: IXFR when
+    /// we have a copy, AXFR when we don't. Returns the new copy if anything
+    /// changed, and nothing if it was already current or another refresh is going.
+    pub fn refresh(&self, zones: &Zones) -> Result<Option<Arc<HostedZone>>, String> {
+        let _refreshing = match self.refreshing.try_lock() {
+            Ok(guard) => guard,
+            Err(_) => return Ok(None),
+        };
+
+        let current = zones.get(&self.origin);
+
+        for primary in &self.primaries {
+            let records = match &current {
+                Some(hosted) => ixfr(primary, &self.origin, &hosted.zone.records(), self.transfer_key.as_ref()),
+                None => axfr(primary, &self.origin, self.transfer_key.as_ref()),
+            };
+
+            let zone = match records.map_err(|err| err.to_string()).and_then(|records| Zone::new(&self.origin, records)) {
+                Ok(zone) => zone,
+                Err(err) => {
+                    println!("Couldn't transfer {} from {}: {}", self.origin, primary, err);
+                    continue;
+                },
+            };
+
+            self.refreshed(zone.soa());
+
+            let journal = match &current {
+                Some(hosted) if !serial_is_newer(zone.serial(), hosted.zone.serial()) => {
+                    if hosted.expired {
+                        println!("{} is current again", self.origin);
+                        zones.set_expired(&self.origin, false);
+                    }
+                    self.touch_saved();
+                    return Ok(None);
+                },
+                Some(hosted) => {
+                    let mut journal = hosted.journal.clone();
+                    if let Some(diff) = Diff::between(&hosted.zone.records(), &zone.records()) {
+                        journal.record(diff);
+                    }
+                    journal
+                },
+                None => Journal::new(self.file.as_ref().map(journal_path)),
+            };
+
+            println!("Transferred {} at serial {} from {}", self.origin, zone.serial(), primary);
+            self.save(&zone);
+            let hosted = Arc::new(HostedZone::transferred(zone, journal, self.allow_transfer.clone(), self.notify.clone()));
+            let _changing = zones.lock_changes();
+            zones.insert(hosted.clone());
+            return Ok(Some(hosted));
+        }
+
+        let retry = current.as_ref()
+            .and_then(|hosted| soa_timers(hosted.zone.soa()))
+            .map(|(_, retry, _)| Duration::from_secs(retry as u64))
+            .unwrap_or(INITIAL_RETRY);
+        self.timers.lock().unwrap().next_refresh = Instant::now() + retry;
+
+        Err(format!("None of the primaries for {} could be reached, trying again in {}s", self.origin, retry.as_secs()))
+    }
+
+    /// Restarts the clocks after hearing from a primary.
+    fn refreshed(&self, soa: &Resource) {
+        let (refresh, _, expire) = soa_timers(soa).unwrap_or_default();
+        let mut timers = self.timers.lock().unwrap();
+        timers.next_refresh = Instant::now() + Duration::from_secs(refresh as u64);
+        timers.expires_at = Some(Instant::now() + Duration::from_secs(expire as u64));
+    }
+
+    fn save(&self, zone: &Zone) {
+        let file = match &self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        if let Err(err) = save_zone(file, &zone.records()) {
+            println!("Failed to save {} to {}: {}", self.origin, file, err);
+        }
+    }
+
+    /// Marks our saved copy as current as of now, which is where its expiry is
+    /// counted from after a restart.
+    fn touch_saved(&self) {
+        if let Some(file) = &self.file {
+            let path = match CString::new(file.as_bytes()) {
+                Ok(path) => path,
+                Err(_) => return,
+            };
+
+            // Null times mean now, like `touch`.
+            if unsafe { libc::utimes(path.as_ptr(), ptr::null()) } != 0 {
+                println!("Failed to touch {}: {}", file, io::Error::last_os_error());
+            }
+        }
+    }
+}
+
+/// The refresh, retry, and expire intervals of an SOA, in seconds.
+fn soa_timers(soa: &Resource) -> Option<(u32, u32, u32)> {
+    let mname_end = read_name(&soa.data, 0)?.1;
+    let serial_start = read_name(&soa.data, mname_end)?.1;
+    if serial_start + 20 > soa.data.len() {
+        return None;
+    }
+
+    Some((get_u32(&soa.data, serial_start + 4), get_u32(&soa.data, serial_start + 8), get_u32(&soa.data, serial_start + 12)))
+}
@@ -0,0 +1,111 @@
+use super::authoritative::Zones;
+use super::data::*;
+use super::enums::*;
+use super::keys::*;
+use super::util::*;
+
+/// How long before and after signing a SIG(0) signature is good for. It only has
+/// to last while the message is on its way, give or take some clock skew.
+const SIG0_VALIDITY: u32 = 300;
+
+/// Signs a message with SIG(0) (RFC 2931): a SIG record on the end, covering the
+/// whole message, made with a private key whose public half is in a KEY record
+/// at the key's name.
+#[allow(clippy::ptr_arg)]
+pub fn sign_message(message: &Vec<u8>, key: &SigningKey) -> Vec<u8> {
+    let now = unix_time() as u32;
+    let mut rdata = sig_rdata_fields(key.algorithm.number(), now.wrapping_add(SIG0_VALIDITY), now.wrapping_sub(SIG0_VALIDITY), key.key_tag(), &key.name);
+
+    let mut signed_data = rdata.clone();
+    signed_data.extend_from_slice(message);
+    rdata.extend(key.sign(&signed_data));
+
+    let mut signed = message.clone();
+    Resource {
+        label: Label::Domain(String::new()),
+        rtype: Type::SIG,
+        rclass: Class::QclassAny,
+        ttl: 0,
+        length: rdata.len() as u16,
+        data: rdata,
+    }.to_raw(&mut signed);
+
+    let additional_count = get_u16(&signed, 10);
+    set_u16(&mut signed, additional_count + 1, 10);
+    signed
+}
+
+/// Checks the SIG(0) signature on a message that came in, if it has one, against
+/// the signer's KEY records in one of our zones. Ok(None) means it wasn't signed.
+/// When the signature's good, what comes back is who signed it, and the message
+/// without its SIG record. When it isn't, the error says why.
+pub fn verify_message(message: &Vec<u8>, zones: &Zones) -> Result<Option<(String, Vec<u8>)>, String> {
+    let packet = Packet::from_vec(message);
+    let locations = packet.locate_records().ok_or("Malformed message")?;
+
+    let last = match locations.last() {
+        Some(last) if last.rtype == Type::SIG.to_u16() && last.section == Section::Additional => last,
+        _ => return Ok(None),
+    };
+
+    let rdata = &message[last.rdata_offset..last.rdata_offset + last.rdata_length];
+    if rdata.len() < 18 {
+        return Err(String::from("Malformed SIG record"));
+    }
+
+    // A SIG that covers a type is an old-style DNSSEC signature on an RRset, not a
+    // signature on the message.
+    if u16::from_be_bytes([rdata[0], rdata[1]]) != 0 {
+        return Ok(None);
+    }
+
+    let algorithm = rdata[2];
+    let expiration = u32::from_be_bytes([rdata[8], rdata[9], rdata[10], rdata[11]]);
+    let inception = u32::from_be_bytes([rdata[12], rdata[13], rdata[14], rdata[15]]);
+    let tag = u16::from_be_bytes([rdata[16], rdata[17]]);
+    let (signer, signer_end) = read_name(message, last.rdata_offset + 18).ok_or("Malformed SIG record")?;
+    let signer = signer.trim_end_matches('.').to_lowercase();
+    let signature = message.get(signer_end..last.rdata_offset + last.rdata_length).ok_or("Malformed SIG record")?;
+
+    let now = unix_time() as u32;
+    if (now.wrapping_sub(inception) as i32) < 0 || (expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(format!("Signature by {} isn't valid now", signer));
+    }
+
+    let keys = match zones.find(&signer) {
+        Some(hosted) if !hosted.expired => hosted.zone.rrset(&signer, Type::KEY),
+        _ => vec![],
+    };
+
+    if keys.is_empty() {
+        return Err(format!("No KEY records for {}", signer));
+    }
+
+    let mut stripped = message[..last.start].to_vec();
+    set_u16(&mut stripped, packet.get_additional_record_count() - 1, 10);
+
+    let mut signed_data = sig_rdata_fields(algorithm, expiration, inception, tag, &signer);
+    signed_data.extend_from_slice(&stripped);
+
+    let is_verified = keys.iter()
+        .filter(|key| key.data.get(3) == Some(&algorithm) && key_tag(&key.data) == tag)
+        .any(|key| verify_with_key(&key.data, &signed_data, signature));
+
+    if is_verified {
+        Ok(Some((signer, stripped)))
+    } else {
+        Err(format!("Bad signature by {}", signer))
+    }
+}
+
+/// The fields of a SIG(0) record's data, up to the signature: the type covered
+/// is 0, and so are the labels and original TTL, since there's no RRset.
+#[allow(clippy::ptr_arg)]
+fn sig_rdata_fields(algorithm: u8, expiration: u32, inception: u32, tag: u16, signer: &String) -> Vec<u8> {
+    let mut fields = vec![0, 0, algorithm, 0, 0, 0, 0, 0];
+    fields.extend_from_slice(&expiration.to_be_bytes());
+    fields.extend_from_slice(&inception.to_be_bytes());
+    fields.extend_from_slice(&tag.to_be_bytes());
+    fields.extend(str_domain_to_dns_domain(&signer.to_lowercase()));
+    fields
+}
@@ -0,0 +1,462 @@
+use std::collections::{ HashMap, HashSet };
+
+use super::authoritative::{ is_within, owner_name, parent_name };
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::keys::{ verify_with_key, SigningKey };
+use super::transfer::soa_serial;
+use super::update::set_soa_serial;
+use super::util::unix_time;
+
+/// How long new signatures are good for, unless told otherwise.
+pub const DEFAULT_SIGNATURE_VALIDITY: u32 = 30 * 86400;
+
+/// Signatures with less than this left on them are made again, unless told
+/// otherwise, so they never get close to running out between signings.
+pub const DEFAULT_REFRESH: u32 = 7 * 86400;
+
+/// New signatures start this far back, for validators whose clocks are behind.
+const INCEPTION_SKEW: u32 = 3600;
+
+/// How a signed zone proves what isn't in it.
+pub enum DenialChain {
+    Nsec,
+    /// Hashed names (RFC 5155). With `opt_out`, unsigned delegations are left out
+    /// of the chain, which saves big delegation-heavy zones a lot of records.
+    Nsec3 { salt: Vec<u8>, iterations: u16, opt_out: bool },
+}
+
+pub struct SignOptions {
+    /// Seconds each new signature is good for.
+    pub validity: u32,
+    /// Existing signatures are kept if they're good for at least this much longer.
+    pub  refresh: u32,
+    pub    chain: DenialChain,
+}
+
+/// A zone with its signatures, and how many of them had to be made this time.
+pub struct SignedZone {
+    pub records: Vec<Resource>,
+    pub  serial: u32,
+    pub    made: usize,
+    pub    kept: usize,
+}
+
+/// Signs a zone (RFC 4035 section 2). Each RRset the zone is the authority for gets
+/// an RRSIG from each key: the key signing keys sign the DNSKEY, CDS, and CDNSKEY
+/// RRsets, and the zone signing keys sign everything else. Zones with only one
+/// kind of key sign everything with what they have. The NSEC or NSEC3 chain, and
+/// the key records for the keys (and CDS and CDNSKEY for the key signing keys, so
+/// the parent can pick them up), are put in too.
+///
+/// The zone can be one that was signed before. Its chain and CDS and CDNSKEY
+/// records are made again, and signatures that still check out, and aren't due
+/// to be refreshed, are kept. The serial goes up by one if anything changed.
+#[allow(clippy::ptr_arg)]
+pub fn sign_zone(records: &Vec<Resource>, origin: &String, keys: &Vec<SigningKey>, options: &SignOptions) -> Result<SignedZone, String> {
+    let origin = origin.trim_end_matches('.').to_lowercase();
+    if keys.is_empty() {
+        return Err(String::from("No keys to sign with"));
+    }
+
+    for key in keys {
+        if key.name != origin || key.flags & ZONE_KEY_FLAG == 0 {
+            return Err(format!("Key {} for {} isn't a zone key for {}", key.key_tag(), key.name, origin));
+        }
+    }
+
+    // Old signatures are kept aside to be reused, and everything else that comes
+    // from signing is thrown out, to be made again.
+    let mut old_signatures: HashMap<(String, u16), Vec<Rrsig>> = HashMap::new();
+    let mut data = vec![];
+    for record in records {
+        let owner = owner_name(record).ok_or("Compressed owner name in zone")?;
+        if !is_within(&owner, &origin) {
+            return Err(format!("{} isn't in {}", owner, origin));
+        }
+
+        match record.rtype {
+            Type::RRSIG => if let Some(rrsig) = Rrsig::parse(&record.data) {
+                old_signatures.entry((owner, rrsig.type_covered.to_u16())).or_default().push(rrsig);
+            },
+            Type::NSEC | Type::NSEC3 | Type::NSEC3PARAM | Type::CDS | Type::CDNSKEY => {},
+            _ => data.push(record.clone()),
+        }
+    }
+
+    let soa = data.iter().find(|record| record.rtype == Type::SOA && owner_name(record).as_ref() == Some(&origin)).cloned();
+    let soa = soa.ok_or(format!("{} has no SOA", origin))?;
+    add_key_records(&mut data, &origin, keys, soa.ttl);
+
+    // Denial records last as long as a negative answer would (RFC 9077).
+    let negative_ttl = soa.ttl.min(soa_minimum(&soa));
+    let mut zone = Zone::new(&origin, data);
+    match &options.chain {
+        DenialChain::Nsec => zone.add_nsec_chain(negative_ttl),
+        DenialChain::Nsec3 { salt, iterations, opt_out } => zone.add_nsec3_chain(salt, *iterations, *opt_out, negative_ttl)?,
+    }
+
+    let mut signer = Signer {
+        origin: &origin,
+        keys,
+        options,
+        old_signatures,
+        now: unix_time() as u32,
+        made: 0,
+        kept: 0,
+    };
+
+    let mut signed = vec![];
+    let mut soa_rrset = None;
+    for (owner, rrset) in zone.rrsets() {
+        if rrset[0].rtype == Type::SOA && owner == origin {
+            soa_rrset = Some(rrset);
+            continue;
+        }
+
+        if zone.is_signed(&owner, rrset[0].rtype) {
+            let signatures = signer.sign(&owner, &rrset);
+            signed.extend(rrset);
+            signed.extend(signatures);
+        } else {
+            signed.extend(rrset);
+        }
+    }
+
+    // The SOA goes last, since its serial goes up if anything else changed, or if
+    // its own signatures are due.
+    let mut soa_rrset = soa_rrset.unwrap_or_default();
+    let mut serial = soa_serial(&soa).unwrap_or(0);
+    let is_soa_current = keys_for(keys, Type::SOA).into_iter().all(|key| signer.reusable(key, &origin, &soa_rrset).is_some());
+    if signer.made > 0 || !is_soa_current {
+        serial = serial.wrapping_add(1);
+        for record in &mut soa_rrset {
+            set_soa_serial(record, serial);
+        }
+    }
+    let soa_signatures = signer.sign(&origin, &soa_rrset);
+
+    let mut output = soa_rrset;
+    output.extend(soa_signatures);
+    output.extend(signed);
+
+    Ok(SignedZone {
+        records: output,
+        serial,
+        made: signer.made,
+        kept: signer.kept,
+    })
+}
+
+/// Adds a DNSKEY for each key that isn't in the zone already, and the CDS and
+/// CDNSKEY records for the key signing keys (RFC 7344).
+fn add_key_records(data: &mut Vec<Resource>, origin: &String, keys: &Vec<SigningKey>, default_ttl: u32) {
+    let ttl = data.iter().find(|record| record.rtype == Type::DNSKEY).map(|record| record.ttl).unwrap_or(default_ttl);
+
+    for key in keys {
+        let is_published = data.iter().any(|record| record.rtype == Type::DNSKEY && record.data == key.rdata());
+        if !is_published {
+            data.push(key.record(Type::DNSKEY, ttl));
+        }
+    }
+
+    for key in keys_for(keys, Type::CDS) {
+        data.push(key.record(Type::CDNSKEY, ttl));
+
+        if let Some(key_data) = Dnskey::parse(&key.rdata()) {
+            if let Some(ds) = Ds::from_dnskey(origin, &key_data, SHA256_DIGEST) {
+                let rdata = ds.to_rdata();
+                data.push(Resource {
+                    label: Label::Domain(origin.clone()),
+                    rtype: Type::CDS,
+                    rclass: Class::Internet,
+                    ttl,
+                    length: rdata.len() as u16,
+                    data: rdata,
+                });
+            }
+        }
+    }
+}
+
+/// The keys that sign an RRset of a type.
+#[allow(clippy::ptr_arg)]
+fn keys_for(keys: &Vec<SigningKey>, rtype: Type) -> Vec<&SigningKey> {
+    let is_key_rrset = matches!(rtype, Type::DNSKEY | Type::CDS | Type::CDNSKEY);
+    let chosen: Vec<&SigningKey> = keys.iter().filter(|key| (key.flags & SECURE_ENTRY_POINT_FLAG != 0) == is_key_rrset).collect();
+
+    if chosen.is_empty() {
+        keys.iter().collect()
+    } else {
+        chosen
+    }
+}
+
+/// The minimum field of an SOA, at the end of its data.
+fn soa_minimum(soa: &Resource) -> u32 {
+    match soa.data.len() {
+        length if length >= 4 => u32::from_be_bytes([soa.data[length - 4], soa.data[length - 3], soa.data[length - 2], soa.data[length - 1]]),
+        _ => 0,
+    }
+}
+
+/// A zone's records, grouped up for signing.
+struct Zone {
+    origin: String,
+    records: Vec<Resource>,
+    /// Names with NS records below the apex.
+    cuts: HashSet<String>,
+}
+
+impl Zone {
+    fn new(origin: &String, records: Vec<Resource>) -> Zone {
+        let cuts = records.iter()
+            .filter(|record| record.rtype == Type::NS)
+            .filter_map(owner_name)
+            .filter(|owner| owner != origin)
+            .collect();
+
+        Zone {
+            origin: origin.clone(),
+            records,
+            cuts,
+        }
+    }
+
+    /// Whether a name is glue, or anything else below a zone cut, which belongs
+    /// to the child zone.
+    #[allow(clippy::ptr_arg)]
+    fn is_below_cut(&self, name: &String) -> bool {
+        let mut ancestor = name.clone();
+        while ancestor != self.origin && !ancestor.is_empty() {
+            ancestor = parent_name(&ancestor);
+            if self.cuts.contains(&ancestor) {
+                return true;
+            }
+        }
+
+        false
+    }
+
+    /// Whether an RRset gets signed. At a zone cut only the DS and the NSEC are
+    /// ours, and below one nothing is.
+    fn is_signed(&self, owner: &String, rtype: Type) -> bool {
+        if self.is_below_cut(owner) {
+            return false;
+        }
+
+        !self.cuts.contains(owner) || rtype == Type::DS || rtype == Type::NSEC
+    }
+
+    /// The names the zone is the authority for, with their types.
+    fn authoritative_names(&self) -> Vec<(String, Vec<Type>)> {
+        let mut names: HashMap<String, Vec<Type>> = HashMap::new();
+        for record in &self.records {
+            let owner = owner_name(record).unwrap_or_default();
+            if self.is_below_cut(&owner) {
+                continue;
+            }
+
+            let types = names.entry(owner).or_default();
+            if !types.contains(&record.rtype) {
+                types.push(record.rtype);
+            }
+        }
+
+        let mut names: Vec<(String, Vec<Type>)> = names.into_iter().collect();
+        names.sort_by(|a, b| canonical_name_cmp(&a.0, &b.0));
+        names
+    }
+
+    /// Links every name the zone has data for to the next, in canonical order, with
+    /// the last going back round to the apex (RFC 4035 section 2.3).
+    fn add_nsec_chain(&mut self, ttl: u32) {
+        let names = self.authoritative_names();
+        for (index, (name, types)) in names.iter().enumerate() {
+            let mut types = types.clone();
+            types.push(Type::RRSIG);
+            types.push(Type::NSEC);
+
+            let nsec = Nsec {
+                next: names[(index + 1) % names.len()].0.clone(),
+                types,
+            };
+            self.records.push(denial_record(name, Type::NSEC, ttl, nsec.to_rdata()));
+        }
+    }
+
+    /// Does the same with hashed names (RFC 5155 section 7.1). Empty non-terminals
+    /// get an NSEC3 too, since they exist even without records, and with opt-out,
+    /// unsigned delegations don't get one.
+    #[allow(clippy::ptr_arg)]
+    fn add_nsec3_chain(&mut self, salt: &Vec<u8>, iterations: u16, opt_out: bool, ttl: u32) -> Result<(), String> {
+        let parameters = Nsec3Param {
+            hash_algorithm: NSEC3_SHA1,
+            flags: 0,
+            iterations,
+            salt: salt.clone(),
+        };
+        self.records.push(denial_record(&self.origin, Type::NSEC3PARAM, ttl, parameters.to_rdata()));
+
+        let mut names: Vec<(String, Vec<Type>)> = self.authoritative_names().into_iter()
+            .filter(|(name, types)| !opt_out || !self.cuts.contains(name) || types.contains(&Type::DS))
+            .collect();
+
+        let existing: HashSet<String> = names.iter().map(|(name, _)| name.clone()).collect();
+        let mut empty_non_terminals = HashSet::new();
+        for (name, _) in &names {
+            let mut ancestor = parent_name(name);
+            while is_within(&ancestor, &self.origin) && ancestor != self.origin {
+                if !existing.contains(&ancestor) {
+                    empty_non_terminals.insert(ancestor.clone());
+                }
+                ancestor = parent_name(&ancestor);
+            }
+        }
+        names.extend(empty_non_terminals.into_iter().map(|name| (name, vec![])));
+
+        let mut hashed: Vec<(Vec<u8>, Vec<Type>)> = vec![];
+        for (name, mut types) in names {
+            // Anything with records has signed ones, except an unsigned delegation.
+            let is_unsigned_delegation = self.cuts.contains(&name) && !types.contains(&Type::DS);
+            if !types.is_empty() && !is_unsigned_delegation {
+                types.push(Type::RRSIG);
+            }
+
+            hashed.push((nsec3_hash(&name, salt, iterations), types));
+        }
+
+        hashed.sort_by(|a, b| a.0.cmp(&b.0));
+        if hashed.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+            return Err(String::from("Two names hash the same, try another salt"));
+        }
+
+        for (index, (hash, types)) in hashed.iter().enumerate() {
+            let nsec3 = Nsec3 {
+                hash_algorithm: NSEC3_SHA1,
+                flags: if opt_out { NSEC3_OPT_OUT_FLAG } else { 0 },
+                iterations,
+                salt: salt.clone(),
+                next_hashed: hashed[(index + 1) % hashed.len()].0.clone(),
+                types: types.clone(),
+            };
+
+            let owner = match self.origin.as_str() {
+                "" => base32hex_encode(hash),
+                origin => format!("{}.{}", base32hex_encode(hash), origin),
+            };
+            self.records.push(denial_record(&owner, Type::NSEC3, ttl, nsec3.to_rdata()));
+        }
+
+        Ok(())
+    }
+
+    /// The zone's RRsets, in canonical order by owner and then by type.
+    fn rrsets(&self) -> Vec<(String, Vec<Resource>)> {
+        let mut rrsets: HashMap<(String, u16), Vec<Resource>> = HashMap::new();
+        for record in &self.records {
+            let owner = owner_name(record).unwrap_or_default();
+            rrsets.entry((owner, record.rtype.to_u16())).or_default().push(record.clone());
+        }
+
+        let mut rrsets: Vec<((String, u16), Vec<Resource>)> = rrsets.into_iter().collect();
+        rrsets.sort_by(|a, b| canonical_name_cmp(&a.0.0, &b.0.0).then(a.0.1.cmp(&b.0.1)));
+        rrsets.into_iter().map(|((owner, _), rrset)| (owner, rrset)).collect()
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn denial_record(owner: &String, rtype: Type, ttl: u32, rdata: Vec<u8>) -> Resource {
+    Resource {
+        label: Label::Domain(owner.clone()),
+        rtype,
+        rclass: Class::Internet,
+        ttl,
+        length: rdata.len() as u16,
+        data: rdata,
+    }
+}
+
+/// Makes (or reuses) the signatures over each RRset, keeping count.
+struct Signer<'a> {
+    origin: &'a String,
+    keys: &'a Vec<SigningKey>,
+    options: &'a SignOptions,
+    old_signatures: HashMap<(String, u16), Vec<Rrsig>>,
+    now: u32,
+    made: usize,
+    kept: usize,
+}
+
+impl<'a> Signer<'a> {
+    /// An RRSIG over the RRset from each of the keys that sign it. An old one is
+    /// kept if it's from the same key, still checks out over the RRset as it is
+    /// now, and has long enough to go.
+    fn sign(&mut self, owner: &String, rrset: &Vec<Resource>) -> Vec<Resource> {
+        let mut signatures = vec![];
+        for key in keys_for(self.keys, rrset[0].rtype) {
+            let rrsig = match self.reusable(key, owner, rrset) {
+                Some(rrsig) => {
+                    self.kept += 1;
+                    rrsig
+                },
+                None => {
+                    self.made += 1;
+                    self.make_signature(key, owner, rrset)
+                },
+            };
+
+            signatures.push(signature_record(owner, rrset[0].ttl, &rrsig));
+        }
+
+        signatures
+    }
+
+    /// An old signature from the key that can be kept as it is.
+    #[allow(clippy::ptr_arg)]
+    fn reusable(&self, key: &SigningKey, owner: &String, rrset: &Vec<Resource>) -> Option<Rrsig> {
+        let old_signatures = self.old_signatures.get(&(owner.clone(), rrset[0].rtype.to_u16()))?;
+        old_signatures.iter()
+            .find(|rrsig| {
+                rrsig.key_tag == key.key_tag()
+                    && rrsig.algorithm == key.algorithm.number()
+                    && rrsig.signer == *self.origin
+                    && (self.now.wrapping_sub(rrsig.inception) as i32) >= 0
+                    && (rrsig.expiration.wrapping_sub(self.now) as i32) >= self.options.refresh as i32
+                    && verify_with_key(&key.rdata(), &rrsig.signed_data(owner, rrset), &rrsig.signature)
+            })
+            .cloned()
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn make_signature(&self, key: &SigningKey, owner: &String, rrset: &Vec<Resource>) -> Rrsig {
+        let mut rrsig = Rrsig {
+            type_covered: rrset[0].rtype,
+            algorithm: key.algorithm.number(),
+            labels: label_count(owner) as u8,
+            original_ttl: rrset[0].ttl,
+            expiration: self.now.wrapping_add(self.options.validity),
+            inception: self.now.wrapping_sub(INCEPTION_SKEW),
+            key_tag: key.key_tag(),
+            signer: self.origin.clone(),
+            signature: vec![],
+        };
+
+        rrsig.signature = key.sign(&rrsig.signed_data(owner, rrset));
+        rrsig
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn signature_record(owner: &String, ttl: u32, rrsig: &Rrsig) -> Resource {
+    let rdata = rrsig.to_rdata();
+    Resource {
+        label: Label::Domain(owner.clone()),
+        rtype: Type::RRSIG,
+        rclass: Class::Internet,
+        ttl,
+        length: rdata.len() as u16,
+        data: rdata,
+    }
+}
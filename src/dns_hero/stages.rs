@@ -0,0 +1,416 @@
+use std::io;
+use std::sync::{ Arc, Mutex };
+use std::thread;
+
+use super::authoritative::*;
+use super::cache::*;
+use super::data::*;
+use super::dnssec::*;
+use super::doh::*;
+use super::enums::*;
+use super::local::LocalRecords;
+use super::pipeline::*;
+use super::upstream::*;
+use super::util::*;
+use super::zone_dnssec::add_edns_records;
+
+/// Answers names pinned in the config or a hosts file, before anything else gets a
+/// look at them.
+pub struct HostsStage {
+    records: LocalRecords,
+}
+
+impl HostsStage {
+    pub fn new(records: LocalRecords) -> HostsStage {
+        HostsStage {
+            records,
+        }
+    }
+}
+
+impl Handler for HostsStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        let answers = match request.packet.questions.first().and_then(|question| self.records.lookup(question)) {
+            Some(answers) => answers,
+            None => return next.run(request),
+        };
+
+        println!("Answering from local records");
+
+        let mut work_packet = request.packet.clone();
+        work_packet.answers = answers;
+        work_packet.authorities.clear();
+        work_packet.additional_records.clear();
+        work_packet.is_response = true;
+        work_packet.is_authoritative = true;
+        work_packet.recursion_available = true;
+        work_packet.response_code = ResponseCode::NoError;
+
+        Some(Response::local(work_packet.to_raw().data))
+    }
+}
+
+/// Answers authoritatively for the zones we serve. Anything outside of them goes on
+/// to the next stage.
+pub struct ZoneStage {
+    zones: Arc<Zones>,
+}
+
+impl ZoneStage {
+    pub fn new(zones: Arc<Zones>) -> ZoneStage {
+        ZoneStage {
+            zones,
+        }
+    }
+}
+
+impl Handler for ZoneStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        let question = match request.packet.questions.first() {
+            Some(question) => question.clone(),
+            None => return next.run(request),
+        };
+
+        let zone = match &question.label {
+            Label::Domain(name) => self.zones.find_for(name, question.qtype),
+            Label::Pointer(_) => None,
+        };
+
+        let hosted = match zone {
+            Some(hosted) => hosted,
+            None => return next.run(request),
+        };
+
+        println!("Answering for zone {}", hosted.zone.origin);
+        let mut answer = match question.qtype {
+            _ if hosted.expired => ZoneAnswer::failed(),
+            // Transfers only happen over TCP, and don't come through here. An IXFR
+            // over UDP gets our SOA, which tells the client to try again over TCP
+            // (RFC 1995 section 2).
+            Type::AXFR => ZoneAnswer::refused(),
+            Type::IXFR => ZoneAnswer::soa_only(&hosted.zone),
+            _ => hosted.zone.lookup(&question),
+        };
+
+        add_edns_records(&hosted.zone, &request.packet, &question, &mut answer);
+
+        let mut work_packet = request.packet.clone();
+        work_packet.questions = vec![question];
+        work_packet.answers = answer.answers;
+        work_packet.authorities = answer.authorities;
+        work_packet.additional_records = answer.additional_records;
+        work_packet.is_response = true;
+        work_packet.is_authoritative = answer.authoritative;
+        work_packet.recursion_available = true;
+        work_packet.response_code = answer.response_code;
+
+        // Too big for the client to take over UDP, so it gets told to ask over TCP
+        // instead (RFC 2181 section 9).
+        let mut raw = work_packet.to_raw().data;
+        if !request.client.over_tcp && raw.len() > udp_payload_size(&request.packet) {
+            work_packet.answers.clear();
+            work_packet.authorities.clear();
+            work_packet.additional_records.retain(|record| record.rtype == Type::OPT);
+            work_packet.is_truncated = true;
+            raw = work_packet.to_raw().data;
+        }
+
+        Some(Response::local(raw))
+    }
+}
+
+/// Answers from the cache when it can. Otherwise it caches whatever the rest of the
+/// pipeline comes up with, and falls back on stale entries if that's nothing.
+pub struct CacheStage {
+    cache: Arc<Mutex<Cache>>,
+}
+
+impl CacheStage {
+    pub fn new(cache: Arc<Mutex<Cache>>) -> CacheStage {
+        CacheStage {
+            cache,
+        }
+    }
+}
+
+impl Handler for CacheStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        let key = match CacheKey::from_query(&Packet::from_vec(&request.raw)) {
+            Some(key) => key,
+            None => return next.run(request),
+        };
+
+        let cached = self.cache.lock().unwrap().get(&key, request.packet.id);
+        if let Some(cached) = cached {
+            println!("Answering from cache");
+
+            if self.cache.lock().unwrap().claim_prefetch(&key) {
+                println!("Prefetching {}", key.name);
+                let cache = self.cache.clone();
+                let mut request = request.clone();
+                thread::spawn(move || {
+                    if let Some(response) = next.run(&mut request) {
+                        store(&cache, key, &response);
+                    }
+                });
+            }
+
+            return Some(Response::local(cached));
+        }
+
+        match next.run(request) {
+            Some(response) => {
+                store(&self.cache, key, &response);
+                Some(response)
+            },
+            None => {
+                let stale = self.cache.lock().unwrap().get_stale(&key, request.packet.id);
+                if stale.is_some() {
+                    println!("Upstream unavailable. Serving stale answer");
+                }
+
+                stale.map(Response::local)
+            },
+        }
+    }
+}
+
+fn store(cache: &Mutex<Cache>, key: CacheKey, response: &Response) {
+    match response.cache_policy {
+        CachePolicy::Records => cache.lock().unwrap().insert(key, &response.data),
+        CachePolicy::For(ttl) => cache.lock().unwrap().insert_for(key, &response.data, ttl),
+        CachePolicy::Never => {},
+    }
+}
+
+enum BlockAllowStatus {
+    Neutral,
+    Block,
+    Allow,
+}
+
+/// Blocks names on the block list outright, and lets names on the allow list skip
+/// the filtering authority.
+pub struct ListStage {
+    block_list: Vec<String>,
+    allow_list: Vec<String>,
+}
+
+impl ListStage {
+    pub fn new(block_list: Vec<String>, allow_list: Vec<String>) -> ListStage {
+        ListStage {
+            block_list,
+            allow_list,
+        }
+    }
+
+    fn get_block_allow_status(&self, parsed_packet: &DecomposedPacket) -> BlockAllowStatus {
+        if parsed_packet.questions.is_empty() {
+            return BlockAllowStatus::Neutral
+        }
+
+        match &parsed_packet.questions[0].label {
+            Label::Pointer(_) => BlockAllowStatus::Neutral,
+            Label::Domain(domain_value) => {
+                if self.block_list.iter().any(|val| val == domain_value) {
+                    println!("Blocking via block list");
+                    BlockAllowStatus::Block
+                } else if self.allow_list.iter().any(|val| val == domain_value) {
+                    println!("Allowing via allow list");
+                    BlockAllowStatus::Allow
+                } else {
+                    BlockAllowStatus::Neutral
+                }
+            }
+        }
+    }
+}
+
+impl Handler for ListStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        match self.get_block_allow_status(&request.packet) {
+            BlockAllowStatus::Block => Some(Response::local(make_block_packet(request.packet.clone()))),
+            BlockAllowStatus::Allow => {
+                request.bypass_filter = true;
+                next.run(request)
+            },
+            BlockAllowStatus::Neutral => next.run(request),
+        }
+    }
+}
+
+/// Where queries for one domain (and everything under it) get forwarded.
+pub struct ForwardZone {
+    /// Lowercase, without a trailing dot.
+    pub suffix: String,
+    pub upstreams: Arc<UpstreamGroup>,
+    pub bypass_filter: bool,
+}
+
+impl ForwardZone {
+    fn covers(&self, name: &str) -> bool {
+        name == self.suffix || name.ends_with(&format!(".{}", self.suffix))
+    }
+}
+
+/// Picks upstreams for queries under particular domains, like internal zones that
+/// only the corporate DNS server knows about. The most specific zone wins. Zones can
+/// also skip the filtering authority, since it has no business with internal names.
+pub struct RouteStage {
+    zones: Vec<ForwardZone>,
+}
+
+impl RouteStage {
+    pub fn new(zones: Vec<ForwardZone>) -> RouteStage {
+        RouteStage {
+            zones,
+        }
+    }
+
+    fn find_zone(&self, request: &Request) -> Option<&ForwardZone> {
+        let name = match &request.packet.questions.first()?.label {
+            Label::Pointer(_) => return None,
+            Label::Domain(name) => name.to_lowercase(),
+        };
+
+        self.zones.iter()
+            .filter(|zone| zone.covers(&name))
+            .max_by_key(|zone| zone.suffix.len())
+    }
+}
+
+impl Handler for RouteStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        if let Some(zone) = self.find_zone(request) {
+            println!("Routing via zone {}", zone.suffix);
+            request.upstreams = Some(zone.upstreams.clone());
+            if zone.bypass_filter {
+                request.bypass_filter = true;
+            }
+        }
+
+        next.run(request)
+    }
+}
+
+/// Asks a filtering DoH authority (CleanBrowsing) about the query. If it blocked the
+/// name, so do we. Otherwise its answer is the answer, since it may have enforced
+/// safe search, unless the query has been routed to particular upstreams. Requests
+/// that bypass filtering go straight to the next stage.
+pub struct AuthorityStage {
+    url: String,
+}
+
+impl AuthorityStage {
+    pub fn new(url: String) -> AuthorityStage {
+        AuthorityStage {
+            url,
+        }
+    }
+}
+
+impl Handler for AuthorityStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        if request.bypass_filter {
+            return next.run(request);
+        }
+
+        let authority_res = check_upstream(exchange_doh(&request.raw, &self.url))?;
+
+        if authority_blocked_request(&authority_res) {
+            println!("Blocking via CB");
+            let block_res = make_block_packet(request.packet.clone());
+
+            // Hold on to the decision as long as CB's own negative answer could be held.
+            Some(match negative_ttl(&Packet::from_vec(&authority_res)) {
+                Some(ttl) => Response { data: block_res, cache_policy: CachePolicy::For(ttl) },
+                None => Response::local(block_res),
+            })
+        } else if request.upstreams.is_some() {
+            println!("Authority is neutral. Forwarding to the zone's upstreams");
+            next.run(request)
+        } else {
+            println!("List and authority are neutral");
+            Some(Response::from_upstream(authority_res))
+        }
+    }
+}
+
+/// Sends the query on to a group of upstreams, or to the ones it was routed to.
+pub struct ForwardStage {
+    upstreams: UpstreamGroup,
+}
+
+impl ForwardStage {
+    pub fn new(upstreams: UpstreamGroup) -> ForwardStage {
+        ForwardStage {
+            upstreams,
+        }
+    }
+}
+
+impl Handler for ForwardStage {
+    fn handle(&self, request: &mut Request, _next: Next) -> Option<Response> {
+        let reply = match &request.upstreams {
+            Some(upstreams) => upstreams.exchange(&request.raw),
+            None => self.upstreams.exchange(&request.raw),
+        };
+
+        check_upstream(reply).map(Response::from_upstream)
+    }
+}
+
+/// Unwraps an upstream's reply. Failures and SERVFAILs both count as no answer.
+fn check_upstream(reply: io::Result<Vec<u8>>) -> Option<Vec<u8>> {
+    match reply {
+        Ok(response) => match Packet::from_vec(&response).get_response_code() {
+            ResponseCode::ServFail => {
+                println!("Upstream returned SERVFAIL");
+                None
+            },
+            _ => Some(response),
+        },
+        Err(err) => {
+            println!("Upstream failed: {}", err);
+            None
+        },
+    }
+}
+
+fn make_block_packet(mut work_packet: DecomposedPacket) -> Vec<u8> {
+    work_packet.answers.push(Resource {
+        label: work_packet.questions[0].label.clone(),
+        rtype: Type::A,
+        rclass: Class::Internet,
+        ttl: 10,
+        length: 4,
+        data: vec![208, 185, 195, 92],
+    });
+
+    work_packet.is_response = true;
+    work_packet.response_code = ResponseCode::NoError;
+
+    work_packet.to_raw().data
+}
+
+const CLEAN_BROWSING_AUTHORITY: &str = "cleanbrowsing.rpz.noc.org";
+
+fn authority_blocked_request(packet: &Vec<u8>) -> bool {
+    let nice_packet = DecomposedPacket::from_packet(&Packet::from_vec(packet));
+    match nice_packet.response_code {
+        ResponseCode::NXDomain => {
+            nice_packet.authorities.into_iter().any(|authority| {
+                match authority.rtype {
+                    Type::SOA => {
+                        match parse_label(&authority.data, 0).0 {
+                            Label::Pointer(_) => false,
+                            Label::Domain(val) => val.as_str() == CLEAN_BROWSING_AUTHORITY,
+                        }
+                    },
+                    _ => false,
+                }
+            })
+        },
+        _ => false,
+    }
+}
@@ -0,0 +1,57 @@
+use std::io;
+use std::io::{ Read, Write };
+use std::net::{ SocketAddr, TcpStream, ToSocketAddrs };
+use std::time::Duration;
+
+/// How long to wait on a TCP peer, per message.
+pub const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Connects to a DNS server over TCP. The address is an IP, or an IP and port.
+pub fn connect_tcp(server: &String) -> io::Result<TcpStream> {
+    let address = parse_server_address(server)?;
+    let stream = TcpStream::connect_timeout(&address, TCP_TIMEOUT)?;
+    stream.set_read_timeout(Some(TCP_TIMEOUT))?;
+    stream.set_write_timeout(Some(TCP_TIMEOUT))?;
+    Ok(stream)
+}
+
+/// Reads a server address, adding the DNS port when there isn't one.
+pub fn parse_server_address(server: &String) -> io::Result<SocketAddr> {
+    let with_port = match server.parse::<SocketAddr>() {
+        Ok(address) => return Ok(address),
+        Err(_) => format!("{}:53", server),
+    };
+
+    with_port.to_socket_addrs()?
+        .next()
+        .ok_or(io::Error::new(io::ErrorKind::InvalidInput, format!("Bad server address {}", server)))
+}
+
+/// Sends one DNS message, with the two-byte length in front that TCP needs (RFC 1035 4.2.2).
+#[allow(clippy::ptr_arg)]
+pub fn send_tcp_message(stream: &mut TcpStream, message: &Vec<u8>) -> io::Result<()> {
+    if message.len() > u16::MAX as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "Message too long for TCP"));
+    }
+
+    let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+    framed.extend_from_slice(message);
+    stream.write_all(&framed)
+}
+
+/// Reads one length-prefixed DNS message.
+pub fn read_tcp_message(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut length = [0; 2];
+    stream.read_exact(&mut length)?;
+
+    let mut message = vec![0; u16::from_be_bytes(length) as usize];
+    stream.read_exact(&mut message)?;
+    Ok(message)
+}
+
+/// Sends a query over a new connection and reads back the one message that answers it.
+pub fn exchange_tcp(message: &Vec<u8>, server: &String) -> io::Result<Vec<u8>> {
+    let mut stream = connect_tcp(server)?;
+    send_tcp_message(&mut stream, message)?;
+    read_tcp_message(&mut stream)
+}
@@ -0,0 +1,89 @@
+use super::authoritative::{ is_within, Zone };
+use super::data::*;
+use super::dnssec::label_count;
+use super::enums::*;
+use super::keys::{ KeyAlgorithm, SigningKey, KEY_SIGNING_KEY_FLAGS };
+use super::signer::{ sign_zone, DenialChain, SignOptions, DEFAULT_REFRESH, DEFAULT_SIGNATURE_VALIDITY };
+use super::validator::TrustAnchor;
+use super::zone::parse_zone;
+
+/// A zone from its records after the SOA and NS, which every zone gets.
+pub fn zone_records(origin: &str, body: &str) -> Vec<Resource> {
+    let text = format!("$TTL 300\n@ SOA ns hostmaster 1 3600 600 86400 300\n@ NS ns\n{}", body);
+    parse_zone(&text, &String::from(origin)).unwrap()
+}
+
+/// Signs a zone with a fresh key, and hands back the DS records its parent
+/// needs, which are its CDS records under another type, and the key's DNSKEY
+/// data.
+pub fn signed(origin: &str, records: Vec<Resource>, parent_ds: Vec<Resource>) -> (Vec<Resource>, Vec<Resource>, Vec<u8>) {
+    signed_with(origin, records, parent_ds, DenialChain::Nsec)
+}
+
+/// The same, with NSEC3 or whatever other chain.
+pub fn signed_with(origin: &str, mut records: Vec<Resource>, parent_ds: Vec<Resource>, chain: DenialChain) -> (Vec<Resource>, Vec<Resource>, Vec<u8>) {
+    records.extend(parent_ds);
+    let origin = String::from(origin);
+    let key = SigningKey::generate(&origin, KeyAlgorithm::Ed25519, KEY_SIGNING_KEY_FLAGS);
+    let options = SignOptions {
+        validity: DEFAULT_SIGNATURE_VALIDITY,
+        refresh: DEFAULT_REFRESH,
+        chain,
+    };
+
+    let key_data = key.rdata();
+    let signed = sign_zone(&records, &origin, &vec![key], &options).unwrap();
+    let ds = signed.records.iter()
+        .filter(|record| record.rtype == Type::CDS)
+        .map(|record| Resource { rtype: Type::DS, ..record.clone() })
+        .collect();
+    (signed.records, ds, key_data)
+}
+
+/// The root, `test`, and four zones under it: `sec.test` is signed, with a
+/// wildcard at `*.wild`, `unsg.test` isn't, `badds.test` is signed with a key
+/// other than the one its DS is for, and `n3.test` is signed with NSEC3 opt-out,
+/// and has an unsigned delegation to `insecure.n3.test`.
+pub fn hierarchy() -> (Vec<Zone>, Vec<TrustAnchor>) {
+    let leaf = |origin: &str| zone_records(origin, "www A 10.0.0.1\n");
+    let (sec, sec_ds, _) = signed("sec.test", zone_records("sec.test", "www A 10.0.0.1\n*.wild A 10.0.0.2\n"), vec![]);
+    let (badds, _, _) = signed("badds.test", leaf("badds.test"), vec![]);
+    let (_, other_ds, _) = signed("badds.test", leaf("badds.test"), vec![]);
+    let unsg = leaf("unsg.test");
+    let opt_out = DenialChain::Nsec3 { salt: vec![0xab], iterations: 1, opt_out: true };
+    let (n3, n3_ds, _) = signed_with("n3.test", zone_records("n3.test", "www A 10.0.0.1\ninsecure NS ns.insecure\nns.insecure A 10.0.0.3\n"), vec![], opt_out);
+
+    let cuts = "sec NS ns.sec\nunsg NS ns.unsg\nbadds NS ns.badds\nn3 NS ns.n3\n";
+    let parent_ds = sec_ds.into_iter().chain(other_ds).chain(n3_ds).collect();
+    let (test, test_ds, _) = signed("test", zone_records("test", cuts), parent_ds);
+    let (root, _, root_key) = signed("", zone_records("", "test NS ns.test.\n"), test_ds);
+
+    let zones = vec![("", root), ("test", test), ("sec.test", sec), ("badds.test", badds), ("unsg.test", unsg), ("n3.test", n3)];
+    let zones = zones.into_iter().map(|(origin, records)| Zone::new(&String::from(origin), records).unwrap()).collect();
+    let anchors = vec![TrustAnchor { name: String::new(), rtype: Type::DNSKEY, data: root_key }];
+    (zones, anchors)
+}
+
+/// Answers from whichever zone a name is in, the way `ZoneStage` would for a
+/// client that asked for DNSSEC. DS questions go to the parent at a zone cut.
+#[allow(clippy::ptr_arg)]
+pub fn answer(zones: &[Zone], name: &String, qtype: Type) -> Result<DecomposedPacket, String> {
+    let zone = zones.iter()
+        .filter(|zone| is_within(name, &zone.origin) && !(qtype == Type::DS && *name == zone.origin))
+        .max_by_key(|zone| label_count(&zone.origin))
+        .ok_or(format!("no zone for {}", name))?;
+
+    let question = Question { label: Label::Domain(name.clone()), qtype, qclass: Class::Internet };
+    let mut answer = zone.lookup(&question);
+    zone.add_dnssec(&question, &mut answer);
+
+    let mut packet = DecomposedPacket::new();
+    packet.is_response = true;
+    packet.is_authoritative = answer.authoritative;
+    packet.response_code = answer.response_code;
+    packet.questions = vec![question];
+    packet.answers = answer.answers;
+    packet.authorities = answer.authorities;
+    packet.additional_records = answer.additional_records;
+    Ok(packet)
+}
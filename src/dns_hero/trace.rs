@@ -0,0 +1,515 @@
+use std::collections::{ HashMap, HashSet };
+
+use super::authoritative::{ is_within, parent_name };
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::keys::{ algorithm_name, is_supported_algorithm };
+use super::util::unix_time;
+use super::validator::*;
+use super::zone::{ write_name, write_record };
+
+/// Asks a server for an RRset, with DNSSEC records and the CD flag set, so it
+/// hands back what it has whether it checks out or not.
+pub type TraceQuery<'a> = dyn Fn(&String, Type) -> Result<DecomposedPacket, String> + 'a;
+
+/// Walks the chain of trust for a name down from the trust anchors, the way the
+/// `validate` stage does, but printing every link as it goes: the DS records at
+/// each zone cut and the keys they pick out, which key signed which RRset, and
+/// the window each signature is good for. Then the answer itself, or the proof
+/// there isn't one. Where the chain doesn't hold, it says exactly where, and why,
+/// and stops there.
+///
+/// Ok says whether the answer is secure, and Err is where the chain broke, the
+/// same as what gets printed at the end.
+#[allow(clippy::ptr_arg)]
+pub fn trace_chain(name: &String, qtype: Type, anchors: &Vec<TrustAnchor>, query: &TraceQuery) -> Result<bool, Break> {
+    let name = name.trim_end_matches('.').to_lowercase();
+    let mut tracer = Tracer {
+        anchors,
+        query,
+        zones: HashMap::new(),
+        not_cuts: HashSet::new(),
+    };
+
+    println!("Tracing {} {}", write_name(&name), qtype);
+    let result = tracer.secure_zone(&name).and_then(|_| tracer.check_answer(&name, qtype));
+
+    println!();
+    match &result {
+        Ok(true) => println!("{} {} is secure", write_name(&name), qtype),
+        Ok(false) => println!("{} {} is insecure", write_name(&name), qtype),
+        Err(broken) => println!("Validation breaks at {}: {}", broken.at, broken.reason),
+    }
+
+    result
+}
+
+/// Where a chain of trust stopped holding, and why. `at` is written the way a
+/// master file would have it, trailing dot and all.
+pub struct Break {
+    pub     at: String,
+    pub reason: String,
+}
+
+impl Break {
+    #[allow(clippy::ptr_arg)]
+    fn at(name: &String, reason: String) -> Break {
+        Break { at: write_name(name), reason }
+    }
+}
+
+/// What's at a name, as far as the chain of trust goes.
+enum Delegation {
+    /// A zone cut with DS records we can use.
+    Signed(Vec<Ds>),
+    /// A zone cut that's proved unsigned, or may as well be.
+    Unsigned,
+    NotCut,
+}
+
+struct Tracer<'a> {
+    anchors: &'a Vec<TrustAnchor>,
+    query: &'a TraceQuery<'a>,
+    /// Zones traced so far, with their keys if they're signed, so following a
+    /// CNAME into a zone we've been through doesn't print it all again.
+    zones: HashMap<String, Option<Vec<Dnskey>>>,
+    not_cuts: HashSet<String>,
+}
+
+impl<'a> Tracer<'a> {
+    fn ask(&self, name: &String, qtype: Type) -> Result<DecomposedPacket, Break> {
+        let packet = (self.query)(name, qtype).map_err(|reason| Break::at(name, reason))?;
+        match packet.response_code {
+            ResponseCode::NoError | ResponseCode::NXDomain => Ok(packet),
+            code => Err(Break::at(name, format!("the server answered {} {} with {}", write_name(name), qtype, code))),
+        }
+    }
+
+    /// Follows the chain of trust from the closest trust anchor down to the zone a
+    /// name is in, one label at a time. None when it ends in an unsigned zone, or
+    /// never starts because no anchor covers the name.
+    #[allow(clippy::ptr_arg)]
+    fn secure_zone(&mut self, name: &String) -> Result<Option<(String, Vec<Dnskey>)>, Break> {
+        let anchor = self.anchors.iter()
+            .filter(|anchor| is_within(name, &anchor.name))
+            .max_by_key(|anchor| label_count(&anchor.name))
+            .map(|anchor| anchor.name.clone());
+
+        let mut zone = match anchor {
+            Some(anchor) => anchor,
+            None => {
+                println!();
+                println!("No trust anchor covers {}", write_name(name));
+                return Ok(None);
+            },
+        };
+
+        let mut keys = match self.zones.get(&zone).cloned() {
+            Some(Some(keys)) => keys,
+            Some(None) => return Ok(None),
+            None => {
+                println!();
+                println!("{} (trust anchor)", write_name(&zone));
+                self.zone_keys(&zone, &vec![])?
+            },
+        };
+
+        for count in label_count(&zone) + 1..=label_count(name) {
+            let child = ancestor_with_labels(name, count);
+            if self.not_cuts.contains(&child) {
+                continue;
+            }
+
+            match self.zones.get(&child).cloned() {
+                Some(Some(child_keys)) => {
+                    zone = child;
+                    keys = child_keys;
+                    continue;
+                },
+                Some(None) => return Ok(None),
+                None => {},
+            }
+
+            println!();
+            println!("{}", write_name(&child));
+            match self.delegation(&child, &zone, &keys)? {
+                Delegation::Signed(ds) => {
+                    keys = self.zone_keys(&child, &ds)?;
+                    zone = child;
+                },
+                Delegation::Unsigned => {
+                    self.zones.insert(child, None);
+                    return Ok(None);
+                },
+                Delegation::NotCut => {
+                    self.not_cuts.insert(child);
+                },
+            }
+        }
+
+        Ok(Some((zone, keys)))
+    }
+
+    /// Looks up the DS records at a name, in its parent zone.
+    fn delegation(&mut self, child: &String, zone: &String, keys: &Vec<Dnskey>) -> Result<Delegation, Break> {
+        let packet = self.ask(child, Type::DS)?;
+        let answers = collect_rrsets(&packet.answers);
+
+        // Nothing else can be at a CNAME, zone cuts included. Its signature gets
+        // checked along with the rest of the answer.
+        if answers.iter().any(|rrset| rrset.owner == *child && rrset.rtype == Type::CNAME) {
+            println!("  A CNAME, so not a zone cut");
+            return Ok(Delegation::NotCut);
+        }
+
+        let rrset = answers.into_iter().find(|rrset| rrset.owner == *child && rrset.rtype == Type::DS);
+
+        let rrset = match rrset {
+            Some(rrset) => rrset,
+            None => return match self.check_denial(&packet, child, Type::DS, zone, keys)? {
+                Denial::NoData { delegation: true } => {
+                    println!("  Unsigned delegation, there are no DS records");
+                    Ok(Delegation::Unsigned)
+                },
+                Denial::Insecure => {
+                    println!("  In an NSEC3 opt-out span, so if it's a delegation, it's unsigned");
+                    Ok(Delegation::Unsigned)
+                },
+                Denial::NoData { delegation: false } | Denial::NoName => {
+                    println!("  Not a zone cut");
+                    Ok(Delegation::NotCut)
+                },
+            },
+        };
+
+        for ds in rrset.records.iter().filter_map(|record| Ds::parse(&record.data)) {
+            println!("  DS {} {} {}", ds.key_tag, algorithm_name(ds.algorithm), digest_name(ds.digest_type));
+        }
+        self.check_signatures(&rrset, zone, keys).map_err(|reason| Break::at(child, reason))?;
+
+        // DS records we can't make sense of don't count, and without any others,
+        // the zone might as well be unsigned (RFC 4035 section 5.2).
+        let usable: Vec<Ds> = rrset.records.iter()
+            .filter_map(|record| Ds::parse(&record.data))
+            .filter(|ds| is_supported_algorithm(ds.algorithm) && is_supported_digest(ds.digest_type))
+            .collect();
+
+        if usable.is_empty() {
+            println!("  None of the DS records use an algorithm and digest we can check, so it counts as unsigned");
+            return Ok(Delegation::Unsigned);
+        }
+
+        Ok(Delegation::Signed(usable))
+    }
+
+    /// Fetches a zone's keys and checks that the DNSKEY RRset is signed by one its
+    /// DS records (or a trust anchor) picks out.
+    #[allow(clippy::ptr_arg)]
+    fn zone_keys(&mut self, zone: &String, delegation: &Vec<Ds>) -> Result<Vec<Dnskey>, Break> {
+        let packet = self.ask(zone, Type::DNSKEY)?;
+        let rrset = match collect_rrsets(&packet.answers).into_iter().find(|rrset| rrset.owner == *zone && rrset.rtype == Type::DNSKEY) {
+            Some(rrset) => rrset,
+            None => return Err(Break::at(zone, String::from("the zone has no DNSKEY records"))),
+        };
+
+        let anchors: Vec<&TrustAnchor> = self.anchors.iter().filter(|anchor| anchor.name == *zone).collect();
+        let mut keys = vec![];
+        let mut entry_keys = vec![];
+        for key in rrset.records.iter().filter_map(|record| Dnskey::parse(&record.data)) {
+            let role = if !key.is_zone_key() {
+                "not a zone key"
+            } else if key.flags & SECURE_ENTRY_POINT_FLAG != 0 {
+                "KSK"
+            } else {
+                "ZSK"
+            };
+
+            let vouched_for = delegation.iter()
+                .find(|ds| ds.matches(zone, &key))
+                .map(|ds| format!(", matches DS {} {}", ds.key_tag, digest_name(ds.digest_type)))
+                .or_else(|| anchors.iter().find(|anchor| anchor.trusts(&key)).map(|_| String::from(", matches the trust anchor")));
+
+            println!("  DNSKEY {} {} {}{}", key.key_tag(), algorithm_name(key.algorithm), role, vouched_for.clone().unwrap_or_default());
+            if vouched_for.is_some() && key.is_zone_key() && is_supported_algorithm(key.algorithm) {
+                entry_keys.push(key.clone());
+            }
+            if key.is_zone_key() {
+                keys.push(key);
+            }
+        }
+
+        if entry_keys.is_empty() {
+            let vouchers = if anchors.is_empty() { "the DS records in its parent" } else { "its trust anchors" };
+            return Err(Break::at(zone, format!("none of its keys match {}", vouchers)));
+        }
+
+        self.check_signatures(&rrset, zone, &entry_keys).map_err(|reason| Break::at(zone, reason))?;
+        self.zones.insert(zone.clone(), Some(keys.clone()));
+        Ok(keys)
+    }
+
+    /// Asks the question itself, and checks each RRset in the answer against the
+    /// keys of the zone that signed it. An answer that isn't there has its denial
+    /// checked instead, at the end of any CNAMEs. Ok(false) when some of it is
+    /// from an unsigned zone.
+    fn check_answer(&mut self, name: &String, qtype: Type) -> Result<bool, Break> {
+        let packet = self.ask(name, qtype)?;
+        let answers = collect_rrsets(&packet.answers);
+        let mut secure = true;
+
+        for rrset in &answers {
+            println!();
+            println!("{} {}", write_name(&rrset.owner), rrset.rtype);
+            for record in &rrset.records {
+                println!("  {}", write_record(record));
+            }
+
+            let signer = signer_zone(rrset);
+
+            let zone = match &signer {
+                Some(signer) => signer.clone(),
+                None if rrset.rtype == Type::DS => parent_name(&rrset.owner),
+                None => rrset.owner.clone(),
+            };
+
+            let keys = match (self.secure_zone(&zone)?, &signer) {
+                (None, _) => {
+                    println!("  From an unsigned zone");
+                    secure = false;
+                    continue;
+                },
+                (Some((found, _)), None) => return Err(Break::at(&rrset.owner, format!("it isn't signed, though {} is", write_name(&found)))),
+                (Some((found, keys)), Some(_)) if found == zone => keys,
+                (Some(_), Some(_)) => return Err(Break::at(&rrset.owner, format!("it's signed by {}, which isn't a zone", write_name(&zone)))),
+            };
+
+            let wildcard = self.check_signatures(rrset, &zone, &keys).map_err(|reason| Break::at(&rrset.owner, reason))?;
+            if let Some(encloser) = wildcard {
+                println!("  Expanded from {}", write_name(&wildcard_name(&encloser)));
+                if !self.check_wildcard_proof(&packet, &rrset.owner, &encloser, &zone, &keys)? {
+                    secure = false;
+                }
+            }
+        }
+
+        // Follow the answer along any CNAMEs, to see if it got there.
+        let target = match chain_target(&answers, name, qtype) {
+            Ok(Some(target)) => target,
+            Ok(None) => return Ok(secure),
+            Err(target) => return Err(Break::at(&target, String::from("its CNAME is garbled"))),
+        };
+
+        println!();
+        println!("{} {} (no answer, {})", write_name(&target), qtype, packet.response_code);
+        let zone_of = if qtype == Type::DS { parent_name(&target) } else { target.clone() };
+        let (zone, keys) = match self.secure_zone(&zone_of)? {
+            Some(found) => found,
+            None => {
+                println!("  From an unsigned zone");
+                return Ok(false);
+            },
+        };
+
+        match (self.check_denial(&packet, &target, qtype, &zone, &keys)?, packet.response_code) {
+            (Denial::NoName, ResponseCode::NXDomain) => println!("  Proves {} doesn't exist", write_name(&target)),
+            (Denial::NoData { .. }, ResponseCode::NoError) => println!("  Proves {} has no {}", write_name(&target), qtype),
+            (Denial::Insecure, _) => {
+                println!("  In an NSEC3 opt-out span, which doesn't prove anything");
+                return Ok(false);
+            },
+            _ => return Err(Break::at(&target, format!("the denial doesn't match the {} response code", packet.response_code))),
+        }
+
+        Ok(secure)
+    }
+
+    /// Checks the NSEC or NSEC3 records in a negative answer from a zone, and
+    /// works out what they prove.
+    fn check_denial(&mut self, packet: &DecomposedPacket, name: &String, qtype: Type, zone: &String, keys: &Vec<Dnskey>) -> Result<Denial, Break> {
+        let rrsets = collect_rrsets(&packet.authorities);
+        let mut nsecs = vec![];
+        let mut nsec3s = vec![];
+        let mut has_soa = false;
+
+        for rrset in rrsets.iter().filter(|rrset| is_within(&rrset.owner, zone)) {
+            match rrset.rtype {
+                Type::SOA if rrset.owner == *zone => has_soa = true,
+                Type::NSEC | Type::NSEC3 => {},
+                _ => continue,
+            }
+
+            for record in &rrset.records {
+                println!("  {}", write_record(record));
+            }
+            self.check_signatures(rrset, zone, keys).map_err(|reason| Break::at(name, reason))?;
+
+            for record in &rrset.records {
+                match rrset.rtype {
+                    Type::NSEC => nsecs.extend(Nsec::parse(&record.data).map(|nsec| (rrset.owner.clone(), nsec))),
+                    Type::NSEC3 => nsec3s.extend(Nsec3::parse(&record.data).map(|nsec3| (rrset.owner.clone(), nsec3))),
+                    _ => {},
+                }
+            }
+        }
+
+        let denial = if !has_soa {
+            Err(format!("the denial for {} {} has no SOA from {}", write_name(name), qtype, write_name(zone)))
+        } else if !nsecs.is_empty() {
+            nsec_denial(&nsecs, name, qtype)
+        } else if !nsec3s.is_empty() {
+            nsec3_denial(&nsec3s, zone, name, qtype)
+        } else {
+            Err(format!("the denial for {} {} has no NSEC or NSEC3 records", write_name(name), qtype))
+        };
+
+        denial.map_err(|reason| Break::at(name, reason))
+    }
+
+    /// Checks the proof that a name answered from a wildcard doesn't exist on its
+    /// own. Ok(false) when it's an NSEC3 chain we don't bother with.
+    fn check_wildcard_proof(&mut self, packet: &DecomposedPacket, owner: &String, encloser: &String, zone: &String, keys: &Vec<Dnskey>) -> Result<bool, Break> {
+        let rrsets = collect_rrsets(&packet.authorities);
+        for rrset in rrsets.iter().filter(|rrset| rrset.rtype == Type::NSEC || rrset.rtype == Type::NSEC3) {
+            for record in &rrset.records {
+                println!("  {}", write_record(record));
+            }
+            self.check_signatures(rrset, zone, keys).map_err(|reason| Break::at(owner, reason))?;
+
+            match proves_wildcard_answer(rrset, owner, encloser) {
+                Some(true) => {
+                    println!("  Proves {} doesn't exist on its own", write_name(owner));
+                    return Ok(true);
+                },
+                Some(false) => {},
+                None => {
+                    println!("  The NSEC3 chain is hashed too many times to bother with");
+                    return Ok(false);
+                },
+            }
+        }
+
+        Err(Break::at(owner, String::from("nothing proves it doesn't exist on its own, for its wildcard answer")))
+    }
+
+    /// Prints each signature on an RRset: the key that made it, its window, and
+    /// whether it checks out against the zone's keys. Any one good signature will
+    /// do. Ok has the closest encloser when the RRset came from a wildcard.
+    #[allow(clippy::ptr_arg)]
+    fn check_signatures(&self, rrset: &SignedRrset, zone: &String, keys: &Vec<Dnskey>) -> Result<Option<String>, String> {
+        if rrset.signatures.is_empty() {
+            println!("  {} {} isn't signed", write_name(&rrset.owner), rrset.rtype);
+            return Err(format!("{} {} isn't signed", write_name(&rrset.owner), rrset.rtype));
+        }
+
+        let now = unix_time() as u32;
+        let mut result = Err(format!("no good signature on {} {} from a key of {}", write_name(&rrset.owner), rrset.rtype, write_name(zone)));
+
+        for rrsig in &rrset.signatures {
+            let checked = if rrsig.signer != *zone {
+                Err(format!("it's from {}, not {}", write_name(&rrsig.signer), write_name(zone)))
+            } else if rrsig.labels as usize > label_count(&rrset.owner) {
+                Err(String::from("it counts too many labels"))
+            } else {
+                let mut checked = Err(format!("{} has no key {}", write_name(zone), rrsig.key_tag));
+                for key in keys.iter().filter(|key| key.algorithm == rrsig.algorithm && key.key_tag() == rrsig.key_tag) {
+                    checked = verify_signature(rrset, rrsig, key);
+                    if checked.is_ok() {
+                        break;
+                    }
+                }
+                checked
+            };
+
+            println!(
+                "  {} RRSIG by {} {}, {} to {} ({}): {}",
+                rrset.rtype,
+                write_name(&rrsig.signer),
+                rrsig.key_tag,
+                write_timestamp(rrsig.inception),
+                write_timestamp(rrsig.expiration),
+                describe_window(rrsig, now),
+                match &checked {
+                    Ok(()) => String::from("good"),
+                    Err(reason) => format!("bad, {}", reason),
+                },
+            );
+
+            match checked {
+                Ok(()) if result.is_err() && (rrsig.labels as usize) < label_count(&rrset.owner) => result = Ok(Some(ancestor_with_labels(&rrset.owner, rrsig.labels as usize))),
+                Ok(()) if result.is_err() => result = Ok(None),
+                Ok(()) => {},
+                Err(reason) if result.is_err() => result = Err(reason),
+                Err(_) => {},
+            }
+        }
+
+        result
+    }
+}
+
+fn wildcard_name(encloser: &String) -> String {
+    if encloser.is_empty() { String::from("*") } else { format!("*.{}", encloser) }
+}
+
+fn digest_name(digest_type: u8) -> String {
+    match digest_type {
+        SHA1_DIGEST => String::from("SHA-1"),
+        SHA256_DIGEST => String::from("SHA-256"),
+        SHA384_DIGEST => String::from("SHA-384"),
+        _ => format!("digest {}", digest_type),
+    }
+}
+
+/// Where now is in a signature's window, roughly.
+fn describe_window(rrsig: &Rrsig, now: u32) -> String {
+    let until_inception = rrsig.inception.wrapping_sub(now) as i32;
+    let until_expiration = rrsig.expiration.wrapping_sub(now) as i32;
+    if until_inception > 0 {
+        format!("starts in {}", describe_seconds(until_inception as u32))
+    } else if until_expiration < 0 {
+        format!("expired {} ago", describe_seconds(until_expiration.unsigned_abs()))
+    } else {
+        format!("{} left", describe_seconds(until_expiration as u32))
+    }
+}
+
+fn describe_seconds(seconds: u32) -> String {
+    match seconds {
+        0..=7199 => format!("{} minutes", seconds / 60),
+        7200..=172_799 => format!("{} hours", seconds / 3600),
+        _ => format!("{} days", seconds / 86400),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hero::authoritative::Zone;
+    use crate::dns_hero::testing::{ answer, hierarchy };
+
+    fn trace(zones: &[Zone], anchors: &Vec<TrustAnchor>, name: &str) -> Result<bool, Break> {
+        trace_chain(&String::from(name), Type::A, anchors, &|name: &String, qtype: Type| answer(zones, name, qtype))
+    }
+
+    #[test]
+    fn signed_all_the_way_down_is_secure() {
+        let (zones, anchors) = hierarchy();
+        assert!(matches!(trace(&zones, &anchors, "www.sec.test"), Ok(true)));
+    }
+
+    #[test]
+    fn unsigned_delegation_is_insecure() {
+        let (zones, anchors) = hierarchy();
+        assert!(matches!(trace(&zones, &anchors, "www.unsg.test"), Ok(false)));
+    }
+
+    #[test]
+    fn ds_for_another_key_breaks_at_the_child() {
+        let (zones, anchors) = hierarchy();
+        match trace(&zones, &anchors, "www.badds.test") {
+            Err(broken) => assert_eq!(broken.at, "badds.test."),
+            Ok(secure) => panic!("traced as {}", if secure { "secure" } else { "insecure" }),
+        }
+    }
+}
@@ -0,0 +1,464 @@
+use std::io;
+use std::net::{ IpAddr, TcpStream };
+
+use super::authoritative::*;
+use super::data::*;
+use super::enums::*;
+use super::tcp::*;
+use super::tsig::*;
+use super::util::*;
+
+/// Asks a server for a whole zone (RFC 5936). The records come back in the order
+/// the server sent them, starting with the SOA, and without the closing SOA. With a
+/// key, the query is signed, and so must the answer be.
+pub fn axfr(server: &String, zone: &String, key: Option<&TsigKey>) -> io::Result<Vec<Resource>> {
+    let records = transfer(server, zone, Type::AXFR, None, key)?;
+    Ok(records[..records.len() - 1].to_vec())
+}
+
+/// Brings our copy of a zone up to date (RFC 1995). `current` is the copy we have,
+/// SOA included, and what comes back is the new copy. If the server has nothing
+/// newer, that's `current` as it was. If it can't do incremental transfers, it
+/// sends the whole zone instead, and that's what we get.
+pub fn ixfr(server: &String, zone: &String, current: &Vec<Resource>, key: Option<&TsigKey>) -> io::Result<Vec<Resource>> {
+    let current_soa = current.iter().find(|record| record.rtype == Type::SOA)
+        .ok_or(invalid("Our copy of the zone has no SOA"))?;
+
+    let records = transfer(server, zone, Type::IXFR, Some(current_soa), key)?;
+
+    if records.len() == 1 {
+        return Ok(current.clone());
+    }
+
+    if is_incremental(&records) {
+        apply_ixfr(current, &records)
+    } else {
+        Ok(records[..records.len() - 1].to_vec())
+    }
+}
+
+/// The serial number in an SOA record.
+pub fn soa_serial(record: &Resource) -> Option<u32> {
+    if record.rtype != Type::SOA {
+        return None;
+    }
+
+    let mname_end = read_name(&record.data, 0)?.1;
+    let rname_end = read_name(&record.data, mname_end)?.1;
+    if rname_end + 4 > record.data.len() {
+        return None;
+    }
+
+    Some(get_u32(&record.data, rname_end))
+}
+
+/// Whether serial `a` comes after serial `b`, allowing for wraparound (RFC 1982).
+pub fn serial_is_newer(a: u32, b: u32) -> bool {
+    a != b && (a.wrapping_sub(b) as i32) > 0
+}
+
+/// Whether two records are the same, going by name, type, class, and data, but not TTL.
+pub fn same_record(a: &Resource, b: &Resource) -> bool {
+    a.label.eq_ignore_case(&b.label) && a.rtype == b.rtype && a.rclass == b.rclass && a.data == b.data
+}
+
+/// Builds the query that starts a transfer. IXFR queries carry our SOA in the
+/// authority section, so the server knows where we're at.
+#[allow(clippy::ptr_arg)]
+pub fn make_transfer_query(zone: &String, qtype: Type, current_soa: Option<&Resource>) -> Vec<u8> {
+    let mut packet = DecomposedPacket::new();
+    packet.id = random_u16();
+    packet.questions.push(Question {
+        label: Label::Domain(zone.trim_end_matches('.').to_string()),
+        qtype,
+        qclass: Class::Internet,
+    });
+
+    if let Some(soa) = current_soa {
+        packet.authorities.push(soa.clone());
+    }
+
+    packet.to_raw().data
+}
+
+/// Sends a transfer query and collects every record of the answer, which may run
+/// across many messages. The answer has to be bracketed by the new SOA at both
+/// ends, and what's returned includes both of them.
+fn transfer(server: &String, zone: &String, qtype: Type, current_soa: Option<&Resource>, key: Option<&TsigKey>) -> io::Result<Vec<Resource>> {
+    let mut query = make_transfer_query(zone, qtype, current_soa);
+    let id = get_u16(&query, 0);
+    let mut session = key.map(TsigSession::new);
+    if let Some(session) = &mut session {
+        query = session.sign(&query);
+    }
+
+    let mut stream = connect_tcp(server)?;
+    send_tcp_message(&mut stream, &query)?;
+    read_transfer(&mut stream, id, qtype == Type::IXFR, session.as_mut())
+}
+
+/// Reads the messages of a transfer until the closing SOA. When the query was
+/// signed, the session checks the signatures as they come in, and the last
+/// message has to be one of the signed ones.
+pub fn read_transfer(stream: &mut TcpStream, id: u16, is_ixfr: bool, mut session: Option<&mut TsigSession>) -> io::Result<Vec<Resource>> {
+    let mut records = Vec::<Resource>::new();
+
+    loop {
+        let mut message = read_tcp_message(stream)?;
+        if let Some(session) = session.as_mut() {
+            message = session.verify(&message).map_err(|err| io::Error::new(io::ErrorKind::Other, err.describe()))?;
+        }
+
+        let is_signed = session.as_ref().map(|session| session.is_caught_up()).unwrap_or(true);
+        let answers = check_transfer_message(&message, id)?;
+        let answer_count = answers.len();
+        records.extend(answers);
+
+        let new_serial = match records.first() {
+            Some(first) => soa_serial(first).ok_or(invalid("Transfer didn't start with an SOA"))?,
+            None => continue,
+        };
+
+        // A lone SOA, all by itself in the first message, means we're up to date.
+        if is_ixfr && records.len() == 1 && answer_count == 1 && is_signed {
+            return Ok(records);
+        }
+
+        if records.len() < 2 {
+            continue;
+        }
+
+        // The new SOA comes up once more in an incremental transfer, at the start of
+        // the last diff's additions.
+        let closing_count = if is_ixfr && is_incremental(&records) { 3 } else { 2 };
+        let closing_positions: Vec<usize> = records.iter().enumerate()
+            .filter(|(_, record)| soa_serial(record) == Some(new_serial))
+            .map(|(index, _)| index)
+            .collect();
+
+        if closing_positions.len() >= closing_count {
+            if closing_positions[closing_count - 1] != records.len() - 1 {
+                return Err(invalid("Records after the closing SOA"));
+            }
+            if !is_signed {
+                return Err(invalid("The last message of the transfer wasn't signed"));
+            }
+            return Ok(records);
+        }
+    }
+}
+
+/// How big the messages of a transfer we're sending are allowed to get. TCP
+/// allows 64K, but smaller messages are kinder to the other end.
+const MAX_TRANSFER_MESSAGE_SIZE: usize = 16384;
+
+/// Answers an AXFR or IXFR query for one of our zones over a TCP connection, if
+/// the client's allowed to have it. IXFR gets the diffs since the client's serial
+/// when the journal has them, a lone SOA when the client's already up to date,
+/// and the whole zone otherwise. If the query was signed, so is every message of
+/// the answer, and the key counts towards the transfer list.
+pub fn serve_transfer(stream: &mut TcpStream, query: &DecomposedPacket, zones: &Zones, client: IpAddr, mut session: Option<&mut TsigSession>) -> io::Result<()> {
+    let key = session.as_ref().map(|session| session.key.name.clone());
+    let mut send = |stream: &mut TcpStream, message: Vec<u8>| match session.as_mut() {
+        Some(session) => send_tcp_message(stream, &session.sign(&message)),
+        None => send_tcp_message(stream, &message),
+    };
+
+    let question = &query.questions[0];
+    let hosted = match &question.label {
+        Label::Domain(name) => zones.get(name),
+        Label::Pointer(_) => None,
+    };
+
+    let hosted = match hosted {
+        Some(hosted) => hosted,
+        None => {
+            println!("Refusing transfer of {} to {}, it isn't one of our zones", question.label, client);
+            return send(stream, refuse_transfer(query, ResponseCode::NotAuth));
+        },
+    };
+
+    if hosted.expired {
+        println!("Refusing transfer of {} to {}, our copy has expired", hosted.zone.origin, client);
+        return send(stream, refuse_transfer(query, ResponseCode::ServFail));
+    }
+
+    if !hosted.allow_transfer.allows(client, key.as_ref()) {
+        println!("Refusing transfer of {} to {}, it isn't on the zone's transfer list", hosted.zone.origin, client);
+        return send(stream, refuse_transfer(query, ResponseCode::Refused));
+    }
+
+    let records = match question.qtype {
+        Type::IXFR => ixfr_records(&hosted, query),
+        _ => axfr_records(&hosted),
+    };
+
+    println!("Sending {} ({} records) to {}", hosted.zone.origin, records.len(), client);
+    for message in transfer_messages(query, &records) {
+        send(stream, message)?;
+    }
+
+    Ok(())
+}
+
+/// The whole zone, bracketed by its SOA.
+fn axfr_records(hosted: &HostedZone) -> Vec<Resource> {
+    let mut records = hosted.zone.records();
+    records.push(hosted.zone.soa().clone());
+    records
+}
+
+fn ixfr_records(hosted: &HostedZone, query: &DecomposedPacket) -> Vec<Resource> {
+    let client_serial = match query.authorities.iter().find_map(soa_serial) {
+        Some(serial) => serial,
+        None => return axfr_records(hosted),
+    };
+
+    let soa = hosted.zone.soa().clone();
+    if !serial_is_newer(hosted.zone.serial(), client_serial) {
+        return vec![soa];
+    }
+
+    match hosted.journal.since(client_serial) {
+        Some(diffs) => {
+            let mut records = vec![soa.clone()];
+            records.extend(diffs.iter().flat_map(|diff| diff.to_records()));
+            records.push(soa);
+            records
+        },
+        None => axfr_records(hosted),
+    }
+}
+
+/// Splits a transfer's records into as many messages as it takes. The question
+/// only goes in the first one.
+fn transfer_messages(query: &DecomposedPacket, records: &Vec<Resource>) -> Vec<Vec<u8>> {
+    let mut messages = vec![];
+    let mut packet = transfer_response(query);
+    let mut size = packet.to_raw().data.len();
+
+    for record in records {
+        let mut raw_record = vec![];
+        record.to_raw(&mut raw_record);
+
+        if !packet.answers.is_empty() && size + raw_record.len() > MAX_TRANSFER_MESSAGE_SIZE {
+            messages.push(packet.to_raw().data);
+            packet = transfer_response(query);
+            packet.questions.clear();
+            size = packet.to_raw().data.len();
+        }
+
+        packet.answers.push(record.clone());
+        size += raw_record.len();
+    }
+
+    messages.push(packet.to_raw().data);
+    messages
+}
+
+fn transfer_response(query: &DecomposedPacket) -> DecomposedPacket {
+    let mut packet = DecomposedPacket::new();
+    packet.id = query.id;
+    packet.is_response = true;
+    packet.opcode = query.opcode;
+    packet.is_authoritative = true;
+    packet.questions = query.questions.clone();
+    packet
+}
+
+fn refuse_transfer(query: &DecomposedPacket, response_code: ResponseCode) -> Vec<u8> {
+    let mut packet = transfer_response(query);
+    packet.is_authoritative = false;
+    packet.response_code = response_code;
+    packet.to_raw().data
+}
+
+/// Makes sure a message is part of our transfer, and gets its records.
+fn check_transfer_message(message: &Vec<u8>, id: u16) -> io::Result<Vec<Resource>> {
+    let packet = Packet::from_vec(message);
+    if packet.locate_records().is_none() {
+        return Err(invalid("Malformed transfer message"));
+    }
+
+    if packet.get_id() != id || !packet.is_response() {
+        return Err(invalid("Transfer message doesn't answer our query"));
+    }
+
+    match packet.get_response_code() {
+        ResponseCode::NoError => Ok(DecomposedPacket::from_packet(&packet).answers),
+        code => Err(io::Error::new(io::ErrorKind::Other, format!("Server refused the transfer: {}", code))),
+    }
+}
+
+/// An IXFR answer is incremental, rather than the whole zone, when the new SOA is
+/// followed straight away by an older one.
+#[allow(clippy::ptr_arg)]
+fn is_incremental(records: &Vec<Resource>) -> bool {
+    match (records.first().and_then(soa_serial), records.get(1).and_then(soa_serial)) {
+        (Some(new_serial), Some(second_serial)) => new_serial != second_serial,
+        _ => false,
+    }
+}
+
+/// Applies the diffs of an incremental transfer to our copy of the zone. Each diff
+/// is the old SOA and the records deleted since, then the new SOA and the records
+/// added since.
+#[allow(clippy::ptr_arg)]
+fn apply_ixfr(current: &Vec<Resource>, records: &Vec<Resource>) -> io::Result<Vec<Resource>> {
+    let mut zone = current.clone();
+    let mut serial = zone.iter().find_map(soa_serial).ok_or(invalid("Our copy of the zone has no SOA"))?;
+    let final_serial = soa_serial(&records[0]).unwrap();
+
+    let diffs = &records[1..records.len() - 1];
+    let mut index = 0;
+
+    while index < diffs.len() {
+        let from_serial = soa_serial(&diffs[index]).ok_or(invalid("IXFR diff doesn't start with an SOA"))?;
+        if from_serial != serial {
+            return Err(invalid(&format!("IXFR diff starts from serial {}, but we're at {}", from_serial, serial)));
+        }
+
+        zone.retain(|record| record.rtype != Type::SOA);
+        index += 1;
+
+        while index < diffs.len() && diffs[index].rtype != Type::SOA {
+            let deleted = &diffs[index];
+            zone.retain(|record| !same_record(record, deleted));
+            index += 1;
+        }
+
+        let new_soa = diffs.get(index).ok_or(invalid("IXFR diff is missing its new SOA"))?;
+        serial = soa_serial(new_soa).ok_or(invalid("IXFR diff has a malformed SOA"))?;
+        zone.insert(0, new_soa.clone());
+        index += 1;
+
+        while index < diffs.len() && diffs[index].rtype != Type::SOA {
+            zone.push(diffs[index].clone());
+            index += 1;
+        }
+    }
+
+    if serial != final_serial {
+        return Err(invalid(&format!("IXFR diffs end at serial {}, not {}", serial, final_serial)));
+    }
+
+    Ok(zone)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+    use crate::dns_hero::zone::{ parse_zone, write_record };
+
+    fn records(text: &str) -> Vec<Resource> {
+        parse_zone(&format!("$TTL 300\n{}", text), &String::from("example.test")).unwrap()
+    }
+
+    fn soa(serial: u32) -> Resource {
+        records(&format!("@ SOA ns hostmaster {} 3600 600 86400 300\n", serial)).remove(0)
+    }
+
+    fn lines(records: &[Resource]) -> Vec<String> {
+        records.iter().map(write_record).collect()
+    }
+
+    /// A server that answers one transfer query with these messages, one per list
+    /// of records, and then hangs up. Hands back its address.
+    fn serve(messages: Vec<Vec<Resource>>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let query = DecomposedPacket::from_packet(&Packet::from_vec(&read_tcp_message(&mut stream).unwrap()));
+            for answers in messages {
+                let mut packet = transfer_response(&query);
+                packet.answers = answers;
+                send_tcp_message(&mut stream, &packet.to_raw().data).unwrap();
+            }
+        });
+
+        address
+    }
+
+    fn zone() -> String {
+        String::from("example.test")
+    }
+
+    #[test]
+    fn axfr_runs_across_messages_until_the_closing_soa() {
+        let body = records("@ NS ns\nns A 10.0.0.1\nwww A 10.0.0.2\n");
+        let server = serve(vec![
+            vec![soa(2), body[0].clone()],
+            vec![body[1].clone(), body[2].clone()],
+            vec![soa(2)],
+        ]);
+
+        let transferred = axfr(&server, &zone(), None).unwrap();
+        let mut expected = vec![soa(2)];
+        expected.extend(body);
+        assert_eq!(lines(&transferred), lines(&expected));
+    }
+
+    #[test]
+    fn ixfr_applies_deletions_and_additions() {
+        let mut current = vec![soa(1)];
+        current.extend(records("@ NS ns\nns A 10.0.0.1\nwww A 10.0.0.2\n"));
+        let old_www = records("www A 10.0.0.2\n").remove(0);
+        let new_www = records("www A 10.0.0.3\n").remove(0);
+        let server = serve(vec![
+            vec![soa(2), soa(1), old_www],
+            vec![soa(2), new_www, soa(2)],
+        ]);
+
+        let updated = ixfr(&server, &zone(), &current, None).unwrap();
+        let mut expected = vec![soa(2)];
+        expected.extend(records("@ NS ns\nns A 10.0.0.1\nwww A 10.0.0.3\n"));
+        assert_eq!(lines(&updated), lines(&expected));
+    }
+
+    #[test]
+    fn ixfr_lone_soa_means_already_current() {
+        let mut current = vec![soa(1)];
+        current.extend(records("@ NS ns\n"));
+        let server = serve(vec![vec![soa(1)]]);
+
+        let updated = ixfr(&server, &zone(), &current, None).unwrap();
+        assert_eq!(lines(&updated), lines(&current));
+    }
+
+    #[test]
+    fn ixfr_takes_a_whole_zone_instead() {
+        let mut current = vec![soa(1)];
+        current.extend(records("@ NS ns\nwww A 10.0.0.2\n"));
+        let mut whole = vec![soa(3)];
+        whole.extend(records("@ NS ns\nwww A 10.0.0.4\n"));
+        let server = serve(vec![[whole.clone(), vec![soa(3)]].concat()]);
+
+        let updated = ixfr(&server, &zone(), &current, None).unwrap();
+        assert_eq!(lines(&updated), lines(&whole));
+    }
+
+    #[test]
+    fn transfer_cut_short_is_an_error() {
+        let server = serve(vec![vec![soa(2)], records("@ NS ns\nns A 10.0.0.1\n")]);
+        assert!(axfr(&server, &zone(), None).is_err());
+    }
+
+    #[test]
+    fn ixfr_with_a_malformed_soa_is_an_error() {
+        let mut current = vec![soa(1)];
+        current.extend(records("@ NS ns\n"));
+        let mut broken = soa(2);
+        broken.data.truncate(4);
+        let received = vec![soa(2), soa(1), broken, soa(2)];
+
+        assert!(apply_ixfr(&current, &received).is_err());
+    }
+}
@@ -0,0 +1,534 @@
+use base64::{ decode, encode };
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::fs;
+
+use super::data::*;
+use super::enums::*;
+use super::util::*;
+
+/// How far apart our clock and the other side's may be, in seconds.
+pub const DEFAULT_FUDGE: u16 = 300;
+
+/// TSIG's own error codes (RFC 8945 section 3), which go in the TSIG record. The
+/// response code in the header is just NOTAUTH.
+pub const TSIG_BADSIG: u16 = 16;
+pub const TSIG_BADKEY: u16 = 17;
+pub const TSIG_BADTIME: u16 = 18;
+pub const TSIG_BADTRUNC: u16 = 22;
+
+/// How many messages of a long answer may go by unsigned before we give up on it
+/// (RFC 8945 section 5.3.1).
+const MAX_UNSIGNED_MESSAGES: usize = 99;
+
+#[derive(Copy, Clone, PartialEq)]
+pub enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    pub fn from_name(name: &str) -> Option<TsigAlgorithm> {
+        match name.trim_end_matches('.').to_lowercase().as_str() {
+            "hmac-sha256" => Some(TsigAlgorithm::HmacSha256),
+            "hmac-sha512" => Some(TsigAlgorithm::HmacSha512),
+            _ => None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            TsigAlgorithm::HmacSha256 => "hmac-sha256",
+            TsigAlgorithm::HmacSha512 => "hmac-sha512",
+        }
+    }
+
+    fn digest(self) -> MessageDigest {
+        match self {
+            TsigAlgorithm::HmacSha256 => MessageDigest::sha256(),
+            TsigAlgorithm::HmacSha512 => MessageDigest::sha512(),
+        }
+    }
+
+    fn mac_length(self) -> usize {
+        self.digest().size()
+    }
+}
+
+/// A shared secret for signing messages, known by its name on both ends.
+#[derive(Clone)]
+pub struct TsigKey {
+    /// Lowercase, without a trailing dot.
+    pub      name: String,
+    pub algorithm: TsigAlgorithm,
+    pub    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /// Makes up a new key with a random secret as long as the algorithm's MAC.
+    #[allow(clippy::ptr_arg)]
+    pub fn generate(name: &String, algorithm: TsigAlgorithm) -> TsigKey {
+        TsigKey {
+            name: name.trim_end_matches('.').to_lowercase(),
+            algorithm,
+            secret: random_bytes(algorithm.mac_length()),
+        }
+    }
+
+    /// The key in the key file format BIND uses, which is what we read too.
+    pub fn to_key_file(&self) -> String {
+        format!("key \"{}\" {{\n\talgorithm {};\n\tsecret \"{}\";\n}};\n", self.name, self.algorithm.name(), encode(&self.secret))
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        let key = PKey::hmac(&self.secret).expect("Could not make HMAC key");
+        let mut signer = Signer::new(self.algorithm.digest(), &key).expect("Could not make HMAC signer");
+        signer.update(data).expect("Could not compute HMAC");
+        signer.sign_to_vec().expect("Could not compute HMAC")
+    }
+}
+
+/// Reads the keys in a key file, which look like this:
+///
+/// ```text
+/// key "transfer-key" {
+///     algorithm hmac-sha256;
+///     secret "b0nJWMvzTa2GUfPn1EqMW6mqJ8BJu9pmXhQ3lqxsFyQ=";
+/// };
+/// ```
+pub fn load_key_file(path: &String) -> Result<Vec<TsigKey>, String> {
+    let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+    parse_keys(&text).map_err(|err| format!("{}: {}", path, err))
+}
+
+pub fn parse_keys(text: &str) -> Result<Vec<TsigKey>, String> {
+    let mut tokens = key_file_tokens(text).into_iter();
+    let mut keys = vec![];
+
+    while let Some(token) = tokens.next() {
+        if token != "key" {
+            return Err(format!("Expected a key, found {}", token));
+        }
+
+        let name = tokens.next().ok_or("Key without a name")?;
+        if tokens.next().as_deref() != Some("{") {
+            return Err(format!("Expected {{ after key {}", name));
+        }
+
+        let mut algorithm = None;
+        let mut secret = None;
+        loop {
+            match tokens.next().as_deref() {
+                Some("algorithm") => {
+                    let value = tokens.next().unwrap_or_default();
+                    algorithm = Some(TsigAlgorithm::from_name(&value).ok_or(format!("Unsupported algorithm {} for key {}", value, name))?);
+                },
+                Some("secret") => {
+                    let value = tokens.next().unwrap_or_default();
+                    secret = Some(decode(&value).map_err(|_| format!("Bad secret for key {}", name))?);
+                },
+                Some("}") => break,
+                Some(other) => return Err(format!("Unexpected {} in key {}", other, name)),
+                None => return Err(format!("Key {} doesn't end", name)),
+            }
+
+            if tokens.next().as_deref() != Some(";") {
+                return Err(format!("Missing ; in key {}", name));
+            }
+        }
+
+        if tokens.next().as_deref() != Some(";") {
+            return Err(format!("Missing ; after key {}", name));
+        }
+
+        keys.push(TsigKey {
+            name: name.trim_end_matches('.').to_lowercase(),
+            algorithm: algorithm.ok_or(format!("Key {} has no algorithm", name))?,
+            secret: secret.ok_or(format!("Key {} has no secret", name))?,
+        });
+    }
+
+    Ok(keys)
+}
+
+/// Splits a key file into words, quoted strings, and punctuation, dropping comments.
+fn key_file_tokens(text: &str) -> Vec<String> {
+    let mut tokens = vec![];
+    let mut current = String::new();
+    let mut characters = text.chars().peekable();
+
+    while let Some(character) = characters.next() {
+        match character {
+            '"' => {
+                current.extend(characters.by_ref().take_while(|next| *next != '"'));
+                tokens.push(std::mem::take(&mut current));
+            },
+            '#' => {
+                characters.by_ref().take_while(|next| *next != '\n').for_each(drop);
+            },
+            '/' if characters.peek() == Some(&'/') => {
+                characters.by_ref().take_while(|next| *next != '\n').for_each(drop);
+            },
+            '{' | '}' | ';' => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+                tokens.push(character.to_string());
+            },
+            _ if character.is_whitespace() => {
+                if !current.is_empty() {
+                    tokens.push(std::mem::take(&mut current));
+                }
+            },
+            _ => current.push(character),
+        }
+    }
+
+    if !current.is_empty() {
+        tokens.push(current);
+    }
+
+    tokens
+}
+
+/// Why a signed message didn't check out.
+pub enum TsigError {
+    /// One of the TSIG error codes, for us to send back.
+    Code(u16),
+    /// The other side sent back a TSIG error code.
+    Remote(u16),
+    /// The message should have been signed and wasn't, or the signature was mangled.
+    Malformed(&'static str),
+}
+
+impl TsigError {
+    pub fn describe(&self) -> String {
+        match self {
+            TsigError::Code(code) => format!("TSIG {}", tsig_error_name(*code)),
+            TsigError::Remote(code) => format!("the other side says TSIG {}", tsig_error_name(*code)),
+            TsigError::Malformed(reason) => String::from(*reason),
+        }
+    }
+}
+
+fn tsig_error_name(code: u16) -> String {
+    match code {
+        TSIG_BADSIG => String::from("BADSIG"),
+        TSIG_BADKEY => String::from("BADKEY"),
+        TSIG_BADTIME => String::from("BADTIME"),
+        TSIG_BADTRUNC => String::from("BADTRUNC"),
+        other => format!("error {}", other),
+    }
+}
+
+/// The fields of a TSIG record (RFC 8945 section 4.2).
+#[derive(Clone)]
+struct TsigRecord {
+    key_name: String,
+    algorithm: String,
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    original_id: u16,
+    error: u16,
+    other: Vec<u8>,
+}
+
+impl TsigRecord {
+    fn new(key: &TsigKey, original_id: u16, error: u16) -> TsigRecord {
+        TsigRecord {
+            key_name: key.name.clone(),
+            algorithm: String::from(key.algorithm.name()),
+            time_signed: unix_time(),
+            fudge: DEFAULT_FUDGE,
+            mac: vec![],
+            original_id,
+            error,
+            other: vec![],
+        }
+    }
+
+    fn parse(message: &Vec<u8>, location: &RecordLocation) -> Option<TsigRecord> {
+        let (key_name, _) = read_name(message, location.start)?;
+        let rdata = message.get(location.rdata_offset..location.rdata_offset + location.rdata_length)?.to_vec();
+        let (algorithm, mut index) = read_name(&rdata, 0)?;
+
+        let fixed = rdata.get(index..index + 10)?;
+        let time_signed = fixed[..6].iter().fold(0u64, |time, byte| (time << 8) | *byte as u64);
+        let fudge = get_u16(&rdata, index + 6);
+        let mac_length = get_u16(&rdata, index + 8) as usize;
+        index += 10;
+
+        let mac = rdata.get(index..index + mac_length)?.to_vec();
+        index += mac_length;
+
+        rdata.get(index..index + 6)?;
+        let original_id = get_u16(&rdata, index);
+        let error = get_u16(&rdata, index + 2);
+        let other_length = get_u16(&rdata, index + 4) as usize;
+        let other = rdata.get(index + 6..index + 6 + other_length)?.to_vec();
+
+        Some(TsigRecord {
+            key_name: key_name.to_lowercase(),
+            algorithm: algorithm.to_lowercase(),
+            time_signed,
+            fudge,
+            mac,
+            original_id,
+            error,
+            other,
+        })
+    }
+
+    /// The whole record, ready to go on the end of a message.
+    fn to_raw(&self) -> Vec<u8> {
+        let mut rdata = str_domain_to_dns_domain(&self.algorithm);
+        rdata.extend_from_slice(&self.time_signed.to_be_bytes()[2..]);
+        rdata.extend_from_slice(&self.fudge.to_be_bytes());
+        rdata.extend_from_slice(&(self.mac.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.mac);
+        rdata.extend_from_slice(&self.original_id.to_be_bytes());
+        rdata.extend_from_slice(&self.error.to_be_bytes());
+        rdata.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        rdata.extend_from_slice(&self.other);
+
+        let mut output = vec![];
+        Resource {
+            label: Label::Domain(self.key_name.clone()),
+            rtype: Type::TSIG,
+            rclass: Class::QclassAny,
+            ttl: 0,
+            length: rdata.len() as u16,
+            data: rdata,
+        }.to_raw(&mut output);
+        output
+    }
+
+    /// What the MAC covers of the record itself, on the first message of an exchange.
+    fn variables(&self) -> Vec<u8> {
+        let mut variables = str_domain_to_dns_domain(&self.key_name);
+        variables.extend_from_slice(&Class::QclassAny.to_u16().to_be_bytes());
+        variables.extend_from_slice(&0u32.to_be_bytes());
+        variables.extend_from_slice(&str_domain_to_dns_domain(&self.algorithm));
+        variables.extend_from_slice(&self.timers());
+        variables.extend_from_slice(&self.error.to_be_bytes());
+        variables.extend_from_slice(&(self.other.len() as u16).to_be_bytes());
+        variables.extend_from_slice(&self.other);
+        variables
+    }
+
+    /// What the MAC covers of the record on later messages of a long answer.
+    fn timers(&self) -> Vec<u8> {
+        let mut timers = self.time_signed.to_be_bytes()[2..].to_vec();
+        timers.extend_from_slice(&self.fudge.to_be_bytes());
+        timers
+    }
+}
+
+/// One signed exchange: a request and the response to it, which may run across
+/// many messages. Each signature covers the one before it, so the session keeps
+/// track of the last MAC, and of any messages that went by unsigned since.
+pub struct TsigSession {
+    pub key: TsigKey,
+    prior_mac: Option<Vec<u8>>,
+    /// Whether the first response has been signed or checked. After that, only
+    /// the timers of each TSIG record are covered.
+    answered: bool,
+    unsigned: Vec<u8>,
+    unsigned_count: usize,
+}
+
+impl TsigSession {
+    pub fn new(key: &TsigKey) -> TsigSession {
+        TsigSession {
+            key: key.clone(),
+            prior_mac: None,
+            answered: false,
+            unsigned: vec![],
+            unsigned_count: 0,
+        }
+    }
+
+    /// Signs a request, or the next message of a response.
+    pub fn sign(&mut self, message: &Vec<u8>) -> Vec<u8> {
+        let mut record = TsigRecord::new(&self.key, get_u16(message, 0), 0);
+        record.mac = self.key.mac(&self.digest_input(message, &record));
+        self.advance(&record.mac);
+        append_record(message, &record)
+    }
+
+    /// Checks the signature on the next message of a response. What comes back is
+    /// the message without its TSIG record. Messages in the middle of a long
+    /// answer may be unsigned, as long as one that's signed comes along soon.
+    pub fn verify(&mut self, message: &Vec<u8>) -> Result<Vec<u8>, TsigError> {
+        let (stripped, record) = match split_tsig(message)? {
+            Some(split) => split,
+            None if self.answered && self.unsigned_count < MAX_UNSIGNED_MESSAGES => {
+                self.unsigned.extend_from_slice(message);
+                self.unsigned_count += 1;
+                return Ok(message.clone());
+            },
+            None => return Err(TsigError::Malformed("Expected a signed message")),
+        };
+
+        if record.error != 0 {
+            return Err(TsigError::Remote(record.error));
+        }
+
+        self.check(&stripped, &record)?;
+        self.advance(&record.mac);
+        Ok(stripped)
+    }
+
+    /// Whether the last message went by signed, so nothing's been left unchecked.
+    pub fn is_caught_up(&self) -> bool {
+        self.unsigned_count == 0
+    }
+
+    fn check(&self, stripped: &Vec<u8>, record: &TsigRecord) -> Result<(), TsigError> {
+        if record.key_name != self.key.name || TsigAlgorithm::from_name(&record.algorithm) != Some(self.key.algorithm) {
+            return Err(TsigError::Code(TSIG_BADKEY));
+        }
+
+        // A MAC may be cut short, but not to less than half of it, or 10 bytes
+        // (RFC 8945 section 5.2.2.1).
+        let expected = self.key.mac(&self.digest_input(stripped, record));
+        if record.mac.len() > expected.len() || record.mac.len() < (expected.len() / 2).max(10) {
+            return Err(TsigError::Code(TSIG_BADTRUNC));
+        }
+
+        if !memcmp::eq(&expected[..record.mac.len()], &record.mac) {
+            return Err(TsigError::Code(TSIG_BADSIG));
+        }
+
+        if unix_time().abs_diff(record.time_signed) > record.fudge as u64 {
+            return Err(TsigError::Code(TSIG_BADTIME));
+        }
+
+        Ok(())
+    }
+
+    /// Everything a MAC covers: the MAC it follows, any unsigned messages since,
+    /// the message, and then some of the TSIG record.
+    #[allow(clippy::ptr_arg)]
+    fn digest_input(&self, stripped: &Vec<u8>, record: &TsigRecord) -> Vec<u8> {
+        let mut input = vec![];
+        if let Some(prior_mac) = &self.prior_mac {
+            input.extend_from_slice(&(prior_mac.len() as u16).to_be_bytes());
+            input.extend_from_slice(prior_mac);
+        }
+
+        input.extend_from_slice(&self.unsigned);
+        input.extend_from_slice(stripped);
+        input.extend(if self.answered { record.timers() } else { record.variables() });
+        input
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn advance(&mut self, mac: &Vec<u8>) {
+        if self.prior_mac.is_some() {
+            self.answered = true;
+        }
+        self.prior_mac = Some(mac.clone());
+        self.unsigned.clear();
+        self.unsigned_count = 0;
+    }
+}
+
+/// Checks the signature on a request that came in, if it has one. Ok(None) means
+/// it wasn't signed. When it's signed properly, the session that comes back signs
+/// the response, and the request is handed back without its TSIG record. When it
+/// isn't, the error is the response to send.
+pub fn verify_request(message: &Vec<u8>, keys: &[TsigKey]) -> Result<Option<(TsigSession, Vec<u8>)>, Vec<u8>> {
+    let (stripped, record) = match split_tsig(message) {
+        Ok(Some(split)) => split,
+        Ok(None) => return Ok(None),
+        Err(_) => return Err(error_response(message, ResponseCode::FormErr, None)),
+    };
+
+    let key = keys.iter().find(|key| key.name == record.key_name && TsigAlgorithm::from_name(&record.algorithm) == Some(key.algorithm));
+    let mut session = match key {
+        Some(key) => TsigSession::new(key),
+        None => {
+            let unsigned_record = TsigRecord { mac: vec![], error: TSIG_BADKEY, ..record };
+            return Err(error_response(&stripped, ResponseCode::NotAuth, Some(unsigned_record.to_raw())));
+        },
+    };
+
+    match session.check(&stripped, &record) {
+        Ok(()) => {
+            session.advance(&record.mac);
+            Ok(Some((session, stripped)))
+        },
+        Err(TsigError::Code(TSIG_BADTIME)) => {
+            // The one error that gets signed, with our clock in it so the client can
+            // see how far off it is.
+            session.advance(&record.mac);
+            let mut response = error_packet(&stripped, ResponseCode::NotAuth);
+            let mut badtime = TsigRecord::new(&session.key, record.original_id, TSIG_BADTIME);
+            badtime.other = unix_time().to_be_bytes()[2..].to_vec();
+            badtime.mac = session.key.mac(&session.digest_input(&response, &badtime));
+            response = append_record(&response, &badtime);
+            Err(response)
+        },
+        Err(TsigError::Code(code)) => {
+            let unsigned_record = TsigRecord { mac: vec![], error: code, ..record };
+            Err(error_response(&stripped, ResponseCode::NotAuth, Some(unsigned_record.to_raw())))
+        },
+        Err(_) => Err(error_response(&stripped, ResponseCode::FormErr, None)),
+    }
+}
+
+/// Takes the TSIG record off the end of a message, if there is one, and puts the
+/// header back the way it was before signing.
+fn split_tsig(message: &Vec<u8>) -> Result<Option<(Vec<u8>, TsigRecord)>, TsigError> {
+    let packet = Packet::from_vec(message);
+    let locations = packet.locate_records().ok_or(TsigError::Malformed("Malformed message"))?;
+
+    let last = match locations.last() {
+        Some(last) if last.rtype == Type::TSIG.to_u16() => last,
+        _ => return Ok(None),
+    };
+
+    if last.section != Section::Additional {
+        return Err(TsigError::Malformed("TSIG record outside the additional section"));
+    }
+
+    let record = TsigRecord::parse(message, last).ok_or(TsigError::Malformed("Malformed TSIG record"))?;
+    let mut stripped = message[..last.start].to_vec();
+    set_u16(&mut stripped, record.original_id, 0);
+    set_u16(&mut stripped, packet.get_additional_record_count() - 1, 10);
+    Ok(Some((stripped, record)))
+}
+
+#[allow(clippy::ptr_arg)]
+fn append_record(message: &Vec<u8>, record: &TsigRecord) -> Vec<u8> {
+    let mut signed = message.clone();
+    signed.extend(record.to_raw());
+    let additional_count = get_u16(&signed, 10);
+    set_u16(&mut signed, additional_count + 1, 10);
+    signed
+}
+
+/// The bare response to a request, with just the question.
+pub fn error_packet(request: &Vec<u8>, response_code: ResponseCode) -> Vec<u8> {
+    let request = DecomposedPacket::from_packet(&Packet::from_vec(request));
+    let mut response = DecomposedPacket::new();
+    response.id = request.id;
+    response.is_response = true;
+    response.opcode = request.opcode;
+    response.questions = request.questions;
+    response.response_code = response_code;
+    response.to_raw().data
+}
+
+fn error_response(request: &Vec<u8>, response_code: ResponseCode, tsig: Option<Vec<u8>>) -> Vec<u8> {
+    let mut response = error_packet(request, response_code);
+    if let Some(tsig) = tsig {
+        response.extend(tsig);
+        set_u16(&mut response, 1, 10);
+    }
+    response
+}
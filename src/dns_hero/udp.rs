@@ -1,5 +1,13 @@
-use std::fmt::Write;
+use std::collections::HashMap;
+use std::io;
 use std::net::UdpSocket;
+use std::sync::Mutex;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::time::{ Duration, Instant };
+
+use super::data::Packet;
+use super::tcp::parse_server_address;
+use super::util::*;
 
 pub const CLEAN_BROWSING_SECURITY_DNS_IP: &str = "185.228.168.9";
 pub const CLEAN_BROWSING_ADULT_DNS_IP:    &str = "185.228.168.10";
@@ -7,16 +15,275 @@ pub const CLEAN_BROWSING_FAMILY_DNS_IP:   &str = "185.228.168.168";
 pub const CLOUDFLARE_DNS_IP:              &str = "1.1.1.1";
 pub const GOOGLE_DNS_IP:                  &str = "8.8.8.8";
 
-pub fn exchange_udp(output_packet: &Vec<u8>, ip_address: &String) -> Vec<u8> {
-    let socket = UdpSocket::bind("0.0.0.0:0").expect("Could not open UDP socket");
+/// How long to wait on a UDP upstream before giving up on it.
+pub const UDP_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How often a wait on an upstream stops to see if the answer is still wanted.
+const CANCEL_CHECK_INTERVAL: Duration = Duration::from_millis(50);
+
+/// How many random source ports to try before letting the OS pick one.
+const PORT_ATTEMPTS: usize = 10;
+
+/// How many times an upstream can mangle the case of a question before we stop
+/// randomizing it for that upstream.
+const CASE_STRIKE_LIMIT: u32 = 3;
+
+/// Remembers which upstreams don't echo the question's case back exactly, so DNS
+/// 0x20 can be turned off for them.
+pub struct CaseTracker {
+    strikes: HashMap<String, u32>,
+}
+
+impl Default for CaseTracker {
+    fn default() -> CaseTracker {
+        CaseTracker::new()
+    }
+}
+
+impl CaseTracker {
+    pub fn new() -> CaseTracker {
+        CaseTracker {
+            strikes: HashMap::new(),
+        }
+    }
+
+    fn should_randomize(&self, ip_address: &String) -> bool {
+        self.strikes.get(ip_address).map(|strikes| *strikes < CASE_STRIKE_LIMIT).unwrap_or(true)
+    }
+
+    fn record(&mut self, ip_address: &String, preserved_case: bool) {
+        let strikes = self.strikes.entry(ip_address.clone()).or_insert(0);
+        if preserved_case {
+            *strikes = 0;
+        } else {
+            *strikes += 1;
+            if *strikes == CASE_STRIKE_LIMIT {
+                println!("{} doesn't preserve question case. Turning off 0x20 for it.", ip_address);
+            }
+        }
+    }
+}
+
+/// The outcome of one round trip to an upstream.
+enum Exchange {
+    Answered(Vec<u8>),
+    /// Nothing answered in time but replies that got the case of the question wrong.
+    CaseMismatch,
+}
+
+/// Why a datagram isn't the response to our query.
+enum Mismatch {
+    Invalid(&'static str),
+    CaseOnly,
+}
+
+/// Sends a query and waits for the matching response.
+///
+/// Every exchange goes out with a fresh random ID from a random source port. Any
+/// datagram that comes back with the wrong ID, without the QR bit, or asking a
+/// different question is discarded, and we keep listening until the timeout. The
+/// response is handed back with the caller's original ID.
+pub fn exchange_udp(output_packet: &Vec<u8>, ip_address: &String) -> io::Result<Vec<u8>> {
+    exchange_udp_until(output_packet, ip_address, &AtomicBool::new(false))
+}
+
+/// Like `exchange_udp`, but gives up with an `Interrupted` error as soon as
+/// `cancelled` is set, like when another upstream has already answered.
+pub fn exchange_udp_until(output_packet: &Vec<u8>, ip_address: &String, cancelled: &AtomicBool) -> io::Result<Vec<u8>> {
+    match exchange(output_packet, ip_address, false, cancelled)? {
+        Exchange::Answered(response) => Ok(response),
+        Exchange::CaseMismatch => unreachable!("Case is only checked when it was randomized"),
+    }
+}
+
+/// Like `exchange_udp`, but also uses DNS 0x20: the letters of the question name
+/// go out in random case, and the response has to echo them exactly. That's a lot
+/// more bits for a spoofer to guess than the ID and port alone.
+///
+/// Replies that get the case wrong are discarded like any other mismatch. Some
+/// upstreams normalize the case of the question, though, so when only those come
+/// back before the timeout, the query is retried without randomization, and after a
+/// few of those it stops being randomized for that upstream at all.
+///
+/// The tracker is only locked to check and update it, not while waiting on the
+/// upstream, so queries to the same upstream don't queue up behind each other. It
+/// gives up early when `cancelled` is set, as `exchange_udp_until` does.
+pub fn exchange_udp_0x20(output_packet: &Vec<u8>, ip_address: &String, tracker: &Mutex<CaseTracker>, cancelled: &AtomicBool) -> io::Result<Vec<u8>> {
+    let should_randomize = tracker.lock().unwrap().should_randomize(ip_address);
+    if should_randomize {
+        match exchange(output_packet, ip_address, true, cancelled)? {
+            Exchange::Answered(response) => {
+                tracker.lock().unwrap().record(ip_address, true);
+                return Ok(response);
+            },
+            Exchange::CaseMismatch => {
+                println!("{} changed the case of the question. Retrying without 0x20.", ip_address);
+                tracker.lock().unwrap().record(ip_address, false);
+            },
+        }
+    }
 
-    let mut dest_socket_addr = String::new();
-    write!(&mut dest_socket_addr, "{}:53", ip_address).expect("Could not create destination socket address");
+    exchange_udp_until(output_packet, ip_address, cancelled)
+}
+
+fn exchange(output_packet: &Vec<u8>, ip_address: &String, randomize_case: bool, cancelled: &AtomicBool) -> io::Result<Exchange> {
+    let socket = bind_random_port()?;
+
+    socket.connect(parse_server_address(ip_address)?)?;
+
+    let mut query = Packet::from_vec(output_packet);
+    let original_id = query.get_id();
+    let query_id = random_u16();
+    query.set_id(query_id);
 
-    socket.connect(dest_socket_addr).expect("Could not conenct to remote");
-    socket.send(&output_packet).expect("Error sending outbound packet");
+    if randomize_case {
+        randomize_question_case(&mut query);
+    }
 
+    socket.send(&query.data)?;
+
+    let deadline = Instant::now() + UDP_TIMEOUT;
     let mut buf = [0; 65536];
-    let received_bytes = socket.recv(&mut buf).expect("Error receiving data");
-    buf[..received_bytes].to_vec()
+
+    // A reply that only gets the case wrong might be a spoofer's, so it doesn't end
+    // the wait. It only counts against the upstream if the real one never comes.
+    let mut case_mismatched = false;
+    let timed_out = |case_mismatched| if case_mismatched {
+        Ok(Exchange::CaseMismatch)
+    } else {
+        Err(io::Error::new(io::ErrorKind::TimedOut, "No matching response from upstream"))
+    };
+
+    loop {
+        if cancelled.load(Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::Interrupted, "No longer wanted"));
+        }
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining == Duration::from_secs(0) {
+            return timed_out(case_mismatched);
+        }
+
+        socket.set_read_timeout(Some(remaining.min(CANCEL_CHECK_INTERVAL)))?;
+        let received_bytes = match socket.recv(&mut buf) {
+            Ok(received_bytes) => received_bytes,
+            Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+            Err(err) => return Err(err),
+        };
+        let mut response = Packet::init_from_full(buf[..received_bytes].to_vec());
+
+        match check_response(&query, &response, query_id, randomize_case) {
+            Ok(()) => {
+                response.set_id(original_id);
+                if randomize_case {
+                    // The client should see its question the way it asked it.
+                    let question_end = question_section_end(output_packet).expect("Query was already checked");
+                    response.data[12..question_end].copy_from_slice(&output_packet[12..question_end]);
+                }
+
+                return Ok(Exchange::Answered(response.data));
+            },
+            Err(Mismatch::CaseOnly) => {
+                println!("Discarding response from {}: question case doesn't match", ip_address);
+                case_mismatched = true;
+            },
+            Err(Mismatch::Invalid(reason)) => println!("Discarding response from {}: {}", ip_address, reason),
+        }
+    }
+}
+
+fn bind_random_port() -> io::Result<UdpSocket> {
+    for _ in 0..PORT_ATTEMPTS {
+        let port = 1024 + random_u16() % (65535 - 1024);
+        if let Ok(socket) = UdpSocket::bind(("0.0.0.0", port)) {
+            return Ok(socket);
+        }
+    }
+
+    UdpSocket::bind("0.0.0.0:0")
+}
+
+/// Finds the index just past the question section.
+fn question_section_end(packet: &Vec<u8>) -> Option<usize> {
+    if packet.len() < 12 {
+        return None;
+    }
+
+    let mut index = 12;
+    for _ in 0..get_u16(packet, 4) {
+        index = read_name(packet, index)?.1 + 4;
+    }
+
+    if index > packet.len() {
+        return None;
+    }
+
+    Some(index)
+}
+
+/// Flips the letters of every question name to a random case.
+fn randomize_question_case(query: &mut Packet) {
+    let question_end = match question_section_end(&query.data) {
+        Some(end) => end,
+        None => return,
+    };
+
+    let coin_flips = random_bytes(question_end);
+    let mut index = 12;
+
+    while index < question_end {
+        let len = query.data[index] as usize;
+        if len & 0xC0 != 0 {
+            // Compressed questions are too odd to bother with. Leave them be.
+            return;
+        }
+
+        if len == 0 {
+            // End of this name. Skip its type and class.
+            index += 5;
+            continue;
+        }
+
+        let characters = query.data[index + 1..=index + len].iter_mut();
+        for (character, coin_flip) in characters.zip(&coin_flips[index + 1..=index + len]) {
+            if character.is_ascii_alphabetic() && coin_flip & 0x01 != 0 {
+                *character ^= 0x20;
+            }
+        }
+
+        index += len + 1;
+    }
+}
+
+/// Makes sure a response is actually the answer to our query.
+fn check_response(query: &Packet, response: &Packet, query_id: u16, exact_case: bool) -> Result<(), Mismatch> {
+    if response.data.len() < 12 {
+        return Err(Mismatch::Invalid("too short"));
+    }
+
+    if response.get_id() != query_id {
+        return Err(Mismatch::Invalid("wrong ID"));
+    }
+
+    if !response.is_response() {
+        return Err(Mismatch::Invalid("not a response"));
+    }
+
+    let query_questions = query.read_questions().ok_or(Mismatch::Invalid("malformed query"))?;
+    let response_questions = response.read_questions().ok_or(Mismatch::Invalid("malformed question section"))?;
+
+    let same_questions = query_questions.len() == response_questions.len()
+        && query_questions.iter().zip(response_questions.iter()).all(|(asked, answered)| {
+            asked.0.eq_ignore_case(&answered.0) && asked.1 == answered.1 && asked.2 == answered.2
+        });
+
+    if !same_questions {
+        return Err(Mismatch::Invalid("question doesn't match"));
+    }
+
+    if exact_case && query_questions.iter().zip(response_questions.iter()).any(|(asked, answered)| asked.0 != answered.0) {
+        return Err(Mismatch::CaseOnly);
+    }
+
+    Ok(())
 }
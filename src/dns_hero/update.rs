@@ -0,0 +1,429 @@
+use std::collections::{ HashMap, HashSet };
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use super::authoritative::*;
+use super::data::*;
+use super::enums::*;
+use super::notify::notify_secondaries;
+use super::transfer::{ serial_is_newer, soa_serial };
+use super::util::*;
+use super::zone::{ parse_name, parse_type, parse_zone, split_fields };
+
+/// Builds a dynamic update (RFC 2136). An UPDATE reuses the sections of a query:
+/// the question names the zone, the answers are the prerequisites, and the
+/// authorities are the changes to make.
+pub struct UpdateBuilder {
+    packet: DecomposedPacket,
+}
+
+impl UpdateBuilder {
+    #[allow(clippy::ptr_arg)]
+    pub fn new(zone: &String) -> UpdateBuilder {
+        let mut packet = DecomposedPacket::new();
+        packet.id = random_u16();
+        packet.opcode = Opcode::Update;
+        packet.questions.push(Question {
+            label: Label::Domain(zone.trim_end_matches('.').to_string()),
+            qtype: Type::SOA,
+            qclass: Class::Internet,
+        });
+
+        UpdateBuilder {
+            packet,
+        }
+    }
+
+    /// Requires the name to have at least one record.
+    pub fn name_in_use(&mut self, name: &String) -> &mut UpdateBuilder {
+        self.packet.answers.push(meta_record(name, Type::ANY, Class::QclassAny));
+        self
+    }
+
+    /// Requires the name to have no records at all.
+    pub fn name_not_in_use(&mut self, name: &String) -> &mut UpdateBuilder {
+        self.packet.answers.push(meta_record(name, Type::ANY, Class::QclassNone));
+        self
+    }
+
+    /// Requires the name to have records of this type, whatever they say.
+    pub fn rrset_exists(&mut self, name: &String, rtype: Type) -> &mut UpdateBuilder {
+        self.packet.answers.push(meta_record(name, rtype, Class::QclassAny));
+        self
+    }
+
+    /// Requires the name to have no records of this type.
+    pub fn rrset_missing(&mut self, name: &String, rtype: Type) -> &mut UpdateBuilder {
+        self.packet.answers.push(meta_record(name, rtype, Class::QclassNone));
+        self
+    }
+
+    /// Requires the record to be there. Every record given for a name and type
+    /// together has to match that RRset exactly, no more and no less.
+    pub fn rrset_includes(&mut self, record: &Resource) -> &mut UpdateBuilder {
+        let mut record = record.clone();
+        record.ttl = 0;
+        self.packet.answers.push(record);
+        self
+    }
+
+    pub fn add(&mut self, record: &Resource) -> &mut UpdateBuilder {
+        self.packet.authorities.push(record.clone());
+        self
+    }
+
+    /// Deletes every record of a type at the name.
+    pub fn delete_rrset(&mut self, name: &String, rtype: Type) -> &mut UpdateBuilder {
+        self.packet.authorities.push(meta_record(name, rtype, Class::QclassAny));
+        self
+    }
+
+    /// Deletes everything at the name.
+    pub fn delete_name(&mut self, name: &String) -> &mut UpdateBuilder {
+        self.packet.authorities.push(meta_record(name, Type::ANY, Class::QclassAny));
+        self
+    }
+
+    /// Deletes one record, matching on its data.
+    pub fn delete_record(&mut self, record: &Resource) -> &mut UpdateBuilder {
+        let mut record = record.clone();
+        record.ttl = 0;
+        record.rclass = Class::QclassNone;
+        self.packet.authorities.push(record);
+        self
+    }
+
+    pub fn build(&self) -> DecomposedPacket {
+        self.packet.clone()
+    }
+}
+
+/// Builds an update from a script of commands, one per line, with names relative
+/// to the zone unless they end in a dot:
+///
+/// ```text
+/// prereq yxdomain www
+/// prereq nxdomain new
+/// prereq yxrrset www A
+/// prereq yxrrset www A 192.0.2.1
+/// prereq nxrrset www AAAA
+/// add    www 300 A 192.0.2.2
+/// add    www 300 TXT "v=spf1; -all"
+/// delete www A 192.0.2.1
+/// delete www A
+/// delete old
+/// ```
+pub fn parse_update_script(text: &str, zone: &String) -> Result<DecomposedPacket, String> {
+    let mut builder = UpdateBuilder::new(zone);
+
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        let fields = split_fields(line).map_err(|err| format!("line {}: {}", line_index + 1, err.message))?;
+        let words: Vec<&str> = fields.iter().map(String::as_str).collect();
+        let result = match words.as_slice() {
+            [] => Ok(()),
+            ["prereq", "yxdomain", name] => parse_name(name, zone).map(|name| { builder.name_in_use(&name); }),
+            ["prereq", "nxdomain", name] => parse_name(name, zone).map(|name| { builder.name_not_in_use(&name); }),
+            ["prereq", "yxrrset", name, rtype] => parse_name_and_type(name, rtype, zone).map(|(name, rtype)| { builder.rrset_exists(&name, rtype); }),
+            ["prereq", "nxrrset", name, rtype] => parse_name_and_type(name, rtype, zone).map(|(name, rtype)| { builder.rrset_missing(&name, rtype); }),
+            ["prereq", "yxrrset", name, rtype, data @ ..] => parse_script_record(name, "0", rtype, data, zone).map(|record| { builder.rrset_includes(&record); }),
+            ["add", name, ttl, rtype, data @ ..] if !data.is_empty() => parse_script_record(name, ttl, rtype, data, zone).map(|record| { builder.add(&record); }),
+            ["delete", name] => parse_name(name, zone).map(|name| { builder.delete_name(&name); }),
+            ["delete", name, rtype] => parse_name_and_type(name, rtype, zone).map(|(name, rtype)| { builder.delete_rrset(&name, rtype); }),
+            ["delete", name, rtype, data @ ..] => parse_script_record(name, "0", rtype, data, zone).map(|record| { builder.delete_record(&record); }),
+            _ => Err(format!("Don't know what to do with \"{}\"", line)),
+        };
+
+        result.map_err(|err| format!("line {}: {}", line_index + 1, err))?;
+    }
+
+    Ok(builder.build())
+}
+
+/// Applies an UPDATE to one of our zones, if the client's allowed to make it and
+/// its prerequisites hold, and says how it went. Secondaries hear about it if the
+/// zone changed. `key` is the TSIG key the update was signed with, if it was.
+pub fn answer_update(query: &DecomposedPacket, client: IpAddr, key: Option<&String>, zones: &Zones) -> Vec<u8> {
+    let mut response = DecomposedPacket::new();
+    response.id = query.id;
+    response.is_response = true;
+    response.opcode = Opcode::Update;
+    response.questions = query.questions.clone();
+
+    response.response_code = match process_update(query, client, key, zones) {
+        Ok(Some(hosted)) => {
+            println!("Updated {} to serial {}", hosted.zone.origin, hosted.zone.serial());
+            notify_secondaries(&hosted);
+            ResponseCode::NoError
+        },
+        Ok(None) => ResponseCode::NoError,
+        Err(code) => code,
+    };
+
+    response.to_raw().data
+}
+
+fn process_update(query: &DecomposedPacket, client: IpAddr, key: Option<&String>, zones: &Zones) -> Result<Option<Arc<HostedZone>>, ResponseCode> {
+    let origin = match query.questions.as_slice() {
+        [Question { label: Label::Domain(name), qtype: Type::SOA, .. }] => name.trim_end_matches('.').to_lowercase(),
+        _ => return Err(ResponseCode::FormErr),
+    };
+
+    let _changing = zones.lock_changes();
+    let hosted = zones.get(&origin).ok_or(ResponseCode::NotAuth)?;
+
+    if hosted.file.is_none() {
+        println!("Refusing update of {} from {}, we only have a copy of it", origin, client);
+        return Err(ResponseCode::NotImp);
+    }
+
+    if !hosted.allow_update.allows(client, key) {
+        println!("Refusing update of {} from {}, it isn't on the zone's update list", origin, client);
+        return Err(ResponseCode::Refused);
+    }
+
+    // We don't hold the zone's keys, so a change would leave its signatures and
+    // denial chain describing records that aren't there any more.
+    if hosted.zone.is_signed() {
+        println!("Refusing update of {} from {}, it's signed and we can't sign the change", origin, client);
+        return Err(ResponseCode::Refused);
+    }
+
+    let records = hosted.zone.records();
+    check_prerequisites(&hosted.zone, &records, &query.answers)?;
+    check_updates(&hosted.zone, &query.authorities)?;
+
+    let updated = match apply_updates(&hosted.zone, records, &query.authorities) {
+        Some(updated) => updated,
+        None => return Ok(None),
+    };
+
+    match hosted.changed(updated) {
+        Ok(changed) => {
+            let changed = Arc::new(changed);
+            zones.insert(changed.clone());
+            Ok(Some(changed))
+        },
+        Err(err) => {
+            println!("Couldn't update {}: {}", origin, err);
+            Err(ResponseCode::ServFail)
+        },
+    }
+}
+
+/// Checks the prerequisite section (RFC 2136 section 3.2).
+#[allow(clippy::ptr_arg)]
+fn check_prerequisites(zone: &Zone, records: &Vec<Resource>, prerequisites: &Vec<Resource>) -> Result<(), ResponseCode> {
+    let mut expected_rrsets: HashMap<(String, Type), HashSet<Vec<u8>>> = HashMap::new();
+
+    for prerequisite in prerequisites {
+        let name = owner(prerequisite);
+        if prerequisite.ttl != 0 {
+            return Err(ResponseCode::FormErr);
+        }
+        if !zone.contains(&name) {
+            return Err(ResponseCode::NotZone);
+        }
+
+        let is_empty = prerequisite.data.is_empty();
+        let in_use = records.iter().any(|record| owner(record) == name);
+        let has_rrset = records.iter().any(|record| owner(record) == name && record.rtype == prerequisite.rtype);
+
+        match (prerequisite.rclass, prerequisite.rtype) {
+            (Class::QclassAny, _) | (Class::QclassNone, _) if !is_empty => return Err(ResponseCode::FormErr),
+            (Class::QclassAny, Type::ANY) if !in_use => return Err(ResponseCode::NXDomain),
+            (Class::QclassAny, Type::ANY) => {},
+            (Class::QclassAny, _) if !has_rrset => return Err(ResponseCode::NXRRSet),
+            (Class::QclassAny, _) => {},
+            (Class::QclassNone, Type::ANY) if in_use => return Err(ResponseCode::YXDomain),
+            (Class::QclassNone, Type::ANY) => {},
+            (Class::QclassNone, _) if has_rrset => return Err(ResponseCode::YXRRSet),
+            (Class::QclassNone, _) => {},
+            (Class::Internet, _) => {
+                expected_rrsets.entry((name, prerequisite.rtype)).or_default().insert(prerequisite.data.clone());
+            },
+            _ => return Err(ResponseCode::FormErr),
+        }
+    }
+
+    for ((name, rtype), expected) in expected_rrsets {
+        let actual: HashSet<Vec<u8>> = records.iter()
+            .filter(|record| owner(record) == name && record.rtype == rtype)
+            .map(|record| record.data.clone())
+            .collect();
+
+        if actual != expected {
+            return Err(ResponseCode::NXRRSet);
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes sure every change in the update section makes sense before any of them
+/// are made (RFC 2136 section 3.4.1).
+fn check_updates(zone: &Zone, updates: &Vec<Resource>) -> Result<(), ResponseCode> {
+    for update in updates {
+        if !zone.contains(&owner(update)) {
+            return Err(ResponseCode::NotZone);
+        }
+
+        let is_meta_type = matches!(update.rtype, Type::ANY | Type::AXFR | Type::IXFR | Type::MAILA | Type::MAILB | Type::OPT | Type::TSIG | Type::TKEY);
+        let is_valid = match update.rclass {
+            Class::Internet => !is_meta_type,
+            Class::QclassAny => update.ttl == 0 && update.data.is_empty() && (update.rtype == Type::ANY || !is_meta_type),
+            Class::QclassNone => update.ttl == 0 && !is_meta_type,
+            _ => false,
+        };
+
+        if !is_valid {
+            return Err(ResponseCode::FormErr);
+        }
+    }
+
+    Ok(())
+}
+
+/// Makes the changes (RFC 2136 section 3.4.2), and bumps the serial if the update
+/// didn't. None means nothing actually changed.
+fn apply_updates(zone: &Zone, mut records: Vec<Resource>, updates: &Vec<Resource>) -> Option<Vec<Resource>> {
+    let before: HashSet<String> = records.iter().map(record_key).collect();
+    let old_serial = zone.serial();
+
+    for update in updates {
+        let name = owner(update);
+        let at_apex = name == zone.origin;
+        let is_protected = |rtype: Type| at_apex && (rtype == Type::SOA || rtype == Type::NS);
+
+        match update.rclass {
+            Class::Internet => add_record(&mut records, update, &name, at_apex),
+            Class::QclassAny if update.rtype == Type::ANY => {
+                records.retain(|record| owner(record) != name || is_protected(record.rtype));
+            },
+            Class::QclassAny if !is_protected(update.rtype) => {
+                records.retain(|record| owner(record) != name || record.rtype != update.rtype);
+            },
+            Class::QclassNone if update.rtype == Type::SOA => {},
+            Class::QclassNone => {
+                let is_last_apex_ns = is_protected(update.rtype)
+                    && records.iter().filter(|record| owner(record) == name && record.rtype == Type::NS).count() == 1;
+                if !is_last_apex_ns {
+                    records.retain(|record| owner(record) != name || record.rtype != update.rtype || record.data != update.data);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    let after: HashSet<String> = records.iter().map(record_key).collect();
+    if before == after {
+        return None;
+    }
+
+    let soa = records.iter_mut().find(|record| record.rtype == Type::SOA)?;
+    if !serial_is_newer(soa_serial(soa)?, old_serial) {
+        set_soa_serial(soa, old_serial.wrapping_add(1));
+    }
+
+    Some(records)
+}
+
+/// Adds a record, unless it would break the rules about CNAMEs and SOAs. One
+/// that's already there just gets its TTL updated.
+fn add_record(records: &mut Vec<Resource>, update: &Resource, name: &String, at_apex: bool) {
+    if update.rtype == Type::SOA {
+        let is_newer = match (records.iter().find_map(soa_serial), soa_serial(update)) {
+            (Some(current), Some(new)) => serial_is_newer(new, current),
+            _ => false,
+        };
+        if is_newer && at_apex {
+            records.retain(|record| record.rtype != Type::SOA);
+            records.insert(0, update.clone());
+        }
+        return;
+    }
+
+    let has_cname = records.iter().any(|record| owner(record) == *name && record.rtype == Type::CNAME);
+    let has_other = records.iter().any(|record| owner(record) == *name && record.rtype != Type::CNAME);
+
+    if update.rtype == Type::CNAME && has_other {
+        return;
+    }
+    if update.rtype != Type::CNAME && has_cname {
+        return;
+    }
+    if update.rtype == Type::CNAME {
+        records.retain(|record| owner(record) != *name || record.rtype != Type::CNAME);
+    }
+
+    match records.iter_mut().find(|record| owner(record) == *name && record.rtype == update.rtype && record.data == update.data) {
+        Some(existing) => existing.ttl = update.ttl,
+        None => records.push(update.clone()),
+    }
+}
+
+pub fn set_soa_serial(soa: &mut Resource, serial: u32) {
+    let serial_start = read_name(&soa.data, 0)
+        .and_then(|(_, mname_end)| read_name(&soa.data, mname_end))
+        .map(|(_, rname_end)| rname_end);
+
+    if let Some(start) = serial_start {
+        set_u32(&mut soa.data, serial, start);
+    }
+}
+
+#[allow(clippy::ptr_arg)]
+fn meta_record(name: &String, rtype: Type, rclass: Class) -> Resource {
+    Resource {
+        label: Label::Domain(name.trim_end_matches('.').to_string()),
+        rtype,
+        rclass,
+        ttl: 0,
+        length: 0,
+        data: vec![],
+    }
+}
+
+fn owner(record: &Resource) -> String {
+    match &record.label {
+        Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+        Label::Pointer(_) => String::new(),
+    }
+}
+
+/// What makes a record distinct, TTL included.
+fn record_key(record: &Resource) -> String {
+    format!("{} {} {} {:?}", owner(record), record.rtype, record.ttl, record.data)
+}
+
+fn parse_name_and_type(name: &str, rtype: &str, zone: &String) -> Result<(String, Type), String> {
+    let name = parse_name(name, zone)?;
+    let rtype = parse_type(rtype).ok_or(format!("Unknown type {}", rtype))?;
+    Ok((name, rtype))
+}
+
+/// Reads a record out of a script line, the way the zone file parser would.
+fn parse_script_record(name: &str, ttl: &str, rtype: &str, data: &[&str], zone: &String) -> Result<Resource, String> {
+    let line = format!("{} {} IN {} {}", name, ttl, rtype, data.join(" "));
+    let mut records = parse_zone(&line, zone).map_err(|err| err.to_string())?;
+    records.pop().ok_or(String::from("Missing record"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn script_keeps_quoted_data_whole() {
+        let script = "add www 300 TXT \"v=spf1; -all\" \"a\\\"b\" ; the record\n; just a comment\n";
+        let update = parse_update_script(script, &String::from("example.test")).unwrap();
+
+        assert_eq!(update.authorities.len(), 1);
+        assert_eq!(update.authorities[0].data, b"\x0cv=spf1; -all\x03a\"b".to_vec());
+    }
+
+    #[test]
+    fn script_with_an_open_quote_is_an_error() {
+        assert!(parse_update_script("add www 300 TXT \"v=spf1\n", &String::from("example.test")).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use std::io;
+use std::sync::{ Arc, Mutex };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc;
+use std::thread;
+use std::time::{ Duration, Instant };
+
+use super::data::Packet;
+use super::doh::exchange_doh;
+use super::enums::*;
+use super::resolver::Resolver;
+use super::udp::*;
+use super::util::random_u16;
+
+/// This many failures in a row and an upstream is benched for a while.
+const UNHEALTHY_FAILURE_COUNT: u32 = 3;
+
+/// How long a benched upstream sits out before it gets another chance.
+const UNHEALTHY_DURATION: Duration = Duration::from_secs(30);
+
+/// How much a new latency sample moves the running average.
+const LATENCY_WEIGHT: f64 = 0.3;
+
+/// How many upstreams a race sends to at once, unless told otherwise.
+pub const DEFAULT_RACE_WIDTH: usize = 2;
+
+/// Where a query can be sent, and how.
+pub enum Upstream {
+    Udp(String, Mutex<CaseTracker>),
+    Doh(String),
+    /// Our own resolver, which goes to the root servers and works down from there.
+    Recursive(String, Arc<Resolver>),
+}
+
+impl Upstream {
+    /// Reads an upstream from config. URLs mean DoH, and anything else is taken as
+    /// the IP (and maybe port) of a plain UDP server.
+    #[allow(clippy::ptr_arg)]
+    pub fn parse(spec: &String) -> Upstream {
+        if spec.starts_with("https://") {
+            Upstream::Doh(spec.clone())
+        } else {
+            Upstream::Udp(spec.clone(), Mutex::new(CaseTracker::new()))
+        }
+    }
+
+    /// Asks this upstream. UDP exchanges and our own resolver give up with an
+    /// `Interrupted` error once `cancelled` is set. DoH runs to the end either way.
+    pub fn exchange(&self, query: &Vec<u8>, cancelled: &AtomicBool) -> io::Result<Vec<u8>> {
+        match self {
+            Upstream::Udp(address, case_tracker) => exchange_udp_0x20(query, address, case_tracker, cancelled),
+            Upstream::Doh(url) => exchange_doh(query, url),
+            Upstream::Recursive(_, resolver) => resolver.exchange(query, cancelled),
+        }
+    }
+
+    pub fn name(&self) -> &String {
+        match self {
+            Upstream::Udp(address, _) => address,
+            Upstream::Doh(url) => url,
+            Upstream::Recursive(name, _) => name,
+        }
+    }
+}
+
+/// How a group picks which of its upstreams to ask first.
+#[derive(Copy, Clone)]
+pub enum Strategy {
+    /// Always in the configured order, moving on only when one fails.
+    Failover,
+    /// Take turns.
+    RoundRobin,
+    Random,
+    /// Whichever has been answering quickest lately.
+    Fastest,
+    /// Ask this many at once, and take the first good answer.
+    Race(usize),
+}
+
+impl Strategy {
+    /// Reads a strategy from config. Only a race takes a width after its name.
+    #[allow(clippy::ptr_arg)]
+    pub fn parse(args: &Vec<String>) -> Result<Strategy, String> {
+        match args.iter().map(String::as_str).collect::<Vec<&str>>().as_slice() {
+            ["failover"] => Ok(Strategy::Failover),
+            ["round-robin"] => Ok(Strategy::RoundRobin),
+            ["random"] => Ok(Strategy::Random),
+            ["fastest"] => Ok(Strategy::Fastest),
+            ["race"] => Ok(Strategy::Race(DEFAULT_RACE_WIDTH)),
+            ["race", width] => match width.parse() {
+                Ok(width) if width > 0 => Ok(Strategy::Race(width)),
+                _ => Err(format!("Bad race width {}", width)),
+            },
+            [name, ..] => Err(format!("Unknown strategy {}", name)),
+            [] => Err(String::from("forward-strategy needs a strategy")),
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+struct Health {
+    consecutive_failures: u32,
+    benched_until: Option<Instant>,
+    /// Exponentially weighted moving average of response times, in milliseconds.
+    latency: Option<f64>,
+}
+
+impl Health {
+    fn is_benched(&self, now: Instant) -> bool {
+        self.benched_until.map(|until| now < until).unwrap_or(false)
+    }
+}
+
+struct GroupState {
+    health: Vec<Health>,
+    next_turn: usize,
+}
+
+impl GroupState {
+    fn record_success(&mut self, index: usize, elapsed: Duration) {
+        let health = &mut self.health[index];
+
+        let sample = elapsed.as_secs_f64() * 1000.0;
+        health.latency = Some(match health.latency {
+            Some(average) => average + LATENCY_WEIGHT * (sample - average),
+            None => sample,
+        });
+
+        health.consecutive_failures = 0;
+        health.benched_until = None;
+    }
+
+    fn record_failure(&mut self, index: usize, name: &String) {
+        let health = &mut self.health[index];
+
+        health.consecutive_failures += 1;
+        if health.consecutive_failures >= UNHEALTHY_FAILURE_COUNT {
+            if !health.is_benched(Instant::now()) {
+                println!("Upstream {} is unhealthy. Benching it for {} seconds", name, UNHEALTHY_DURATION.as_secs());
+            }
+
+            health.benched_until = Some(Instant::now() + UNHEALTHY_DURATION);
+        }
+    }
+}
+
+/// A set of interchangeable upstreams.
+///
+/// Each query goes to one upstream at a time, in the order the strategy picks, until
+/// one of them answers. Racing groups send to several at a time instead. Upstreams
+/// that keep failing are left out of that order for a while, unless everything is
+/// failing, in which case they're still tried last.
+pub struct UpstreamGroup {
+    upstreams: Vec<Arc<Upstream>>,
+    strategy: Strategy,
+    state: Arc<Mutex<GroupState>>,
+}
+
+impl UpstreamGroup {
+    pub fn new(upstreams: Vec<Upstream>, strategy: Strategy) -> UpstreamGroup {
+        let health = vec![Health::default(); upstreams.len()];
+        UpstreamGroup {
+            upstreams: upstreams.into_iter().map(Arc::new).collect(),
+            strategy,
+            state: Arc::new(Mutex::new(GroupState {
+                health,
+                next_turn: 0,
+            })),
+        }
+    }
+
+    /// Sends the query to each upstream in turn (or each few, when racing) until one
+    /// gives a real answer. SERVFAILs count as failures, since another upstream may
+    /// do better.
+    pub fn exchange(&self, query: &Vec<u8>) -> io::Result<Vec<u8>> {
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No upstreams configured");
+
+        let width = match self.strategy {
+            Strategy::Race(width) => width,
+            _ => 1,
+        };
+
+        for heat in self.plan().chunks(width) {
+            let reply = match heat {
+                [index] => self.ask(*index, query),
+                _ => self.race(heat, query),
+            };
+
+            match reply {
+                Ok(response) => return Ok(response),
+                Err(err) => last_error = err,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    fn ask(&self, index: usize, query: &Vec<u8>) -> io::Result<Vec<u8>> {
+        ask_upstream(&self.upstreams[index], index, &self.state, query, &AtomicBool::new(false))
+    }
+
+    /// Sends the query to all of these upstreams at once, and returns the first good
+    /// answer without waiting on the others. Those are called off once there's a
+    /// winner, so they don't go on waiting for answers nobody wants.
+    #[allow(clippy::ptr_arg)]
+    fn race(&self, heat: &[usize], query: &Vec<u8>) -> io::Result<Vec<u8>> {
+        let (tx, rx) = mpsc::channel();
+        let cancelled = Arc::new(AtomicBool::new(false));
+
+        for index in heat {
+            let index = *index;
+            let upstream = self.upstreams[index].clone();
+            let state = self.state.clone();
+            let query = query.clone();
+            let tx = tx.clone();
+            let cancelled = cancelled.clone();
+
+            thread::spawn(move || {
+                // The race may already be over, in which case nobody's listening.
+                let _ = tx.send(ask_upstream(&upstream, index, &state, &query, &cancelled));
+            });
+        }
+
+        drop(tx);
+
+        let mut last_error = io::Error::new(io::ErrorKind::NotFound, "No upstreams in race");
+        for reply in rx {
+            match reply {
+                Ok(response) => {
+                    cancelled.store(true, Ordering::Relaxed);
+                    return Ok(response);
+                },
+                Err(err) => last_error = err,
+            }
+        }
+
+        Err(last_error)
+    }
+
+    /// Picks the order to try the upstreams in for one query.
+    fn plan(&self) -> Vec<usize> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let count = self.upstreams.len();
+
+        let mut order: Vec<usize> = (0..count).collect();
+        match self.strategy {
+            Strategy::Failover | Strategy::Race(_) => {},
+            Strategy::RoundRobin => {
+                order.rotate_left(state.next_turn % count.max(1));
+                state.next_turn = state.next_turn.wrapping_add(1);
+            },
+            Strategy::Random => {
+                for i in (1..count).rev() {
+                    order.swap(i, random_u16() as usize % (i + 1));
+                }
+            },
+            Strategy::Fastest => {
+                // Upstreams we haven't timed yet go first, so they get timed.
+                let health = &state.health;
+                order.sort_by(|a, b| {
+                    let a_latency = health[*a].latency.unwrap_or(0.0);
+                    let b_latency = health[*b].latency.unwrap_or(0.0);
+                    a_latency.partial_cmp(&b_latency).unwrap()
+                });
+            },
+        }
+
+        let (mut healthy, benched): (Vec<usize>, Vec<usize>) = order.into_iter()
+            .partition(|index| !state.health[*index].is_benched(now));
+
+        healthy.extend(benched);
+        healthy
+    }
+}
+
+/// Asks one upstream, keeping track of how it did. Being called off doesn't count
+/// against it.
+fn ask_upstream(upstream: &Upstream, index: usize, state: &Mutex<GroupState>, query: &Vec<u8>, cancelled: &AtomicBool) -> io::Result<Vec<u8>> {
+    let started = Instant::now();
+
+    match check_answer(upstream.exchange(query, cancelled)) {
+        Ok(response) => {
+            state.lock().unwrap().record_success(index, started.elapsed());
+            Ok(response)
+        },
+        Err(err) if err.kind() == io::ErrorKind::Interrupted => Err(err),
+        Err(err) => {
+            println!("Upstream {} failed: {}", upstream.name(), err);
+            state.lock().unwrap().record_failure(index, upstream.name());
+            Err(err)
+        },
+    }
+}
+
+/// Turns a SERVFAIL into an error, so it's treated like any other failure.
+fn check_answer(reply: io::Result<Vec<u8>>) -> io::Result<Vec<u8>> {
+    let response = reply?;
+    match Packet::from_vec(&response).get_response_code() {
+        ResponseCode::ServFail => Err(io::Error::new(io::ErrorKind::Other, "SERVFAIL")),
+        _ => Ok(response),
+    }
+}
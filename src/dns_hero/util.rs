@@ -1,6 +1,13 @@
+extern crate openssl;
+
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use openssl::rand::rand_bytes;
+
 use super::data::{ Label, Question, Resource };
 use super::enums::*;
 
+#[allow(clippy::ptr_arg)]
 pub fn set_u16(bytes: &mut Vec<u8>, value: u16, offset: usize) {
     let b1 = ((value >> 8) & 0x00FF) as u8;
     let b2 = (value & 0x00FF) as u8;
@@ -9,6 +16,7 @@ pub fn set_u16(bytes: &mut Vec<u8>, value: u16, offset: usize) {
     bytes[offset + 1] = b2;
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn get_u16(bytes: &Vec<u8>, offset: usize) -> u16{
     let mut datum: u16 = bytes[offset] as u16;
     datum <<= 8;
@@ -17,11 +25,20 @@ pub fn get_u16(bytes: &Vec<u8>, offset: usize) -> u16{
     datum
 }
 
+#[allow(clippy::ptr_arg)]
+pub fn set_u32(bytes: &mut Vec<u8>, value: u32, offset: usize) {
+    bytes[offset] = ((value >> 24) & 0x000000FF) as u8;
+    bytes[offset + 1] = ((value >> 16) & 0x000000FF) as u8;
+    bytes[offset + 2] = ((value >> 8) & 0x000000FF) as u8;
+    bytes[offset + 3] = (value & 0x000000FF) as u8;
+}
+
+#[allow(clippy::ptr_arg)]
 pub fn get_u32(bytes: &Vec<u8>, offset: usize) -> u32{
     let mut datum: u32 = bytes[offset] as u32;
-    datum <<= 24;
+    datum <<= 8;
     datum |= bytes[offset + 1] as u32;
-    datum <<= 16;
+    datum <<= 8;
     datum |= bytes[offset + 2] as u32;
     datum <<= 8;
     datum |= bytes[offset + 3] as u32;
@@ -29,31 +46,44 @@ pub fn get_u32(bytes: &Vec<u8>, offset: usize) -> u32{
     datum
 }
 
+#[allow(clippy::ptr_arg)]
 pub fn str_domain_to_dns_domain(domain_str: &String) -> Vec<u8> {
-    let parts: Vec<&str> = domain_str.split('.').collect();
+    // Empty parts come from the root name, or a trailing dot, and aren't labels.
+    let parts: Vec<&str> = domain_str.split('.').filter(|part| !part.is_empty()).collect();
     let mut bytes = Vec::<u8>::new();
 
     for part in parts.into_iter() {
-        let part_length = part.len();
+        // Each character of a name stands for one byte of the label, the way
+        // `parse_label` reads them, so `\200` from a zone file goes out as 0x80 and
+        // not as its UTF-8 encoding. Anything past a byte can only have been typed
+        // in, and gets its UTF-8 bytes.
+        let mut label = Vec::<u8>::new();
+        for character in part.chars() {
+            if (character as u32) < 0x100 {
+                label.push(character as u8);
+            } else {
+                let mut buffer = [0; 4];
+                label.extend_from_slice(character.encode_utf8(&mut buffer).as_bytes());
+            }
+        }
 
         // Label part lengths are a single byte. However, having the first two bytes set
         // signifies a QNAME pointer, so the actual range is 6 bits, 0-63.
-        if part_length >= 64 {
+        if label.len() >= 64 {
             panic!("Error: {} longer than range of a label segment", part);
         }
 
-        bytes.push(part_length as u8);
-        for character in part.bytes() {
-            bytes.push(character as u8);
-        }
+        bytes.push(label.len() as u8);
+        bytes.append(&mut label);
     }
 
-    bytes.push(0 as u8);
+    bytes.push(0);
     bytes
 }
 
 pub type PacketParserFn<T> = fn(&Vec<u8>, usize) -> (T, usize);
 
+#[allow(clippy::ptr_arg)]
 pub fn parse_label(bytes: &Vec<u8>, start: usize) -> (Label, usize) {
     // If the first two bits are set, this is a pointer.
     if bytes[start] & 0xC0 == 0xC0 {
@@ -87,14 +117,78 @@ pub fn parse_label(bytes: &Vec<u8>, start: usize) -> (Label, usize) {
     (Label::Domain(domain), i)
 }
 
+/// Reads a domain name starting at `start`, following compression pointers.
+///
+/// Unlike `parse_label`, this never panics on bad input; it returns `None` if the
+/// name runs off the end of the packet or the pointers loop back on themselves.
+/// On success, the second value is the index just past the name in the original
+/// byte stream (i.e. after the first pointer, if there was one).
+#[allow(clippy::ptr_arg)]
+pub fn read_name(bytes: &Vec<u8>, start: usize) -> Option<(String, usize)> {
+    let mut domain = String::new();
+    let mut index = start;
+    let mut end = None;
+    let mut jumps = 0;
+
+    loop {
+        let len = *bytes.get(index)? as usize;
+
+        if len & 0xC0 == 0xC0 {
+            let offset = ((len & 0x3F) << 8) | *bytes.get(index + 1)? as usize;
+            if end.is_none() {
+                end = Some(index + 2);
+            }
+
+            // A well-formed packet can't have more pointers than it has bytes.
+            jumps += 1;
+            if jumps > bytes.len() {
+                return None;
+            }
+
+            index = offset;
+            continue;
+        }
+
+        // The other label types (0x40, 0x80) never caught on, and aren't lengths.
+        if len & 0xC0 != 0 {
+            return None;
+        }
+
+        index += 1;
+        if len == 0 {
+            break;
+        }
+
+        if !domain.is_empty() {
+            domain.push('.');
+        }
+
+        for byte in bytes.get(index..index + len)? {
+            domain.push(*byte as char);
+        }
+
+        index += len;
+    }
+
+    Some((domain, end.unwrap_or(index)))
+}
+
+/// Reads an owner name, decompressed when it can be.
+fn parse_owner(bytes: &Vec<u8>, start: usize) -> (Label, usize) {
+    match read_name(bytes, start) {
+        Some((name, end)) => (Label::Domain(name), end),
+        None => parse_label(bytes, start),
+    }
+}
+
 pub fn parse_question(bytes: &Vec<u8>, start: usize) -> (Question, usize) {
     let mut index = start;
-    let label_result = parse_label(bytes, index);
+    let label_result = parse_owner(bytes, index);
     index = label_result.1;
 
-    let qtype: Type = unsafe { ::std::mem::transmute(get_u16(bytes, index)) };
+    let qtype = Type::from_raw(get_u16(bytes, index));
     index += 2;
-    let qclass: Class = unsafe { ::std::mem::transmute(get_u16(bytes, index)) };
+    let qclass = Class::from_raw(get_u16(bytes, index));
     index += 2;
 
     (Question {
@@ -106,12 +200,12 @@ pub fn parse_question(bytes: &Vec<u8>, start: usize) -> (Question, usize) {
 
 pub fn parse_resource(bytes: &Vec<u8>, start: usize) -> (Resource, usize) {
     let mut index = start;
-    let label_result = parse_label(bytes, index);
+    let label_result = parse_owner(bytes, index);
     index = label_result.1;
 
-    let rtype: Type = unsafe { ::std::mem::transmute(get_u16(bytes, index)) };
+    let rtype = Type::from_raw(get_u16(bytes, index));
     index += 2;
-    let rclass: Class = unsafe { ::std::mem::transmute(get_u16(bytes, index)) };
+    let rclass = Class::from_raw(get_u16(bytes, index));
     index += 2;
 
     let ttl = get_u32(bytes, index);
@@ -126,16 +220,51 @@ pub fn parse_resource(bytes: &Vec<u8>, start: usize) -> (Resource, usize) {
         index += 1;
     }
 
+    // Names in the data may point elsewhere in the packet, which won't mean anything
+    // once the record is out of it.
+    if let Some(decompressed) = decompress_rdata(bytes, rtype, index - length as usize, index) {
+        resource_data = decompressed;
+    }
+
     (Resource {
         label: label_result.0,
         rtype,
         rclass,
         ttl,
-        length,
+        length: resource_data.len() as u16,
         data: resource_data,
     }, index)
 }
 
+/// Copies a record's data with any names in it decompressed, for the types that
+/// RFC 3597 says may have compressed names. None if there's nothing to do, or the
+/// data doesn't look like its type.
+fn decompress_rdata(bytes: &Vec<u8>, rtype: Type, start: usize, end: usize) -> Option<Vec<u8>> {
+    // How many fixed bytes come before the names, how many names, and what's after.
+    let (prefix, names) = match rtype {
+        Type::NS | Type::CNAME | Type::PTR | Type::MB | Type::MD | Type::MF | Type::MG | Type::MR => (0, 1),
+        Type::SOA | Type::MINFO | Type::RP => (0, 2),
+        Type::MX | Type::AFSDB | Type::RT | Type::KX => (2, 1),
+        Type::SRV => (6, 1),
+        _ => return None,
+    };
+
+    let mut output = bytes.get(start..start + prefix)?.to_vec();
+    let mut index = start + prefix;
+    for _ in 0..names {
+        let (name, next) = read_name(bytes, index)?;
+        output.append(&mut str_domain_to_dns_domain(&name));
+        index = next;
+    }
+
+    if index > end {
+        return None;
+    }
+
+    output.extend_from_slice(bytes.get(index..end)?);
+    Some(output)
+}
+
 pub fn collect_resources<T>(receiver: &mut Vec<T>, parser: PacketParserFn<T>,
                                 data: &Vec<u8>,     index: usize,
                                count: u16) -> usize {
@@ -149,6 +278,24 @@ pub fn collect_resources<T>(receiver: &mut Vec<T>, parser: PacketParserFn<T>,
     packet_index
 }
 
+/// Seconds since the Unix epoch, by the wall clock.
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0)
+}
+
+/// Gets cryptographically random bytes.
+pub fn random_bytes(count: usize) -> Vec<u8> {
+    let mut bytes = vec![0; count];
+    rand_bytes(&mut bytes).expect("Could not generate random bytes");
+    bytes
+}
+
+/// Gets a cryptographically random u16, for query IDs and source ports.
+pub fn random_u16() -> u16 {
+    let bytes = random_bytes(2);
+    ((bytes[0] as u16) << 8) | bytes[1] as u16
+}
+
 pub fn get_flag(byte: u8, index: u8) -> bool {
     byte & (0x01 << index) != 0
 }
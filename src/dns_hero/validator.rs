@@ -0,0 +1,1118 @@
+use std::collections::{ HashMap, HashSet };
+use std::sync::Mutex;
+
+use super::authoritative::{ dname_target, is_within, owner_name, parent_name, wildcard_at };
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::keys::{ is_supported_algorithm, verify_with_key };
+use super::pipeline::*;
+use super::util::{ read_name, unix_time };
+use super::zone::{ parse_zone, read_zone_file };
+
+/// The root zone's key signing keys, as IANA publishes them: KSK-2017 and KSK-2024.
+const ROOT_TRUST_ANCHORS: [&str; 2] = [
+    "20326 8 2 E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D",
+    "38696 8 2 683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16",
+];
+
+/// NSEC3 chains hashed more times than this are treated as unsigned, rather than
+/// spending the effort (RFC 9276 section 3.2).
+const MAX_NSEC3_ITERATIONS: u16 = 150;
+
+/// How many key and DS lookups deep a chain of trust may go before we give up on
+/// it. Each zone takes one of each.
+const MAX_CHAIN_DEPTH: usize = 64;
+
+/// How long validated keys and DS records are kept, at most. Their own TTLs can
+/// make it shorter.
+const MAX_KEY_CACHE_SECONDS: u64 = 3600;
+
+/// How long we remember that a zone is unsigned, or isn't a zone at all.
+const INSECURE_CACHE_SECONDS: u64 = 300;
+
+/// How long we remember that a zone's keys didn't check out, so a broken zone
+/// doesn't get its keys fetched again for every query.
+const BOGUS_CACHE_SECONDS: u64 = 60;
+
+/// A key we trust to start chains of trust from, either as a DS or the DNSKEY itself.
+#[derive(Clone)]
+pub struct TrustAnchor {
+    /// Lowercase, without a trailing dot.
+    pub  name: String,
+    pub rtype: Type,
+    pub  data: Vec<u8>,
+}
+
+impl TrustAnchor {
+    /// Reads a `trust-anchor` line, which is a name, DS or DNSKEY, and the record's
+    /// data the way a master file has it.
+    #[allow(clippy::ptr_arg)]
+    pub fn parse(args: &Vec<String>) -> Result<TrustAnchor, String> {
+        if args.len() < 3 {
+            return Err(String::from("trust-anchor takes a name, DS or DNSKEY, and the record's data"));
+        }
+
+        let text = format!("{}. 0 IN {}", args[0].trim_end_matches('.'), args[1..].join(" "));
+        let records = parse_zone(&text, &String::new()).map_err(|err| err.to_string())?;
+        match TrustAnchor::from_records(&records).as_slice() {
+            [anchor] => Ok(anchor.clone()),
+            _ => Err(format!("trust-anchor {} has to be a DS or DNSKEY", args[0])),
+        }
+    }
+
+    /// Reads the DS and DNSKEY records in a master file as trust anchors, and
+    /// ignores anything else in it.
+    pub fn load_file(path: &String) -> Result<Vec<TrustAnchor>, String> {
+        let records = read_zone_file(path, &String::new()).map_err(|err| err.to_string())?;
+        let anchors = TrustAnchor::from_records(&records);
+        if anchors.is_empty() {
+            return Err(format!("{} has no DS or DNSKEY records", path));
+        }
+
+        Ok(anchors)
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn from_records(records: &Vec<Resource>) -> Vec<TrustAnchor> {
+        records.iter()
+            .filter(|record| record.rtype == Type::DS || record.rtype == Type::DNSKEY)
+            .filter_map(|record| Some(TrustAnchor {
+                name: owner_name(record)?,
+                rtype: record.rtype,
+                data: record.data.clone(),
+            }))
+            .collect()
+    }
+
+    /// The root's own anchors, for when the config doesn't name any.
+    pub fn root() -> Vec<TrustAnchor> {
+        ROOT_TRUST_ANCHORS.iter()
+            .map(|ds| TrustAnchor::parse(&vec![String::from("."), String::from("DS"), String::from(*ds)]).unwrap())
+            .collect()
+    }
+
+    pub fn trusts(&self, key: &Dnskey) -> bool {
+        match self.rtype {
+            Type::DS => Ds::parse(&self.data).map(|ds| ds.matches(&self.name, key)).unwrap_or(false),
+            _ => Dnskey::parse(&self.data).as_ref() == Some(key),
+        }
+    }
+}
+
+/// Checks the DNSSEC signatures on everything the rest of the pipeline answers
+/// with, following the chain of trust down from our trust anchors (RFC 4035
+/// section 5). Answers that check out get the AD flag, ones from unsigned zones
+/// go through without it, and ones that should be signed but aren't, or don't
+/// check out, become a SERVFAIL. Queries with the CD flag are passed through
+/// untouched, since the client wants to do its own checking.
+///
+/// The keys and DS records it has had to look up along the way are kept for
+/// later queries.
+pub struct ValidateStage {
+    anchors: Vec<TrustAnchor>,
+    keys: Mutex<HashMap<String, (ZoneKeys, u64)>>,
+    delegations: Mutex<HashMap<String, (DsAnswer, u64)>>,
+}
+
+impl ValidateStage {
+    pub fn new(anchors: Vec<TrustAnchor>) -> ValidateStage {
+        ValidateStage {
+            anchors,
+            keys: Mutex::new(HashMap::new()),
+            delegations: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Handler for ValidateStage {
+    fn handle(&self, request: &mut Request, next: Next) -> Option<Response> {
+        if request.packet.checking_disabled {
+            return next.run(request);
+        }
+
+        let question = match request.packet.questions.first() {
+            Some(Question { label: Label::Domain(name), qtype, .. }) => (name.trim_end_matches('.').to_lowercase(), *qtype),
+            _ => return next.run(request),
+        };
+
+        // We need the signatures whether the client does or not, and we don't want
+        // anything further along throwing out the data that doesn't check out.
+        let original = request.clone();
+        request.modify(|packet| {
+            packet.checking_disabled = true;
+            set_dnssec_ok(packet);
+        });
+
+        let response = next.clone().run(request);
+        *request = original;
+        let response = response?;
+
+        let mut packet = DecomposedPacket::from_packet(&Packet::from_vec(&response.data));
+        let mut validation = Validation {
+            stage: self,
+            next,
+            request: request.clone(),
+            pending: HashSet::new(),
+        };
+
+        let client = &request.packet;
+        match validation.check_response(&packet, &question.0, question.1) {
+            Security::Secure => packet.authentic_data = dnssec_ok(client) || client.authentic_data,
+            Security::Insecure => packet.authentic_data = false,
+            Security::Bogus(reason) => {
+                println!("Answer for {} {} is bogus: {}", question.0, question.1, reason);
+                return Some(Response::local(make_servfail_packet(client.clone())));
+            },
+        }
+
+        packet.checking_disabled = false;
+        if !dnssec_ok(client) {
+            let is_wanted = |record: &Resource| record.rtype == question.1 || !matches!(record.rtype, Type::RRSIG | Type::NSEC | Type::NSEC3);
+            packet.answers.retain(is_wanted);
+            packet.authorities.retain(is_wanted);
+            packet.additional_records.retain(is_wanted);
+        }
+
+        packet.additional_records.retain(|record| record.rtype != Type::OPT);
+        if has_edns(client) {
+            packet.additional_records.push(opt_record(dnssec_ok(client)));
+        }
+
+        Some(Response {
+            data: packet.to_raw().data,
+            cache_policy: response.cache_policy,
+        })
+    }
+}
+
+/// How far some data can be trusted.
+#[derive(Clone)]
+enum Security {
+    /// Signed, all the way up to a trust anchor.
+    Secure,
+    /// From a zone the chain of trust proves is unsigned, or outside our anchors.
+    Insecure,
+    /// Should have been signed, and wasn't, or the signatures don't check out.
+    Bogus(String),
+}
+
+impl Security {
+    /// The less trustworthy of the two.
+    fn and(self, other: Security) -> Security {
+        match (self, other) {
+            (Security::Bogus(reason), _) | (_, Security::Bogus(reason)) => Security::Bogus(reason),
+            (Security::Insecure, _) | (_, Security::Insecure) => Security::Insecure,
+            _ => Security::Secure,
+        }
+    }
+}
+
+/// What we know about a zone's keys.
+#[derive(Clone)]
+enum ZoneKeys {
+    /// The zone keys in its DNSKEY RRset, which is signed by one its parent's DS
+    /// (or a trust anchor) vouches for.
+    Secure(Vec<Dnskey>),
+    Insecure,
+    Bogus(String),
+}
+
+/// What a DS lookup turned up.
+#[derive(Clone)]
+enum DsAnswer {
+    Secure(Vec<Ds>),
+    /// There's a delegation, and it's proved unsigned (or its parent is).
+    Insecure,
+    /// The name isn't a zone cut at all.
+    NotCut,
+    Bogus(String),
+}
+
+/// What a negative answer proves.
+pub enum Denial {
+    NoName,
+    /// The name exists, without the type. `delegation` says whether it's a zone cut.
+    NoData { delegation: bool },
+    /// The proof is from an unsigned part of the zone, with NSEC3 opt-out, so it
+    /// doesn't prove anything.
+    Insecure,
+}
+
+/// An RRset from a response, and the RRSIGs that came with it.
+pub struct SignedRrset {
+    pub      owner: String,
+    pub      rtype: Type,
+    pub    records: Vec<Resource>,
+    pub signatures: Vec<Rrsig>,
+}
+
+/// Groups records into RRsets, with the RRSIGs over each.
+#[allow(clippy::ptr_arg)]
+pub fn collect_rrsets(records: &Vec<Resource>) -> Vec<SignedRrset> {
+    let mut rrsets: Vec<SignedRrset> = vec![];
+    for record in records.iter().filter(|record| record.rtype != Type::RRSIG && record.rtype != Type::OPT) {
+        let owner = match owner_name(record) {
+            Some(owner) => owner,
+            None => continue,
+        };
+
+        match rrsets.iter_mut().find(|rrset| rrset.owner == owner && rrset.rtype == record.rtype) {
+            Some(rrset) => rrset.records.push(record.clone()),
+            None => rrsets.push(SignedRrset {
+                owner,
+                rtype: record.rtype,
+                records: vec![record.clone()],
+                signatures: vec![],
+            }),
+        }
+    }
+
+    for record in records.iter().filter(|record| record.rtype == Type::RRSIG) {
+        let (owner, rrsig) = match (owner_name(record), Rrsig::parse(&record.data)) {
+            (Some(owner), Some(rrsig)) => (owner, rrsig),
+            _ => continue,
+        };
+
+        if let Some(rrset) = rrsets.iter_mut().find(|rrset| rrset.owner == owner && rrset.rtype == rrsig.type_covered) {
+            rrset.signatures.push(rrsig);
+        }
+    }
+
+    rrsets
+}
+
+/// Follows an answer's CNAMEs from a name. None when the answer has what was asked
+/// for, and otherwise the name they end at, which is where the denial should be.
+/// The error is where there's a CNAME that can't be read.
+#[allow(clippy::ptr_arg)]
+pub fn chain_target(answers: &Vec<SignedRrset>, name: &String, qtype: Type) -> Result<Option<String>, String> {
+    let mut target = name.clone();
+    for _ in 0..answers.len() + 1 {
+        if answers.iter().any(|rrset| rrset.owner == target && (rrset.rtype == qtype || qtype == Type::ANY)) {
+            return Ok(None);
+        }
+
+        match answers.iter().find(|rrset| rrset.owner == target && rrset.rtype == Type::CNAME) {
+            Some(cname) if qtype != Type::CNAME => match read_name(&cname.records[0].data, 0) {
+                Some((next, _)) => target = next.trim_end_matches('.').to_lowercase(),
+                None => return Err(target),
+            },
+            _ => break,
+        }
+    }
+
+    Ok(Some(target))
+}
+
+/// The zone whose signatures on an RRset count. A DS is signed by the parent, and
+/// anything else by its own zone, which is at or above it.
+pub fn signer_zone(rrset: &SignedRrset) -> Option<String> {
+    rrset.signatures.iter()
+        .map(|rrsig| rrsig.signer.clone())
+        .filter(|signer| is_within(&rrset.owner, signer) && (rrset.rtype != Type::DS || *signer != rrset.owner))
+        .max_by_key(|signer| label_count(signer))
+}
+
+/// The last `count` labels of a name.
+pub fn ancestor_with_labels(name: &str, count: usize) -> String {
+    let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+    labels[labels.len() - count.min(labels.len())..].join(".")
+}
+
+/// The longest name both names are within.
+fn common_ancestor(a: &str, b: &str) -> String {
+    let a_labels: Vec<&str> = a.split('.').filter(|label| !label.is_empty()).rev().collect();
+    let b_labels: Vec<&str> = b.split('.').filter(|label| !label.is_empty()).rev().collect();
+    let mut common: Vec<&str> = a_labels.iter().zip(&b_labels).take_while(|(a, b)| a == b).map(|(a, _)| *a).collect();
+    common.reverse();
+    common.join(".")
+}
+
+/// Checks one RRSIG against one key: the window it's valid in, and the signature
+/// itself over the RRset in canonical form (RFC 4035 section 5.3).
+pub fn verify_signature(rrset: &SignedRrset, rrsig: &Rrsig, key: &Dnskey) -> Result<(), String> {
+    let now = unix_time() as u32;
+    if (now.wrapping_sub(rrsig.inception) as i32) < 0 {
+        return Err(format!("signature by key {} on {} {} isn't valid until {}", rrsig.key_tag, rrset.owner, rrset.rtype, write_timestamp(rrsig.inception)));
+    }
+    if (rrsig.expiration.wrapping_sub(now) as i32) < 0 {
+        return Err(format!("signature by key {} on {} {} expired at {}", rrsig.key_tag, rrset.owner, rrset.rtype, write_timestamp(rrsig.expiration)));
+    }
+
+    // A wildcard's signature counts fewer labels than the name it was expanded to
+    // (RFC 4035 section 5.3.2).
+    let owner = if (rrsig.labels as usize) < label_count(&rrset.owner) {
+        wildcard_at(&ancestor_with_labels(&rrset.owner, rrsig.labels as usize))
+    } else {
+        rrset.owner.clone()
+    };
+
+    if verify_with_key(&key.to_rdata(), &rrsig.signed_data(&owner, &rrset.records), &rrsig.signature) {
+        Ok(())
+    } else {
+        Err(format!("signature by key {} on {} {} doesn't check out", rrsig.key_tag, rrset.owner, rrset.rtype))
+    }
+}
+
+/// Checks an RRset against a zone's keys. Any one good signature will do. Ok has
+/// the closest encloser when the RRset came from a wildcard.
+#[allow(clippy::ptr_arg)]
+fn verify_rrset(rrset: &SignedRrset, zone: &String, keys: &Vec<Dnskey>) -> Result<Option<String>, String> {
+    let mut reason = format!("no signature on {} {} from a key of {}", rrset.owner, rrset.rtype, zone);
+    for rrsig in rrset.signatures.iter().filter(|rrsig| rrsig.signer == *zone && rrsig.type_covered == rrset.rtype) {
+        if rrsig.labels as usize > label_count(&rrset.owner) {
+            reason = format!("signature on {} {} counts too many labels", rrset.owner, rrset.rtype);
+            continue;
+        }
+
+        let matching_keys = keys.iter().filter(|key| {
+            key.algorithm == rrsig.algorithm && key.key_tag() == rrsig.key_tag && key.is_zone_key() && key.protocol == 3
+        });
+
+        for key in matching_keys {
+            match verify_signature(rrset, rrsig, key) {
+                Ok(()) if (rrsig.labels as usize) < label_count(&rrset.owner) => return Ok(Some(ancestor_with_labels(&rrset.owner, rrsig.labels as usize))),
+                Ok(()) => return Ok(None),
+                Err(err) => reason = err,
+            }
+        }
+    }
+
+    Err(reason)
+}
+
+/// The work of validating one response, with the subqueries that takes going
+/// through the rest of the pipeline.
+struct Validation<'a> {
+    stage: &'a ValidateStage,
+    next: Next,
+    request: Request,
+    /// The keys (DNSKEY) and delegations (DS) we're in the middle of finding, so
+    /// a loop of signatures or denials can't send us round in circles.
+    pending: HashSet<(String, Type)>,
+}
+
+impl<'a> Validation<'a> {
+    /// Asks the rest of the pipeline for something, with DNSSEC records and no checking.
+    #[allow(clippy::ptr_arg)]
+    fn query(&self, name: &String, qtype: Type) -> Option<DecomposedPacket> {
+        let mut request = self.request.clone();
+        request.modify(|packet| {
+            packet.questions = vec![Question {
+                label: Label::Domain(name.clone()),
+                qtype,
+                qclass: Class::Internet,
+            }];
+            packet.answers = vec![];
+            packet.authorities = vec![];
+            packet.additional_records = vec![opt_record(true)];
+            packet.recursion_desired = true;
+            packet.checking_disabled = true;
+        });
+
+        let response = self.next.clone().run(&mut request)?;
+        Some(DecomposedPacket::from_packet(&Packet::from_vec(&response.data)))
+    }
+
+    /// The trust anchors at a name, and whether any are above it.
+    fn anchors_at(&self, name: &String) -> (Vec<&'a TrustAnchor>, bool) {
+        let stage = self.stage;
+        let here = stage.anchors.iter().filter(|anchor| anchor.name == *name).collect();
+        let above = stage.anchors.iter().any(|anchor| is_within(name, &anchor.name));
+        (here, above)
+    }
+
+    /// Checks a whole response to a question: each RRset in the answer, any
+    /// wildcards they came from, and the denial at the end of the CNAME chain if
+    /// the answer isn't there.
+    fn check_response(&mut self, packet: &DecomposedPacket, name: &String, qtype: Type) -> Security {
+        match packet.response_code {
+            ResponseCode::NoError | ResponseCode::NXDomain => {},
+            _ => return Security::Insecure,
+        }
+
+        let answers = collect_rrsets(&packet.answers);
+        let mut security = Security::Secure;
+
+        for rrset in &answers {
+            if self.is_synthesized(rrset, &answers) {
+                continue;
+            }
+
+            let (checked, wildcard) = self.check_rrset(rrset);
+            security = security.and(checked);
+            if let (Security::Secure, Some(encloser)) = (&security, wildcard) {
+                security = security.and(self.check_wildcard_answer(packet, &rrset.owner, &encloser));
+            }
+        }
+
+        // Follow the answer along any CNAMEs and DNAMEs, to see if it got there.
+        let target = match chain_target(&answers, name, qtype) {
+            Ok(Some(target)) => target,
+            Ok(None) => return security,
+            Err(target) => return Security::Bogus(format!("CNAME at {} is garbled", target)),
+        };
+
+        let denial = match self.check_negative(packet, &target, qtype) {
+            Ok(denial) => denial,
+            Err(security) => return security,
+        };
+
+        match (denial, packet.response_code) {
+            (Denial::Insecure, _) => Security::Insecure,
+            (Denial::NoName, ResponseCode::NXDomain) | (Denial::NoData { .. }, ResponseCode::NoError) => security,
+            _ => Security::Bogus(format!("denial for {} {} doesn't match the response code", target, qtype)),
+        }
+    }
+
+    /// Whether an RRset is a CNAME made up from a DNAME in the same answer (RFC
+    /// 6672 section 5.3.1). Those aren't signed, and don't need to be.
+    #[allow(clippy::ptr_arg)]
+    fn is_synthesized(&self, rrset: &SignedRrset, answers: &Vec<SignedRrset>) -> bool {
+        if rrset.rtype != Type::CNAME || !rrset.signatures.is_empty() {
+            return false;
+        }
+
+        let target = match read_name(&rrset.records[0].data, 0) {
+            Some((target, _)) => target.trim_end_matches('.').to_lowercase(),
+            None => return false,
+        };
+
+        answers.iter().filter(|dname| dname.rtype == Type::DNAME && dname.owner != rrset.owner && is_within(&rrset.owner, &dname.owner)).any(|dname| {
+            match read_name(&dname.records[0].data, 0) {
+                Some((replacement, _)) => target == dname_target(&rrset.owner, &dname.owner, &replacement.trim_end_matches('.').to_lowercase()),
+                None => false,
+            }
+        })
+    }
+
+    /// Checks an RRset's signatures against the keys of the zone that signed it,
+    /// or, when it isn't signed, that it's from a zone that isn't either. The
+    /// second part is the closest encloser if it was expanded from a wildcard.
+    fn check_rrset(&mut self, rrset: &SignedRrset) -> (Security, Option<String>) {
+        let signer = match signer_zone(rrset) {
+            Some(signer) => signer,
+            None => {
+                let zone = if rrset.rtype == Type::DS { parent_name(&rrset.owner) } else { rrset.owner.clone() };
+                return match self.zone_security(&zone) {
+                    Security::Secure => (Security::Bogus(format!("{} {} isn't signed", rrset.owner, rrset.rtype)), None),
+                    security => (security, None),
+                };
+            },
+        };
+
+        match self.zone_keys(&signer) {
+            ZoneKeys::Secure(keys) => match verify_rrset(rrset, &signer, &keys) {
+                Ok(wildcard) => (Security::Secure, wildcard),
+                Err(reason) => (Security::Bogus(reason), None),
+            },
+            ZoneKeys::Insecure => (Security::Insecure, None),
+            ZoneKeys::Bogus(reason) => (Security::Bogus(reason), None),
+        }
+    }
+
+    /// Finds how far a zone can be trusted, by walking down from the closest trust
+    /// anchor above it, one label at a time, looking for zone cuts.
+    #[allow(clippy::ptr_arg)]
+    fn zone_security(&mut self, name: &String) -> Security {
+        let anchor = self.stage.anchors.iter()
+            .filter(|anchor| is_within(name, &anchor.name))
+            .max_by_key(|anchor| label_count(&anchor.name))
+            .map(|anchor| anchor.name.clone());
+
+        let anchor = match anchor {
+            Some(anchor) => anchor,
+            None => return Security::Insecure,
+        };
+
+        match self.zone_keys(&anchor) {
+            ZoneKeys::Secure(_) => {},
+            ZoneKeys::Insecure => return Security::Insecure,
+            ZoneKeys::Bogus(reason) => return Security::Bogus(reason),
+        }
+
+        let labels: Vec<&str> = name.split('.').filter(|label| !label.is_empty()).collect();
+        for count in label_count(&anchor) + 1..=labels.len() {
+            let child = ancestor_with_labels(name, count);
+            match self.lookup_ds(&child) {
+                DsAnswer::Secure(_) => match self.zone_keys(&child) {
+                    ZoneKeys::Secure(_) => {},
+                    ZoneKeys::Insecure => return Security::Insecure,
+                    ZoneKeys::Bogus(reason) => return Security::Bogus(reason),
+                },
+                DsAnswer::Insecure => return Security::Insecure,
+                DsAnswer::NotCut => {},
+                DsAnswer::Bogus(reason) => return Security::Bogus(reason),
+            }
+        }
+
+        Security::Secure
+    }
+
+    /// Finds a zone's keys, and checks that they're the ones its parent's DS
+    /// records (or a trust anchor) vouch for.
+    fn zone_keys(&mut self, zone: &String) -> ZoneKeys {
+        let now = unix_time();
+        if let Some((keys, expires)) = self.stage.keys.lock().unwrap().get(zone) {
+            if *expires > now {
+                return keys.clone();
+            }
+        }
+
+        let step = (zone.clone(), Type::DNSKEY);
+        if self.pending.contains(&step) || self.pending.len() >= MAX_CHAIN_DEPTH {
+            return ZoneKeys::Bogus(format!("chain of trust for {} goes round in circles", zone));
+        }
+
+        self.pending.insert(step.clone());
+        let (keys, ttl) = self.fetch_zone_keys(zone);
+        self.pending.remove(&step);
+
+        let cache_seconds = match keys {
+            ZoneKeys::Secure(_) => ttl.min(MAX_KEY_CACHE_SECONDS),
+            ZoneKeys::Insecure => INSECURE_CACHE_SECONDS,
+            ZoneKeys::Bogus(_) => BOGUS_CACHE_SECONDS,
+        };
+
+        self.stage.keys.lock().unwrap().insert(zone.clone(), (keys.clone(), now + cache_seconds));
+        keys
+    }
+
+    fn fetch_zone_keys(&mut self, zone: &String) -> (ZoneKeys, u64) {
+        let (anchors, is_anchored) = self.anchors_at(zone);
+        if !is_anchored {
+            return (ZoneKeys::Insecure, 0);
+        }
+
+        let delegation = if anchors.is_empty() {
+            match self.lookup_ds(zone) {
+                DsAnswer::Secure(ds) => ds,
+                DsAnswer::Insecure => return (ZoneKeys::Insecure, 0),
+                DsAnswer::NotCut => return (ZoneKeys::Bogus(format!("{} isn't a zone", zone)), 0),
+                DsAnswer::Bogus(reason) => return (ZoneKeys::Bogus(reason), 0),
+            }
+        } else {
+            vec![]
+        };
+
+        // Only DS records we can make sense of count. If there aren't any, the zone
+        // might as well be unsigned (RFC 4035 section 5.2).
+        let usable: Vec<&Ds> = delegation.iter().filter(|ds| is_supported_algorithm(ds.algorithm) && is_supported_digest(ds.digest_type)).collect();
+        if anchors.is_empty() && usable.is_empty() {
+            return (ZoneKeys::Insecure, 0);
+        }
+
+        println!("Fetching keys for {}", if zone.is_empty() { "." } else { zone });
+        let packet = match self.query(zone, Type::DNSKEY) {
+            Some(packet) => packet,
+            None => return (ZoneKeys::Bogus(format!("couldn't get the keys for {}", zone)), 0),
+        };
+
+        let rrset = match collect_rrsets(&packet.answers).into_iter().find(|rrset| rrset.owner == *zone && rrset.rtype == Type::DNSKEY) {
+            Some(rrset) => rrset,
+            None => return (ZoneKeys::Bogus(format!("{} has no keys", zone)), 0),
+        };
+
+        let keys: Vec<Dnskey> = rrset.records.iter().filter_map(|record| Dnskey::parse(&record.data)).collect();
+        let entry_keys: Vec<Dnskey> = keys.iter()
+            .filter(|key| anchors.iter().any(|anchor| anchor.trusts(key)) || usable.iter().any(|ds| ds.algorithm == key.algorithm && ds.matches(zone, key)))
+            .cloned()
+            .collect();
+
+        if entry_keys.is_empty() {
+            return (ZoneKeys::Bogus(format!("none of the keys for {} match its DS records or trust anchors", zone)), 0);
+        }
+
+        match verify_rrset(&rrset, zone, &entry_keys) {
+            Ok(_) => {
+                let ttl = rrset.records.iter().map(|record| record.ttl).min().unwrap_or(0);
+                (ZoneKeys::Secure(keys.into_iter().filter(Dnskey::is_zone_key).collect()), ttl as u64)
+            },
+            Err(reason) => (ZoneKeys::Bogus(reason), 0),
+        }
+    }
+
+    /// Asks for the DS records at a name, and checks what comes back: the records,
+    /// or proof there aren't any.
+    fn lookup_ds(&mut self, name: &String) -> DsAnswer {
+        let now = unix_time();
+        if let Some((answer, expires)) = self.stage.delegations.lock().unwrap().get(name) {
+            if *expires > now {
+                return answer.clone();
+            }
+        }
+
+        let step = (name.clone(), Type::DS);
+        if self.pending.contains(&step) || self.pending.len() >= MAX_CHAIN_DEPTH {
+            return DsAnswer::Bogus(format!("DS lookups for {} go round in circles", name));
+        }
+
+        self.pending.insert(step.clone());
+        let (answer, ttl) = self.fetch_ds(name);
+        self.pending.remove(&step);
+
+        let cache_seconds = match answer {
+            DsAnswer::Secure(_) => ttl.min(MAX_KEY_CACHE_SECONDS),
+            DsAnswer::Insecure | DsAnswer::NotCut => INSECURE_CACHE_SECONDS,
+            DsAnswer::Bogus(_) => BOGUS_CACHE_SECONDS,
+        };
+
+        self.stage.delegations.lock().unwrap().insert(name.clone(), (answer.clone(), now + cache_seconds));
+        answer
+    }
+
+    fn fetch_ds(&mut self, name: &String) -> (DsAnswer, u64) {
+        let packet = match self.query(name, Type::DS) {
+            Some(packet) => packet,
+            None => return (DsAnswer::Bogus(format!("couldn't get the DS records for {}", name)), 0),
+        };
+
+        if let Some(rrset) = collect_rrsets(&packet.answers).into_iter().find(|rrset| rrset.owner == *name && rrset.rtype == Type::DS) {
+            return match self.check_rrset(&rrset) {
+                (Security::Secure, _) => {
+                    let ttl = rrset.records.iter().map(|record| record.ttl).min().unwrap_or(0);
+                    (DsAnswer::Secure(rrset.records.iter().filter_map(|record| Ds::parse(&record.data)).collect()), ttl as u64)
+                },
+                (Security::Insecure, _) => (DsAnswer::Insecure, 0),
+                (Security::Bogus(reason), _) => (DsAnswer::Bogus(reason), 0),
+            };
+        }
+
+        match self.check_negative(&packet, name, Type::DS) {
+            Ok(Denial::NoData { delegation: true }) | Ok(Denial::Insecure) => (DsAnswer::Insecure, 0),
+            Ok(Denial::NoData { delegation: false }) | Ok(Denial::NoName) => (DsAnswer::NotCut, 0),
+            Err(Security::Bogus(reason)) => (DsAnswer::Bogus(reason), 0),
+            Err(_) => (DsAnswer::Insecure, 0),
+        }
+    }
+
+    /// Checks the proof in a negative answer: that it's signed, and what it
+    /// proves. Err when it can't prove anything, because it's insecure or bogus.
+    fn check_negative(&mut self, packet: &DecomposedPacket, name: &String, qtype: Type) -> Result<Denial, Security> {
+        let rrsets = collect_rrsets(&packet.authorities);
+
+        let soa = match rrsets.iter().find(|rrset| rrset.rtype == Type::SOA && is_within(name, &rrset.owner)) {
+            Some(soa) => soa,
+            None => {
+                let zone = if qtype == Type::DS { parent_name(name) } else { name.clone() };
+                return Err(match self.zone_security(&zone) {
+                    Security::Secure => Security::Bogus(format!("denial for {} {} has no SOA", name, qtype)),
+                    security => security,
+                });
+            },
+        };
+
+        match self.check_rrset(soa).0 {
+            Security::Secure => {},
+            security => return Err(security),
+        }
+
+        let zone = soa.owner.clone();
+        let mut nsecs = vec![];
+        let mut nsec3s = vec![];
+        for rrset in rrsets.iter().filter(|rrset| is_within(&rrset.owner, &zone)) {
+            if rrset.rtype != Type::NSEC && rrset.rtype != Type::NSEC3 {
+                continue;
+            }
+
+            // The SOA says the zone is signed, so there's no need to go looking for
+            // a zone cut that would excuse this.
+            if rrset.signatures.is_empty() {
+                return Err(Security::Bogus(format!("{} {} isn't signed, though its zone is", rrset.owner, rrset.rtype)));
+            }
+
+            match self.check_rrset(rrset).0 {
+                Security::Secure => {},
+                Security::Insecure => return Err(Security::Bogus(format!("{} {} isn't signed, though its zone is", rrset.owner, rrset.rtype))),
+                Security::Bogus(reason) => return Err(Security::Bogus(reason)),
+            }
+
+            for record in &rrset.records {
+                match rrset.rtype {
+                    Type::NSEC => nsecs.extend(Nsec::parse(&record.data).map(|nsec| (rrset.owner.clone(), nsec))),
+                    _ => nsec3s.extend(Nsec3::parse(&record.data).map(|nsec3| (rrset.owner.clone(), nsec3))),
+                }
+            }
+        }
+
+        if !nsecs.is_empty() {
+            nsec_denial(&nsecs, name, qtype).map_err(Security::Bogus)
+        } else if !nsec3s.is_empty() {
+            nsec3_denial(&nsec3s, &zone, name, qtype).map_err(Security::Bogus)
+        } else {
+            Err(Security::Bogus(format!("denial for {} {} has no NSEC or NSEC3 records", name, qtype)))
+        }
+    }
+
+    /// Checks the proof that comes with an answer expanded from a wildcard: that
+    /// the name it was asked for doesn't exist on its own (RFC 4035 section 5.3.4).
+    fn check_wildcard_answer(&mut self, packet: &DecomposedPacket, owner: &String, encloser: &String) -> Security {
+        let rrsets = collect_rrsets(&packet.authorities);
+        let proofs: Vec<&SignedRrset> = rrsets.iter().filter(|rrset| rrset.rtype == Type::NSEC || rrset.rtype == Type::NSEC3).collect();
+
+        for rrset in proofs {
+            // The answer checked out, so its zone is signed, and so are its proofs.
+            if rrset.signatures.is_empty() {
+                return Security::Bogus(format!("proof for wildcard answer {} isn't signed", owner));
+            }
+
+            match self.check_rrset(rrset).0 {
+                Security::Secure => {},
+                security => return security.and(Security::Bogus(format!("proof for wildcard answer {} isn't signed", owner))),
+            }
+
+            match proves_wildcard_answer(rrset, owner, encloser) {
+                Some(true) => return Security::Secure,
+                Some(false) => {},
+                None => return Security::Insecure,
+            }
+        }
+
+        Security::Bogus(format!("no proof that {} doesn't exist, for its wildcard answer", owner))
+    }
+}
+
+/// Whether an NSEC or NSEC3 RRset proves that a name answered from a wildcard
+/// doesn't exist on its own, so the wildcard was the right answer. None when it's
+/// an NSEC3 chain we don't bother with.
+#[allow(clippy::ptr_arg)]
+pub fn proves_wildcard_answer(rrset: &SignedRrset, owner: &String, encloser: &String) -> Option<bool> {
+    let next_closer = ancestor_with_labels(owner, label_count(encloser) + 1);
+    for record in &rrset.records {
+        let is_proof = match rrset.rtype {
+            Type::NSEC => Nsec::parse(&record.data).map(|nsec| nsec_covers(&rrset.owner, &nsec.next, owner)).unwrap_or(false),
+            _ => match (Nsec3::parse(&record.data), base32hex_decode(rrset.owner.split('.').next().unwrap_or(""))) {
+                (Some(nsec3), Ok(owner_hash)) => {
+                    if nsec3.iterations > MAX_NSEC3_ITERATIONS || nsec3.hash_algorithm != NSEC3_SHA1 {
+                        return None;
+                    }
+                    nsec3_covers(&owner_hash, &nsec3.next_hashed, &nsec3_hash(&next_closer, &nsec3.salt, nsec3.iterations))
+                },
+                _ => false,
+            },
+        };
+
+        if is_proof {
+            return Some(true);
+        }
+    }
+
+    Some(false)
+}
+
+/// Checks the types an NSEC or NSEC3 at the name itself says are there. Proof
+/// from the parent's side of a zone cut only works for DS, and proof from the
+/// child's apex only for anything but (RFC 6840 section 4.1).
+#[allow(clippy::ptr_arg)]
+fn check_types(types: &Vec<Type>, name: &String, qtype: Type) -> Result<Denial, String> {
+    if types.contains(&qtype) || types.contains(&Type::CNAME) {
+        return Err(format!("{} has {} after all", name, qtype));
+    }
+
+    let is_delegation = types.contains(&Type::NS) && !types.contains(&Type::SOA);
+    if qtype == Type::DS && types.contains(&Type::SOA) && !name.is_empty() {
+        return Err(format!("denial for {} DS is from the child zone", name));
+    }
+    if qtype != Type::DS && is_delegation {
+        return Err(format!("denial for {} {} is from the parent zone", name, qtype));
+    }
+
+    Ok(Denial::NoData { delegation: is_delegation })
+}
+
+/// Works out what some NSECs prove about a name (RFC 4035 section 5.4): NoData
+/// when there's one at the name, or at the wildcard that would stand in for it,
+/// and NoName when both are covered instead.
+#[allow(clippy::ptr_arg)]
+pub fn nsec_denial(nsecs: &Vec<(String, Nsec)>, name: &String, qtype: Type) -> Result<Denial, String> {
+    if let Some((_, nsec)) = nsecs.iter().find(|(owner, _)| owner == name) {
+        return check_types(&nsec.types, name, qtype);
+    }
+
+    let (owner, nsec) = match nsecs.iter().find(|(owner, nsec)| nsec_covers(owner, &nsec.next, name)) {
+        Some(covering) => covering,
+        None => return Err(format!("nothing proves {} doesn't exist", name)),
+    };
+
+    // Names below a zone cut or a DNAME aren't the zone's to deny.
+    let is_cut = (nsec.types.contains(&Type::NS) && !nsec.types.contains(&Type::SOA)) || nsec.types.contains(&Type::DNAME);
+    if is_cut && is_within(name, owner) {
+        return Err(format!("denial for {} is from above a zone cut", name));
+    }
+
+    // If the next name is below this one, this one is an empty non-terminal.
+    let next = nsec.next.trim_end_matches('.').to_ascii_lowercase();
+    if is_within(&next, name) {
+        return Ok(Denial::NoData { delegation: false });
+    }
+
+    let owner_ancestor = common_ancestor(owner, name);
+    let next_ancestor = common_ancestor(&next, name);
+    let encloser = if label_count(&owner_ancestor) > label_count(&next_ancestor) { owner_ancestor } else { next_ancestor };
+
+    let wildcard = wildcard_at(&encloser);
+    if let Some((_, nsec)) = nsecs.iter().find(|(owner, _)| *owner == wildcard) {
+        return check_types(&nsec.types, &wildcard, qtype);
+    }
+
+    if nsecs.iter().any(|(owner, nsec)| nsec_covers(owner, &nsec.next, &wildcard)) {
+        Ok(Denial::NoName)
+    } else {
+        Err(format!("nothing proves there's no {} to stand in for {}", wildcard, name))
+    }
+}
+
+/// The same for NSEC3s (RFC 5155 section 8), where it takes a closest encloser
+/// proof to show a name doesn't exist. Opt-out spans, and chains hashed too
+/// many times, don't prove anything.
+#[allow(clippy::ptr_arg)]
+pub fn nsec3_denial(nsec3s: &Vec<(String, Nsec3)>, zone: &String, name: &String, qtype: Type) -> Result<Denial, String> {
+    let nsec3s: Vec<(Vec<u8>, &Nsec3)> = nsec3s.iter()
+        .filter(|(owner, nsec3)| nsec3.hash_algorithm == NSEC3_SHA1 && parent_name(owner) == *zone)
+        .filter_map(|(owner, nsec3)| Some((base32hex_decode(owner.split('.').next()?).ok()?, nsec3)))
+        .collect();
+
+    let (salt, iterations) = match nsec3s.first() {
+        Some((_, nsec3)) => (nsec3.salt.clone(), nsec3.iterations),
+        None => return Ok(Denial::Insecure),
+    };
+
+    if iterations > MAX_NSEC3_ITERATIONS {
+        return Ok(Denial::Insecure);
+    }
+
+    let matching = |name: &String| {
+        let hash = nsec3_hash(name, &salt, iterations);
+        nsec3s.iter().find(|(owner_hash, _)| *owner_hash == hash).map(|(_, nsec3)| *nsec3)
+    };
+    let covering = |name: &String| {
+        let hash = nsec3_hash(name, &salt, iterations);
+        nsec3s.iter().find(|(owner_hash, nsec3)| nsec3_covers(owner_hash, &nsec3.next_hashed, &hash)).map(|(_, nsec3)| *nsec3)
+    };
+
+    if let Some(nsec3) = matching(name) {
+        return check_types(&nsec3.types, name, qtype);
+    }
+
+    // The closest encloser is the nearest ancestor that's there, and the next
+    // closer name, one label below it, has to be covered.
+    let mut next_closer = name.clone();
+    let mut encloser = parent_name(name);
+    let encloser_nsec3 = loop {
+        if let Some(nsec3) = matching(&encloser) {
+            break nsec3;
+        }
+        if encloser == *zone || encloser.is_empty() {
+            return Err(format!("no closest encloser for {}", name));
+        }
+        next_closer = encloser.clone();
+        encloser = parent_name(&encloser);
+    };
+
+    let is_cut = (encloser_nsec3.types.contains(&Type::NS) && !encloser_nsec3.types.contains(&Type::SOA)) || encloser_nsec3.types.contains(&Type::DNAME);
+    if is_cut {
+        return Err(format!("denial for {} is from above a zone cut", name));
+    }
+
+    let cover = match covering(&next_closer) {
+        Some(cover) => cover,
+        None => return Err(format!("nothing proves {} doesn't exist", next_closer)),
+    };
+
+    if cover.flags & NSEC3_OPT_OUT_FLAG != 0 {
+        return Ok(Denial::Insecure);
+    }
+
+    let wildcard = wildcard_at(&encloser);
+    if let Some(nsec3) = matching(&wildcard) {
+        return check_types(&nsec3.types, &wildcard, qtype);
+    }
+
+    if covering(&wildcard).is_some() {
+        Ok(Denial::NoName)
+    } else {
+        Err(format!("nothing proves there's no {} to stand in for {}", wildcard, name))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns_hero::authoritative::Zone;
+    use crate::dns_hero::signer::DenialChain;
+    use crate::dns_hero::testing::{ answer, hierarchy, signed_with, zone_records };
+
+    /// Serves the stand-in hierarchy at the end of a pipeline, with a chance to
+    /// mess with each response on its way out.
+    struct Hierarchy {
+        zones: Vec<Zone>,
+        tamper: fn(&mut DecomposedPacket),
+    }
+
+    impl Handler for Hierarchy {
+        fn handle(&self, request: &mut Request, _: Next) -> Option<Response> {
+            let question = request.packet.questions.first()?;
+            let name = match &question.label {
+                Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+                Label::Pointer(_) => return None,
+            };
+
+            let mut packet = answer(&self.zones, &name, question.qtype).ok()?;
+            packet.id = request.packet.id;
+            (self.tamper)(&mut packet);
+            Some(Response::from_upstream(packet.to_raw().data))
+        }
+    }
+
+    fn untouched(_: &mut DecomposedPacket) {}
+
+    /// Asks for a name through a validate stage in front of the hierarchy, as a
+    /// client that wants DNSSEC.
+    fn validate(name: &str, qtype: Type, tamper: fn(&mut DecomposedPacket)) -> DecomposedPacket {
+        let (zones, anchors) = hierarchy();
+        let pipeline = Pipeline::new(vec![Box::new(ValidateStage::new(anchors)), Box::new(Hierarchy { zones, tamper })]);
+
+        let mut query = DecomposedPacket::new();
+        query.id = 1234;
+        query.recursion_desired = true;
+        query.questions.push(Question { label: Label::Domain(String::from(name)), qtype, qclass: Class::Internet });
+        query.additional_records.push(opt_record(true));
+
+        let client = ClientInfo { address: "127.0.0.1:5353".parse().unwrap(), over_tcp: true };
+        let mut request = Request::new(query.to_raw().data, client);
+        DecomposedPacket::from_packet(&Packet::from_vec(&pipeline.resolve(&mut request)))
+    }
+
+    fn is_servfail(packet: &DecomposedPacket) -> bool {
+        matches!(packet.response_code, ResponseCode::ServFail)
+    }
+
+    fn name(text: &str) -> String {
+        String::from(text)
+    }
+
+    fn nsecs(zone: &Zone) -> Vec<(String, Nsec)> {
+        zone.records_of_type(Type::NSEC).into_iter()
+            .filter_map(|record| Some((owner_name(record)?, Nsec::parse(&record.data)?)))
+            .collect()
+    }
+
+    fn nsec3s(zone: &Zone) -> Vec<(String, Nsec3)> {
+        zone.records_of_type(Type::NSEC3).into_iter()
+            .filter_map(|record| Some((owner_name(record)?, Nsec3::parse(&record.data)?)))
+            .collect()
+    }
+
+    fn zone<'a>(zones: &'a [Zone], origin: &str) -> &'a Zone {
+        zones.iter().find(|zone| zone.origin == origin).unwrap()
+    }
+
+    #[test]
+    fn signed_answers_and_denials_are_authentic() {
+        let packet = validate("www.sec.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NoError) && packet.authentic_data);
+        assert_eq!(packet.answers.iter().filter(|record| record.rtype == Type::A).count(), 1);
+
+        let packet = validate("nope.sec.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NXDomain) && packet.authentic_data);
+
+        let packet = validate("www.sec.test", Type::AAAA, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NoError) && packet.authentic_data);
+    }
+
+    #[test]
+    fn wildcard_answers_need_their_proof() {
+        let packet = validate("anything.wild.sec.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NoError) && packet.authentic_data);
+
+        let without_proof = |packet: &mut DecomposedPacket| packet.authorities.retain(|record| record.rtype != Type::NSEC && record.rtype != Type::RRSIG);
+        assert!(is_servfail(&validate("anything.wild.sec.test", Type::A, without_proof)));
+    }
+
+    #[test]
+    fn unsigned_zones_are_insecure() {
+        let packet = validate("www.unsg.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NoError) && !packet.authentic_data);
+        assert_eq!(packet.answers.len(), 1);
+    }
+
+    #[test]
+    fn broken_chains_are_bogus() {
+        assert!(is_servfail(&validate("www.badds.test", Type::A, untouched)));
+
+        let without_signatures = |packet: &mut DecomposedPacket| packet.answers.retain(|record| record.rtype != Type::RRSIG);
+        assert!(is_servfail(&validate("www.sec.test", Type::A, without_signatures)));
+    }
+
+    #[test]
+    fn unsigned_nsec_under_a_signed_soa_is_bogus() {
+        // Every NSEC comes without its signature, including the ones proving there's
+        // no DS at a deeper name, which used to send the validator round in circles.
+        let unsigned_nsecs = |packet: &mut DecomposedPacket| {
+            let covers_nsec = |record: &Resource| record.rtype == Type::RRSIG && Rrsig::parse(&record.data).map(|rrsig| rrsig.type_covered == Type::NSEC).unwrap_or(false);
+            packet.authorities.retain(|record| !covers_nsec(record));
+        };
+        assert!(is_servfail(&validate("nope.www.sec.test", Type::A, unsigned_nsecs)));
+        assert!(is_servfail(&validate("www.sec.test", Type::AAAA, unsigned_nsecs)));
+    }
+
+    #[test]
+    fn nsec3_opt_out_is_insecure() {
+        let packet = validate("www.n3.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NoError) && packet.authentic_data);
+
+        let packet = validate("nope.n3.test", Type::A, untouched);
+        assert!(matches!(packet.response_code, ResponseCode::NXDomain) && !packet.authentic_data);
+
+        let packet = validate("www.insecure.n3.test", Type::A, untouched);
+        assert!(!is_servfail(&packet) && !packet.authentic_data);
+    }
+
+    #[test]
+    fn nsec_denials() {
+        let (zones, _) = hierarchy();
+        let sec = nsecs(zone(&zones, "sec.test"));
+
+        assert!(matches!(nsec_denial(&sec, &name("www.sec.test"), Type::AAAA), Ok(Denial::NoData { delegation: false })));
+        assert!(nsec_denial(&sec, &name("www.sec.test"), Type::A).is_err());
+        assert!(matches!(nsec_denial(&sec, &name("nope.sec.test"), Type::A), Ok(Denial::NoName)));
+        // An empty non-terminal, above the wildcard.
+        assert!(matches!(nsec_denial(&sec, &name("wild.sec.test"), Type::A), Ok(Denial::NoData { delegation: false })));
+        // The wildcard has an answer for it, or doesn't.
+        assert!(nsec_denial(&sec, &name("x.wild.sec.test"), Type::A).is_err());
+        assert!(matches!(nsec_denial(&sec, &name("x.wild.sec.test"), Type::AAAA), Ok(Denial::NoData { delegation: false })));
+
+        let test = nsecs(zone(&zones, "test"));
+        assert!(matches!(nsec_denial(&test, &name("unsg.test"), Type::DS), Ok(Denial::NoData { delegation: true })));
+        assert!(nsec_denial(&test, &name("unsg.test"), Type::A).is_err());
+        assert!(nsec_denial(&test, &name("www.unsg.test"), Type::A).is_err());
+        assert!(nsec_denial(&test, &name("sec.test"), Type::DS).is_err());
+    }
+
+    #[test]
+    fn nsec3_denials() {
+        let chain = DenialChain::Nsec3 { salt: vec![1, 2], iterations: 2, opt_out: false };
+        let (records, _, _) = signed_with("h.test", zone_records("h.test", "www A 10.0.0.1\n*.wild A 10.0.0.2\nsub NS ns.sub\n"), vec![], chain);
+        let hashed = Zone::new(&name("h.test"), records).unwrap();
+        let h = nsec3s(&hashed);
+        let origin = name("h.test");
+
+        assert!(matches!(nsec3_denial(&h, &origin, &name("www.h.test"), Type::AAAA), Ok(Denial::NoData { delegation: false })));
+        assert!(nsec3_denial(&h, &origin, &name("www.h.test"), Type::A).is_err());
+        assert!(matches!(nsec3_denial(&h, &origin, &name("nope.h.test"), Type::A), Ok(Denial::NoName)));
+        assert!(matches!(nsec3_denial(&h, &origin, &name("x.wild.h.test"), Type::AAAA), Ok(Denial::NoData { delegation: false })));
+        assert!(nsec3_denial(&h, &origin, &name("x.wild.h.test"), Type::A).is_err());
+        assert!(matches!(nsec3_denial(&h, &origin, &name("sub.h.test"), Type::DS), Ok(Denial::NoData { delegation: true })));
+        assert!(nsec3_denial(&h, &origin, &name("www.sub.h.test"), Type::A).is_err());
+
+        // With opt-out, the unsigned delegation isn't in the chain at all, and the
+        // spans that would have had it don't prove anything.
+        let (zones, _) = hierarchy();
+        let n3 = nsec3s(zone(&zones, "n3.test"));
+        let origin = name("n3.test");
+        assert!(matches!(nsec3_denial(&n3, &origin, &name("www.n3.test"), Type::AAAA), Ok(Denial::NoData { delegation: false })));
+        assert!(matches!(nsec3_denial(&n3, &origin, &name("nope.n3.test"), Type::A), Ok(Denial::Insecure)));
+        assert!(matches!(nsec3_denial(&n3, &origin, &name("insecure.n3.test"), Type::DS), Ok(Denial::Insecure)));
+    }
+}
@@ -0,0 +1,1054 @@
+use base64::decode;
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{ Ipv4Addr, Ipv6Addr };
+use std::path::Path;
+
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::util::*;
+
+/// How deep `$INCLUDE`s can nest before we assume they're going in circles.
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+/// What's wrong with a master file, and where.
+#[derive(Debug)]
+pub struct ZoneError {
+    /// The file the error is in, which may be one it `$INCLUDE`s. None for text
+    /// that didn't come from a file.
+    pub file: Option<String>,
+    /// Both start at 1. They're 0 when the error isn't about any one place, like
+    /// when the file can't be read at all.
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl ZoneError {
+    fn new(line: usize, column: usize, message: String) -> ZoneError {
+        ZoneError {
+            file: None,
+            line,
+            column,
+            message,
+        }
+    }
+
+    fn at(token: &Token, message: String) -> ZoneError {
+        ZoneError::new(token.line, token.column, message)
+    }
+
+    #[allow(clippy::ptr_arg)]
+    fn in_file(mut self, path: &String) -> ZoneError {
+        if self.file.is_none() {
+            self.file = Some(path.clone());
+        }
+        self
+    }
+}
+
+impl fmt::Display for ZoneError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.file, self.line) {
+            (Some(file), 0) => write!(f, "{}: {}", file, self.message),
+            (Some(file), line) => write!(f, "{}:{}:{}: {}", file, line, self.column, self.message),
+            (None, 0) => write!(f, "{}", self.message),
+            (None, line) => write!(f, "{}:{}: {}", line, self.column, self.message),
+        }
+    }
+}
+
+/// A word of a master file, with escapes left in for whoever reads it to sort out.
+struct Token {
+    text: String,
+    /// Quoted words are always data, never names, directives, or numbers.
+    quoted: bool,
+    line: usize,
+    column: usize,
+}
+
+/// One record's (or directive's) worth of tokens, which parentheses can spread
+/// across several lines.
+struct Entry {
+    /// Entries starting with whitespace belong to the previous owner.
+    continues_owner: bool,
+    tokens: Vec<Token>,
+}
+
+/// Where the parser is in a master file, carried from one record to the next.
+struct ParseState {
+    origin: String,
+    last_owner: Option<String>,
+    default_ttl: Option<u32>,
+    last_ttl: Option<u32>,
+    last_class: Class,
+}
+
+impl ParseState {
+    #[allow(clippy::ptr_arg)]
+    fn new(origin: &String) -> ParseState {
+        ParseState {
+            origin: origin.trim_end_matches('.').to_string(),
+            last_owner: None,
+            default_ttl: None,
+            last_ttl: None,
+            last_class: Class::Internet,
+        }
+    }
+}
+
+/// Reads a master file (RFC 1035 section 5) into records.
+///
+/// `$ORIGIN`, `$TTL` and `$INCLUDE` are understood, as are relative names, `@`,
+/// parentheses, comments, TTLs with units like `1h30m`, and the RFC 3597 `\#`
+/// form for record types we can't read otherwise. Names in the records' data come
+/// out uncompressed, so the records can go straight into a packet.
+pub fn read_zone_file(path: &String, origin: &String) -> Result<Vec<Resource>, ZoneError> {
+    let mut state = ParseState::new(origin);
+    let mut records = vec![];
+    read_file_into(path, &mut state, &mut records, 0)?;
+    Ok(records)
+}
+
+/// Like `read_zone_file`, for master file text that's already in hand. Any
+/// `$INCLUDE`s are found relative to the working directory.
+pub fn parse_zone(text: &str, origin: &String) -> Result<Vec<Resource>, ZoneError> {
+    let mut state = ParseState::new(origin);
+    let mut records = vec![];
+    parse_into(text, Path::new(""), &mut state, &mut records, 0)?;
+    Ok(records)
+}
+
+fn read_file_into(path: &String, state: &mut ParseState, records: &mut Vec<Resource>, depth: usize) -> Result<(), ZoneError> {
+    let text = fs::read_to_string(path).map_err(|err| ZoneError::new(0, 0, err.to_string()).in_file(path))?;
+    let directory = Path::new(path).parent().unwrap_or(Path::new(""));
+    parse_into(&text, directory, state, records, depth).map_err(|err| err.in_file(path))
+}
+
+fn parse_into(text: &str, directory: &Path, state: &mut ParseState, records: &mut Vec<Resource>, depth: usize) -> Result<(), ZoneError> {
+    for entry in tokenize(text)? {
+        let first = &entry.tokens[0];
+        if first.quoted || !first.text.starts_with('$') {
+            records.push(parse_record(&entry, state)?);
+            continue;
+        }
+
+        let mut fields = Fields::new(&entry.tokens[1..], first);
+        match first.text.as_str() {
+            "$ORIGIN" => {
+                state.origin = fields.name(&state.origin)?;
+                fields.finish()?;
+            },
+            "$TTL" => {
+                state.default_ttl = Some(fields.ttl()?);
+                fields.finish()?;
+            },
+            "$INCLUDE" => {
+                if depth >= MAX_INCLUDE_DEPTH {
+                    return Err(ZoneError::at(first, String::from("$INCLUDEs nested too deeply")));
+                }
+
+                let file = fields.next("file name")?.text.clone();
+                let origin = match fields.is_done() {
+                    true => None,
+                    false => Some(fields.name(&state.origin)?),
+                };
+                fields.finish()?;
+
+                // Included files are found relative to the file including them.
+                let include_path = directory.join(file).to_string_lossy().to_string();
+
+                // The included file gets its own origin, and ours comes back after it.
+                let saved_origin = state.origin.clone();
+                if let Some(origin) = origin {
+                    state.origin = origin;
+                }
+
+                read_file_into(&include_path, state, records, depth + 1)?;
+                state.origin = saved_origin;
+            },
+            directive => return Err(ZoneError::at(first, format!("Unsupported directive {}", directive))),
+        }
+    }
+
+    Ok(())
+}
+
+/// Splits a master file into entries, dealing with comments, quotes, and
+/// parentheses along the way.
+fn tokenize(text: &str) -> Result<Vec<Entry>, ZoneError> {
+    let mut entries = vec![];
+    let mut chars = text.chars().peekable();
+
+    let mut line = 1;
+    let mut column = 0;
+    let mut entry: Option<Entry> = None;
+    let mut line_indented = text.starts_with([' ', '\t']);
+    let mut parens = vec![];
+
+    while let Some(character) = chars.next() {
+        column += 1;
+
+        match character {
+            '\n' => {
+                line += 1;
+                column = 0;
+                line_indented = chars.peek().map(|next| *next == ' ' || *next == '\t').unwrap_or(false);
+                if parens.is_empty() {
+                    entries.extend(entry.take());
+                }
+            },
+            ';' => {
+                while chars.peek().map(|next| *next != '\n').unwrap_or(false) {
+                    chars.next();
+                }
+            },
+            '(' => parens.push((line, column)),
+            ')' => {
+                if parens.pop().is_none() {
+                    return Err(ZoneError::new(line, column, String::from("Unbalanced )")));
+                }
+            },
+            character if character.is_whitespace() => {},
+            _ => {
+                let mut token = Token { text: String::new(), quoted: character == '"', line, column };
+                if !token.quoted {
+                    token.text.push(character);
+                }
+
+                let mut escaped = character == '\\';
+                loop {
+                    let next = match chars.peek() {
+                        Some(next) => *next,
+                        None if token.quoted => return Err(ZoneError::at(&token, String::from("Unterminated quote"))),
+                        None => break,
+                    };
+
+                    if escaped {
+                        escaped = false;
+                    } else if next == '\\' {
+                        escaped = true;
+                    } else if token.quoted && next == '"' {
+                        chars.next();
+                        column += 1;
+                        break;
+                    } else if !token.quoted && (next.is_whitespace() || "();\"".contains(next)) {
+                        break;
+                    } else if token.quoted && next == '\n' {
+                        return Err(ZoneError::at(&token, String::from("Unterminated quote")));
+                    }
+
+                    token.text.push(next);
+                    chars.next();
+                    column += 1;
+                }
+
+                entry.get_or_insert_with(|| Entry {
+                    continues_owner: line_indented,
+                    tokens: vec![],
+                }).tokens.push(token);
+            },
+        }
+    }
+
+    if let Some((line, column)) = parens.first() {
+        return Err(ZoneError::new(*line, *column, String::from("Unbalanced (")));
+    }
+
+    entries.extend(entry.take());
+    Ok(entries)
+}
+
+/// Splits one line into its fields the way a master file would, so quotes,
+/// escapes, and comments mean the same thing. Quoted fields keep their quotes,
+/// so the fields can be joined back up and parsed as a record.
+pub fn split_fields(line: &str) -> Result<Vec<String>, ZoneError> {
+    let tokens = tokenize(line)?.into_iter().flat_map(|entry| entry.tokens);
+    Ok(tokens.map(|token| match token.quoted {
+        true => format!("\"{}\"", token.text),
+        false => token.text,
+    }).collect())
+}
+
+/// Works through the fields of an entry, so errors can point at the one that's wrong.
+struct Fields<'a> {
+    tokens: &'a [Token],
+    index: usize,
+    /// Where to point when a field is missing altogether.
+    last: &'a Token,
+}
+
+impl<'a> Fields<'a> {
+    fn new(tokens: &'a [Token], before: &'a Token) -> Fields<'a> {
+        Fields {
+            tokens,
+            index: 0,
+            last: tokens.last().unwrap_or(before),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.index >= self.tokens.len()
+    }
+
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self, what: &str) -> Result<&'a Token, ZoneError> {
+        let token = self.tokens.get(self.index).ok_or(ZoneError::at(self.last, format!("Missing {}", what)))?;
+        self.index += 1;
+        Ok(token)
+    }
+
+    fn rest(&mut self) -> &'a [Token] {
+        let rest = &self.tokens[self.index.min(self.tokens.len())..];
+        self.index = self.tokens.len();
+        rest
+    }
+
+    fn finish(&self) -> Result<(), ZoneError> {
+        match self.peek() {
+            Some(extra) => Err(ZoneError::at(extra, format!("Unexpected {}", extra.text))),
+            None => Ok(()),
+        }
+    }
+
+    fn parse<T>(&mut self, what: &str, parser: impl FnOnce(&str) -> Result<T, String>) -> Result<T, ZoneError> {
+        let token = self.next(what)?;
+        parser(&token.text).map_err(|err| ZoneError::at(token, err))
+    }
+
+    fn name(&mut self, origin: &String) -> Result<String, ZoneError> {
+        self.parse("name", |text| parse_name(text, origin))
+    }
+
+    fn ttl(&mut self) -> Result<u32, ZoneError> {
+        self.parse("TTL", parse_ttl)
+    }
+
+    fn number<T: std::str::FromStr>(&mut self, what: &str) -> Result<T, ZoneError> {
+        self.parse(what, |text| text.parse().map_err(|_| format!("Bad {} {}", what, text)))
+    }
+
+    fn push_name(&mut self, data: &mut Vec<u8>, origin: &String) -> Result<(), ZoneError> {
+        data.append(&mut str_domain_to_dns_domain(&self.name(origin)?));
+        Ok(())
+    }
+
+    fn push_u16(&mut self, data: &mut Vec<u8>, what: &str) -> Result<(), ZoneError> {
+        data.extend_from_slice(&self.number::<u16>(what)?.to_be_bytes());
+        Ok(())
+    }
+
+    fn push_character_string(&mut self, data: &mut Vec<u8>, what: &str) -> Result<(), ZoneError> {
+        let bytes = self.parse(what, parse_character_string)?;
+        data.push(bytes.len() as u8);
+        data.extend(bytes);
+        Ok(())
+    }
+
+    /// Reads the rest of the fields as one run of hex digits.
+    fn hex(&mut self) -> Result<Vec<u8>, ZoneError> {
+        let rest = self.rest();
+        let hex: String = rest.iter().map(|token| token.text.as_str()).collect();
+        parse_hex(&hex).map_err(|err| ZoneError::at(rest.first().unwrap_or(self.last), err))
+    }
+
+    /// Reads an NSEC3 salt, which is hex, or a dash when there isn't one.
+    fn salt(&mut self) -> Result<Vec<u8>, ZoneError> {
+        self.parse("salt", |text| if text == "-" { Ok(vec![]) } else { parse_hex(text) })
+    }
+
+    /// Reads the rest of the fields as a list of types, the way NSEC records have them.
+    fn types(&mut self) -> Result<Vec<Type>, ZoneError> {
+        self.rest().iter()
+            .map(|token| parse_type(&token.text).ok_or(ZoneError::at(token, format!("Unknown type {}", token.text))))
+            .collect()
+    }
+
+    /// Reads the rest of the fields as one run of base64, which is often split up
+    /// over several lines.
+    fn base64(&mut self) -> Result<Vec<u8>, ZoneError> {
+        let rest = self.rest();
+        let text: String = rest.iter().map(|token| token.text.as_str()).collect();
+        decode(&text).map_err(|_| ZoneError::at(rest.first().unwrap_or(self.last), format!("Bad base64 {}", text)))
+    }
+}
+
+/// Turns a name as written in a master file into the form the rest of the crate
+/// uses: escapes decoded, relative names completed, and no trailing dot.
+#[allow(clippy::ptr_arg)]
+pub fn parse_name(text: &str, origin: &String) -> Result<String, String> {
+    if text == "@" {
+        return Ok(origin.clone());
+    }
+
+    let mut labels = vec![String::new()];
+    let mut characters = text.chars();
+    let mut absolute = false;
+
+    while let Some(character) = characters.next() {
+        absolute = false;
+        match character {
+            '.' => {
+                if labels.last().map(|label| label.is_empty()).unwrap_or(false) && text != "." {
+                    return Err(format!("Empty label in {}", text));
+                }
+                labels.push(String::new());
+                absolute = true;
+            },
+            '\\' => {
+                let bytes = decode_escape(&mut characters).ok_or(format!("Bad escape in {}", text))?;
+                if bytes == [b'.'] {
+                    return Err(format!("Dots inside labels aren't supported: {}", text));
+                }
+                labels.last_mut().unwrap().extend(bytes.iter().map(|byte| *byte as char));
+            },
+            character if character.is_ascii() => labels.last_mut().unwrap().push(character),
+            character => {
+                // Names hold one character per byte, so anything else written out
+                // literally becomes its UTF-8 bytes, the same as in a character-string.
+                let mut buffer = [0; 4];
+                let label = labels.last_mut().unwrap();
+                label.extend(character.encode_utf8(&mut buffer).bytes().map(|byte| byte as char));
+            },
+        }
+    }
+
+    labels.retain(|label| !label.is_empty());
+    if labels.iter().any(|label| label.chars().count() > 63) {
+        return Err(format!("Label too long in {}", text));
+    }
+
+    let mut name = labels.join(".");
+    if !absolute && !origin.is_empty() {
+        if !name.is_empty() {
+            name.push('.');
+        }
+        name.push_str(origin);
+    }
+
+    if name.chars().count() > 253 {
+        return Err(format!("Name too long: {}", name));
+    }
+
+    Ok(name)
+}
+
+/// Reads what comes after a backslash: three decimal digits, which are one byte,
+/// or one literal character, which is its UTF-8 bytes.
+fn decode_escape(characters: &mut std::str::Chars) -> Option<Vec<u8>> {
+    let first = characters.next()?;
+    if !first.is_ascii_digit() {
+        let mut buffer = [0; 4];
+        return Some(first.encode_utf8(&mut buffer).as_bytes().to_vec());
+    }
+
+    let digits: String = [Some(first), characters.next(), characters.next()].iter().flatten().collect();
+    let value: u32 = digits.parse().ok().filter(|_| digits.len() == 3)?;
+    if value > 255 {
+        return None;
+    }
+
+    Some(vec![value as u8])
+}
+
+/// Reads a TTL, either plain seconds or BIND style with units, like `1w2d` or `1h30m`.
+pub fn parse_ttl(text: &str) -> Result<u32, String> {
+    if let Ok(seconds) = text.parse() {
+        return Ok(seconds);
+    }
+
+    let mut total: u64 = 0;
+    let mut number = String::new();
+    for character in text.chars() {
+        if character.is_ascii_digit() {
+            number.push(character);
+            continue;
+        }
+
+        let unit = match character.to_ascii_lowercase() {
+            's' => 1,
+            'm' => 60,
+            'h' => 3600,
+            'd' => 86400,
+            'w' => 604800,
+            _ => return Err(format!("Bad TTL {}", text)),
+        };
+
+        let value: u64 = number.parse().map_err(|_| format!("Bad TTL {}", text))?;
+        total += value * unit;
+        number.clear();
+    }
+
+    if !number.is_empty() || total > u32::MAX as u64 {
+        return Err(format!("Bad TTL {}", text));
+    }
+
+    Ok(total as u32)
+}
+
+pub fn parse_class(text: &str) -> Option<Class> {
+    match text.to_uppercase().as_str() {
+        "IN" => Some(Class::Internet),
+        "CH" => Some(Class::Chaos),
+        "HS" => Some(Class::Hesiod),
+        "NONE" => Some(Class::QclassNone),
+        "ANY" => Some(Class::QclassAny),
+        other => Class::from_name(other),
+    }
+}
+
+pub fn parse_type(text: &str) -> Option<Type> {
+    match text.to_uppercase().as_str() {
+        "NULL" => Some(Type::NULLDATA),
+        "NSAP-PTR" => Some(Type::NsapPtr),
+        other => Type::from_name(other),
+    }
+}
+
+pub fn parse_hex(text: &str) -> Result<Vec<u8>, String> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return Err(format!("Bad hex {}", text));
+    }
+
+    (0..text.len()).step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).map_err(|_| format!("Bad hex {}", text)))
+        .collect()
+}
+
+/// Reads a character-string's bytes (without the length), escapes and all.
+fn parse_character_string(text: &str) -> Result<Vec<u8>, String> {
+    let bytes = parse_data_bytes(text)?;
+    if bytes.len() > 255 {
+        return Err(format!("Character string longer than 255 bytes: {}", text));
+    }
+
+    Ok(bytes)
+}
+
+/// Reads a field's bytes with its escapes decoded, however long it is.
+fn parse_data_bytes(text: &str) -> Result<Vec<u8>, String> {
+    let mut bytes = vec![];
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        match character {
+            '\\' => bytes.append(&mut decode_escape(&mut characters).ok_or(format!("Bad escape in {}", text))?),
+            _ => {
+                let mut buffer = [0; 4];
+                bytes.extend_from_slice(character.encode_utf8(&mut buffer).as_bytes());
+            },
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Reads one record: an owner (unless the entry continues the last one), a TTL and
+/// class in either order (both optional), the type, and the data.
+fn parse_record(entry: &Entry, state: &mut ParseState) -> Result<Resource, ZoneError> {
+    let first = &entry.tokens[0];
+    let mut fields = Fields::new(&entry.tokens, first);
+
+    let owner = if entry.continues_owner {
+        state.last_owner.clone().ok_or(ZoneError::at(first, String::from("No owner for this record")))?
+    } else {
+        fields.name(&state.origin)?
+    };
+
+    let mut ttl = None;
+    let mut class = None;
+    for _ in 0..2 {
+        let token = match fields.peek() {
+            Some(token) if !token.quoted => token,
+            _ => break,
+        };
+
+        if ttl.is_none() && token.text.starts_with(|character: char| character.is_ascii_digit()) {
+            ttl = Some(fields.ttl()?);
+        } else if class.is_none() && parse_type(&token.text).is_none() && parse_class(&token.text).is_some() {
+            class = parse_class(&fields.next("class")?.text);
+        } else {
+            break;
+        }
+    }
+
+    let type_token = fields.next("record type")?;
+    let rtype = parse_type(&type_token.text).ok_or(ZoneError::at(type_token, format!("Unknown record type {}", type_token.text)))?;
+    let data = parse_rdata(rtype, &mut fields, &state.origin)?;
+
+    let ttl = match ttl.or(state.default_ttl).or(state.last_ttl) {
+        Some(ttl) => ttl,
+        // RFC 2308 says an SOA with no TTL in sight goes by its own minimum.
+        None if rtype == Type::SOA && data.len() >= 4 => get_u32(&data, data.len() - 4),
+        None => return Err(ZoneError::at(first, String::from("No TTL given, and no $TTL to fall back on"))),
+    };
+
+    let rclass = class.unwrap_or(state.last_class);
+
+    state.last_owner = Some(owner.clone());
+    state.last_ttl = Some(ttl);
+    state.last_class = rclass;
+
+    Ok(Resource {
+        label: Label::Domain(owner),
+        rtype,
+        rclass,
+        ttl,
+        length: data.len() as u16,
+        data,
+    })
+}
+
+/// Reads a record's data into wire format.
+fn parse_rdata(rtype: Type, fields: &mut Fields, origin: &String) -> Result<Vec<u8>, ZoneError> {
+    if fields.peek().map(|token| token.text == "\\#" && !token.quoted).unwrap_or(false) {
+        return parse_generic_rdata(fields);
+    }
+
+    let start = fields.peek().unwrap_or(fields.last);
+    let mut data = vec![];
+
+    match rtype {
+        Type::A => {
+            let address: Ipv4Addr = fields.number("IPv4 address")?;
+            data.extend_from_slice(&address.octets());
+        },
+        Type::AAAA => {
+            let address: Ipv6Addr = fields.number("IPv6 address")?;
+            data.extend_from_slice(&address.octets());
+        },
+        Type::NS | Type::CNAME | Type::PTR | Type::DNAME | Type::MB | Type::MD | Type::MF | Type::MG | Type::MR => {
+            fields.push_name(&mut data, origin)?;
+        },
+        Type::MX | Type::AFSDB | Type::RT | Type::KX => {
+            fields.push_u16(&mut data, "preference")?;
+            fields.push_name(&mut data, origin)?;
+        },
+        Type::SOA => {
+            fields.push_name(&mut data, origin)?;
+            fields.push_name(&mut data, origin)?;
+            data.extend_from_slice(&fields.number::<u32>("serial")?.to_be_bytes());
+            for _ in 0..4 {
+                data.extend_from_slice(&fields.ttl()?.to_be_bytes());
+            }
+        },
+        Type::TXT | Type::SPF => {
+            fields.push_character_string(&mut data, "text")?;
+            while !fields.is_done() {
+                fields.push_character_string(&mut data, "text")?;
+            }
+        },
+        Type::HINFO => {
+            fields.push_character_string(&mut data, "CPU")?;
+            fields.push_character_string(&mut data, "OS")?;
+        },
+        Type::SRV => {
+            fields.push_u16(&mut data, "priority")?;
+            fields.push_u16(&mut data, "weight")?;
+            fields.push_u16(&mut data, "port")?;
+            fields.push_name(&mut data, origin)?;
+        },
+        Type::CAA => {
+            data.push(fields.number("CAA flags")?);
+            fields.push_character_string(&mut data, "CAA tag")?;
+            // The value isn't a character-string on the wire, just the rest of the data.
+            data.extend(fields.parse("CAA value", parse_data_bytes)?);
+        },
+        Type::SSHFP => {
+            data.push(fields.number("SSHFP algorithm")?);
+            data.push(fields.number("SSHFP type")?);
+            data.extend(fields.hex()?);
+        },
+        Type::DNSKEY | Type::CDNSKEY | Type::KEY => {
+            data.extend(Dnskey {
+                flags: fields.number("flags")?,
+                protocol: fields.number("protocol")?,
+                algorithm: fields.number("algorithm")?,
+                public_key: fields.base64()?,
+            }.to_rdata());
+        },
+        Type::DS | Type::CDS => {
+            data.extend(Ds {
+                key_tag: fields.number("key tag")?,
+                algorithm: fields.number("algorithm")?,
+                digest_type: fields.number("digest type")?,
+                digest: fields.hex()?,
+            }.to_rdata());
+        },
+        Type::RRSIG | Type::SIG => {
+            data.extend(Rrsig {
+                type_covered: fields.parse("type covered", |text| parse_type(text).ok_or(format!("Unknown type {}", text)))?,
+                algorithm: fields.number("algorithm")?,
+                labels: fields.number("labels")?,
+                original_ttl: fields.ttl()?,
+                expiration: fields.parse("expiration", parse_timestamp)?,
+                inception: fields.parse("inception", parse_timestamp)?,
+                key_tag: fields.number("key tag")?,
+                signer: fields.name(origin)?,
+                signature: fields.base64()?,
+            }.to_rdata());
+        },
+        Type::NSEC => {
+            data.extend(Nsec {
+                next: fields.name(origin)?,
+                types: fields.types()?,
+            }.to_rdata());
+        },
+        Type::NSEC3 => {
+            data.extend(Nsec3 {
+                hash_algorithm: fields.number("hash algorithm")?,
+                flags: fields.number("flags")?,
+                iterations: fields.number("iterations")?,
+                salt: fields.salt()?,
+                next_hashed: fields.parse("next hashed owner", base32hex_decode)?,
+                types: fields.types()?,
+            }.to_rdata());
+        },
+        Type::NSEC3PARAM => {
+            data.extend(Nsec3Param {
+                hash_algorithm: fields.number("hash algorithm")?,
+                flags: fields.number("flags")?,
+                iterations: fields.number("iterations")?,
+                salt: fields.salt()?,
+            }.to_rdata());
+        },
+        _ => return Err(ZoneError::at(start, format!("Can't read {} records yet. Use the \\# form for them", rtype))),
+    }
+
+    fields.finish()?;
+
+    if data.len() > u16::MAX as usize {
+        return Err(ZoneError::at(start, String::from("Record data too long")));
+    }
+
+    Ok(data)
+}
+
+/// Reads RFC 3597 unknown record data: `\# length hex...`.
+fn parse_generic_rdata(fields: &mut Fields) -> Result<Vec<u8>, ZoneError> {
+    let marker = fields.next("\\#")?;
+    let length: usize = fields.number("length")?;
+    let data = if length == 0 { vec![] } else { fields.hex()? };
+
+    if data.len() != length {
+        return Err(ZoneError::at(marker, format!("\\# says {} bytes, but there are {}", length, data.len())));
+    }
+
+    fields.finish()?;
+    Ok(data)
+}
+
+/// Writes records out as a master file, one per line, in canonical presentation
+/// format: absolute lowercase names, TTLs in seconds, and mnemonics for classes and
+/// types. Data we don't know how to present comes out in the RFC 3597 `\#` form.
+pub fn write_zone(records: &Vec<Resource>) -> String {
+    let mut text = String::new();
+    for record in records {
+        text.push_str(&write_record(record));
+        text.push('\n');
+    }
+
+    text
+}
+
+/// Saves records to a master file. They're written off to the side and moved into
+/// place, so a crash halfway through doesn't leave half a zone behind.
+pub fn save_zone(path: &String, records: &Vec<Resource>) -> io::Result<()> {
+    let temporary = format!("{}.tmp", path);
+    fs::write(&temporary, write_zone(records))?;
+    fs::rename(&temporary, path)
+}
+
+/// Writes the answers of a packet as master file lines.
+pub fn write_answers(packet: &DecomposedPacket) -> String {
+    write_zone(&packet.answers)
+}
+
+pub fn write_record(record: &Resource) -> String {
+    let owner = match &record.label {
+        Label::Domain(name) => write_name(name),
+        Label::Pointer(_) => record.label.to_string(),
+    };
+
+    format!("{} {} {} {} {}", owner, record.ttl, write_class(record.rclass), write_type(record.rtype), write_rdata(record.rtype, &record.data))
+}
+
+/// Writes a name as an absolute, lowercase master file name, escaping anything
+/// that would otherwise mean something else.
+pub fn write_name(name: &str) -> String {
+    let name = name.trim_end_matches('.');
+    if name.is_empty() {
+        return String::from(".");
+    }
+
+    let mut text = String::new();
+    for character in name.to_ascii_lowercase().chars() {
+        match character {
+            '"' | '\\' | ';' | '(' | ')' | '@' | '$' => {
+                text.push('\\');
+                text.push(character);
+            },
+            character if (character as u32) <= 0x20 || (character as u32) >= 0x7F => {
+                text.push_str(&format!("\\{:03}", character as u32 & 0xFF));
+            },
+            character => text.push(character),
+        }
+    }
+
+    text.push('.');
+    text
+}
+
+pub fn write_class(class: Class) -> String {
+    match class {
+        Class::Internet => String::from("IN"),
+        Class::Chaos => String::from("CH"),
+        Class::Hesiod => String::from("HS"),
+        Class::QclassNone => String::from("NONE"),
+        Class::QclassAny => String::from("ANY"),
+        Class::Unknown(_) => class.to_string(),
+    }
+}
+
+pub fn write_type(rtype: Type) -> String {
+    match rtype {
+        Type::NULLDATA => String::from("NULL"),
+        Type::NsapPtr => String::from("NSAP-PTR"),
+        _ => rtype.to_string(),
+    }
+}
+
+/// Writes a record's data the way a master file would have it.
+pub fn write_rdata(rtype: Type, data: &Vec<u8>) -> String {
+    present_rdata(rtype, data).unwrap_or_else(|| write_generic_rdata(data))
+}
+
+#[allow(clippy::ptr_arg)]
+fn write_generic_rdata(data: &Vec<u8>) -> String {
+    if data.is_empty() {
+        return String::from("\\# 0");
+    }
+
+    format!("\\# {} {}", data.len(), write_hex(data))
+}
+
+pub fn write_hex(data: &[u8]) -> String {
+    data.iter().map(|byte| format!("{:02X}", byte)).collect()
+}
+
+/// Presents data of the types we know. None if we don't know the type, or the data
+/// doesn't actually fit it.
+fn present_rdata(rtype: Type, data: &Vec<u8>) -> Option<String> {
+    let mut reader = RdataReader::new(data);
+
+    let fields = match rtype {
+        Type::A => vec![Ipv4Addr::from(<[u8; 4]>::try_from(reader.take(4)?).ok()?).to_string()],
+        Type::AAAA => vec![Ipv6Addr::from(<[u8; 16]>::try_from(reader.take(16)?).ok()?).to_string()],
+        Type::NS | Type::CNAME | Type::PTR | Type::DNAME | Type::MB | Type::MD | Type::MF | Type::MG | Type::MR => {
+            vec![reader.name()?]
+        },
+        Type::MX | Type::AFSDB | Type::RT | Type::KX => vec![reader.u16()?.to_string(), reader.name()?],
+        Type::SOA => {
+            let mut fields = vec![reader.name()?, reader.name()?];
+            for _ in 0..5 {
+                fields.push(reader.u32()?.to_string());
+            }
+            fields
+        },
+        Type::TXT | Type::SPF => {
+            let mut fields = vec![reader.character_string()?];
+            while !reader.is_done() {
+                fields.push(reader.character_string()?);
+            }
+            fields
+        },
+        Type::HINFO => vec![reader.character_string()?, reader.character_string()?],
+        Type::SRV => vec![reader.u16()?.to_string(), reader.u16()?.to_string(), reader.u16()?.to_string(), reader.name()?],
+        Type::CAA => {
+            let flags = reader.take(1)?[0];
+            let tag = reader.character_string()?;
+            let value = reader.rest();
+            vec![flags.to_string(), tag.trim_matches('"').to_string(), quote(value)]
+        },
+        Type::SSHFP => {
+            let algorithm = reader.take(1)?[0];
+            let fingerprint_type = reader.take(1)?[0];
+            vec![algorithm.to_string(), fingerprint_type.to_string(), write_hex(reader.rest())]
+        },
+        Type::DNSKEY | Type::CDNSKEY | Type::KEY | Type::DS | Type::CDS | Type::RRSIG | Type::SIG
+            | Type::NSEC | Type::NSEC3 | Type::NSEC3PARAM => return present_dnssec_rdata(rtype, data),
+        _ => return None,
+    };
+
+    if !reader.is_done() {
+        return None;
+    }
+
+    Some(fields.join(" "))
+}
+
+/// Reads the fields of record data one at a time, giving up if it runs short.
+pub struct RdataReader<'a> {
+    data: &'a Vec<u8>,
+    index: usize,
+}
+
+impl<'a> RdataReader<'a> {
+    pub fn new(data: &'a Vec<u8>) -> RdataReader<'a> {
+        RdataReader { data, index: 0 }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.index >= self.data.len()
+    }
+
+    pub fn take(&mut self, count: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.index..self.index + count)?;
+        self.index += count;
+        Some(bytes)
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let rest = &self.data[self.index.min(self.data.len())..];
+        self.index = self.data.len();
+        rest
+    }
+
+    pub fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    pub fn u16(&mut self) -> Option<u16> {
+        let bytes = self.take(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn u32(&mut self) -> Option<u32> {
+        let bytes = self.take(4)?;
+        Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a name as the rest of the crate has it, without a trailing dot.
+    pub fn domain(&mut self) -> Option<String> {
+        let (name, end) = read_name(self.data, self.index)?;
+        self.index = end;
+        Some(name)
+    }
+
+    /// Reads a name as a master file has it.
+    pub fn name(&mut self) -> Option<String> {
+        Some(write_name(&self.domain()?))
+    }
+
+    pub fn character_string(&mut self) -> Option<String> {
+        let length = self.u8()? as usize;
+        Some(quote(self.take(length)?))
+    }
+}
+
+/// Quotes bytes as a character-string, escaping anything unprintable.
+fn quote(bytes: &[u8]) -> String {
+    let mut text = String::from("\"");
+    for byte in bytes {
+        match byte {
+            b'"' | b'\\' => {
+                text.push('\\');
+                text.push(*byte as char);
+            },
+            0x20..=0x7E => text.push(*byte as char),
+            _ => text.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+
+    text.push('"');
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    fn origin() -> String {
+        String::from("example.test")
+    }
+
+    fn lines(records: &[Resource]) -> Vec<String> {
+        records.iter().map(write_record).collect()
+    }
+
+    /// Parses the text, writes it back out, and parses that again, which has to
+    /// come back the same. Hands back the records.
+    fn round_trip(text: &str) -> Vec<Resource> {
+        let records = parse_zone(text, &origin()).unwrap();
+        let written = write_zone(&records);
+        let reparsed = parse_zone(&written, &origin()).unwrap();
+        assert_eq!(lines(&reparsed), lines(&records));
+        assert_eq!(write_zone(&reparsed), written);
+        records
+    }
+
+    #[test]
+    fn origin_and_ttl_directives() {
+        let records = round_trip("$TTL 1h\nwww A 192.0.2.1\n$ORIGIN sub.example.test.\n$TTL 60\nmail A 192.0.2.2\n@ 30 TXT here\n");
+        assert_eq!(lines(&records), vec![
+            "www.example.test. 3600 IN A 192.0.2.1",
+            "mail.sub.example.test. 60 IN A 192.0.2.2",
+            "sub.example.test. 30 IN TXT \"here\"",
+        ]);
+    }
+
+    #[test]
+    fn parentheses_span_lines() {
+        let records = round_trip("$TTL 300\n@ SOA ns hostmaster (\n    7      ; serial\n    3600 600\n    86400 300 )\n  NS ns\n");
+        assert_eq!(lines(&records), vec![
+            "example.test. 300 IN SOA ns.example.test. hostmaster.example.test. 7 3600 600 86400 300",
+            "example.test. 300 IN NS ns.example.test.",
+        ]);
+    }
+
+    #[test]
+    fn escapes_survive() {
+        let records = round_trip("$TTL 300\na\\032b A 192.0.2.1\nweird\\(name TXT \"quote \\\" semicolon ; byte \\255\"\n");
+        assert!(matches!(&records[0].label, Label::Domain(name) if name == "a b.example.test"));
+        assert_eq!(records[1].data, b"\x1aquote \" semicolon ; byte \xff".to_vec());
+    }
+
+    #[test]
+    fn caa_values_can_be_long() {
+        let value = format!("https://example.test/{}", "x".repeat(300));
+        let records = round_trip(&format!("$TTL 300\n@ CAA 0 iodef \"{}\"\n@ CAA 128 issue \"ca.example; account=1\"\n", value));
+        assert_eq!(records[0].data, [&[0, 5][..], b"iodef", value.as_bytes()].concat());
+        assert_eq!(lines(&records)[1], "example.test. 300 IN CAA 128 issue \"ca.example; account=1\"");
+    }
+
+    #[test]
+    fn includes_are_relative_and_can_change_origin() {
+        let directory = std::env::temp_dir().join(format!("dns-hero-zone-test-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+        fs::write(directory.join("main.zone"), "$TTL 300\nwww A 192.0.2.1\n$INCLUDE hosts.zone sub\n$INCLUDE hosts.zone\nafter A 192.0.2.3\n").unwrap();
+        fs::write(directory.join("hosts.zone"), "host A 192.0.2.2\n").unwrap();
+
+        let records = read_zone_file(&directory.join("main.zone").to_string_lossy().to_string(), &origin());
+        fs::remove_dir_all(&directory).unwrap();
+
+        let records = records.unwrap();
+        assert_eq!(lines(&records), vec![
+            "www.example.test. 300 IN A 192.0.2.1",
+            "host.sub.example.test. 300 IN A 192.0.2.2",
+            "host.example.test. 300 IN A 192.0.2.2",
+            "after.example.test. 300 IN A 192.0.2.3",
+        ]);
+        assert_eq!(lines(&parse_zone(&write_zone(&records), &origin()).unwrap()), lines(&records));
+    }
+
+    #[test]
+    fn character_strings_still_have_a_limit() {
+        assert!(parse_zone(&format!("@ 300 TXT \"{}\"\n", "x".repeat(256)), &origin()).is_err());
+    }
+}
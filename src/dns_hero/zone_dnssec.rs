@@ -0,0 +1,243 @@
+use std::collections::HashSet;
+
+use super::authoritative::{ owner_name, parent_name, wildcard_at, with_owner, Zone, ZoneAnswer };
+use super::data::*;
+use super::dnssec::*;
+use super::enums::*;
+use super::util::*;
+
+/// Adds what an EDNS client gets along with an answer from one of our zones: the
+/// DNSSEC records, if it set the DO bit, and an OPT echoing whether it got them.
+pub fn add_edns_records(zone: &Zone, query: &DecomposedPacket, question: &Question, answer: &mut ZoneAnswer) {
+    let dnssec_ok = dnssec_ok(query);
+    if dnssec_ok {
+        zone.add_dnssec(question, answer);
+    }
+
+    if has_edns(query) {
+        answer.additional_records.push(opt_record(dnssec_ok));
+    }
+}
+
+impl Zone {
+    /// Whether the zone has been signed, going by its SOA's RRSIGs.
+    pub fn is_signed(&self) -> bool {
+        !self.signatures(&self.origin, Type::SOA).is_empty()
+    }
+
+    /// Adds what a client that asked for DNSSEC needs to check an answer from a
+    /// signed zone (RFC 4035 section 3.1): the RRSIGs over each RRset, the DS (or
+    /// proof there isn't one) on referrals, and the NSEC or NSEC3 records that show
+    /// anything missing really is. Unsigned zones have none of that to give.
+    pub fn add_dnssec(&self, question: &Question, answer: &mut ZoneAnswer) {
+        if !self.is_signed() {
+            return;
+        }
+
+        let mut proof = vec![];
+        answer.answers = self.sign_records(&answer.answers, &mut proof);
+        answer.authorities = self.sign_records(&answer.authorities, &mut proof);
+
+        // Where the answer ended up, after any CNAMEs.
+        let mut target = match &question.label {
+            Label::Domain(name) => name.trim_end_matches('.').to_lowercase(),
+            Label::Pointer(_) => return,
+        };
+        for record in &answer.answers {
+            if record.rtype == Type::CNAME && owner_name(record).as_ref() == Some(&target) {
+                if let Some((next, _)) = read_name(&record.data, 0) {
+                    target = next.to_lowercase();
+                }
+            }
+        }
+
+        let is_denial = answer.authorities.iter().any(|record| record.rtype == Type::SOA);
+        if !answer.authoritative {
+            let cut = answer.authorities.iter().find(|record| record.rtype == Type::NS).and_then(owner_name);
+            if let Some(cut) = cut {
+                let ds = self.rrset(&cut, Type::DS);
+                if ds.is_empty() {
+                    proof.extend(self.denial(&cut, Denial::NoData));
+                } else {
+                    answer.authorities.extend(ds);
+                    answer.authorities.extend(self.signatures(&cut, Type::DS));
+                }
+            }
+        } else if let ResponseCode::NXDomain = answer.response_code {
+            proof.extend(self.denial(&target, Denial::NoName));
+        } else if is_denial {
+            let denial = if self.has_name(&target) { Denial::NoData } else { Denial::WildcardNoData };
+            proof.extend(self.denial(&target, denial));
+        }
+
+        for record in proof {
+            let is_new = !answer.authorities.iter().any(|existing| existing.rtype == record.rtype && existing.data == record.data && existing.label.eq_ignore_case(&record.label));
+            if is_new {
+                answer.authorities.push(record);
+            }
+        }
+    }
+
+    /// The RRSIGs at a name over one of its RRsets.
+    fn signatures(&self, name: &str, rtype: Type) -> Vec<Resource> {
+        self.rrset(name, Type::RRSIG).into_iter()
+            .filter(|record| Rrsig::parse(&record.data).map(|rrsig| rrsig.type_covered == rtype).unwrap_or(false))
+            .collect()
+    }
+
+    /// Adds the RRSIGs over each RRset in some records. Records made up from a
+    /// wildcard get the wildcard's RRSIGs, and there has to be proof that nothing
+    /// closer matched, which goes in `proof`.
+    fn sign_records(&self, records: &Vec<Resource>, proof: &mut Vec<Resource>) -> Vec<Resource> {
+        let mut signed = records.clone();
+        let mut seen = HashSet::new();
+
+        for record in records {
+            let owner = match owner_name(record) {
+                Some(owner) => owner,
+                None => continue,
+            };
+
+            if !seen.insert((owner.clone(), record.rtype.to_u16())) {
+                continue;
+            }
+
+            let source = if self.has_name(&owner) {
+                owner
+            } else {
+                match self.closest_encloser(&owner) {
+                    Some(encloser) => {
+                        proof.extend(self.denial(&owner, Denial::WildcardAnswer));
+                        wildcard_at(&encloser)
+                    },
+                    None => continue,
+                }
+            };
+
+            signed.extend(self.signatures(&source, record.rtype).iter().map(|rrsig| with_owner(rrsig, &record.label)));
+        }
+
+        signed
+    }
+
+    /// The NSEC or NSEC3 records, with their RRSIGs, that prove something about a
+    /// name that isn't there.
+    fn denial(&self, name: &str, denial: Denial) -> Vec<Resource> {
+        let records = match self.rrset(&self.origin, Type::NSEC3PARAM).first().and_then(|record| Nsec3Param::parse(&record.data)) {
+            Some(parameters) => self.nsec3_denial(name, denial, &parameters),
+            None => self.nsec_denial(name, denial),
+        };
+
+        let mut signed = vec![];
+        for record in records {
+            let owner = owner_name(&record).unwrap_or_default();
+            signed.extend(self.signatures(&owner, record.rtype));
+            signed.push(record);
+        }
+
+        signed
+    }
+
+    /// NSECs that prove a denial (RFC 4035 section 3.1.3). The one at or covering
+    /// the name shows whether it exists and with what types, and the one at or
+    /// covering the wildcard shows whether a wildcard could have answered instead.
+    fn nsec_denial(&self, name: &str, denial: Denial) -> Vec<Resource> {
+        let mut names = vec![name.to_string()];
+        if let Denial::NoName | Denial::WildcardNoData = denial {
+            names.extend(self.closest_encloser(name).map(|encloser| wildcard_at(&encloser)));
+        }
+
+        let mut nsecs: Vec<Resource> = vec![];
+        for name in names {
+            let nsec = self.records_of_type(Type::NSEC).into_iter()
+                .find(|record| {
+                    let owner = owner_name(record).unwrap_or_default();
+                    owner == name || Nsec::parse(&record.data).map(|nsec| nsec_covers(&owner, &nsec.next, &name)).unwrap_or(false)
+                });
+
+            if let Some(nsec) = nsec {
+                if !nsecs.iter().any(|existing| existing.label == nsec.label) {
+                    nsecs.push(nsec.clone());
+                }
+            }
+        }
+
+        nsecs
+    }
+
+    /// NSEC3s that prove a denial (RFC 5155 section 7.2): one matching the closest
+    /// encloser, one covering the next closer name, and one for the wildcard, as
+    /// each kind of denial needs.
+    fn nsec3_denial(&self, name: &str, denial: Denial, parameters: &Nsec3Param) -> Vec<Resource> {
+        let find = |name: &str, exact: bool| {
+            let hash = nsec3_hash(&name.to_string(), &parameters.salt, parameters.iterations);
+            self.records_of_type(Type::NSEC3).into_iter()
+                .find(|record| {
+                    let owner = owner_name(record).unwrap_or_default();
+                    let owner_hash = match base32hex_decode(owner.split('.').next().unwrap_or("")) {
+                        Ok(owner_hash) => owner_hash,
+                        Err(_) => return false,
+                    };
+
+                    match Nsec3::parse(&record.data) {
+                        Some(_) if exact => owner_hash == hash,
+                        Some(nsec3) => nsec3_covers(&owner_hash, &nsec3.next_hashed, &hash),
+                        None => false,
+                    }
+                })
+                .cloned()
+        };
+
+        if let Denial::NoData = denial {
+            if let Some(matching) = find(name, true) {
+                return vec![matching];
+            }
+        }
+
+        // The closest ancestor with an NSEC3, and the name one label below it on
+        // the way down to this one.
+        let mut next_closer = name.to_string();
+        let mut encloser = parent_name(name);
+        while find(&encloser, true).is_none() {
+            if encloser == self.origin || encloser.is_empty() {
+                return vec![];
+            }
+            next_closer = encloser.clone();
+            encloser = parent_name(&encloser);
+        }
+
+        let wanted = match denial {
+            Denial::WildcardAnswer => vec![(next_closer, false)],
+            Denial::NoData => vec![(encloser.clone(), true), (next_closer, false)],
+            Denial::NoName => vec![(encloser.clone(), true), (next_closer, false), (wildcard_at(&encloser), false)],
+            Denial::WildcardNoData => vec![(encloser.clone(), true), (next_closer, false), (wildcard_at(&encloser), true)],
+        };
+
+        let mut nsec3s: Vec<Resource> = vec![];
+        for (name, exact) in wanted {
+            if let Some(nsec3) = find(&name, exact) {
+                if !nsec3s.iter().any(|existing| existing.label == nsec3.label) {
+                    nsec3s.push(nsec3);
+                }
+            }
+        }
+
+        nsec3s
+    }
+}
+
+
+/// What a signed zone has to prove when it doesn't have what was asked for.
+#[derive(Copy, Clone)]
+enum Denial {
+    /// The name doesn't exist, and there's no wildcard that could stand in for it.
+    NoName,
+    /// The name exists, but not with that type.
+    NoData,
+    /// The name doesn't exist, but a wildcard answered for it.
+    WildcardAnswer,
+    /// The name doesn't exist, and the wildcard that stood in for it doesn't have
+    /// that type either.
+    WildcardNoData,
+}
+
@@ -0,0 +1,9 @@
+//! The pieces of dns-hero, for the binary and anyone else who wants them. Master
+//! files can be read with `parse_zone` or `read_zone_file`, written back out with
+//! `write_zone`, and record data picked apart with `RdataReader`.
+
+mod dns_hero;
+
+pub use crate::dns_hero::*;
+pub use crate::dns_hero::data::Resource;
+pub use crate::dns_hero::zone::{ parse_zone, read_zone_file, write_zone, RdataReader, ZoneError };
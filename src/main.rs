@@ -1,13 +1,15 @@
 
 use std::env;
 
-mod dns_hero;
 use dns_hero::commands::*;
 
 const MSG_HELP: &str = r#"Usage
     help     Print this help message
 
     daemon   Run the DNS daemon.
+             --config <path>      read settings from this file.
+             --cache-file <path>  keep the cache in this file
+                                  across restarts.
 
     b64      Create base64-wireformat query
              for the following arg.
@@ -21,7 +23,93 @@ const MSG_HELP: &str = r#"Usage
              --cb-family   use CleanBrowsing Family filter
              --cb-security use CleanBrowsing Security filter
              --cloudflare  use CloudFlare DNS
-             --google      use Google DNS"#;
+             --google      use Google DNS
+             --zone-format print the answers as
+                           master file lines.
+             --server <address[:port]>
+                           ask this server directly,
+                           over TCP.
+             --sig0 <file> sign the query with this
+                           SIG(0) key (needs --server).
+
+    zone     Check a zone file and print it back
+             in canonical form.
+             <file> <origin>  a file of - reads stdin.
+
+    transfer Transfer a zone from a server over TCP
+             and print it as a zone file.
+             <server[:port]> <zone>
+             --ixfr <file>    only fetch what's changed
+                              since this copy of the zone.
+             --output <file>  write the zone here instead.
+             --key <file>     sign the transfer with the
+                              TSIG key in this file.
+
+    update   Send a dynamic update to a server
+             over TCP.
+             <server[:port]> <zone> [<file>]
+             --key <file>     sign the update with the
+                              TSIG key in this file.
+             --sig0 <file>    sign the update with this
+                              SIG(0) key instead.
+                              the update commands, from
+                              the file or stdin:
+                              prereq yxdomain|nxdomain <name>
+                              prereq yxrrset <name> <type> [<data>]
+                              prereq nxrrset <name> <type>
+                              add <name> <ttl> <type> <data>
+                              delete <name> [<type> [<data>]]
+
+    tsig-keygen
+             Make a new TSIG key and print it
+             as a key file.
+             <name> [hmac-sha256|hmac-sha512]
+
+    keygen   Make a new key pair for SIG(0) and
+             save it as K<name>.+<alg>+<tag>.key
+             and .private. The .key file has the
+             KEY record to put in the zone.
+             <name> [ED25519|ECDSAP256SHA256|ECDSAP384SHA384]
+             --directory <dir>  save it here instead.
+             --zsk              make a zone signing key
+                                for DNSSEC instead, with a
+                                DNSKEY record.
+             --ksk              make a key signing key.
+
+    sign-zone
+             Sign a zone with DNSSEC and print it,
+             or re-sign one that was signed before,
+             keeping signatures that aren't due.
+             <file> <origin> <key file>...
+             --output <file>    write the zone here instead,
+                                and print the DS records
+                                for the parent.
+             --nsec3            deny with NSEC3, not NSEC.
+             --salt <hex|->     NSEC3 salt (none by default).
+             --iterations <n>   extra NSEC3 hash iterations
+                                (0 by default).
+             --opt-out          NSEC3 with opt-out, leaving
+                                unsigned delegations out.
+             --validity <time>  how long signatures last,
+                                like 30d (the default).
+             --refresh <time>   remake signatures with less
+                                than this left (7d).
+
+    dnssec-trace
+             Follow the chain of trust for a name
+             down from the root, printing each DS
+             and DNSKEY, which key signed what and
+             for how long, and where it breaks.
+             <name> [<type>]  A by default.
+             --server <address[:port]>
+                              ask this server over TCP,
+                              instead of Cloudflare.
+             --trust-anchor-file <file>
+                              start from the DS or DNSKEY
+                              records in this file instead
+                              of the root's keys.
+             Exits 0 when the answer is secure, 1 when
+             it's insecure, and 2 when the chain breaks."#;
 
 fn print_help() {
     println!("{}", MSG_HELP);
@@ -40,6 +128,13 @@ fn main() {
         "b64" => b64(&args),
         "explain" => explain(&args),
         "resolve" => resolve(&args),
+        "zone" => zone(&args),
+        "transfer" => transfer(&args),
+        "update" => update(&args),
+        "tsig-keygen" => tsig_keygen(&args),
+        "keygen" => keygen(&args),
+        "sign-zone" => sign_zone(&args),
+        "dnssec-trace" => dnssec_trace(&args),
         _ => print_help(),
     }
 }